@@ -0,0 +1,23 @@
+// 含有长常数列表的表达式：列表编译为单个节点，衡量编译与求值的开销
+use criterion::{Criterion, criterion_group, criterion_main};
+use oxidice::roll_with_seed;
+use std::hint::black_box;
+
+fn constant_list_expr(len: usize) -> String {
+    let items: Vec<String> = (1..=len).map(|i| i.to_string()).collect();
+    format!("max([{}], 1d20)", items.join(","))
+}
+
+fn bench_constant_list(c: &mut Criterion) {
+    let short = constant_list_expr(10);
+    let long = constant_list_expr(1000);
+    c.bench_function("max([1..10], 1d20)", |b| {
+        b.iter(|| roll_with_seed(black_box(&short), 42))
+    });
+    c.bench_function("max([1..1000], 1d20)", |b| {
+        b.iter(|| roll_with_seed(black_box(&long), 42))
+    });
+}
+
+criterion_group!(benches, bench_constant_list);
+criterion_main!(benches);
@@ -0,0 +1,16 @@
+// 常见的 XdY cs>=T 检定：投掷后立即计数成功，衡量骰池到成功池的转换开销
+use criterion::{Criterion, criterion_group, criterion_main};
+use oxidice::roll_with_seed;
+use std::hint::black_box;
+
+fn bench_count_successes(c: &mut Criterion) {
+    c.bench_function("10d10cs>=7", |b| {
+        b.iter(|| roll_with_seed(black_box("10d10cs>=7"), 42))
+    });
+    c.bench_function("500d10cs>=7", |b| {
+        b.iter(|| roll_with_seed(black_box("500d10cs>=7"), 42))
+    });
+}
+
+criterion_group!(benches, bench_count_successes);
+criterion_main!(benches);
@@ -1,5 +1,9 @@
 use crate::types::eval_graph::*;
 use crate::types::hir::*;
+use std::collections::HashMap;
+
+// 元素全为常数且不少于该长度的列表编译为单个 ConstantList 节点
+const CONSTANT_LIST_MIN_LEN: usize = 16;
 
 // 编译函数 HIR -> EvalGraph
 pub fn compile_hir_to_eval_graph(hir: HIR) -> EvalGraph {
@@ -9,11 +13,15 @@ pub fn compile_hir_to_eval_graph(hir: HIR) -> EvalGraph {
 
 struct Compiler {
     nodes: Vec<EvalNode>,
+    shared: HashMap<u32, NodeId>, // let share 绑定编号 -> 已编译的节点
 }
 
 impl Compiler {
     pub fn new() -> Self {
-        Self { nodes: Vec::new() }
+        Self {
+            nodes: Vec::new(),
+            shared: HashMap::new(),
+        }
     }
 
     // 入口函数，将 HIR 编译为 EvalGraph
@@ -74,6 +82,11 @@ impl Compiler {
                     let rid = self.compile_number(*r);
                     self.push(EvalNode::NumModulo(lid, rid))
                 }
+                NumberBinaryType::Compare(l, op, r) => {
+                    let lid = self.compile_number(*l);
+                    let rid = self.compile_number(*r);
+                    self.push(EvalNode::NumCompare(lid, op, rid))
+                }
             },
             NumberType::NumberFunction(func) => match func {
                 NumberFunctionType::Floor(n) => {
@@ -88,6 +101,10 @@ impl Compiler {
                     let id = self.compile_number(*n);
                     self.push(EvalNode::NumRound(id))
                 }
+                NumberFunctionType::RoundEven(n) => {
+                    let id = self.compile_number(*n);
+                    self.push(EvalNode::NumRoundEven(id))
+                }
                 NumberFunctionType::Abs(n) => {
                     let id = self.compile_number(*n);
                     self.push(EvalNode::NumAbs(id))
@@ -100,6 +117,16 @@ impl Compiler {
                     let id = self.compile_list(*list);
                     self.push(EvalNode::NumMin(id))
                 }
+                NumberFunctionType::MaxOr(list, default) => {
+                    let l = self.compile_list(*list);
+                    let d = self.compile_number(*default);
+                    self.push(EvalNode::NumMaxOr(l, d))
+                }
+                NumberFunctionType::MinOr(list, default) => {
+                    let l = self.compile_list(*list);
+                    let d = self.compile_number(*default);
+                    self.push(EvalNode::NumMinOr(l, d))
+                }
                 NumberFunctionType::Sum(list) => {
                     let id = self.compile_list(*list);
                     self.push(EvalNode::NumSum(id))
@@ -112,11 +139,38 @@ impl Compiler {
                     let id = self.compile_list(*list);
                     self.push(EvalNode::NumLen(id))
                 }
+                NumberFunctionType::HighDie(dice_pool) => {
+                    let id = self.compile_dice_pool(*dice_pool);
+                    self.push(EvalNode::DiceHighest(id))
+                }
+                NumberFunctionType::LowDie(dice_pool) => {
+                    let id = self.compile_dice_pool(*dice_pool);
+                    self.push(EvalNode::DiceLowest(id))
+                }
+                NumberFunctionType::Margin(rolled, target) => {
+                    let r = self.compile_number(*rolled);
+                    let t = self.compile_number(*target);
+                    self.push(EvalNode::NumMargin(r, t))
+                }
+                NumberFunctionType::GroupKh(groups, count) => {
+                    let l = self.compile_list(*groups);
+                    let c = self.compile_number(*count);
+                    self.push(EvalNode::NumGroupKh(l, c))
+                }
             },
             NumberType::Neg(n) => {
                 let nid = self.compile_number(*n);
                 self.push(EvalNode::NumNegate(nid))
             }
+            // 同一绑定的所有引用指向同一个节点，运行时只求值 (投掷) 一次
+            NumberType::Shared(key, n) => {
+                if let Some(id) = self.shared.get(&key) {
+                    return *id;
+                }
+                let id = self.compile_number(*n);
+                self.shared.insert(key, id);
+                id
+            }
         }
     }
 
@@ -125,6 +179,21 @@ impl Compiler {
     // ==========================================
     fn compile_list(&mut self, list: ListType) -> NodeId {
         match list {
+            ListType::Explicit(elements)
+                if elements.len() >= CONSTANT_LIST_MIN_LEN
+                    && elements
+                        .iter()
+                        .all(|e| matches!(e, NumberType::Constant(_))) =>
+            {
+                let values = elements
+                    .into_iter()
+                    .map(|e| match e {
+                        NumberType::Constant(v) => v,
+                        _ => unreachable!("Already checked all elements are constants"),
+                    })
+                    .collect();
+                self.push(EvalNode::ConstantList(values))
+            }
             ListType::Explicit(elements) => {
                 let ids = elements
                     .into_iter()
@@ -224,6 +293,14 @@ impl Compiler {
                     let lid = self.compile_list(*list);
                     self.push(EvalNode::ListSortDesc(lid))
                 }
+                ListFunctionType::FilterEven(list) => {
+                    let lid = self.compile_list(*list);
+                    self.push(EvalNode::ListFilterEven(lid))
+                }
+                ListFunctionType::FilterOdd(list) => {
+                    let lid = self.compile_list(*list);
+                    self.push(EvalNode::ListFilterOdd(lid))
+                }
                 ListFunctionType::ToListFromDicePool(dpool) => {
                     let dpid = self.compile_dice_pool(*dpool);
                     self.push(EvalNode::ListToListFromDicePool(dpid))
@@ -255,6 +332,11 @@ impl Compiler {
                 let c = self.compile_number(*count);
                 self.push(EvalNode::DiceFudge(c))
             }
+            DicePoolType::Percentile => self.push(EvalNode::DicePercentile),
+            DicePoolType::Labeled(count, faces) => {
+                let c = self.compile_number(*count);
+                self.push(EvalNode::DiceLabeled(c, faces))
+            }
             DicePoolType::Coin(count) => {
                 let c = self.compile_number(*count);
                 self.push(EvalNode::DiceCoin(c))
@@ -295,6 +377,22 @@ impl Compiler {
                 let l = limit.map(|x| self.compile_limit(x));
                 self.push(EvalNode::DiceExplode(source, p, l))
             }
+            DicePoolType::ExplodeOnce(pool, param, limit) => {
+                let source = self.compile_dice_pool(*pool);
+                let p = param.map(|x| self.compile_mod_param(x));
+                let l = limit.map(|x| self.compile_limit(x));
+                self.push(EvalNode::DiceExplodeOnce(source, p, l))
+            }
+            DicePoolType::ExplodeUntil(pool, op, value, quota) => {
+                let source = self.compile_dice_pool(*pool);
+                self.push(EvalNode::DiceExplodeUntil(source, op, value, quota))
+            }
+            DicePoolType::ExplodeMulti(pool, count, param, limit) => {
+                let source = self.compile_dice_pool(*pool);
+                let p = param.map(|x| self.compile_mod_param(x));
+                let l = limit.map(|x| self.compile_limit(x));
+                self.push(EvalNode::DiceExplodeMulti(source, count, p, l))
+            }
             DicePoolType::CompoundExplode(pool, param, limit) => {
                 let source = self.compile_dice_pool(*pool);
                 let p = param.map(|x| self.compile_mod_param(x));
@@ -307,11 +405,66 @@ impl Compiler {
                 let l = limit.map(|x| self.compile_limit(x));
                 self.push(EvalNode::DiceReroll(source, p, l))
             }
+            DicePoolType::RerollPool(pool, param, limit) => {
+                let source = self.compile_dice_pool(*pool);
+                let p = self.compile_mod_param(param);
+                let l = limit.map(|x| self.compile_limit(x));
+                self.push(EvalNode::DiceRerollPool(source, p, l))
+            }
             DicePoolType::SubtractFailures(pool, param) => {
                 let source = self.compile_dice_pool(*pool);
                 let p = self.compile_mod_param(param);
                 self.push(EvalNode::DiceSubtractFailures(source, p))
             }
+            DicePoolType::RerollMax(pool, param) => {
+                let source = self.compile_dice_pool(*pool);
+                let p = self.compile_mod_param(param);
+                self.push(EvalNode::DiceRerollMax(source, p))
+            }
+            DicePoolType::ExplodeIfTotal(pool, param) => {
+                let source = self.compile_dice_pool(*pool);
+                let p = self.compile_mod_param(param);
+                self.push(EvalNode::DiceExplodeIfTotal(source, p))
+            }
+            DicePoolType::Each(pool, op, value) => {
+                let source = self.compile_dice_pool(*pool);
+                let v = self.compile_number(*value);
+                self.push(EvalNode::DiceMapEach(source, op, v))
+            }
+            DicePoolType::FromSuccessPool(pool) => {
+                let source = self.compile_success_pool(*pool);
+                self.push(EvalNode::DiceFromSuccessPool(source))
+            }
+            DicePoolType::ConditionalDropHigh(pool, count, param) => {
+                let source = self.compile_dice_pool(*pool);
+                let c = self.compile_number(*count);
+                let p = self.compile_mod_param(param);
+                self.push(EvalNode::DiceConditionalDropHigh(source, c, p))
+            }
+            DicePoolType::ConditionalDropLow(pool, count, param) => {
+                let source = self.compile_dice_pool(*pool);
+                let c = self.compile_number(*count);
+                let p = self.compile_mod_param(param);
+                self.push(EvalNode::DiceConditionalDropLow(source, c, p))
+            }
+            DicePoolType::RemoveN(pool, count, param) => {
+                let source = self.compile_dice_pool(*pool);
+                let c = self.compile_number(*count);
+                let p = self.compile_mod_param(param);
+                self.push(EvalNode::DiceRemoveN(source, c, p))
+            }
+            DicePoolType::KeepDistinct(pool) => {
+                let source = self.compile_dice_pool(*pool);
+                self.push(EvalNode::DiceKeepDistinct(source))
+            }
+            DicePoolType::KeepRankRange(pool, low, high) => {
+                let source = self.compile_dice_pool(*pool);
+                self.push(EvalNode::DiceKeepRankRange(source, low, high))
+            }
+            DicePoolType::KeepRelativeToAverage(pool, compare) => {
+                let source = self.compile_dice_pool(*pool);
+                self.push(EvalNode::DiceKeepRelative(source, compare))
+            }
         }
     }
 
@@ -325,6 +478,16 @@ impl Compiler {
                 let p = self.compile_mod_param(param);
                 self.push(EvalNode::DiceCountSuccessesFromDicePool(source, p))
             }
+            SuccessPoolType::SpendFromDicePool(dice_pool, param) => {
+                let source = self.compile_dice_pool(*dice_pool);
+                let p = self.compile_mod_param(param);
+                self.push(EvalNode::DiceSpendSuccessesFromDicePool(source, p))
+            }
+            SuccessPoolType::NetFromDicePool(dice_pool, param) => {
+                let source = self.compile_dice_pool(*dice_pool);
+                let p = self.compile_mod_param(param);
+                self.push(EvalNode::DiceNetSuccess(source, p))
+            }
             SuccessPoolType::DeductFailuresFromDicePool(dice_pool, param) => {
                 let source = self.compile_dice_pool(*dice_pool);
                 let p = self.compile_mod_param(param);
@@ -340,6 +503,16 @@ impl Compiler {
                 let p = self.compile_mod_param(param);
                 self.push(EvalNode::DiceDeductFailures(source, p))
             }
+            SuccessPoolType::WeightedCountFromDicePool(dice_pool, weights) => {
+                let source = self.compile_dice_pool(*dice_pool);
+                self.push(EvalNode::DiceWeightedSuccesses(source, weights))
+            }
+            SuccessPoolType::Cascade(dice_pool, param, limit) => {
+                let source = self.compile_dice_pool(*dice_pool);
+                let p = self.compile_mod_param(param);
+                let l = self.compile_limit(limit);
+                self.push(EvalNode::DiceCascade(source, p, l))
+            }
         }
     }
 
@@ -356,9 +529,60 @@ impl Compiler {
     fn compile_limit(&mut self, limit: Limit) -> LimitNode {
         let t = limit.limit_times.map(|n| self.compile_number(*n));
         let c = limit.limit_counts.map(|n| self.compile_number(*n));
+        let s = limit.limit_size.map(|n| self.compile_number(*n));
         LimitNode {
             limit_times: t,
             limit_counts: c,
+            limit_size: s,
         }
     }
 }
+
+// ==========================================
+// 单元测试
+// ==========================================
+
+#[test]
+fn test_large_constant_list_compiles_to_single_node() {
+    use crate::grammar::parse_dice;
+    use crate::lower::lower_expr;
+    let items: Vec<String> = (1..=1000).map(|i| i.to_string()).collect();
+    let hir = lower_expr(parse_dice(&format!("[{}]", items.join(","))).unwrap()).unwrap();
+    let graph = compile_hir_to_eval_graph(hir);
+    assert_eq!(graph.nodes.len(), 1);
+    let EvalNode::ConstantList(values) = &graph.nodes[0] else {
+        panic!("a large constant list should compile to a single node");
+    };
+    assert_eq!(values.len(), 1000);
+    assert_eq!(values[999], 1000.0);
+    // 含有骰子或较短的列表仍然逐个编译元素
+    let hir = lower_expr(parse_dice("[1,2,3]").unwrap()).unwrap();
+    let graph = compile_hir_to_eval_graph(hir);
+    assert!(matches!(
+        graph.nodes[graph.root.to_index()],
+        EvalNode::ListConstruct(_)
+    ));
+    let hir = lower_expr(parse_dice(&format!("[1d6,{}]", items.join(","))).unwrap()).unwrap();
+    let graph = compile_hir_to_eval_graph(hir);
+    assert!(matches!(
+        graph.nodes[graph.root.to_index()],
+        EvalNode::ListConstruct(_)
+    ));
+}
+
+#[test]
+fn test_count_successes_compiles_to_single_node() {
+    use crate::grammar::parse_dice;
+    use crate::lower::lower_expr;
+    // XdY cs>=T 直接从骰池计数，中间不经过骰池到成功池的转换节点
+    let hir = lower_expr(parse_dice("10d10cs>=7").unwrap()).unwrap();
+    let graph = compile_hir_to_eval_graph(hir);
+    let EvalNode::DiceCountSuccessesFromDicePool(source, _) = &graph.nodes[graph.root.to_index()]
+    else {
+        panic!("root should count successes directly from the dice pool");
+    };
+    assert!(matches!(
+        graph.nodes[source.to_index()],
+        EvalNode::DiceStandard(_, _)
+    ));
+}
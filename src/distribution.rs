@@ -0,0 +1,333 @@
+use crate::grammar::parse_dice;
+use crate::lower::lower_expr;
+use crate::optimizer::constant_fold::constant_fold_hir;
+use crate::runtime::DEFAULT_DICE_COUNT_LIMIT;
+use crate::types::hir::{DicePoolType, HIR, ModParam, NumberType, SuccessPoolType};
+
+// ==========================================
+// 入口函数
+// ==========================================
+
+// 解析地计算表达式结果的概率分布 (PMF)
+// 返回按结果升序排列的 (结果, 概率) 列表，首尾概率为 0 的结果会被裁掉
+pub fn distribution(input: &str) -> Result<Vec<(i32, f64)>, String> {
+    let ast = parse_dice(input)?;
+    let hir = lower_expr(ast)?;
+    let hir = constant_fold_hir(hir)?;
+    match hir {
+        HIR::Number(NumberType::SuccessPool(success_pool)) => {
+            success_pool_distribution(&success_pool)
+        }
+        HIR::Number(NumberType::DicePool(DicePoolType::KeepHigh(pool, keep))) => {
+            keep_one_distribution(&pool, &keep, true)
+        }
+        HIR::Number(NumberType::DicePool(DicePoolType::KeepLow(pool, keep))) => {
+            keep_one_distribution(&pool, &keep, false)
+        }
+        _ => Err("Distribution is not supported for this expression".to_string()),
+    }
+}
+
+// 结果不小于 target 的概率，如优势检定 2d20kh1 达到 DC 15 的概率
+pub fn probability_at_least(input: &str, target: f64) -> Result<f64, String> {
+    Ok(distribution(input)?
+        .into_iter()
+        .filter(|(value, _)| *value as f64 >= target)
+        .map(|(_, p)| p)
+        .sum())
+}
+
+// ==========================================
+// 只保留一个骰子的 kh/kl：顺序统计量
+// ==========================================
+
+// N 个骰子中最大值不超过 v 的概率为 F(v)^N，最小值不小于 v 的概率为 S(v)^N，
+// 相邻两项作差即为恰好等于 v 的概率
+fn keep_one_distribution(
+    dice_pool: &DicePoolType,
+    keep: &NumberType,
+    keep_highest: bool,
+) -> Result<Vec<(i32, f64)>, String> {
+    if !matches!(keep, NumberType::Constant(c) if *c as i32 == 1) {
+        return Err(
+            "Distribution of kh/kl is only supported when keeping a single die".to_string(),
+        );
+    }
+    let (count, mut faces) = base_dice_faces(dice_pool)?;
+    if faces.is_empty() {
+        return Ok(vec![(0, 1.0)]);
+    }
+    let face_probability = 1.0 / faces.len() as f64;
+    faces.sort_unstable();
+    let mut values = faces.clone();
+    values.dedup();
+    // 最小值从大到小累积 S(v)，最大值从小到大累积 F(v)
+    if !keep_highest {
+        values.reverse();
+    }
+    let mut cumulative = 0.0_f64;
+    let mut previous = 0.0_f64;
+    let mut pmf = Vec::with_capacity(values.len());
+    for value in values {
+        cumulative += faces.iter().filter(|f| **f == value).count() as f64 * face_probability;
+        let current = cumulative.min(1.0).powi(count as i32);
+        pmf.push((value, current - previous));
+        previous = current;
+    }
+    if !keep_highest {
+        pmf.reverse();
+    }
+    Ok(pmf)
+}
+
+// ==========================================
+// 成功池：逐骰卷积
+// ==========================================
+
+// 单个骰子在成功池中的贡献：成功 +1，失败 -1，不计 0
+fn success_pool_distribution(success_pool: &SuccessPoolType) -> Result<Vec<(i32, f64)>, String> {
+    // 1. 从外向内收集成功/失败判定，直到遇到底层骰池
+    // 第三项为 true 时，满足条件的骰子被消耗 (sp)，之后的判定不再覆盖它
+    let mut checks: Vec<(&ModParam, i32, bool)> = Vec::new();
+    let mut base_outcome = 0;
+    let mut current = success_pool;
+    let dice_pool = loop {
+        match current {
+            SuccessPoolType::CountSuccesses(inner, mp) => {
+                checks.push((mp, 1, false));
+                current = inner;
+            }
+            SuccessPoolType::DeductFailures(inner, mp) => {
+                checks.push((mp, -1, false));
+                current = inner;
+            }
+            SuccessPoolType::CountSuccessesFromDicePool(dp, mp) => {
+                checks.push((mp, 1, false));
+                break dp;
+            }
+            SuccessPoolType::DeductFailuresFromDicePool(dp, mp) => {
+                checks.push((mp, -1, false));
+                break dp;
+            }
+            SuccessPoolType::SpendFromDicePool(dp, mp) => {
+                checks.push((mp, 1, true));
+                break dp;
+            }
+            SuccessPoolType::NetFromDicePool(dp, mp) => {
+                // 不满足条件的骰子计为失败，相当于所有骰子先记为 -1
+                base_outcome = -1;
+                checks.push((mp, 1, false));
+                break dp;
+            }
+            SuccessPoolType::Cascade(..) => {
+                return Err("Distribution is not supported for cascade".to_string());
+            }
+            SuccessPoolType::WeightedCountFromDicePool(..) => {
+                return Err("Distribution is not supported for weighted success counts".to_string());
+            }
+        }
+    };
+    // 运行时按从内到外的顺序判定，后面的判定会覆盖前面的结果
+    checks.reverse();
+
+    let (count, faces) = base_dice_faces(dice_pool)?;
+    if faces.is_empty() {
+        return Ok(vec![(0, 1.0)]);
+    }
+
+    // 2. 计算单个骰子的分布，下标 0/1/2 分别对应 -1/0/+1
+    let mut single_die = [0.0_f64; 3];
+    let face_probability = 1.0 / faces.len() as f64;
+    for face in faces {
+        let mut outcome = base_outcome;
+        for (mod_param, value, spent) in checks.iter() {
+            let compare = mod_param
+                .get_compare_function()
+                .ok_or("Distribution requires a constant comparison value".to_string())?;
+            if compare(face as f64) {
+                outcome = *value;
+                if *spent {
+                    break;
+                }
+            }
+        }
+        single_die[(outcome + 1) as usize] += face_probability;
+    }
+
+    // 3. 逐骰卷积，pmf[i] 对应结果 i - count
+    let mut pmf = vec![0.0_f64; 2 * count + 1];
+    pmf[count] = 1.0;
+    for _ in 0..count {
+        let mut next = vec![0.0_f64; pmf.len()];
+        for (i, p) in pmf.iter().enumerate() {
+            if *p == 0.0 {
+                continue;
+            }
+            for (j, q) in single_die.iter().enumerate() {
+                // j - 1 为单骰贡献，下标越界的情况概率必然为 0
+                if let Some(slot) = (i + j).checked_sub(1).and_then(|k| next.get_mut(k)) {
+                    *slot += p * q;
+                }
+            }
+        }
+        pmf = next;
+    }
+
+    let first = pmf.iter().position(|p| *p > 0.0).unwrap_or(count);
+    let last = pmf.iter().rposition(|p| *p > 0.0).unwrap_or(count);
+    Ok((first..=last)
+        .map(|i| (i as i32 - count as i32, pmf[i]))
+        .collect())
+}
+
+// 获取底层骰池的骰子个数与所有可能的面值，要求个数与面数均为常数
+fn base_dice_faces(dice_pool: &DicePoolType) -> Result<(usize, Vec<i32>), String> {
+    fn constant_of(n: &NumberType) -> Result<f64, String> {
+        match n {
+            NumberType::Constant(c) => Ok(*c),
+            _ => Err("Distribution requires a constant dice count and sides".to_string()),
+        }
+    }
+    // 与投掷时的骰子数上限一致，面数也受同样的限制，避免逐骰卷积耗时过长
+    fn check_limit(value: f64, what: &str) -> Result<f64, String> {
+        if value > DEFAULT_DICE_COUNT_LIMIT as f64 {
+            return Err(format!(
+                "Distribution requires the dice {} to be at most {}",
+                what, DEFAULT_DICE_COUNT_LIMIT
+            ));
+        }
+        Ok(value)
+    }
+    // 与运行时一致：个数和面数按截断取整处理，非正数视为空骰池
+    let (count, faces) = match dice_pool {
+        DicePoolType::Standard(count, sides) => {
+            let sides = check_limit(constant_of(sides)?, "sides")? as i32;
+            (constant_of(count)? as i32, (1..=sides).collect())
+        }
+        DicePoolType::Fudge(count) => (constant_of(count)? as i32, vec![-1, 0, 1]),
+        DicePoolType::Coin(count) => (constant_of(count)? as i32, vec![0, 1]),
+        DicePoolType::Percentile => (1, (1..=100).collect()),
+        DicePoolType::Labeled(count, faces) => (
+            constant_of(count)? as i32,
+            faces.iter().map(|f| f.1).collect(),
+        ),
+        // each 对每颗骰子做同样的运算，相当于换了一组面值
+        DicePoolType::Each(inner, op, value) => {
+            let value = constant_of(value)? as i32;
+            let (count, faces) = base_dice_faces(inner)?;
+            return Ok((
+                count,
+                faces.into_iter().map(|f| op.apply(f, value)).collect(),
+            ));
+        }
+        _ => {
+            return Err(
+                "Distribution is only supported for success checks on plain dice pools".to_string(),
+            );
+        }
+    };
+    if count <= 0 {
+        return Ok((0, Vec::new()));
+    }
+    check_limit(count as f64, "count")?;
+    Ok((count as usize, faces))
+}
+
+// ==========================================
+// 单元测试
+// ==========================================
+
+#[cfg(test)]
+fn assert_pmf_eq(actual: &[(i32, f64)], expected: &[(i32, f64)]) {
+    assert_eq!(actual.len(), expected.len(), "actual: {:?}", actual);
+    for ((a_value, a_p), (e_value, e_p)) in actual.iter().zip(expected.iter()) {
+        assert_eq!(a_value, e_value);
+        assert!((a_p - e_p).abs() < 1e-12, "actual: {:?}", actual);
+    }
+}
+
+#[test]
+fn test_success_pool_binomial() {
+    // binomial(5, 0.4): C(5,k) * 0.4^k * 0.6^(5-k)
+    let expected = [
+        (0, 0.07776),
+        (1, 0.2592),
+        (2, 0.3456),
+        (3, 0.2304),
+        (4, 0.0768),
+        (5, 0.01024),
+    ];
+    assert_pmf_eq(&distribution("5d10cs>=7").unwrap(), &expected);
+}
+
+#[test]
+fn test_success_pool_with_deducted_failures() {
+    // 1d10: 成功 (>=7) 0.4，失败 (=1) 0.1，不计 0.5
+    let expected = [(-1, 0.1), (0, 0.5), (1, 0.4)];
+    assert_pmf_eq(&distribution("1d10cs>=7df1").unwrap(), &expected);
+}
+
+#[test]
+fn test_spent_successes_ignore_later_checks() {
+    // 1d6: 6 被消耗 (+1)，之后的 df<=6 只作用于 1~5
+    let expected = [(-1, 5.0 / 6.0), (0, 0.0), (1, 1.0 / 6.0)];
+    assert_pmf_eq(&distribution("1d6sp6df<=6").unwrap(), &expected);
+    assert_pmf_eq(
+        &distribution("5d10sp>=7").unwrap(),
+        &distribution("5d10cs>=7").unwrap(),
+    );
+}
+
+#[test]
+fn test_net_successes_count_misses_as_failures() {
+    assert_pmf_eq(
+        &distribution("3d10net>=7").unwrap(),
+        &distribution("3d10cs>=7df<7").unwrap(),
+    );
+}
+
+#[test]
+fn test_keep_one_order_statistics() {
+    // 2d6kh1 取到 k 的概率为 (2k - 1) / 36
+    let expected: Vec<(i32, f64)> = (1..=6).map(|k| (k, (2 * k - 1) as f64 / 36.0)).collect();
+    assert_pmf_eq(&distribution("2d6kh1").unwrap(), &expected);
+    // 2d6kl 取到 k 的概率为 (13 - 2k) / 36
+    let expected: Vec<(i32, f64)> = (1..=6).map(|k| (k, (13 - 2 * k) as f64 / 36.0)).collect();
+    assert_pmf_eq(&distribution("2d6kl").unwrap(), &expected);
+    // 优势检定：1 - ((T - 1) / 20)^2；劣势检定：((21 - T) / 20)^2
+    for (dc, advantage, disadvantage) in [
+        (2, 0.9975, 0.9025),
+        (11, 0.75, 0.25),
+        (15, 0.51, 0.09),
+        (20, 0.0975, 0.0025),
+    ] {
+        let p = probability_at_least("2d20kh1", dc as f64).unwrap();
+        assert!((p - advantage).abs() < 1e-12, "DC {}: {}", dc, p);
+        let p = probability_at_least("2d20kl1", dc as f64).unwrap();
+        assert!((p - disadvantage).abs() < 1e-12, "DC {}: {}", dc, p);
+    }
+    // each 平移面值后分布同样平移
+    let expected: Vec<(i32, f64)> = (2..=7).map(|k| (k, (2 * k - 3) as f64 / 36.0)).collect();
+    assert_pmf_eq(&distribution("each(2d6, +1)kh1").unwrap(), &expected);
+    assert!(distribution("3d6kh2").is_err());
+}
+
+#[test]
+fn test_distribution_unsupported() {
+    assert!(distribution("2d6").is_err());
+    assert!(distribution("(1d4)d10cs>=7").is_err());
+}
+
+#[test]
+fn test_distribution_dice_limit() {
+    assert!(distribution("1000d6cs>=5").is_ok());
+    assert_eq!(
+        distribution("2000000d6cs>=5"),
+        Err("Distribution requires the dice count to be at most 1000".to_string())
+    );
+    assert_eq!(
+        distribution("2d2000000000cs>=5"),
+        Err("Distribution requires the dice sides to be at most 1000".to_string())
+    );
+    assert!(distribution("2000000d6kh1").is_err());
+}
@@ -1,60 +1,382 @@
 use crate::types::expr::*;
+use std::cell::{Cell, RefCell};
 use winnow::Parser;
 use winnow::Result as WNResultBase;
 use winnow::ascii::alpha1;
-use winnow::ascii::{Caseless, float, space0};
+use winnow::ascii::{Caseless, digit1, space0, space1};
 use winnow::combinator::cut_err;
 use winnow::combinator::dispatch;
-use winnow::combinator::{alt, delimited, fail, opt, peek, preceded, separated};
+use winnow::combinator::{
+    alt, delimited, fail, opt, peek, preceded, repeat, separated, terminated,
+};
 use winnow::error::ContextError;
 use winnow::error::ErrMode;
-use winnow::stream::Stream;
-use winnow::token::any;
+use winnow::error::{StrContext, StrContextValue};
+use winnow::stream::{Stateful, Stream};
+use winnow::token::{any, one_of, take_while};
 
 pub type WNResult<O, E = ContextError> = WNResultBase<O, ErrMode<E>>;
 
+// 解析过程中的状态，随输入一起显式传递给每个解析函数
+#[derive(Debug, Default)]
+struct ParseState {
+    // 已经解析过的 let 绑定的变量名，解析原子时只有这些名字会被识别为变量，
+    // 因此未声明的名字不会干扰骰子 (d6、dF) 与修饰符 (kh、dl) 的解析
+    declared_vars: RefCell<Vec<String>>,
+    // parse_full_expr 是否已经解析出完整的表达式，为 true 时解析失败只可能是因为还有多余的内容
+    complete: Cell<bool>,
+}
+
+type Input<'a> = Stateful<&'a str, &'a ParseState>;
+
 // ==========================================
 // 入口函数
 // ==========================================
 
+// 解析前的输入规模限制，用于在机器人等场景下拒绝恶意构造的超长或超深输入
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseLimits {
+    pub max_length: usize, // 最大字符数
+    pub max_depth: usize,  // 括号 ( [ { 与一元正负号的最大嵌套层数
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        ParseLimits {
+            max_length: 10_000,
+            max_depth: 64,
+        }
+    }
+}
+
 pub fn parse_dice(input: &str) -> Result<Expr, String> {
-    match parse_full_expr.parse(input) {
-        Ok(expr) => Ok(expr),
-        Err(e) => Err(e.to_string()),
+    parse_dice_with_limits(input, ParseLimits::default())
+}
+
+pub fn parse_dice_with_limits(input: &str, limits: ParseLimits) -> Result<Expr, String> {
+    let mut roots = parse_dice_all_with_limits(input, limits)?;
+    if roots.len() > 1 {
+        return Err(format!(
+            "Expected a single expression but found {} comma-separated expressions",
+            roots.len()
+        ));
+    }
+    Ok(roots.pop().unwrap()) // 至少有一个表达式
+}
+
+// 解析以逗号分隔的多个顶层表达式，如 "1d20+5, 2d6"，开头的 let 绑定对每个表达式都生效
+pub fn parse_dice_all(input: &str) -> Result<Vec<Expr>, String> {
+    parse_dice_all_with_limits(input, ParseLimits::default())
+}
+
+pub fn parse_dice_all_with_limits(input: &str, limits: ParseLimits) -> Result<Vec<Expr>, String> {
+    // 空白输入单独报错，winnow 对空输入给出的错误信息不够直观
+    if input.trim().is_empty() {
+        return Err("Empty expression".to_string());
+    }
+    // 先做廉价的规模检查，避免把超长输入交给 winnow 或让递归下降耗尽栈空间
+    if input.len() > limits.max_length {
+        let length = input.chars().count();
+        if length > limits.max_length {
+            return Err(format!(
+                "Expression is too long: {} characters (limit {})",
+                length, limits.max_length
+            ));
+        }
+    }
+    let depth = max_nesting_depth(input);
+    if depth > limits.max_depth {
+        return Err(format!(
+            "Expression is nested too deeply: {} levels of brackets and unary signs (limit {})",
+            depth, limits.max_depth
+        ));
+    }
+    let state = ParseState::default();
+    let result = parse_full_expr.parse(Input {
+        input,
+        state: &state,
+    });
+    match result {
+        Ok(roots) => Ok(roots),
+        Err(e) => {
+            let mut message = e.to_string();
+            let notes = [
+                trailing_input_note(input, e.offset(), &state),
+                suggest_modifier(input, e.offset()),
+            ];
+            for note in notes.into_iter().flatten() {
+                if !message.ends_with('\n') {
+                    message.push('\n');
+                }
+                message.push_str(&note);
+            }
+            Err(message)
+        }
+    }
+}
+
+// 输入的开头已经是完整的表达式、只是后面还有多余的内容时，指出解析停止的字节位置
+fn trailing_input_note(input: &str, offset: usize, state: &ParseState) -> Option<String> {
+    if !state.complete.get() {
+        return None;
+    }
+    let rest = input.get(offset..)?;
+    Some(format!(
+        "Unexpected `{}` at byte offset {}, after a complete expression",
+        rest.trim_end(),
+        offset
+    ))
+}
+
+// ==========================================
+// 拼写错误提示
+// ==========================================
+
+const KNOWN_MODIFIERS: &[&str] = &[
+    "kh",
+    "kl",
+    "dh",
+    "dl",
+    "min",
+    "max",
+    "r",
+    "rp",
+    "!",
+    "!!",
+    "!o",
+    "!x",
+    "!until",
+    "!total",
+    "cs",
+    "df",
+    "sf",
+    "sp",
+    "net",
+    "lt",
+    "lc",
+    "lsz",
+    "rm",
+    "kdistinct",
+    "cascade",
+];
+
+// 解析失败时，取出错位置所在的单词 (连续的字母或 !)，
+// 若它紧跟在完整的骰子项之后，且与某个修饰符只差一个字符，则给出 "did you mean" 提示
+fn suggest_modifier(input: &str, offset: usize) -> Option<String> {
+    let is_word = |c: char| c.is_ascii_alphabetic() || c == '!';
+    let before = input[..offset].trim_end_matches(is_word);
+    let after = input[offset..].trim_start_matches(is_word);
+    let word = input[before.len()..input.len() - after.len()].to_lowercase();
+    // 单个字母与太多修饰符只差一个字符，给出的提示没有意义
+    if word.len() < 2 || KNOWN_MODIFIERS.contains(&word.as_str()) {
+        return None;
+    }
+    // 修饰符只能跟在骰子项之后，如 2dx6 中的 dx 不可能是修饰符
+    if !ends_with_dice_term(before) {
+        return None;
     }
+    let candidates: Vec<String> = KNOWN_MODIFIERS
+        .iter()
+        .filter(|m| edit_distance(&word, m) == 1)
+        .map(|m| format!("`{}`", m))
+        .collect();
+    if candidates.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "Unknown modifier `{}`, did you mean {}?",
+        word,
+        candidates.join(" or ")
+    ))
 }
 
-fn parse_full_expr(input: &mut &str) -> WNResult<Expr> {
+// 单词之前的内容是否是一个以骰子项 (骰子或带修饰符的骰子) 结尾的完整表达式
+fn ends_with_dice_term(before: &str) -> bool {
+    let state = ParseState::default();
+    let Ok(mut roots) = parse_full_expr.parse(Input {
+        input: before,
+        state: &state,
+    }) else {
+        return false;
+    };
+    let mut last = roots.pop();
+    while let Some(expr) = last {
+        last = match expr {
+            Expr::Let(let_expr) => Some(*let_expr.body),
+            Expr::Binary(binary) => Some(*binary.rhs),
+            Expr::Dice(_) | Expr::Modifier(_) => return true,
+            _ => return false,
+        };
+    }
+    false
+}
+
+// 编辑距离 (Levenshtein)，修饰符都很短，直接用滚动数组计算
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut curr = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = prev[j] + usize::from(ca != *cb);
+            curr.push(substitute.min(prev[j + 1] + 1).min(curr[j] + 1));
+        }
+        prev = curr;
+    }
+    prev[b.len()]
+}
+
+// 统计括号与一元正负号的嵌套层数，括号是否配对交给解析器报错
+// 递归下降中每个一元符号同样占用一层，因此一串一元符号计入其后操作数 (包括操作数中的括号) 的层数，
+// 遇到操作数后这串符号结束；难以区分的位置 (如 d%% 之后) 按一元符号计算，只会略微高估
+fn max_nesting_depth(input: &str) -> usize {
+    let mut outer: Vec<usize> = Vec::new(); // 外层括号内的基础层数
+    let mut base: usize = 0; // 当前括号内的基础层数
+    let mut depth: usize = 0; // 基础层数加上尚未结束的一元符号
+    let mut max_depth = 0;
+    let mut prev: Option<char> = None; // 上一个非空白字符
+    for c in input.chars() {
+        match c {
+            _ if c.is_whitespace() => continue,
+            '(' | '[' | '{' => {
+                outer.push(base);
+                base = depth + 1;
+                depth = base;
+            }
+            ')' | ']' | '}' => {
+                base = outer.pop().unwrap_or(0);
+                depth = base;
+            }
+            '+' | '-' if prev.is_none_or(|p| !p.is_alphanumeric() && !")]}".contains(p)) => {
+                depth += 1;
+            }
+            _ if c.is_alphanumeric() => depth = base,
+            _ => {}
+        }
+        max_depth = max_depth.max(depth);
+        prev = Some(c);
+    }
+    max_depth
+}
+
+fn parse_full_expr(input: &mut Input<'_>) -> WNResult<Vec<Expr>> {
     let _ = space0.parse_next(input)?; // 吃掉开头的空白
-    let x = parse_expr.parse_next(input)?;
+    let bindings: Vec<LetBinding> = repeat(0.., parse_let_binding).parse_next(input)?;
+    // 顶层的逗号分隔多个独立的表达式，与列表 [...] 不同，每个表达式保留各自的结果树
+    let roots: Vec<Expr> = separated(1.., parse_comparison, ws(",")).parse_next(input)?;
     let _ = space0.parse_next(input)?; // 吃掉结尾的空白
-    Ok(x)
+    input.state.complete.set(true);
+    if bindings.is_empty() {
+        Ok(roots)
+    } else {
+        Ok(roots
+            .into_iter()
+            .map(|x| Expr::let_in(bindings.clone(), x))
+            .collect())
+    }
+}
+
+// ==========================================
+// let 绑定
+// ==========================================
+
+// let [share] name = expr ;
+fn parse_let_binding(input: &mut Input<'_>) -> WNResult<LetBinding> {
+    let _ = terminated(Caseless("let"), space1).parse_next(input)?;
+    let shared = opt(terminated(Caseless("share"), space1))
+        .parse_next(input)?
+        .is_some();
+    let name = cut_err(alpha1.verify(|name: &str| !is_reserved_name(name)).context(
+        StrContext::Expected(StrContextValue::Description(
+            "a variable name made of letters that is not a function, modifier or keyword",
+        )),
+    ))
+    .parse_next(input)?;
+    let value = cut_err(preceded(ws("="), parse_expr)).parse_next(input)?;
+    let _ = cut_err(ws(";")).parse_next(input)?;
+    input
+        .state
+        .declared_vars
+        .borrow_mut()
+        .push(name.to_string());
+    Ok(Expr::let_binding(name, value, shared))
+}
+
+fn is_reserved_name(name: &str) -> bool {
+    let name = name.to_lowercase();
+    function_name_of(&name).is_some()
+        || KNOWN_MODIFIERS.contains(&name.as_str())
+        || [
+            "filter", "each", "let", "share", "remove", "rmax", "d", "df", "dc",
+        ]
+        .contains(&name.as_str())
+}
+
+fn parse_var(input: &mut Input<'_>) -> WNResult<Expr> {
+    let start = input.checkpoint();
+    let name: &str = alpha1.parse_next(input)?;
+    if input.state.declared_vars.borrow().iter().any(|v| v == name) {
+        Ok(Expr::Var(name.to_string()))
+    } else {
+        input.reset(&start);
+        fail.parse_next(input)
+    }
 }
 
 // ==========================================
 // 基础工具
 // ==========================================
 
-fn ws<'a, F, O>(inner: F) -> impl Parser<&'a str, O, ErrMode<ContextError>>
+fn ws<'a, F, O>(inner: F) -> impl Parser<Input<'a>, O, ErrMode<ContextError>>
 where
-    F: Parser<&'a str, O, ErrMode<ContextError>>,
+    F: Parser<Input<'a>, O, ErrMode<ContextError>>,
 {
     delimited(space0, inner, space0)
 }
 
-fn parse_number(input: &mut &str) -> WNResult<Expr> {
-    float.map(Expr::number).parse_next(input)
+// 小数点两侧都必须有数字：0.5 合法，.5 与 5. 均明确报错
+// 连续的两个点 (如 2..4) 不视为小数点，留给后续语法使用
+fn parse_number(input: &mut Input<'_>) -> WNResult<Expr> {
+    if input.starts_with('.') {
+        return cut_err(
+            fail.context(StrContext::Expected(StrContextValue::Description(
+                "a digit before the decimal point (write 0.5 instead of .5)",
+            ))),
+        )
+        .parse_next(input);
+    }
+    let (text, (_, fraction, _)) = (
+        digit1,
+        opt(preceded(".", digit1)),
+        opt((one_of(['e', 'E']), opt(one_of(['+', '-'])), digit1)),
+    )
+        .with_taken()
+        .map(|(parsed, taken)| (taken, parsed))
+        .parse_next(input)?;
+    if input.starts_with('.') && !input.starts_with("..") {
+        let message = if fraction.is_some() {
+            "only one decimal point in a number"
+        } else {
+            "a digit after the decimal point (write 5 or 5.0 instead of 5.)"
+        };
+        return cut_err(fail.context(StrContext::Expected(StrContextValue::Description(message))))
+            .parse_next(input);
+    }
+    let value = text
+        .parse::<f64>()
+        .map_err(|_| ErrMode::Cut(ContextError::new()))?;
+    Ok(Expr::number(value))
 }
 
 // ==========================================
 // 运算符解析
 // ==========================================
 
-fn parse_bin_op_add_sub(input: &mut &str) -> WNResult<BinOp> {
+fn parse_bin_op_add_sub(input: &mut Input<'_>) -> WNResult<BinOp> {
     alt(("+".map(|_| BinOp::Add), "-".map(|_| BinOp::Sub))).parse_next(input)
 }
 
-fn parse_bin_op_mul_div(input: &mut &str) -> WNResult<BinOp> {
+fn parse_bin_op_mul_div(input: &mut Input<'_>) -> WNResult<BinOp> {
     alt((
         "//".map(|_| BinOp::Idiv),
         "/".map(|_| BinOp::Div),
@@ -65,9 +387,12 @@ fn parse_bin_op_mul_div(input: &mut &str) -> WNResult<BinOp> {
     .parse_next(input)
 }
 
-fn parse_compare_op(input: &mut &str) -> WNResult<CompareOp> {
+// != 是 <> 的别名。爆炸修饰符的 ! 总是先于比较符被解析，
+// 因此 2d6!=3 表示 "投出 3 时爆炸"，不等于时爆炸需写作 2d6!<>3
+fn parse_compare_op(input: &mut Input<'_>) -> WNResult<CompareOp> {
     alt((
         "<>".map(|_| CompareOp::NotEqual),
+        "!=".map(|_| CompareOp::NotEqual),
         ">=".map(|_| CompareOp::GreaterEqual),
         "<=".map(|_| CompareOp::LessEqual),
         ">".map(|_| CompareOp::Greater),
@@ -81,8 +406,66 @@ fn parse_compare_op(input: &mut &str) -> WNResult<CompareOp> {
 // 递归下降解析主逻辑（递归下降逻辑可以保留，扁平化收益不大）
 // ==========================================
 
-// Level 6: Expr (加减法, 优先级最低)
-fn parse_expr(input: &mut &str) -> WNResult<Expr> {
+// Level 7: Comparison (数值比较, 优先级最低，不可连写)
+// 只出现在顶层与括号中，避免与函数参数、列表元素中的其它语法混淆
+fn parse_comparison(input: &mut Input<'_>) -> WNResult<Expr> {
+    let (left, left_text) = parse_expr.with_taken().parse_next(input)?;
+    let start = input.checkpoint();
+    let Some(op) = opt(preceded(space0, parse_compare_op)).parse_next(input)? else {
+        input.reset(&start);
+        return Ok(left);
+    };
+    // 左侧以骰子或修饰符结尾时，比较符会与成功检定 (2d20<3)、修饰符的条件 (4d6dl<3) 混淆，
+    // 而含义不应取决于中间是否有空白，因此这时必须给左侧的最后一项加括号，如 "(2d20)<3"
+    if ends_with_dice(&left) && !last_term_parenthesized(left_text) {
+        return cut_err(
+            fail.context(StrContext::Expected(StrContextValue::Description(
+                "parentheses around dice or a modifier before a comparison, as in (2d20)<3",
+            ))),
+        )
+        .parse_next(input);
+    }
+    let right = cut_err(preceded(space0, parse_expr)).parse_next(input)?;
+    Ok(Expr::binary(left, BinOp::Compare(op), right))
+}
+
+// 表达式最右侧的一项是骰子或修饰符
+fn ends_with_dice(expr: &Expr) -> bool {
+    match expr {
+        Expr::Dice(_) | Expr::Modifier(_) => true,
+        Expr::Binary(binary) => ends_with_dice(&binary.rhs),
+        Expr::Neg(inner) => ends_with_dice(inner),
+        _ => false,
+    }
+}
+
+// 最后一项整体在括号中，如 "1 + (2d20)"；"4d6kh(2)" 中的括号只包住了修饰符的参数
+fn last_term_parenthesized(text: &str) -> bool {
+    let text = text.trim_end();
+    if !text.ends_with([')', '}']) {
+        return false;
+    }
+    let mut depth = 0;
+    for (index, c) in text.char_indices().rev() {
+        match c {
+            ')' | '}' => depth += 1,
+            '(' | '{' => depth -= 1,
+            _ => {}
+        }
+        if depth == 0 {
+            // 括号前是运算符或已到开头时，括号包住的是一整项
+            return text[..index]
+                .trim_end()
+                .chars()
+                .last()
+                .is_none_or(|before| "+-*/%([{,".contains(before));
+        }
+    }
+    false
+}
+
+// Level 6: Expr (加减法)
+fn parse_expr(input: &mut Input<'_>) -> WNResult<Expr> {
     let mut left = parse_term(input)?;
     while let Some(op) = opt(ws(parse_bin_op_add_sub)).parse_next(input)? {
         let right = parse_term(input)?;
@@ -92,7 +475,7 @@ fn parse_expr(input: &mut &str) -> WNResult<Expr> {
 }
 
 // Level 5: Term (乘除模)
-fn parse_term(input: &mut &str) -> WNResult<Expr> {
+fn parse_term(input: &mut Input<'_>) -> WNResult<Expr> {
     let mut left = parse_unary(input)?;
     while let Some(op) = opt(ws(parse_bin_op_mul_div)).parse_next(input)? {
         let right = parse_unary(input)?;
@@ -102,7 +485,7 @@ fn parse_term(input: &mut &str) -> WNResult<Expr> {
 }
 
 // Level 4: Unary Prefix (正负号)
-fn parse_unary(input: &mut &str) -> WNResult<Expr> {
+fn parse_unary(input: &mut Input<'_>) -> WNResult<Expr> {
     alt((
         // 负号: 递归调用 parse_unary (支持 --1) 或进入下一层
         preceded(ws("-"), parse_unary).map(Expr::neg),
@@ -115,7 +498,7 @@ fn parse_unary(input: &mut &str) -> WNResult<Expr> {
 }
 
 // Level 3: Dice Modifiers (后缀修饰符)
-fn parse_dice_with_modifiers(input: &mut &str) -> WNResult<Expr> {
+fn parse_dice_with_modifiers(input: &mut Input<'_>) -> WNResult<Expr> {
     let mut base = parse_dice_expr(input)?;
     while let Some(builder) = opt(parse_modifier_op).parse_next(input)? {
         base = builder(base);
@@ -125,8 +508,8 @@ fn parse_dice_with_modifiers(input: &mut &str) -> WNResult<Expr> {
 
 type ModifierBuilder = Box<dyn FnOnce(Expr) -> Expr>;
 
-fn parse_modifier_op(input: &mut &str) -> WNResult<ModifierBuilder> {
-    fn parse_d_modifiers(input: &mut &str) -> WNResult<ModifierBuilder> {
+fn parse_modifier_op(input: &mut Input<'_>) -> WNResult<ModifierBuilder> {
+    fn parse_d_modifiers(input: &mut Input<'_>) -> WNResult<ModifierBuilder> {
         dispatch!(peek(preceded(any, any));
             'h' | 'H' | 'l' | 'L' => parse_type1_modifier, // 匹配 dh, dl
             'f' | 'F' => parse_type3_modifier,             // 匹配 df
@@ -135,13 +518,14 @@ fn parse_modifier_op(input: &mut &str) -> WNResult<ModifierBuilder> {
         .parse_next(input)
     }
     dispatch!(peek(any);
-        'k' | 'K' => parse_type1_modifier, // kh, kl
+        'k' | 'K' => alt((parse_type6_modifier, parse_type8_modifier, parse_type1_modifier)), // kdistinct vs k2..4 vs kh, kl
         'd' | 'D' => parse_d_modifiers, // dh, dl (Type1) vs df (Type3)
         'm' | 'M' => parse_type1_modifier, // min, max
-        'r' | 'R' => parse_type2_modifier, // r (Type2)
-        '!'       => parse_type2_modifier, // !, !! (Type2)
-        'c' | 'C' => parse_type3_modifier, // cs (Type3)
+        'r' | 'R' => alt((parse_reroll_max_modifier, parse_remove_modifier, parse_type2_modifier)), // rmax (Type3) vs rm (Type4) vs r (Type2)
+        '!'       => alt((parse_type10_modifier, parse_explode_total_modifier, parse_type9_modifier, parse_type2_modifier)), // !until (Type10) vs !total (Type3) vs !x2 (Type9) vs !, !! (Type2)
+        'c' | 'C' => alt((parse_type2_modifier, parse_type3_modifier)), // cascade (Type2) vs cs (Type3)
         's' | 'S' => parse_type3_modifier, // sf (Type3)
+        'n' | 'N' => parse_type3_modifier, // net (Type3)
         _ => fail
     )
     .parse_next(input)
@@ -149,7 +533,7 @@ fn parse_modifier_op(input: &mut &str) -> WNResult<ModifierBuilder> {
 
 // Level 2: Dice Expression (XdY, dY, XdF)
 // 逻辑: (Atom ~ "d" ~ Atom) | ("d" ~ Atom)
-fn parse_dice_expr(input: &mut &str) -> WNResult<Expr> {
+fn parse_dice_expr(input: &mut Input<'_>) -> WNResult<Expr> {
     // 先尝试解析一个Atom
     let left_opt = opt(parse_atom).parse_next(input)?;
 
@@ -169,7 +553,26 @@ fn parse_dice_expr(input: &mut &str) -> WNResult<Expr> {
     }
 
     // 解析操作符
-    let op_str = alt((Caseless("df"), Caseless("dc"), Caseless("d"))).parse_next(input)?;
+    let op_str = alt((
+        Caseless("df"),
+        Caseless("dc"),
+        Caseless("d%%"),
+        Caseless("d"),
+    ))
+    .parse_next(input)?;
+
+    // d%% 是固定形式，不接受骰子个数
+    if op_str.eq_ignore_ascii_case("d%%") {
+        if left_opt.is_some() {
+            return cut_err(
+                fail.context(StrContext::Expected(StrContextValue::Description(
+                    "no dice count before d%% (roll several with rep(d%%, N))",
+                ))),
+            )
+            .parse_next(input);
+        }
+        return Ok(Expr::percentile_dice());
+    }
 
     // 确定左值，默认为 1
     let count = left_opt.unwrap_or_else(|| Expr::number(1.0));
@@ -178,7 +581,10 @@ fn parse_dice_expr(input: &mut &str) -> WNResult<Expr> {
         "df" => Ok(Expr::fudge_dice(count)),
         "dc" => Ok(Expr::coin_dice(count)),
         "d" => {
-            // 标准骰子，必须跟面数
+            // 带标签的骰子 d[miss:0, hit:1]，否则为标准骰子，必须跟面数
+            if let Some(faces) = opt(parse_labeled_faces).parse_next(input)? {
+                return Ok(Expr::labeled_dice(count, faces));
+            }
             let sides = parse_atom(input)?;
             Ok(Expr::normal_dice(count, sides))
         }
@@ -186,15 +592,38 @@ fn parse_dice_expr(input: &mut &str) -> WNResult<Expr> {
     }
 }
 
+// 标签骰子的各面: [label: value, ...]，第一个标签后出现 ":" 时才确定不是普通列表
+fn parse_labeled_faces(input: &mut Input<'_>) -> WNResult<Vec<(String, Expr)>> {
+    fn parse_face_label(input: &mut Input<'_>) -> WNResult<String> {
+        take_while(1.., |c: char| c.is_alphanumeric() || "_+-?*".contains(c))
+            .map(|s: &str| s.to_string())
+            .parse_next(input)
+    }
+    fn parse_face(input: &mut Input<'_>) -> WNResult<(String, Expr)> {
+        let label = parse_face_label(input)?;
+        ws(":").parse_next(input)?;
+        let value = cut_err(parse_expr).parse_next(input)?;
+        Ok((label, value))
+    }
+    ("[", space0).parse_next(input)?;
+    let first = parse_face(input)?;
+    let rest: Vec<(String, Expr)> =
+        cut_err(repeat(0.., preceded(ws(","), parse_face))).parse_next(input)?;
+    cut_err((space0, "]")).parse_next(input)?;
+    let mut faces = vec![first];
+    faces.extend(rest);
+    Ok(faces)
+}
+
 // Level 1: Atom
 // 优先级最高的基础单元
-fn parse_atom(input: &mut &str) -> WNResult<Expr> {
+fn parse_atom(input: &mut Input<'_>) -> WNResult<Expr> {
     dispatch!(peek(any);
-        'a'..='z' | 'A'..='Z' => parse_function_call, // 是字母，直接解析函数
+        'a'..='z' | 'A'..='Z' => alt((parse_function_call, parse_var)), // 是字母，解析函数或变量
         '[' => parse_list,          // 是[，解析列表
         '0'..='9' | '.' => parse_number,        // 是数字，解析数字
-        '(' => delimited("(", delimited(space0, parse_expr, space0), ")"), // 括号表达式
-        '{' => delimited("{", delimited(space0, parse_expr, space0), "}"), // 花括号表达式
+        '(' => delimited("(", delimited(space0, parse_comparison, space0), ")"), // 括号表达式
+        '{' => delimited("{", delimited(space0, parse_comparison, space0), "}"), // 花括号表达式
         _ => fail                              // 其他字符直接报错
     )
     .parse_next(input)
@@ -204,7 +633,7 @@ fn parse_atom(input: &mut &str) -> WNResult<Expr> {
 // 具体组件解析 (Lists, Functions)
 // ==========================================
 
-fn parse_list(input: &mut &str) -> WNResult<Expr> {
+fn parse_list(input: &mut Input<'_>) -> WNResult<Expr> {
     delimited(
         "[",
         separated(0.., parse_expr, ws(",")).map(Expr::list),
@@ -213,10 +642,45 @@ fn parse_list(input: &mut &str) -> WNResult<Expr> {
     .parse_next(input)
 }
 
-fn parse_function_call(input: &mut &str) -> WNResult<Expr> {
+fn parse_function_call(input: &mut Input<'_>) -> WNResult<Expr> {
     let start = input.checkpoint();
     let name = alpha1.parse_next(input)?; // 吃掉函数名
-    let func_type = match name.to_lowercase().as_str() {
+    let name = name.to_lowercase();
+    let func_type = if name == "filter" {
+        let param = cut_err(parse_mod_param).parse_next(input)?;
+        FunctionName::Filter(param)
+    } else if name == "each" {
+        return cut_err(delimited("(", parse_each_args, ")")).parse_next(input);
+    } else if let Some(func_type) = function_name_of(&name) {
+        func_type
+    } else {
+        input.reset(&start);
+        return fail(input);
+    };
+    let args = cut_err(delimited("(", parse_args, ")")).parse_next(input)?;
+    Ok(Expr::function(func_type, args))
+}
+
+// each(pool, +N)：第二个参数前可以写 +、-、* 表示对每颗骰子做的运算，省略时为 +
+fn parse_each_args(input: &mut Input<'_>) -> WNResult<Expr> {
+    let pool = parse_expr.parse_next(input)?;
+    let _ = ws(",").parse_next(input)?;
+    let op = opt(ws(alt((
+        "+".map(|_| EachOp::Add),
+        "-".map(|_| EachOp::Sub),
+        "*".map(|_| EachOp::Mul),
+    ))))
+    .parse_next(input)?;
+    let value = parse_expr.parse_next(input)?;
+    Ok(Expr::function(
+        FunctionName::Each(op.unwrap_or(EachOp::Add)),
+        vec![pool, value],
+    ))
+}
+
+// 除 filter 外的函数名 (filter 后面紧跟比较参数，单独处理)
+fn function_name_of(name: &str) -> Option<FunctionName> {
+    let func_type = match name {
         "floor" => FunctionName::Floor,
         "ceil" => FunctionName::Ceil,
         "round" => FunctionName::Round,
@@ -225,25 +689,36 @@ fn parse_function_call(input: &mut &str) -> WNResult<Expr> {
         "min" => FunctionName::Min,
         "sum" => FunctionName::Sum,
         "avg" => FunctionName::Avg,
+        "ravg" => FunctionName::Ravg,
+        "roundeven" => FunctionName::RoundEven,
         "len" => FunctionName::Len,
         "rpdice" => FunctionName::Rpdice,
+        "rep" => FunctionName::Rep,
+        "gen" => FunctionName::Gen,
+        "asdice" => FunctionName::AsDice,
+        "margin" => FunctionName::Margin,
+        "groupkh" => FunctionName::GroupKh,
+        "maxor" => FunctionName::MaxOr,
+        "minor" => FunctionName::MinOr,
+        "sortdiced" => FunctionName::SortDiced,
+        "sortdice" => FunctionName::SortDice,
         "sortd" => FunctionName::Sortd,
+        "filtereven" => FunctionName::FilterEven,
+        "filterodd" => FunctionName::FilterOdd,
         "sort" => FunctionName::Sort,
         "tolist" => FunctionName::ToList,
-        "filter" => {
-            let param = cut_err(parse_mod_param).parse_next(input)?;
-            FunctionName::Filter(param)
-        }
-        _ => {
-            input.reset(&start);
-            return fail(input);
-        }
+        "highdie" => FunctionName::HighDie,
+        "lowdie" => FunctionName::LowDie,
+        "roundto" => FunctionName::RoundTo,
+        "floorto" => FunctionName::FloorTo,
+        "ceilto" => FunctionName::CeilTo,
+        "alldice" => FunctionName::AllDice,
+        _ => return None,
     };
-    let args = cut_err(delimited("(", parse_args, ")")).parse_next(input)?;
-    Ok(Expr::function(func_type, args))
+    Some(func_type)
 }
 
-fn parse_args(input: &mut &str) -> WNResult<Vec<Expr>> {
+fn parse_args(input: &mut Input<'_>) -> WNResult<Vec<Expr>> {
     separated(0.., parse_expr, ws(",")).parse_next(input)
 }
 
@@ -251,7 +726,7 @@ fn parse_args(input: &mut &str) -> WNResult<Vec<Expr>> {
 // 5. 修饰符细节解析
 // ==========================================
 
-fn parse_mod_param(input: &mut &str) -> WNResult<ModParam> {
+fn parse_mod_param(input: &mut Input<'_>) -> WNResult<ModParam> {
     let (op, val) = (opt(parse_compare_op), parse_atom).parse_next(input)?;
     // 如果没有提供比较符号，默认为 Equal
     Ok(Expr::mod_param(op.unwrap_or(CompareOp::Equal), val))
@@ -259,7 +734,7 @@ fn parse_mod_param(input: &mut &str) -> WNResult<ModParam> {
 
 // Type 1: kh, kl, dh, dl, min, max (Optional Atom)
 // 返回一个构建器闭包
-fn parse_type1_modifier(input: &mut &str) -> WNResult<ModifierBuilder> {
+fn parse_type1_modifier(input: &mut Input<'_>) -> WNResult<ModifierBuilder> {
     let tag_str = alt((
         Caseless("kh"),
         Caseless("kl"),
@@ -280,6 +755,26 @@ fn parse_type1_modifier(input: &mut &str) -> WNResult<ModifierBuilder> {
         _ => unreachable!(),
     };
 
+    // kh>avg、kh>=avg、kl<avg、kl<=avg：保留与被保留骰子平均值比较后满足条件的骰子
+    // 只有 "avg" 紧跟在比较符之后才按此解析，否则回退到普通的 kh/kl
+    if op == Type1Op::KeepHigh || op == Type1Op::KeepLow {
+        let average_op = if op == Type1Op::KeepHigh {
+            opt(terminated(alt((">=", ">")), Caseless("avg"))).parse_next(input)?
+        } else {
+            opt(terminated(alt(("<=", "<")), Caseless("avg"))).parse_next(input)?
+        };
+        if let Some(operator) = average_op {
+            let compare = match operator {
+                ">=" => CompareOp::GreaterEqual,
+                ">" => CompareOp::Greater,
+                "<=" => CompareOp::LessEqual,
+                "<" => CompareOp::Less,
+                _ => unreachable!(),
+            };
+            return Ok(Box::new(move |lhs| Expr::modifier_type5(lhs, compare)));
+        }
+    }
+
     let val_opt = if op == Type1Op::Min || op == Type1Op::Max {
         // min/max 必须有参数
         Some(cut_err(parse_atom).parse_next(input)?)
@@ -288,33 +783,62 @@ fn parse_type1_modifier(input: &mut &str) -> WNResult<ModifierBuilder> {
         opt(parse_atom).parse_next(input)?
     };
 
+    // dh, dl 后面若紧跟比较符，则为条件丢弃 (Type4)，如 dl1<2
+    // 条件中的比较符不可省略，否则无法与个数区分
+    if (op == Type1Op::DropHigh || op == Type1Op::DropLow)
+        && let Some(operator) = opt(parse_compare_op).parse_next(input)?
+    {
+        let value = cut_err(parse_atom).parse_next(input)?;
+        let op = if op == Type1Op::DropHigh {
+            Type4Op::ConditionalDropHigh
+        } else {
+            Type4Op::ConditionalDropLow
+        };
+        let param = Expr::mod_param(operator, value);
+        return Ok(Box::new(move |lhs| {
+            let count = val_opt.unwrap_or(Expr::number(1.0));
+            Expr::modifier_type4(lhs, op, count, param)
+        }));
+    }
+
     Ok(Box::new(move |lhs| {
         let param = val_opt.unwrap_or(Expr::number(1.0));
         Expr::modifier_type1(lhs, op, param)
     }))
 }
 
-// Type 2: r, !!, ! (ModParam? + Limit?)
-fn parse_type2_modifier(input: &mut &str) -> WNResult<ModifierBuilder> {
+// Type 2: r, rp, !!, !o, !, cascade (ModParam? + Limit?)
+fn parse_type2_modifier(input: &mut Input<'_>) -> WNResult<ModifierBuilder> {
     let tag_str = alt((
         "!!", // compound explode 必须在 explode 前面匹配
+        Caseless("!o"),
         "!",
+        Caseless("rerollpool"), // 整池重投必须在 r 前面匹配
+        Caseless("rp"),
         Caseless("r"),
+        Caseless("cascade"),
     ))
     .parse_next(input)?;
 
     let op = match tag_str.to_lowercase().as_str() {
         "!!" => Type2Op::CompoundExplode,
+        "!o" => Type2Op::ExplodeOnce,
         "!" => Type2Op::Explode,
+        "rerollpool" | "rp" => Type2Op::RerollPool,
         "r" => Type2Op::Reroll,
+        "cascade" => Type2Op::Cascade,
         _ => unreachable!(),
     };
 
     let param = opt(parse_mod_param).parse_next(input)?;
     let limit = opt(parse_limit).parse_next(input)?;
 
-    if op == Type2Op::Reroll && param.is_none() {
-        // r 修饰符必须有参数
+    if matches!(op, Type2Op::Reroll | Type2Op::RerollPool) && param.is_none() {
+        // r 与 rp 修饰符必须有参数
+        return fail(input);
+    }
+    if op == Type2Op::Cascade && (param.is_none() || limit.is_none()) {
+        // cascade 必须给出成功条件与轮数限制，如 6d10cascade>=8lt3
         return fail(input);
     }
 
@@ -323,62 +847,165 @@ fn parse_type2_modifier(input: &mut &str) -> WNResult<ModifierBuilder> {
     }))
 }
 
-// Type 3: cs, df, sf (Required ModParam)
-fn parse_type3_modifier(input: &mut &str) -> WNResult<ModifierBuilder> {
-    let tag_str = alt((Caseless("cs"), Caseless("df"), Caseless("sf"))).parse_next(input)?;
+// Type 9: !x2 (每次触发爆炸出多个骰子，Atom + Optional ModParam + Optional Limit)
+// 只有 !x 之后紧跟 Atom 才按此解析，否则回退到普通的爆炸
+fn parse_type9_modifier(input: &mut Input<'_>) -> WNResult<ModifierBuilder> {
+    let count = preceded(Caseless("!x"), parse_atom).parse_next(input)?;
+    let param = opt(parse_mod_param).parse_next(input)?;
+    let limit = opt(parse_limit).parse_next(input)?;
+    Ok(Box::new(move |lhs| {
+        Expr::modifier_type9(lhs, count, param, limit)
+    }))
+}
+
+// Type 10: !until(cs>=5, 3) (追加骰子直到成功数达标，ModParam + Expr)
+fn parse_type10_modifier(input: &mut Input<'_>) -> WNResult<ModifierBuilder> {
+    let _ = Caseless("!until").parse_next(input)?;
+    let (param, quota) = cut_err(delimited(
+        ("(", space0),
+        (
+            preceded(Caseless("cs"), parse_mod_param),
+            preceded(ws(","), parse_expr),
+        ),
+        (space0, ")"),
+    ))
+    .parse_next(input)?;
+    Ok(Box::new(move |lhs| {
+        Expr::modifier_type10(lhs, param, quota)
+    }))
+}
+
+// rm, remove: 按投掷顺序移除前 N 个满足条件的骰子，如 4d6rm=6、4d6rm2<3
+// 个数可省略 (默认为 1)，比较符不可省略，否则无法与个数区分
+fn parse_remove_modifier(input: &mut Input<'_>) -> WNResult<ModifierBuilder> {
+    alt((Caseless("remove"), Caseless("rm"))).parse_next(input)?;
+    let count = opt(parse_atom).parse_next(input)?;
+    let operator = cut_err(parse_compare_op).parse_next(input)?;
+    let value = cut_err(parse_atom).parse_next(input)?;
+    let param = Expr::mod_param(operator, value);
+    Ok(Box::new(move |lhs| {
+        let count = count.unwrap_or(Expr::number(1.0));
+        Expr::modifier_type4(lhs, Type4Op::RemoveMatching, count, param)
+    }))
+}
+
+// rmax: 调试用，将满足条件的骰子改为最大面，如 4d6rmax<3
+// 只在启用 debug-modifiers feature 时可用，否则 rmax 不是合法的修饰符
+fn parse_reroll_max_modifier(input: &mut Input<'_>) -> WNResult<ModifierBuilder> {
+    if !cfg!(feature = "debug-modifiers") {
+        return fail(input);
+    }
+    Caseless("rmax").parse_next(input)?;
+    let param = cut_err(parse_mod_param).parse_next(input)?;
+    Ok(Box::new(move |lhs| {
+        Expr::modifier_type3(lhs, Type3Op::RerollMax, param)
+    }))
+}
+
+// !total>=20: 骰子池总和满足条件时，最大面的骰子爆炸一轮
+fn parse_explode_total_modifier(input: &mut Input<'_>) -> WNResult<ModifierBuilder> {
+    Caseless("!total").parse_next(input)?;
+    let param = cut_err(parse_mod_param).parse_next(input)?;
+    Ok(Box::new(move |lhs| {
+        Expr::modifier_type3(lhs, Type3Op::ExplodeIfTotal, param)
+    }))
+}
+
+// Type 3: cs, df, sf, sp, net (Required ModParam)
+fn parse_type3_modifier(input: &mut Input<'_>) -> WNResult<ModifierBuilder> {
+    let tag_str = alt((
+        Caseless("cs"),
+        Caseless("df"),
+        Caseless("sf"),
+        Caseless("spend"), // spend 必须在 sp 前面匹配
+        Caseless("sp"),
+        Caseless("net"),
+    ))
+    .parse_next(input)?;
 
     let op = match tag_str.to_lowercase().as_str() {
         "cs" => Type3Op::CountSuccesses,
         "df" => Type3Op::DeductFailures,
         "sf" => Type3Op::SubtractFailures,
+        "spend" | "sp" => Type3Op::Spend,
+        "net" => Type3Op::Net,
         _ => unreachable!(),
     };
 
+    // cs 后可以跟一张权重表，如 cs[=10:2, >=7:1]
+    if op == Type3Op::CountSuccesses
+        && let Some(weights) = opt(parse_success_weights).parse_next(input)?
+    {
+        return Ok(Box::new(move |lhs| Expr::modifier_type7(lhs, weights)));
+    }
+
     let param = cut_err(parse_mod_param).parse_next(input)?;
 
     Ok(Box::new(move |lhs| Expr::modifier_type3(lhs, op, param)))
 }
 
+// 权重表：[mod_param:atom, ...]，第一项的 ":" 之前都可以回溯 (此时按普通的列表参数解析)
+fn parse_success_weights(input: &mut Input<'_>) -> WNResult<Vec<(ModParam, Expr)>> {
+    fn parse_weight(input: &mut Input<'_>) -> WNResult<(ModParam, Expr)> {
+        let param = parse_mod_param(input)?;
+        ws(":").parse_next(input)?;
+        let weight = cut_err(parse_atom).parse_next(input)?;
+        Ok((param, weight))
+    }
+    ("[", space0).parse_next(input)?;
+    let first = parse_weight(input)?;
+    let rest: Vec<(ModParam, Expr)> =
+        cut_err(repeat(0.., preceded(ws(","), parse_weight))).parse_next(input)?;
+    cut_err((space0, "]")).parse_next(input)?;
+    let mut weights = vec![first];
+    weights.extend(rest);
+    Ok(weights)
+}
+
+// Type 6: kdistinct (无参数)
+fn parse_type6_modifier(input: &mut Input<'_>) -> WNResult<ModifierBuilder> {
+    Caseless("kdistinct").parse_next(input)?;
+    Ok(Box::new(|lhs| {
+        Expr::modifier_type6(lhs, Type6Op::KeepDistinct)
+    }))
+}
+
+// Type 8: k2..4 (按排名区间保留，两个 Atom)
+// 只有 k 之后的 Atom 紧跟 ".." 才按此解析，否则回退到 kh、kl
+fn parse_type8_modifier(input: &mut Input<'_>) -> WNResult<ModifierBuilder> {
+    let low = delimited(Caseless("k"), parse_atom, "..").parse_next(input)?;
+    let high = cut_err(parse_atom).parse_next(input)?;
+    Ok(Box::new(move |lhs| Expr::modifier_type8(lhs, low, high)))
+}
+
 // 解析 limit: lt3, lc2, 或组合
-fn parse_limit(input: &mut &str) -> WNResult<Limit> {
-    let mut times = None;
-    let mut counts = None;
-    let mut parsed_times = false;
-    let mut parsed_counts = false;
-
-    // 尝试解析最多两个组件
-    for _ in 0..2 {
-        if peek::<_, _, ContextError, _>(Caseless("lt"))
-            .parse_next(input)
-            .is_ok()
-        {
-            if parsed_times {
-                return cut_err(fail).parse_next(input);
-            }
-            parsed_times = true;
-            let val = preceded(Caseless("lt"), parse_atom).parse_next(input)?;
-            times = Some(Box::new(val));
-        } else if peek::<_, _, ContextError, _>(Caseless("lc"))
+// limit 子句的前缀，按 Limit 中字段的顺序排列；每种子句最多出现一次，顺序任意
+const LIMIT_CLAUSES: [&str; 3] = ["lt", "lc", "lsz"];
+
+fn parse_limit(input: &mut Input<'_>) -> WNResult<Limit> {
+    let mut clauses: [Option<Box<Expr>>; 3] = [None, None, None];
+
+    // 每轮要么填入一个新的子句，要么报错或结束，因此最多循环 LIMIT_CLAUSES.len() 次
+    while let Some(kind) = LIMIT_CLAUSES.iter().position(|prefix| {
+        peek::<_, _, ContextError, _>(Caseless(*prefix))
             .parse_next(input)
             .is_ok()
-        {
-            if parsed_counts {
-                return cut_err(fail).parse_next(input);
-            }
-            parsed_counts = true;
-            let val = preceded(Caseless("lc"), parse_atom).parse_next(input)?;
-            counts = Some(Box::new(val));
-        } else {
-            break;
+    }) {
+        if clauses[kind].is_some() {
+            return cut_err(fail).parse_next(input); // 同一种子句重复出现
         }
+        let val = preceded(Caseless(LIMIT_CLAUSES[kind]), parse_atom).parse_next(input)?;
+        clauses[kind] = Some(Box::new(val));
     }
 
-    if times.is_none() && counts.is_none() {
+    let [times, counts, size] = clauses;
+    if times.is_none() && counts.is_none() && size.is_none() {
         fail(input) // 不是 limit
     } else {
         Ok(Limit {
             limit_times: times,
             limit_counts: counts,
+            limit_size: size,
         })
     }
 }
@@ -1136,6 +1763,208 @@ fn test_explode_expr_with_param() {
     );
 }
 
+#[test]
+fn test_numeric_comparison() {
+    // 括号中的成功池作为比较的左侧，比较作用于整个成功池
+    assert_eq!(
+        parse_dice("(2d6cs>=5) >= 3").unwrap(),
+        Expr::binary(
+            Expr::modifier_type3(
+                Expr::normal_dice(Expr::number(2.0), Expr::number(6.0)),
+                Type3Op::CountSuccesses,
+                Expr::mod_param(CompareOp::GreaterEqual, Expr::number(5.0)),
+            ),
+            BinOp::Compare(CompareOp::GreaterEqual),
+            Expr::number(3.0),
+        )
+    );
+    // 比较的优先级低于加减法
+    assert_eq!(
+        parse_dice("1 + 2 < 4").unwrap(),
+        Expr::binary(
+            Expr::binary(Expr::number(1.0), BinOp::Add, Expr::number(2.0)),
+            BinOp::Compare(CompareOp::Less),
+            Expr::number(4.0),
+        )
+    );
+    assert!(parse_dice("1 < 2 < 3").is_err());
+    // 左侧以骰子或修饰符结尾时必须加括号，与中间是否有空白无关
+    for input in [
+        "4d6>3",
+        "4d6 > 3",
+        "4d6kh>3",
+        "4d6kh >3",
+        "4d6kh2>3",
+        "4d6dl <3",
+        "1 + 2d6 > 3",
+        "4d6kh(2) > 3",
+        "2d6cs>=5 >= 3",
+    ] {
+        assert!(parse_dice(input).is_err(), "{}", input);
+    }
+    assert!(parse_dice("let x = 4d6; x>3").is_ok());
+    for input in [
+        "(4d6)>3",
+        "(4d6) > 3",
+        "(4d6kh2)>3",
+        "1 + (2d6) > 3",
+        "2d6 + 1 > 3",
+        "(2d6cs>=5)>=3",
+    ] {
+        assert!(
+            matches!(
+                parse_dice(input).unwrap(),
+                Expr::Binary(BinaryOp {
+                    op: BinOp::Compare(_),
+                    ..
+                })
+            ),
+            "{}",
+            input
+        );
+    }
+}
+
+#[test]
+fn test_compare_op_display_round_trip() {
+    use CompareOp::*;
+    let state = ParseState::default();
+    let all = [Greater, GreaterEqual, Less, LessEqual, Equal, NotEqual];
+    for op in all.clone() {
+        // 新增变体时这里无法编译，提醒同时补充上面的列表、解析与显示
+        match op {
+            Greater | GreaterEqual | Less | LessEqual | Equal | NotEqual => {}
+        }
+        let text = op.to_string();
+        let mut input = Input {
+            input: text.as_str(),
+            state: &state,
+        };
+        assert_eq!(parse_compare_op(&mut input).unwrap(), op, "{}", text);
+        assert!(input.is_empty(), "{} was not fully consumed", text);
+    }
+    // 规范的显示形式
+    let symbols: Vec<String> = all.iter().map(|op| op.to_string()).collect();
+    assert_eq!(symbols, [">", ">=", "<", "<=", "=", "<>"]);
+    // 别名只用于解析，显示时总是使用规范形式
+    let mut input = Input {
+        input: "!=",
+        state: &state,
+    };
+    assert_eq!(parse_compare_op(&mut input).unwrap().to_string(), "<>");
+}
+
+#[test]
+fn test_not_equal_alias() {
+    let not_three = || Expr::mod_param(CompareOp::NotEqual, Expr::number(3.0));
+    assert_eq!(
+        parse_dice("filter!=3([1,2,3])").unwrap(),
+        parse_dice("filter<>3([1,2,3])").unwrap()
+    );
+    assert_eq!(
+        parse_dice("4d6cs!=3").unwrap(),
+        Expr::modifier_type3(
+            Expr::normal_dice(Expr::number(4.0), Expr::number(6.0)),
+            Type3Op::CountSuccesses,
+            not_three()
+        )
+    );
+    // 爆炸修饰符的 ! 优先被解析，2d6!=3 是 "投出 3 时爆炸"
+    let dice = || Expr::normal_dice(Expr::number(2.0), Expr::number(6.0));
+    assert_eq!(
+        parse_dice("2d6!=3").unwrap(),
+        Expr::modifier_type2(
+            dice(),
+            Type2Op::Explode,
+            Some(Expr::mod_param(CompareOp::Equal, Expr::number(3.0))),
+            None
+        )
+    );
+    assert_eq!(
+        parse_dice("2d6!!=3").unwrap(),
+        Expr::modifier_type2(
+            dice(),
+            Type2Op::CompoundExplode,
+            Some(Expr::mod_param(CompareOp::Equal, Expr::number(3.0))),
+            None
+        )
+    );
+    assert_eq!(
+        parse_dice("2d6!<>3").unwrap(),
+        Expr::modifier_type2(dice(), Type2Op::Explode, Some(not_three()), None)
+    );
+}
+
+#[test]
+fn test_explode_once() {
+    let dice = || Expr::normal_dice(Expr::number(4.0), Expr::number(6.0));
+    assert_eq!(
+        parse_dice("4d6!o").unwrap(),
+        Expr::modifier_type2(dice(), Type2Op::ExplodeOnce, None, None)
+    );
+    assert_eq!(
+        parse_dice("4d6!O>4").unwrap(),
+        Expr::modifier_type2(
+            dice(),
+            Type2Op::ExplodeOnce,
+            Some(Expr::mod_param(CompareOp::Greater, Expr::number(4.0))),
+            None
+        )
+    );
+}
+
+#[test]
+fn test_keep_relative_to_average() {
+    let dice = || Expr::normal_dice(Expr::number(4.0), Expr::number(6.0));
+    let cases = [
+        ("4d6kh>avg", CompareOp::Greater),
+        ("4d6KH>=AVG", CompareOp::GreaterEqual),
+        ("4d6kl<avg", CompareOp::Less),
+        ("4d6kl<=avg", CompareOp::LessEqual),
+    ];
+    for (input, compare) in cases {
+        assert_eq!(
+            parse_dice(input).unwrap(),
+            Expr::modifier_type5(dice(), compare)
+        );
+    }
+    // 方向不匹配或缺少 avg 时不是合法的修饰符
+    assert!(parse_dice("4d6kh<avg").is_err());
+    assert!(parse_dice("4d6kl>avg").is_err());
+    assert!(parse_dice("4d6kh>3").is_err());
+}
+
+#[test]
+fn test_implicit_equal_mod_param() {
+    // 省略比较符时一律视为等于，包括爆炸 (!3 表示只在投出 3 时爆炸，而不是 >=3)
+    let dice = || Expr::normal_dice(Expr::number(4.0), Expr::number(6.0));
+    let equal_three = || Expr::mod_param(CompareOp::Equal, Expr::number(3.0));
+    let type2_cases = [
+        ("4d6r3", Type2Op::Reroll),
+        ("4d6!3", Type2Op::Explode),
+        ("4d6!!3", Type2Op::CompoundExplode),
+    ];
+    for (input, op) in type2_cases {
+        assert_eq!(
+            parse_dice(input).unwrap(),
+            Expr::modifier_type2(dice(), op, Some(equal_three()), None),
+            "{input}"
+        );
+    }
+    let type3_cases = [
+        ("4d6cs3", Type3Op::CountSuccesses),
+        ("4d6df3", Type3Op::DeductFailures),
+        ("4d6sf3", Type3Op::SubtractFailures),
+    ];
+    for (input, op) in type3_cases {
+        assert_eq!(
+            parse_dice(input).unwrap(),
+            Expr::modifier_type3(dice(), op, equal_three()),
+            "{input}"
+        );
+    }
+}
+
 #[test]
 fn test_explode_compound_expr() {
     let result = parse_dice("2d6!!");
@@ -1189,6 +2018,7 @@ fn test_explode_compound_expr_with_param_and_limit() {
                     Expr::number(1.0)
                 ))),
                 limit_counts: None,
+                limit_size: None,
             })
         )
     )
@@ -1207,6 +2037,7 @@ fn test_explode_compound_expr_with_limit() {
             Some(Limit {
                 limit_times: None,
                 limit_counts: Some(Box::new(Expr::number(4.0))),
+                limit_size: None,
             })
         )
     );
@@ -1446,6 +2277,7 @@ fn test_chaos_theory() {
                     Expr::number(1.0),
                 ))),
                 limit_counts: None,
+                limit_size: None,
             }),
         ),
         Type1Op::KeepHigh,
@@ -1527,6 +2359,40 @@ fn test_limit_times_without_value() {
     assert!(result.is_err());
 }
 
+#[test]
+fn test_limit_clauses_any_order() {
+    let limit = |times: f64, counts: f64, size: f64| Limit {
+        limit_times: Some(Box::new(Expr::number(times))),
+        limit_counts: Some(Box::new(Expr::number(counts))),
+        limit_size: Some(Box::new(Expr::number(size))),
+    };
+    let expected = Expr::modifier_type2(
+        Expr::normal_dice(Expr::number(2.0), Expr::number(6.0)),
+        Type2Op::Explode,
+        None,
+        Some(limit(3.0, 4.0, 5.0)),
+    );
+    for input in [
+        "2d6!lt3lc4lsz5",
+        "2d6!lt3lsz5lc4",
+        "2d6!lc4lt3lsz5",
+        "2d6!lc4lsz5lt3",
+        "2d6!lsz5lt3lc4",
+        "2d6!LSZ5LC4LT3",
+    ] {
+        assert_eq!(parse_dice(input), Ok(expected.clone()), "{input}");
+    }
+    // 每种子句最多出现一次
+    for input in [
+        "2d6!lt3lt4",
+        "2d6!lc3lsz5lc4",
+        "2d6!lsz5lt3lsz6",
+        "2d6!lt3lc4lsz5lt6",
+    ] {
+        assert!(parse_dice(input).is_err(), "{input}");
+    }
+}
+
 #[test]
 fn test_count_success_without_target() {
     // 假设 cs 定义为必须参数: count_successes = { ^"cs" ~ mod_param }
@@ -1646,3 +2512,489 @@ fn test_wrong_modifer7() {
 
     assert!(result.is_err());
 }
+
+#[test]
+fn test_conditional_drop() {
+    let result = parse_dice("4d6dl1<2");
+    assert_eq!(
+        result.unwrap(),
+        Expr::modifier_type4(
+            Expr::normal_dice(Expr::number(4.0), Expr::number(6.0)),
+            Type4Op::ConditionalDropLow,
+            Expr::number(1.0),
+            Expr::mod_param(CompareOp::Less, Expr::number(2.0))
+        )
+    );
+    // 省略个数时默认为 1
+    let result = parse_dice("4d6DH>=5");
+    assert_eq!(
+        result.unwrap(),
+        Expr::modifier_type4(
+            Expr::normal_dice(Expr::number(4.0), Expr::number(6.0)),
+            Type4Op::ConditionalDropHigh,
+            Expr::number(1.0),
+            Expr::mod_param(CompareOp::GreaterEqual, Expr::number(5.0))
+        )
+    );
+}
+
+#[test]
+fn test_comma_separated_roots() {
+    let roots = parse_dice_all("1d20+5, 2d6").unwrap();
+    assert_eq!(
+        roots,
+        vec![
+            Expr::binary(
+                Expr::normal_dice(Expr::number(1.0), Expr::number(20.0)),
+                BinOp::Add,
+                Expr::number(5.0)
+            ),
+            Expr::normal_dice(Expr::number(2.0), Expr::number(6.0)),
+        ]
+    );
+    // 列表与函数参数中的逗号不会分隔顶层表达式
+    assert_eq!(parse_dice_all("[1d20, 2d6]").unwrap().len(), 1);
+    assert_eq!(parse_dice_all("max(1d20, 2d6)").unwrap().len(), 1);
+    assert!(parse_dice_all("1d20,").is_err());
+    assert!(parse_dice("1d20, 2d6").is_err());
+}
+
+#[test]
+fn test_remove_matching() {
+    let expected = Expr::modifier_type4(
+        Expr::normal_dice(Expr::number(4.0), Expr::number(6.0)),
+        Type4Op::RemoveMatching,
+        Expr::number(1.0),
+        Expr::mod_param(CompareOp::Equal, Expr::number(6.0)),
+    );
+    assert_eq!(parse_dice("4d6rm=6").unwrap(), expected);
+    assert_eq!(parse_dice("4d6remove1=6").unwrap(), expected);
+    // 比较符不可省略
+    assert!(parse_dice("4d6rm6").is_err());
+}
+
+#[test]
+fn test_input_limits() {
+    // 超长输入在解析前即被拒绝
+    let long_input = "1+".repeat(100_000) + "1";
+    let err = parse_dice(&long_input).unwrap_err();
+    assert_eq!(
+        err,
+        "Expression is too long: 200001 characters (limit 10000)"
+    );
+    // 深度嵌套的括号同样在解析前被拒绝
+    let deep_input = "(".repeat(65) + "1" + &")".repeat(65);
+    assert_eq!(
+        parse_dice(&deep_input).unwrap_err(),
+        "Expression is nested too deeply: 65 levels of brackets and unary signs (limit 64)"
+    );
+    let deep_input = "(".repeat(64) + "1" + &")".repeat(64);
+    assert!(parse_dice(&deep_input).is_ok());
+    // 一串一元符号同样计入嵌套层数，避免递归解析耗尽栈空间
+    let signs_input = "-".repeat(9000) + "1";
+    assert_eq!(
+        parse_dice(&signs_input).unwrap_err(),
+        "Expression is nested too deeply: 9000 levels of brackets and unary signs (limit 64)"
+    );
+    assert!(parse_dice(&("-(".repeat(32) + "1" + &")".repeat(32))).is_ok());
+    assert!(parse_dice(&("-(".repeat(33) + "1" + &")".repeat(33))).is_err());
+    // 二元运算符与分开的一元符号不会累加
+    assert!(parse_dice(&("1-".repeat(1000) + "1")).is_ok());
+    assert!(parse_dice(&("-1+".repeat(1000) + "1")).is_ok());
+    assert!(parse_dice(&("(1)-".repeat(1000) + "1")).is_ok());
+    // 限制可以自定义
+    let limits = ParseLimits {
+        max_length: 5,
+        max_depth: 1,
+    };
+    assert!(parse_dice_with_limits("1+2+3", limits).is_ok());
+    assert!(parse_dice_with_limits("1+2+34", limits).is_err());
+    assert!(parse_dice_with_limits("((1))", limits).is_err());
+}
+
+#[test]
+fn test_percentile_dice() {
+    assert_eq!(parse_dice("d%%").unwrap(), Expr::percentile_dice());
+    assert_eq!(
+        parse_dice("D%% + 1").unwrap(),
+        Expr::binary(Expr::percentile_dice(), BinOp::Add, Expr::number(1.0))
+    );
+    assert!(parse_dice("2d%%").is_err());
+}
+
+#[test]
+fn test_labeled_dice() {
+    assert_eq!(
+        parse_dice("2d[miss: 0, hit:1, crit : 2]").unwrap(),
+        Expr::labeled_dice(
+            Expr::number(2.0),
+            vec![
+                ("miss".to_string(), Expr::number(0.0)),
+                ("hit".to_string(), Expr::number(1.0)),
+                ("crit".to_string(), Expr::number(2.0)),
+            ]
+        )
+    );
+    assert_eq!(
+        parse_dice("d[-:-1, +:1]").unwrap(),
+        Expr::labeled_dice(
+            Expr::number(1.0),
+            vec![
+                ("-".to_string(), Expr::neg(Expr::number(1.0))),
+                ("+".to_string(), Expr::number(1.0)),
+            ]
+        )
+    );
+    // 没有标签时仍然是普通列表作为面数
+    assert_eq!(
+        parse_dice("2d[6]").unwrap(),
+        Expr::normal_dice(Expr::number(2.0), Expr::list(vec![Expr::number(6.0)]))
+    );
+    assert!(parse_dice("d[a:1,]").is_err());
+    assert!(parse_dice("d[a:1, 2]").is_err());
+}
+
+#[test]
+fn test_cascade_modifier() {
+    let result = parse_dice("6d10cascade>=8lt3").unwrap();
+    assert_eq!(
+        result,
+        Expr::modifier_type2(
+            Expr::normal_dice(Expr::number(6.0), Expr::number(10.0)),
+            Type2Op::Cascade,
+            Some(Expr::mod_param(CompareOp::GreaterEqual, Expr::number(8.0))),
+            Some(Limit {
+                limit_times: Some(Box::new(Expr::number(3.0))),
+                limit_counts: None,
+                limit_size: None,
+            }),
+        )
+    );
+    // 成功条件与轮数限制都不可省略
+    assert!(parse_dice("6d10cascade>=8").is_err());
+    assert!(parse_dice("6d10cascadelt3").is_err());
+    assert!(parse_dice("6d10cs>=8").is_ok());
+}
+
+#[test]
+fn test_explode_multi() {
+    let dice = || Expr::normal_dice(Expr::number(4.0), Expr::number(6.0));
+    assert_eq!(
+        parse_dice("4d6!x2>=5lt1").unwrap(),
+        Expr::modifier_type9(
+            dice(),
+            Expr::number(2.0),
+            Some(Expr::mod_param(CompareOp::GreaterEqual, Expr::number(5.0))),
+            Some(Limit {
+                limit_times: Some(Box::new(Expr::number(1.0))),
+                limit_counts: None,
+                limit_size: None,
+            }),
+        )
+    );
+    assert_eq!(
+        parse_dice("4d6!").unwrap(),
+        Expr::modifier_type2(dice(), Type2Op::Explode, None, None)
+    );
+}
+
+#[test]
+fn test_explode_until() {
+    let dice = || Expr::normal_dice(Expr::number(2.0), Expr::number(6.0));
+    assert_eq!(
+        parse_dice("2d6!until(cs>=5, 3)").unwrap(),
+        Expr::modifier_type10(
+            dice(),
+            Expr::mod_param(CompareOp::GreaterEqual, Expr::number(5.0)),
+            Expr::number(3.0),
+        )
+    );
+    assert_eq!(
+        parse_dice("2d6!UNTIL( cs6 , 1 + 1 )").unwrap(),
+        Expr::modifier_type10(
+            dice(),
+            Expr::mod_param(CompareOp::Equal, Expr::number(6.0)),
+            Expr::binary(Expr::number(1.0), BinOp::Add, Expr::number(1.0)),
+        )
+    );
+    assert!(parse_dice("2d6!until(>=5, 3)").is_err());
+    assert!(parse_dice("2d6!until(cs>=5)").is_err());
+}
+
+#[test]
+fn test_explode_if_total() {
+    let dice = || Expr::normal_dice(Expr::number(4.0), Expr::number(6.0));
+    assert_eq!(
+        parse_dice("4d6!total>=20").unwrap(),
+        Expr::modifier_type3(
+            dice(),
+            Type3Op::ExplodeIfTotal,
+            Expr::mod_param(CompareOp::GreaterEqual, Expr::number(20.0)),
+        )
+    );
+    assert_eq!(
+        parse_dice("4d6!TOTAL20").unwrap(),
+        Expr::modifier_type3(
+            dice(),
+            Type3Op::ExplodeIfTotal,
+            Expr::mod_param(CompareOp::Equal, Expr::number(20.0)),
+        )
+    );
+    assert!(parse_dice("4d6!total").is_err());
+}
+
+#[test]
+fn test_empty_expression() {
+    for input in ["", "   ", "\t", " \t\n "] {
+        assert_eq!(parse_dice(input), Err("Empty expression".to_string()));
+        assert_eq!(parse_dice_all(input), Err("Empty expression".to_string()));
+    }
+    // 只有 let 绑定、没有表达式时仍是普通的语法错误
+    assert_ne!(
+        parse_dice("let x = 1;"),
+        Err("Empty expression".to_string())
+    );
+}
+
+#[test]
+fn test_keep_rank_range() {
+    let result = parse_dice("6d6k2..4").unwrap();
+    assert_eq!(
+        result,
+        Expr::modifier_type8(
+            Expr::normal_dice(Expr::number(6.0), Expr::number(6.0)),
+            Expr::number(2.0),
+            Expr::number(4.0),
+        )
+    );
+    // 不带 ".." 时仍是 kh、kl
+    assert_eq!(
+        parse_dice("6d6kh2").unwrap(),
+        Expr::modifier_type1(
+            Expr::normal_dice(Expr::number(6.0), Expr::number(6.0)),
+            Type1Op::KeepHigh,
+            Expr::number(2.0),
+        )
+    );
+    assert!(parse_dice("6d6k2").is_err());
+    assert!(parse_dice("6d6k2..").is_err());
+}
+
+#[test]
+fn test_success_weights() {
+    let result = parse_dice("10d10cs[=10:2, >=7:1]").unwrap();
+    assert_eq!(
+        result,
+        Expr::modifier_type7(
+            Expr::normal_dice(Expr::number(10.0), Expr::number(10.0)),
+            vec![
+                (
+                    Expr::mod_param(CompareOp::Equal, Expr::number(10.0)),
+                    Expr::number(2.0)
+                ),
+                (
+                    Expr::mod_param(CompareOp::GreaterEqual, Expr::number(7.0)),
+                    Expr::number(1.0)
+                ),
+            ],
+        )
+    );
+    // 不带冒号时仍按普通的比较参数解析
+    assert_eq!(
+        parse_dice("2d6cs[1,2]").unwrap(),
+        Expr::modifier_type3(
+            Expr::normal_dice(Expr::number(2.0), Expr::number(6.0)),
+            Type3Op::CountSuccesses,
+            Expr::mod_param(
+                CompareOp::Equal,
+                Expr::List(vec![Expr::number(1.0), Expr::number(2.0)])
+            ),
+        )
+    );
+    // 第一项之后的格式错误不再回溯
+    assert!(parse_dice("10d10cs[=10:2, >=7]").is_err());
+    assert!(parse_dice("10d10cs[=10:2").is_err());
+    assert!(parse_dice("10d10df[=10:2]").is_err());
+}
+
+#[test]
+fn test_each_args() {
+    let pool = || Expr::normal_dice(Expr::number(4.0), Expr::number(6.0));
+    for (input, op) in [
+        ("each(4d6, +1)", EachOp::Add),
+        ("each(4d6,1)", EachOp::Add),
+        ("each(4d6, - 1)", EachOp::Sub),
+        ("EACH(4d6, *1)", EachOp::Mul),
+    ] {
+        assert_eq!(
+            parse_dice(input).unwrap(),
+            Expr::function(FunctionName::Each(op), vec![pool(), Expr::number(1.0)]),
+            "{}",
+            input
+        );
+    }
+    assert!(parse_dice("each(4d6)").is_err());
+    assert!(parse_dice("each(4d6, /2)").is_err());
+    assert!(parse_dice("let each = 1; each").is_err());
+}
+
+#[test]
+fn test_rep_args() {
+    let result = parse_dice("rep(1d6, 3)");
+    assert_eq!(
+        result.unwrap(),
+        Expr::function(
+            FunctionName::Rep,
+            vec![
+                Expr::normal_dice(Expr::number(1.0), Expr::number(6.0)),
+                Expr::number(3.0)
+            ]
+        )
+    );
+}
+
+#[test]
+fn test_gen_args() {
+    let result = parse_dice("gen(3, 1d6)");
+    assert_eq!(
+        result.unwrap(),
+        Expr::function(
+            FunctionName::Gen,
+            vec![
+                Expr::number(3.0),
+                Expr::normal_dice(Expr::number(1.0), Expr::number(6.0))
+            ]
+        )
+    );
+}
+
+#[test]
+fn test_leading_dot_float() {
+    // 小数点前必须有数字
+    let result = parse_dice(".5d6");
+    assert!(
+        result
+            .unwrap_err()
+            .contains("digit before the decimal point")
+    );
+    let result = parse_dice("3d6+.5");
+    assert!(
+        result
+            .unwrap_err()
+            .contains("digit before the decimal point")
+    );
+    assert_eq!(parse_dice("0.5").unwrap(), Expr::number(0.5));
+}
+
+#[test]
+fn test_trailing_dot_float() {
+    // 小数点后必须有数字
+    let result = parse_dice("5.d6");
+    assert!(
+        result
+            .unwrap_err()
+            .contains("digit after the decimal point")
+    );
+    let result = parse_dice("5.");
+    assert!(
+        result
+            .unwrap_err()
+            .contains("digit after the decimal point")
+    );
+    let result = parse_dice("1.2.3");
+    assert!(result.unwrap_err().contains("only one decimal point"));
+    assert_eq!(parse_dice("5.0").unwrap(), Expr::number(5.0));
+    assert_eq!(parse_dice("2.5e-1").unwrap(), Expr::number(0.25));
+}
+
+#[test]
+fn test_trailing_input_position() {
+    let err = parse_dice("2d6 garbage").unwrap_err();
+    assert!(
+        err.contains("Unexpected `garbage` at byte offset 4"),
+        "{}",
+        err
+    );
+    let err = parse_dice("1+2 )").unwrap_err();
+    assert!(err.contains("Unexpected `)` at byte offset 4"), "{}", err);
+    // 偏移量按字节计算
+    let err = parse_dice("1 + 2 ✓x  ").unwrap_err();
+    assert!(err.contains("Unexpected `✓x` at byte offset 6"), "{}", err);
+    // 表达式本身不完整时不是多余的输入
+    assert!(!parse_dice("2d6+").unwrap_err().contains("byte offset"));
+    assert!(!parse_dice("(1+2").unwrap_err().contains("byte offset"));
+}
+
+#[test]
+fn test_modifier_typo_suggestion() {
+    let err = parse_dice("2d20khh2").unwrap_err();
+    assert!(err.ends_with("Unknown modifier `khh`, did you mean `kh`?"));
+    let err = parse_dice("4d6css>3").unwrap_err();
+    assert!(err.ends_with("Unknown modifier `css`, did you mean `cs`?"));
+    let err = parse_dice("2d20ro").unwrap_err();
+    assert!(err.contains("did you mean `r` or `rp`"));
+    // 与任何修饰符都相差较远时不给出提示
+    assert!(!parse_dice("flor(2)").unwrap_err().contains("did you mean"));
+    assert!(!parse_dice("2d6+").unwrap_err().contains("did you mean"));
+    // 不跟在骰子项之后的单词不可能是修饰符
+    assert!(!parse_dice("2dx6").unwrap_err().contains("did you mean"));
+    assert!(!parse_dice("3+khh").unwrap_err().contains("did you mean"));
+    assert!(
+        parse_dice("1+2d20khh2")
+            .unwrap_err()
+            .contains("did you mean `kh`")
+    );
+    assert!(
+        parse_dice("(2d6)css>3")
+            .unwrap_err()
+            .contains("did you mean `cs`")
+    );
+    // 出错位置前后为多字节字符时也能正确截取单词
+    assert!(parse_dice("２khh").is_err());
+}
+
+#[test]
+fn test_let_bindings() {
+    let atk = || Expr::Var("atk".to_string());
+    assert_eq!(
+        parse_dice("let atk = 1d20+5; atk + atk").unwrap(),
+        Expr::let_in(
+            vec![Expr::let_binding(
+                "atk",
+                Expr::binary(
+                    Expr::normal_dice(Expr::number(1.0), Expr::number(20.0)),
+                    BinOp::Add,
+                    Expr::number(5.0)
+                ),
+                false
+            )],
+            Expr::binary(atk(), BinOp::Add, atk())
+        )
+    );
+    assert_eq!(
+        parse_dice("let share x = d6; let y = x * 2; y").unwrap(),
+        Expr::let_in(
+            vec![
+                Expr::let_binding(
+                    "x",
+                    Expr::normal_dice(Expr::number(1.0), Expr::number(6.0)),
+                    true
+                ),
+                Expr::let_binding(
+                    "y",
+                    Expr::binary(Expr::Var("x".to_string()), BinOp::Mul, Expr::number(2.0)),
+                    false
+                ),
+            ],
+            Expr::Var("y".to_string())
+        )
+    );
+    // 未声明的名字不是变量，d6 这类骰子写法不受影响
+    assert!(parse_dice("atk + 1").is_err());
+    assert!(parse_dice("let dmg = 2; d6 + dmg").is_ok());
+    // 变量名不能与函数、修饰符或关键字重名
+    assert!(parse_dice("let max = 3; max").is_err());
+    assert!(parse_dice("let kh = 3; 4d6kh").is_err());
+    assert!(parse_dice("let share = 3; share").is_err());
+    assert!(parse_dice("let x = 3 x").is_err());
+}
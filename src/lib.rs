@@ -3,10 +3,12 @@
 //! This crate provides functionality for dice rolling and related utilities.
 
 pub(crate) mod compiler;
+pub(crate) mod distribution;
 pub(crate) mod grammar;
 pub(crate) mod lower;
 pub(crate) mod optimizer;
 pub(crate) mod render_result;
+pub(crate) mod render_text;
 pub(crate) mod runtime;
 pub(crate) mod runtime_engine;
 pub(crate) mod types;
@@ -28,7 +30,60 @@ pub fn parse_dice_and_show(input: &str) -> Result<String, String> {
     Ok(format!("{}", hir))
 }
 
-pub use runtime::roll_without_animation;
+// 与 parse_dice_and_show 相同，但使用自定义的输入长度与嵌套深度限制
+pub fn parse_dice_and_show_with_limits(input: &str, limits: ParseLimits) -> Result<String, String> {
+    let ast = grammar::parse_dice_with_limits(input, limits)?;
+    let hir = lower::lower_expr(ast)?;
+    let hir = constant_fold_hir(hir)?;
+    Ok(format!("{}", hir))
+}
+
+// 表达式的规范形式，可作为缓存结果的键：与空白无关，加法与乘法链中的常数被交换、结合为一项，
+// 同类骰子被合并与重排，因此 "2+3 * 1d6" 与 "3*1d6 + 2" 得到同一个字符串。
+// 与 parse_dice_and_show 的输出一致：常数使用精确表示，共享绑定写作 let share，
+// 以骰子结尾的比较左侧带括号，输出重新解析后含义不变，因此含义不同的表达式不会得到相同的键
+pub fn canonicalize(input: &str) -> Result<String, String> {
+    parse_dice_and_show(input)
+}
+
+// 若表达式可以被完全折叠为常数 (不含骰子)，返回该常数，否则返回 None
+// 宿主程序可以据此跳过纯数学表达式的投掷流程；解析或类型错误时返回 Err
+pub fn try_const_eval(input: &str) -> Result<Option<f64>, String> {
+    use types::hir::{HIR, NumberType};
+    let ast = grammar::parse_dice(input)?;
+    let hir = lower::lower_expr(ast)?;
+    match constant_fold_hir(hir)? {
+        HIR::Number(NumberType::Constant(c)) => Ok(Some(c)),
+        _ => Ok(None),
+    }
+}
+
+pub use distribution::{distribution, probability_at_least};
+pub use grammar::ParseLimits;
+pub use render_result::RenderOptions;
+pub use render_text::{
+    TextFormatOptions, format_breakdown_text, format_result_text, format_result_text_with_options,
+};
+pub use runtime::{
+    AnimatedRoll, AnimationFrame, DEFAULT_DICE_COUNT_LIMIT, DEFAULT_RECURSION_LIMIT,
+    DiceRollerWithoutAnimation, eval_hir, roll_all, roll_animated, roll_batch,
+    roll_breakdown_without_animation, roll_total, roll_total_with_seed, roll_with_seed,
+    roll_without_animation,
+};
+pub use types::expr::CompareOp;
+pub use types::list_ops::partition;
+#[cfg(feature = "cache")]
+pub use types::output_cache::{
+    breakdown_from_bytes, breakdown_to_bytes, output_from_bytes, output_to_bytes,
+};
+pub use types::output_node::{
+    BreakdownTerm, CoinTally, DiceFaceType, DiceShape, DieDetailSummary, FaceSymbols, NodeLayout,
+    OutcomeType, OutputNode, RollBreakdown, ValueSummary,
+};
+pub use types::output_visitor::OutputVisitor;
+pub use types::runtime_value::{
+    DiceFace, DieDetail, DieOutcome, DynamicOpKind, RoundingMode, SuccessPoolType, TraceEvent,
+};
 
 // ==========================================
 // 辅助类型定义
@@ -1,9 +1,28 @@
+use crate::optimizer::constant_fold::constant_fold_hir;
+use crate::runtime::DEFAULT_DICE_COUNT_LIMIT;
 use crate::types::expr::{
-    BinOp, BinaryOp, DiceType, Expr, FunctionCall, FunctionName, ModifierNode, Type1Modifier,
-    Type1Op, Type2Modifier, Type2Op, Type3Modifier, Type3Op,
+    BinOp, BinaryOp, CompareOp, DiceType, EachOp, Expr, FunctionCall, FunctionName, LetExpr, Limit,
+    ModParam, ModifierNode, Type1Modifier, Type1Op, Type2Modifier, Type2Op, Type3Modifier, Type3Op,
+    Type4Modifier, Type4Op, Type5Modifier, Type6Modifier, Type6Op, Type7Modifier, Type8Modifier,
+    Type9Modifier, Type10Modifier,
 };
 use crate::types::hir::{DicePoolType, HIR, ListType, NumberType};
+use crate::types::hir_fmt::keep_relative_label;
 use crate::types::hir_rewriter::HirVisitor;
+use crate::types::runtime_value::get_compare_function;
+use std::cell::Cell;
+
+// 列表重复 (**) 展开后允许的最大长度
+const MAX_LIST_REPEAT_LEN: usize = 10_000;
+
+// let 绑定替换变量后，表达式允许的最大节点数
+const MAX_LET_EXPANSION_NODES: usize = 10_000;
+
+// round/floor/ceil 允许的最大小数位数
+const MAX_ROUNDING_PLACES: usize = 15;
+
+// floor/ceil 保留小数位时，放大后的值先舍入到 1 / SNAP_SCALE 以消除浮点误差
+const SNAP_SCALE: f64 = 1e9;
 
 // ==========================================
 // 从 AST 降低到 HIR
@@ -29,6 +48,223 @@ pub fn lower_expr(expr: Expr) -> Result<HIR, String> {
         Expr::Modifier(ModifierNode::Type3(Type3Modifier { lhs, op, param })) => {
             lower_modifier_type3(*lhs, op, param)
         }
+        Expr::Modifier(ModifierNode::Type4(Type4Modifier {
+            lhs,
+            op,
+            count,
+            param,
+        })) => lower_modifier_type4(*lhs, op, *count, param),
+        Expr::Modifier(ModifierNode::Type5(Type5Modifier { lhs, compare })) => {
+            let label = format!("{}avg", keep_relative_label(&compare));
+            let lowered_lhs = expect_modifier_pool(&label, lower_expr(*lhs)?)?;
+            Ok(HIR::keep_relative_to_average(lowered_lhs, compare))
+        }
+        Expr::Modifier(ModifierNode::Type6(Type6Modifier { lhs, op })) => match op {
+            Type6Op::KeepDistinct => {
+                let lowered_lhs = expect_modifier_pool("kdistinct", lower_expr(*lhs)?)?;
+                Ok(HIR::keep_distinct(lowered_lhs))
+            }
+        },
+        Expr::Modifier(ModifierNode::Type7(Type7Modifier { lhs, weights })) => {
+            lower_success_weights(*lhs, weights)
+        }
+        Expr::Modifier(ModifierNode::Type8(Type8Modifier { lhs, low, high })) => {
+            lower_keep_rank_range(*lhs, *low, *high)
+        }
+        Expr::Modifier(ModifierNode::Type9(Type9Modifier {
+            lhs,
+            count,
+            param,
+            limit,
+        })) => lower_explode_multi(*lhs, *count, param, limit),
+        Expr::Modifier(ModifierNode::Type10(Type10Modifier { lhs, param, quota })) => {
+            lower_explode_until(*lhs, param, *quota)
+        }
+        Expr::Let(let_expr) => lower_let(let_expr),
+        Expr::Var(name) => Err(format!("Unknown variable {}", name)),
+        Expr::Shared(id, value) => match lower_expr(*value)? {
+            HIR::Number(num) => Ok(HIR::shared(id, num)),
+            HIR::List(_) => Err(
+                "A let share binding must be a number; use let without share for a list"
+                    .to_string(),
+            ),
+        },
+    }
+}
+
+// ==========================================
+// let 绑定：将变量替换为其绑定的表达式
+// ==========================================
+
+// 普通绑定直接替换为表达式本身，每次引用都会生成独立的子图 (独立投掷)；
+// share 绑定替换为带编号的 Expr::Shared，编译时同一编号只生成一个节点 (共用一次投掷)
+// 绑定的值可以引用前面的绑定，同名的后一个绑定会遮蔽前一个
+// 链式绑定 (let b=a+a; let c=b+b; ...) 会使替换后的表达式成倍增长，因此替换时统计节点数并设置上限
+fn lower_let(let_expr: LetExpr) -> Result<HIR, String> {
+    let mut env: Vec<(String, Expr, usize)> = Vec::new();
+    for (index, binding) in let_expr.bindings.into_iter().enumerate() {
+        let size = Cell::new(0);
+        let value = substitute_vars(*binding.value, &env, &size);
+        check_let_expansion(&size)?;
+        let value = if binding.shared {
+            Expr::Shared(index as u32, Box::new(value))
+        } else {
+            value
+        };
+        env.push((binding.name, value, size.get()));
+    }
+    let size = Cell::new(0);
+    let body = substitute_vars(*let_expr.body, &env, &size);
+    check_let_expansion(&size)?;
+    lower_expr(body)
+}
+
+fn check_let_expansion(size: &Cell<usize>) -> Result<(), String> {
+    if size.get() > MAX_LET_EXPANSION_NODES {
+        return Err(format!(
+            "let bindings expand to an expression that is too large (limit {} nodes)",
+            MAX_LET_EXPANSION_NODES
+        ));
+    }
+    Ok(())
+}
+
+// size 累计替换后表达式的节点数，超过上限后不再复制绑定的值，由调用方报错
+fn substitute_vars(expr: Expr, env: &[(String, Expr, usize)], size: &Cell<usize>) -> Expr {
+    size.set(size.get().saturating_add(1));
+    let sub = |e: Box<Expr>| Box::new(substitute_vars(*e, env, size));
+    let sub_param = |p: ModParam| ModParam {
+        operator: p.operator,
+        value: sub(p.value),
+    };
+    let sub_limit = |l: Limit| Limit {
+        limit_times: l.limit_times.map(sub),
+        limit_counts: l.limit_counts.map(sub),
+        limit_size: l.limit_size.map(sub),
+    };
+    match expr {
+        Expr::Var(name) => match env.iter().rev().find(|(n, _, _)| *n == name) {
+            Some((_, value, value_size)) => {
+                size.set(size.get().saturating_add(*value_size));
+                if size.get() > MAX_LET_EXPANSION_NODES {
+                    return Expr::Var(name);
+                }
+                value.clone()
+            }
+            None => Expr::Var(name),
+        },
+        Expr::Number(_) | Expr::Dice(DiceType::Percentile) => expr,
+        Expr::Neg(e) => Expr::Neg(sub(e)),
+        Expr::Dice(DiceType::Standard { count, sides }) => Expr::Dice(DiceType::Standard {
+            count: sub(count),
+            sides: sub(sides),
+        }),
+        Expr::Dice(DiceType::Fudge { count }) => Expr::Dice(DiceType::Fudge { count: sub(count) }),
+        Expr::Dice(DiceType::Coin { count }) => Expr::Dice(DiceType::Coin { count: sub(count) }),
+        Expr::Dice(DiceType::Labeled { count, faces }) => Expr::Dice(DiceType::Labeled {
+            count: sub(count),
+            faces: faces
+                .into_iter()
+                .map(|(label, value)| (label, substitute_vars(value, env, size)))
+                .collect(),
+        }),
+        Expr::List(elements) => Expr::List(
+            elements
+                .into_iter()
+                .map(|e| substitute_vars(e, env, size))
+                .collect(),
+        ),
+        Expr::Binary(BinaryOp { lhs, op, rhs }) => Expr::Binary(BinaryOp {
+            lhs: sub(lhs),
+            op,
+            rhs: sub(rhs),
+        }),
+        Expr::Function(FunctionCall { name, args }) => Expr::Function(FunctionCall {
+            name: match name {
+                FunctionName::Filter(param) => FunctionName::Filter(sub_param(param)),
+                other => other,
+            },
+            args: args
+                .into_iter()
+                .map(|e| substitute_vars(e, env, size))
+                .collect(),
+        }),
+        Expr::Modifier(ModifierNode::Type1(m)) => {
+            Expr::Modifier(ModifierNode::Type1(Type1Modifier {
+                lhs: sub(m.lhs),
+                op: m.op,
+                param: sub(m.param),
+            }))
+        }
+        Expr::Modifier(ModifierNode::Type2(m)) => {
+            Expr::Modifier(ModifierNode::Type2(Type2Modifier {
+                lhs: sub(m.lhs),
+                op: m.op,
+                param: m.param.map(sub_param),
+                limit: m.limit.map(sub_limit),
+            }))
+        }
+        Expr::Modifier(ModifierNode::Type3(m)) => {
+            Expr::Modifier(ModifierNode::Type3(Type3Modifier {
+                lhs: sub(m.lhs),
+                op: m.op,
+                param: sub_param(m.param),
+            }))
+        }
+        Expr::Modifier(ModifierNode::Type4(m)) => {
+            Expr::Modifier(ModifierNode::Type4(Type4Modifier {
+                lhs: sub(m.lhs),
+                op: m.op,
+                count: sub(m.count),
+                param: sub_param(m.param),
+            }))
+        }
+        Expr::Modifier(ModifierNode::Type5(m)) => {
+            Expr::Modifier(ModifierNode::Type5(Type5Modifier {
+                lhs: sub(m.lhs),
+                compare: m.compare,
+            }))
+        }
+        Expr::Modifier(ModifierNode::Type6(m)) => {
+            Expr::Modifier(ModifierNode::Type6(Type6Modifier {
+                lhs: sub(m.lhs),
+                op: m.op,
+            }))
+        }
+        Expr::Modifier(ModifierNode::Type7(m)) => {
+            Expr::Modifier(ModifierNode::Type7(Type7Modifier {
+                lhs: sub(m.lhs),
+                weights: m
+                    .weights
+                    .into_iter()
+                    .map(|(param, weight)| (sub_param(param), sub(weight)))
+                    .collect(),
+            }))
+        }
+        Expr::Modifier(ModifierNode::Type8(m)) => {
+            Expr::Modifier(ModifierNode::Type8(Type8Modifier {
+                lhs: sub(m.lhs),
+                low: sub(m.low),
+                high: sub(m.high),
+            }))
+        }
+        Expr::Modifier(ModifierNode::Type9(m)) => {
+            Expr::Modifier(ModifierNode::Type9(Type9Modifier {
+                lhs: sub(m.lhs),
+                count: sub(m.count),
+                param: m.param.map(sub_param),
+                limit: m.limit.map(sub_limit),
+            }))
+        }
+        Expr::Modifier(ModifierNode::Type10(m)) => {
+            Expr::Modifier(ModifierNode::Type10(Type10Modifier {
+                lhs: sub(m.lhs),
+                param: sub_param(m.param),
+                quota: sub(m.quota),
+            }))
+        }
+        // 语法上 let 只出现在表达式开头，共享值在替换时已不含变量
+        Expr::Let(_) | Expr::Shared(_, _) => expr,
     }
 }
 
@@ -44,6 +280,7 @@ fn lower_neg(expr: Expr) -> Result<HIR, String> {
     Ok(HIR::negate(num))
 }
 
+// 骰池可以作为个数或面数 (如 (1d6)d6)，只有列表会在此报错
 fn lower_dice(dice_item: DiceType) -> Result<HIR, String> {
     match dice_item {
         DiceType::Standard { count, sides } => {
@@ -51,26 +288,50 @@ fn lower_dice(dice_item: DiceType) -> Result<HIR, String> {
             let lowered_sides = lower_expr(*sides)?;
             let count_num = lowered_count
                 .except_number()
-                .map_err(|_| "Dice count must be a number".to_string())?;
+                .map_err(|_| "Dice count must be a number, got a list".to_string())?;
             let sides_num = lowered_sides
                 .except_number()
-                .map_err(|_| "Dice sides must be a number".to_string())?;
+                .map_err(|_| "Dice sides must be a number, got a list".to_string())?;
             Ok(HIR::standard_dice_pool(count_num, sides_num))
         }
         DiceType::Fudge { count } => {
             let lowered_count = lower_expr(*count)?;
             let count_num = lowered_count
                 .except_number()
-                .map_err(|_| "Fudge dice count must be a number".to_string())?;
+                .map_err(|_| "Fudge dice count must be a number, got a list".to_string())?;
             Ok(HIR::fudge_dice_pool(count_num))
         }
+        DiceType::Percentile => Ok(HIR::percentile_dice_pool()),
         DiceType::Coin { count } => {
             let lowered_count = lower_expr(*count)?;
             let count_num = lowered_count
                 .except_number()
-                .map_err(|_| "Coin dice count must be a number".to_string())?;
+                .map_err(|_| "Coin dice count must be a number, got a list".to_string())?;
             Ok(HIR::coin_dice_pool(count_num))
         }
+        DiceType::Labeled { count, faces } => {
+            let lowered_count = lower_expr(*count)?;
+            let count_num = lowered_count
+                .except_number()
+                .map_err(|_| "Labeled dice count must be a number, got a list".to_string())?;
+            let faces = faces
+                .into_iter()
+                .map(
+                    |(label, value)| match constant_fold_hir(lower_expr(value)?)? {
+                        HIR::Number(NumberType::Constant(v))
+                            if v.fract() == 0.0 && v.abs() <= i32::MAX as f64 =>
+                        {
+                            Ok((label, v as i32))
+                        }
+                        _ => Err(format!(
+                            "Face `{}` of a labeled die must have a constant integer value",
+                            label
+                        )),
+                    },
+                )
+                .collect::<Result<Vec<_>, String>>()?;
+            Ok(HIR::labeled_dice_pool(count_num, faces))
+        }
     }
 }
 
@@ -110,6 +371,10 @@ fn lower_binary(lhs: Expr, op: BinOp, rhs: Expr) -> Result<HIR, String> {
         (HIR::Number(lhs_num), BinOp::Idiv, HIR::Number(rhs_num)) => {
             Ok(HIR::int_divide_number(lhs_num, rhs_num))
         }
+        // 骰子池与成功池在比较中按总和或成功数参与，如 2d6cs>=5 >= 3
+        (HIR::Number(lhs_num), BinOp::Compare(op), HIR::Number(rhs_num)) => {
+            Ok(HIR::compare_number(lhs_num, op, rhs_num))
+        }
         // 列表特殊操作，列表相加，列表重复
         (HIR::List(lhs_list), BinOp::Add, HIR::List(rhs_list)) => {
             Ok(HIR::add_list(lhs_list, rhs_list))
@@ -141,6 +406,14 @@ fn lower_binary(lhs: Expr, op: BinOp, rhs: Expr) -> Result<HIR, String> {
                 ListType::Explicit(vals) => vals,
                 _ => unreachable!(),
             };
+            // 在展开之前检查结果长度，避免 [1]**1000000000 这样的输入耗尽内存
+            let expanded_len = list_val.len().saturating_mul(times_val);
+            if expanded_len > MAX_LIST_REPEAT_LEN {
+                return Err(format!(
+                    "List multiplication would produce {} elements (limit {})",
+                    expanded_len, MAX_LIST_REPEAT_LEN
+                ));
+            }
 
             let mut combined = Vec::with_capacity(list_val.len() * times_val);
             for _ in 0..times_val {
@@ -180,6 +453,9 @@ fn lower_binary(lhs: Expr, op: BinOp, rhs: Expr) -> Result<HIR, String> {
             Ok(HIR::modulo_reverse_broadcast_list(num, list))
         }
         // 不支持的操作
+        (_, BinOp::Compare(_), _) => {
+            Err("Comparison is only supported between two numbers".to_string())
+        }
         (_, BinOp::ListMul, _) => {
             Err("List multiplication is only supported between a list and a number".to_string())
         }
@@ -196,9 +472,22 @@ fn lower_function_call(function_name: FunctionName, args: Vec<Expr>) -> Result<H
         .into_iter()
         .map(lower_expr)
         .collect::<Result<Vec<_>, String>>()?;
+    // sum 与 avg (包括 ravg) 的唯一参数是骰子池时，作用于其中被保留的骰子，而不是骰子池的总和
+    let args_hir = match function_name {
+        Sum | Avg | Ravg => kept_dice_as_list(args_hir),
+        _ => args_hir,
+    };
     match function_name {
         Floor => {
-            if is_exactly_one_number(&args_hir) {
+            if is_value_and_decimal_places(&args_hir) {
+                lower_with_decimal_places(
+                    args_hir,
+                    "floor",
+                    HIR::floor_number,
+                    HIR::floor_list,
+                    true,
+                )
+            } else if is_exactly_one_number(&args_hir) {
                 let num = exactly_one_number(args_hir);
                 Ok(HIR::floor_number(num))
             } else if is_exactly_one_list(&args_hir) {
@@ -210,7 +499,9 @@ fn lower_function_call(function_name: FunctionName, args: Vec<Expr>) -> Result<H
             }
         }
         Ceil => {
-            if is_exactly_one_number(&args_hir) {
+            if is_value_and_decimal_places(&args_hir) {
+                lower_with_decimal_places(args_hir, "ceil", HIR::ceil_number, HIR::ceil_list, true)
+            } else if is_exactly_one_number(&args_hir) {
                 let num = exactly_one_number(args_hir);
                 Ok(HIR::ceil_number(num))
             } else if is_exactly_one_list(&args_hir) {
@@ -222,7 +513,15 @@ fn lower_function_call(function_name: FunctionName, args: Vec<Expr>) -> Result<H
             }
         }
         Round => {
-            if is_exactly_one_number(&args_hir) {
+            if is_value_and_decimal_places(&args_hir) {
+                lower_with_decimal_places(
+                    args_hir,
+                    "round",
+                    HIR::round_number,
+                    HIR::round_list,
+                    false,
+                )
+            } else if is_exactly_one_number(&args_hir) {
                 let num = exactly_one_number(args_hir);
                 Ok(HIR::round_number(num))
             } else if is_exactly_one_list(&args_hir) {
@@ -233,6 +532,17 @@ fn lower_function_call(function_name: FunctionName, args: Vec<Expr>) -> Result<H
                 Ok(HIR::round_list(list))
             }
         }
+        RoundTo => lower_to_multiple(args_hir, "roundto", HIR::round_number, HIR::round_list),
+        FloorTo => lower_to_multiple(args_hir, "floorto", HIR::floor_number, HIR::floor_list),
+        CeilTo => lower_to_multiple(args_hir, "ceilto", HIR::ceil_number, HIR::ceil_list),
+        RoundEven => {
+            if !is_exactly_one_number(&args_hir) {
+                return Err(
+                    "roundeven function requires exactly one number as argument".to_string()
+                );
+            }
+            Ok(HIR::round_even_number(exactly_one_number(args_hir)))
+        }
         Abs => {
             if is_exactly_one_number(&args_hir) {
                 let num = exactly_one_number(args_hir);
@@ -285,6 +595,15 @@ fn lower_function_call(function_name: FunctionName, args: Vec<Expr>) -> Result<H
             };
             Ok(HIR::avg(list))
         }
+        Ravg => {
+            let list = if is_exactly_one_list(&args_hir) {
+                exactly_one_list(args_hir)
+            } else {
+                treat_as_list(args_hir)?
+            };
+            let avg = HIR::avg(list).except_number().unwrap();
+            Ok(HIR::round_even_number(avg))
+        }
         Len => {
             let list = if is_exactly_one_list(&args_hir) {
                 exactly_one_list(args_hir)
@@ -309,30 +628,169 @@ fn lower_function_call(function_name: FunctionName, args: Vec<Expr>) -> Result<H
             };
             Ok(HIR::sort_desc_list(list))
         }
-        ToList => {
-            if args_hir.len() != 1 {
-                return Err("tolist function requires exactly one argument".to_string());
+        FilterEven | FilterOdd => {
+            let name = if function_name == FilterEven {
+                "filtereven"
+            } else {
+                "filterodd"
+            };
+            if !is_exactly_one_list(&args_hir) {
+                return Err(format!(
+                    "{name} function requires exactly one list argument"
+                ));
             }
-            let pool = args_hir.into_iter().next().unwrap();
-            match pool {
+            let list = exactly_one_list(args_hir);
+            if function_name == FilterEven {
+                Ok(HIR::filter_even_list(list))
+            } else {
+                Ok(HIR::filter_odd_list(list))
+            }
+        }
+        Margin => {
+            if args_hir.len() != 2 {
+                return Err("margin function requires exactly two arguments".to_string());
+            }
+            let mut args = args_hir.into_iter();
+            let (Ok(rolled), Ok(target)) = (
+                args.next().unwrap().except_number(),
+                args.next().unwrap().except_number(),
+            ) else {
+                return Err("margin function requires two numbers as arguments".to_string());
+            };
+            Ok(HIR::margin(rolled, target))
+        }
+        MaxOr | MinOr => {
+            let name = if function_name == MaxOr {
+                "maxor"
+            } else {
+                "minor"
+            };
+            if args_hir.len() != 2 {
+                return Err(format!(
+                    "{name} function requires a list and a default value as arguments"
+                ));
+            }
+            let mut args = args_hir.into_iter();
+            // 骰子池作用于其中被保留的骰子，全部被丢弃时返回默认值
+            let list = match args.next().unwrap() {
                 HIR::Number(NumberType::DicePool(dice_pool)) => {
-                    Ok(HIR::tolist_from_dice_pool(dice_pool))
+                    HIR::tolist_from_dice_pool(dice_pool)
+                }
+                arg => arg,
+            };
+            let (Ok(list), Ok(default)) =
+                (list.except_list(), args.next().unwrap().except_number())
+            else {
+                return Err(format!(
+                    "{name} function requires a list (or dice pool) and a number as arguments"
+                ));
+            };
+            if function_name == MaxOr {
+                Ok(HIR::max_or_number(list, default))
+            } else {
+                Ok(HIR::min_or_number(list, default))
+            }
+        }
+        GroupKh => {
+            // 每一组 (通常是一个骰池) 按总和参与比较，保留总和最大的 n 组并求和
+            if args_hir.len() < 2 {
+                return Err(
+                    "groupkh function requires at least one group and a keep count".to_string(),
+                );
+            }
+            let mut args = args_hir
+                .into_iter()
+                .map(|arg| arg.except_number())
+                .collect::<Result<Vec<_>, ()>>()
+                .map_err(|_| "groupkh function requires numbers as arguments".to_string())?;
+            let count = args.pop().unwrap();
+            Ok(HIR::group_kh(ListType::Explicit(args), count))
+        }
+        ToList => pool_to_list("tolist", args_hir),
+        AllDice => {
+            // 各骰子池被保留的骰子依次拼接为一个列表
+            let mut lists = Vec::new();
+            for arg in args_hir {
+                let pool = arg.except_dice_pool().map_err(|_| {
+                    "alldice function requires every argument to be a dice pool".to_string()
+                })?;
+                lists.push(HIR::tolist_from_dice_pool(pool).except_list().unwrap());
+            }
+            let list = lists
+                .into_iter()
+                .reduce(|acc, next| HIR::add_list(acc, next).except_list().unwrap())
+                .ok_or("alldice function requires at least one dice pool".to_string())?;
+            Ok(HIR::List(list))
+        }
+        Each(op) => {
+            if args_hir.len() != 2 {
+                return Err("each function requires a dice pool and an adjustment".to_string());
+            }
+            let mut args = args_hir.into_iter();
+            let pool = args.next().unwrap().except_dice_pool().map_err(|_| {
+                "each function requires a dice pool as the first argument".to_string()
+            })?;
+            let value = match constant_fold_hir(args.next().unwrap())? {
+                HIR::Number(NumberType::Constant(v))
+                    if v.fract() == 0.0 && v.abs() <= i32::MAX as f64 =>
+                {
+                    v
                 }
-                HIR::Number(NumberType::SuccessPool(success_pool)) => {
-                    Ok(HIR::tolist_from_success_pool(success_pool))
+                _ => {
+                    return Err("each function requires a constant integer adjustment".to_string());
                 }
-                _ => Err(
-                    "tolist function requires a dice pool or success pool as argument".to_string(),
-                ),
+            };
+            // 加上负数等价于减去其绝对值，统一写法以便显示为 each(4d6,-1)
+            let (op, value) = match op {
+                EachOp::Add if value < 0.0 => (EachOp::Sub, -value),
+                EachOp::Sub if value < 0.0 => (EachOp::Add, -value),
+                _ => (op, value),
+            };
+            Ok(HIR::each_die(pool, op, NumberType::Constant(value)))
+        }
+        HighDie | LowDie => {
+            let name = if function_name == HighDie {
+                "highdie"
+            } else {
+                "lowdie"
+            };
+            if args_hir.len() != 1 {
+                return Err(format!("{name} function requires exactly one argument"));
+            }
+            let pool = args_hir
+                .into_iter()
+                .next()
+                .unwrap()
+                .except_dice_pool()
+                .map_err(|_| format!("{name} function requires a dice pool as argument"))?;
+            if function_name == HighDie {
+                Ok(HIR::high_die(pool))
+            } else {
+                Ok(HIR::low_die(pool))
             }
         }
+        SortDice => {
+            let list = pool_to_list("sortdice", args_hir)?.except_list().unwrap();
+            Ok(HIR::sort_list(list))
+        }
+        SortDiced => {
+            let list = pool_to_list("sortdiced", args_hir)?.except_list().unwrap();
+            Ok(HIR::sort_desc_list(list))
+        }
         Filter(compare_expr) => {
             let list = if is_exactly_one_list(&args_hir) {
                 exactly_one_list(args_hir)
             } else {
                 treat_as_list(args_hir)?
             };
+            // 比较值必须是常量，否则每次过滤的条件都不确定
             let compare_param = expr_mp_to_hir_mp(compare_expr)?;
+            let compare_param = match constant_fold_hir(HIR::Number(*compare_param.value))? {
+                HIR::Number(NumberType::Constant(n)) => {
+                    HIR::compare_param(compare_param.operator, NumberType::Constant(n))
+                }
+                _ => return Err("filter function requires a constant compare value".to_string()),
+            };
             Ok(HIR::filter_list(list, compare_param))
         }
         // Rpdice函数需要特殊处理
@@ -343,13 +801,86 @@ fn lower_function_call(function_name: FunctionName, args: Vec<Expr>) -> Result<H
             let orginal_hir = args_hir.into_iter().next().unwrap();
             rpdice(orginal_hir)
         }
+        AsDice => {
+            if args_hir.len() != 1 {
+                return Err("asdice function requires exactly one argument".to_string());
+            }
+            let pool = args_hir.into_iter().next().unwrap();
+            match pool {
+                HIR::Number(NumberType::SuccessPool(success_pool)) => Ok(HIR::asdice(success_pool)),
+                _ => Err("asdice function requires a success pool as argument".to_string()),
+            }
+        }
+        // Rep函数将表达式展开为N个独立的列表元素
+        Rep => {
+            if args_hir.len() != 2 {
+                return Err("rep function requires exactly two arguments".to_string());
+            }
+            let mut args = args_hir.into_iter();
+            let inner = args.next().unwrap();
+            let times = args.next().unwrap();
+            repeat_expr("rep", inner, times, "first")
+        }
+        // Gen函数与Rep相同，只是重复次数写在前面
+        Gen => {
+            if args_hir.len() != 2 {
+                return Err("gen function requires exactly two arguments".to_string());
+            }
+            let mut args = args_hir.into_iter();
+            let times = args.next().unwrap();
+            let inner = args.next().unwrap();
+            repeat_expr("gen", inner, times, "second")
+        }
+    }
+}
+
+// 修饰符的操作数必须是骰子池，否则给出说明操作数实际类型的错误信息
+fn expect_modifier_pool(modifier: &str, lhs: HIR) -> Result<DicePoolType, String> {
+    if lhs.is_dice_pool() {
+        Ok(lhs.except_dice_pool().unwrap()) // safe unwrap
+    } else {
+        Err(modifier_operand_error(modifier, "a dice pool", &lhs))
     }
 }
 
+fn modifier_operand_error(modifier: &str, expected: &str, lhs: &HIR) -> String {
+    let found = match lhs {
+        HIR::List(_) => "a list",
+        HIR::Number(NumberType::SuccessPool(_)) => {
+            "a success pool (use asdice(...) to turn it back into a dice pool)"
+        }
+        HIR::Number(NumberType::Constant(_)) => "a constant",
+        HIR::Number(NumberType::Shared(_, _)) => {
+            "a shared value (a let share binding is a plain number; \
+             apply the modifier inside the binding or use let without share)"
+        }
+        // 括号中的加减乘除等运算结果只是一个数字，不再是骰子池，如 (2d6+1d8)kh1
+        HIR::Number(_) => {
+            "a number, not a dice pool (arithmetic on dice gives a plain number; \
+             apply the modifier to a single dice expression instead)"
+        }
+    };
+    // 共享值单独显示时会带上 let share 前缀，这里只显示绑定的值
+    let shown = match lhs {
+        HIR::Number(NumberType::Shared(_, value)) => value.to_string(),
+        _ => lhs.to_string(),
+    };
+    format!(
+        "The {} modifier can only be applied to {}, but {} is {}",
+        modifier, expected, shown, found
+    )
+}
+
 fn lower_modifier_type1(lhs: Expr, op: Type1Op, param: Expr) -> Result<HIR, String> {
-    let lowered_lhs = lower_expr(lhs)?
-        .except_dice_pool()
-        .map_err(|_| "Type1 modifier can only be applied to a dice pool".to_string())?;
+    let label = match op {
+        Type1Op::KeepHigh => "kh",
+        Type1Op::KeepLow => "kl",
+        Type1Op::DropHigh => "dh",
+        Type1Op::DropLow => "dl",
+        Type1Op::Min => "min",
+        Type1Op::Max => "max",
+    };
+    let lowered_lhs = expect_modifier_pool(label, lower_expr(lhs)?)?;
     let param = lower_expr(param)?
         .except_number()
         .map_err(|_| "Type1 modifier parameter must be a number".to_string())?;
@@ -369,21 +900,76 @@ fn lower_modifier_type2(
     param: Option<crate::types::expr::ModParam>,
     limit: Option<crate::types::expr::Limit>,
 ) -> Result<HIR, String> {
-    let lowered_lhs = lower_expr(lhs)?
-        .except_dice_pool()
-        .map_err(|_| "Type2 modifier can only be applied to a dice pool".to_string())?;
+    let label = match op {
+        Type2Op::CompoundExplode => "!!",
+        Type2Op::Explode => "!",
+        Type2Op::ExplodeOnce => "!o",
+        Type2Op::Reroll => "r",
+        Type2Op::RerollPool => "rp",
+        Type2Op::Cascade => "cascade",
+    };
+    let lowered_lhs = expect_modifier_pool(label, lower_expr(lhs)?)?;
     let compare_param = param.map(|mp| expr_mp_to_hir_mp(mp)).transpose()?;
     let limit = limit.map(|lim| expr_limit_to_hir_limit(lim)).transpose()?;
+    // 只有普通爆炸会增加骰子池中的骰子数，lsz 对其他修饰符没有意义
+    if !matches!(op, Type2Op::Explode | Type2Op::ExplodeOnce)
+        && limit.as_ref().is_some_and(|l| l.limit_size.is_some())
+    {
+        return Err("lsz limit can only be used with the ! or !o explode modifier".to_string());
+    }
     match op {
         Type2Op::Reroll => {
             if let Some(cp) = compare_param {
+                if limit.is_none() {
+                    check_reroll_not_always_true(&lowered_lhs, &cp)?;
+                }
                 Ok(HIR::reroll(lowered_lhs, cp, limit))
             } else {
                 Err("Reroll modifier requires a compare parameter".to_string()) // unreachable
             }
         }
+        Type2Op::RerollPool => {
+            let Some(cp) = compare_param else {
+                return Err("Reroll pool modifier requires a compare parameter".to_string()); // unreachable
+            };
+            let cp = HIR::compare_param(cp.operator, constant_pool_target(*cp.value)?);
+            if limit
+                .as_ref()
+                .is_some_and(|l| l.limit_counts.is_some() || l.limit_size.is_some())
+            {
+                return Err("rp modifier only accepts an lt limit".to_string());
+            }
+            Ok(HIR::reroll_pool(lowered_lhs, cp, limit))
+        }
         Type2Op::Explode => Ok(HIR::explode(lowered_lhs, compare_param, limit)),
+        Type2Op::ExplodeOnce => Ok(HIR::explode_once(lowered_lhs, compare_param, limit)),
         Type2Op::CompoundExplode => Ok(HIR::compound_explode(lowered_lhs, compare_param, limit)),
+        Type2Op::Cascade => {
+            let Some(cp) = compare_param else {
+                return Err("cascade modifier requires a compare parameter".to_string()); // unreachable
+            };
+            // 成功条件与轮数都必须是常量，奖励骰子的判定条件在开始前就已确定
+            let cp = match constant_fold_hir(HIR::Number(*cp.value))? {
+                HIR::Number(NumberType::Constant(n)) => {
+                    HIR::compare_param(cp.operator, NumberType::Constant(n))
+                }
+                _ => return Err("cascade modifier requires a constant compare value".to_string()),
+            };
+            let Some(mut limit) = limit.filter(|l| l.limit_times.is_some()) else {
+                return Err("cascade modifier requires an lt limit".to_string());
+            };
+            if limit.limit_counts.is_some() {
+                return Err("cascade modifier only accepts an lt limit".to_string());
+            }
+            let times = limit.limit_times.take().unwrap();
+            match constant_fold_hir(HIR::Number(*times))? {
+                HIR::Number(NumberType::Constant(n)) => {
+                    limit.limit_times = Some(Box::new(NumberType::Constant(n)));
+                }
+                _ => return Err("cascade modifier requires a constant lt limit".to_string()),
+            }
+            Ok(HIR::cascade(lowered_lhs, cp, limit))
+        }
     }
 }
 
@@ -396,11 +982,52 @@ fn lower_modifier_type3(
     let compare_param = expr_mp_to_hir_mp(param)?;
     match op {
         Type3Op::SubtractFailures => {
-            let lowered_lhs = lowered_lhs.except_dice_pool().map_err(|_| {
-                "SubtractFailures modifier can only be applied to a dice pool".to_string()
-            })?;
+            let lowered_lhs = expect_modifier_pool("sf", lowered_lhs)?;
             Ok(HIR::subtract_failures(lowered_lhs, compare_param))
         }
+        Type3Op::Spend => {
+            let lowered_lhs = expect_modifier_pool("sp", lowered_lhs)?;
+            // 与 cascade 相同，判定条件必须在投掷前确定
+            let compare_param = match constant_fold_hir(HIR::Number(*compare_param.value))? {
+                HIR::Number(NumberType::Constant(n)) => {
+                    HIR::compare_param(compare_param.operator, NumberType::Constant(n))
+                }
+                _ => return Err("sp modifier requires a constant compare value".to_string()),
+            };
+            Ok(HIR::spend_successes_from_dice_pool(
+                lowered_lhs,
+                compare_param,
+            ))
+        }
+        Type3Op::Net => {
+            let lowered_lhs = expect_modifier_pool("net", lowered_lhs)?;
+            // 成功与失败共用同一个阈值，判定条件必须在投掷前确定
+            let compare_param = match constant_fold_hir(HIR::Number(*compare_param.value))? {
+                HIR::Number(NumberType::Constant(n)) => {
+                    HIR::compare_param(compare_param.operator, NumberType::Constant(n))
+                }
+                _ => return Err("net modifier requires a constant compare value".to_string()),
+            };
+            Ok(HIR::net_successes_from_dice_pool(
+                lowered_lhs,
+                compare_param,
+            ))
+        }
+        Type3Op::ExplodeIfTotal => {
+            let lowered_lhs = expect_modifier_pool("!total", lowered_lhs)?;
+            // 总和条件在第一次扫描前判定，阈值必须在投掷前确定
+            let compare_param = match constant_fold_hir(HIR::Number(*compare_param.value))? {
+                HIR::Number(NumberType::Constant(n)) => {
+                    HIR::compare_param(compare_param.operator, NumberType::Constant(n))
+                }
+                _ => return Err("!total modifier requires a constant threshold".to_string()),
+            };
+            Ok(HIR::explode_if_total(lowered_lhs, compare_param))
+        }
+        Type3Op::RerollMax => {
+            let lowered_lhs = expect_modifier_pool("rmax", lowered_lhs)?;
+            Ok(HIR::reroll_max(lowered_lhs, compare_param))
+        }
         Type3Op::CountSuccesses => {
             if lowered_lhs.is_dice_pool() {
                 let lowered_lhs = lowered_lhs.except_dice_pool().unwrap(); // safe unwrap
@@ -412,10 +1039,11 @@ fn lower_modifier_type3(
                 let lowered_lhs = lowered_lhs.except_success_pool().unwrap(); // safe unwrap
                 Ok(HIR::count_successes(lowered_lhs, compare_param))
             } else {
-                Err(
-                    "CountSuccesses modifier can only be applied to a dice pool or success pool"
-                        .to_string(),
-                )
+                Err(modifier_operand_error(
+                    "cs",
+                    "a dice pool or success pool",
+                    &lowered_lhs,
+                ))
             }
         }
         Type3Op::DeductFailures => {
@@ -429,19 +1057,51 @@ fn lower_modifier_type3(
                 let lowered_lhs = lowered_lhs.except_success_pool().unwrap(); // safe unwrap
                 Ok(HIR::deduct_failures(lowered_lhs, compare_param))
             } else {
-                Err(
-                    "DeductFailures modifier can only be applied to a dice pool or success pool"
-                        .to_string(),
-                ) // unreachable
+                Err(modifier_operand_error(
+                    "df",
+                    "a dice pool or success pool",
+                    &lowered_lhs,
+                ))
             }
         }
     }
 }
 
+fn lower_modifier_type4(
+    lhs: Expr,
+    op: Type4Op,
+    count: Expr,
+    param: crate::types::expr::ModParam,
+) -> Result<HIR, String> {
+    let label = match op {
+        Type4Op::ConditionalDropHigh => "dh",
+        Type4Op::ConditionalDropLow => "dl",
+        Type4Op::RemoveMatching => "rm",
+    };
+    let lowered_lhs = expect_modifier_pool(label, lower_expr(lhs)?)?;
+    let count = lower_expr(count)?.except_number().map_err(|_| match op {
+        Type4Op::RemoveMatching => "Remove count must be a number".to_string(),
+        _ => "Conditional drop count must be a number".to_string(),
+    })?;
+    let compare_param = expr_mp_to_hir_mp(param)?;
+    match op {
+        Type4Op::ConditionalDropHigh => Ok(HIR::conditional_drop_high(
+            lowered_lhs,
+            count,
+            compare_param,
+        )),
+        Type4Op::ConditionalDropLow => {
+            Ok(HIR::conditional_drop_low(lowered_lhs, count, compare_param))
+        }
+        Type4Op::RemoveMatching => Ok(HIR::remove_n(lowered_lhs, count, compare_param)),
+    }
+}
+
 // ==========================================
 // Vec<HIR> 特殊逻辑
 // ==========================================
 
+// 骰子池与成功池都属于 NumberType，单目数学函数直接作用于它们的总和/净成功数
 fn is_exactly_one_number(args: &Vec<HIR>) -> bool {
     args.len() == 1 && args[0].is_number()
 }
@@ -469,6 +1129,32 @@ fn exactly_one_list_and_one_number(args: Vec<HIR>) -> (ListType, NumberType) {
     (list, number)
 }
 
+// 将唯一的骰子池或成功池参数转换为列表，列表中只包含被保留的骰子
+fn pool_to_list(name: &str, args: Vec<HIR>) -> Result<HIR, String> {
+    if args.len() != 1 {
+        return Err(format!("{name} function requires exactly one argument"));
+    }
+    match args.into_iter().next().unwrap() {
+        HIR::Number(NumberType::DicePool(dice_pool)) => Ok(HIR::tolist_from_dice_pool(dice_pool)),
+        HIR::Number(NumberType::SuccessPool(success_pool)) => {
+            Ok(HIR::tolist_from_success_pool(success_pool))
+        }
+        _ => Err(format!(
+            "{name} function requires a dice pool or success pool as argument"
+        )),
+    }
+}
+
+fn kept_dice_as_list(args: Vec<HIR>) -> Vec<HIR> {
+    match <[HIR; 1]>::try_from(args) {
+        Ok([HIR::Number(NumberType::DicePool(dice_pool))]) => {
+            vec![HIR::tolist_from_dice_pool(dice_pool)]
+        }
+        Ok([arg]) => vec![arg],
+        Err(args) => args,
+    }
+}
+
 fn treat_as_list(args: Vec<HIR>) -> Result<ListType, String> {
     // 尝试将所有参数都解释为数字，然后组成一个显式列表
     args.into_iter()
@@ -499,7 +1185,7 @@ fn rpdice(orginal_hir: HIR) -> Result<HIR, String> {
         fn visit_dice_pool_self(&mut self, d: &mut DicePoolType) -> Result<(), String> {
             use DicePoolType::*;
             match d {
-                Standard(count, _) | Fudge(count) | Coin(count) => {
+                Standard(count, _) | Fudge(count) | Coin(count) | Labeled(count, _) => {
                     double_count(count);
                 }
                 _ => {}
@@ -514,10 +1200,336 @@ fn rpdice(orginal_hir: HIR) -> Result<HIR, String> {
     Ok(hir_copy)
 }
 
+// ==========================================
+// 带小数位数的 floor/ceil/round
+// ==========================================
+
+// 第一个参数为数字或列表，第二个参数为数字时，第二个参数视为小数位数
+fn is_value_and_decimal_places(args: &[HIR]) -> bool {
+    args.len() == 2 && args[1].is_number()
+}
+
+// round(v, p) 降级为 round(v * 10^p) / 10^p，floor/ceil 同理
+fn lower_with_decimal_places(
+    args_hir: Vec<HIR>,
+    name: &str,
+    number_func: fn(NumberType) -> HIR,
+    list_func: fn(ListType) -> HIR,
+    snap_scaled: bool,
+) -> Result<HIR, String> {
+    let mut args = args_hir.into_iter();
+    let value = args.next().unwrap();
+    let places = constant_fold_hir(args.next().unwrap())?;
+    let places = match places {
+        HIR::Number(NumberType::Constant(p)) if p >= 0.0 && p.fract() == 0.0 => p,
+        _ => {
+            return Err(format!(
+                "{} function requires a constant non-negative integer as decimal places",
+                name
+            ));
+        }
+    };
+    // f64 只有约 15 位有效数字，更多的小数位没有意义，且 10^places 会溢出
+    if places > MAX_ROUNDING_PLACES as f64 {
+        return Err(format!(
+            "{} function supports at most {} decimal places",
+            name, MAX_ROUNDING_PLACES
+        ));
+    }
+    let places = places as i32;
+    let scale = || NumberType::Constant(10f64.powi(places));
+    // 放大后的值带有浮点误差 (0.29 * 100 = 28.999999999999996)，
+    // floor/ceil 前先舍入到 1e-9，避免 floor(0.29, 2) 得到 0.28
+    let snap = || NumberType::Constant(SNAP_SCALE);
+    match value {
+        HIR::Number(n) if places == 0 => Ok(number_func(n)),
+        HIR::List(l) if places == 0 => Ok(list_func(l)),
+        HIR::Number(n) => {
+            let mut scaled = HIR::multiply_number(n, scale()).except_number().unwrap();
+            if snap_scaled {
+                let magnified = HIR::multiply_number(scaled, snap())
+                    .except_number()
+                    .unwrap();
+                let snapped = HIR::round_number(magnified).except_number().unwrap();
+                scaled = HIR::divide_number(snapped, snap()).except_number().unwrap();
+            }
+            let rounded = number_func(scaled).except_number().unwrap();
+            Ok(HIR::divide_number(rounded, scale()))
+        }
+        HIR::List(l) => {
+            let mut scaled = HIR::multiply_broadcast_list(l, scale())
+                .except_list()
+                .unwrap();
+            if snap_scaled {
+                let magnified = HIR::multiply_broadcast_list(scaled, snap())
+                    .except_list()
+                    .unwrap();
+                let snapped = HIR::round_list(magnified).except_list().unwrap();
+                scaled = HIR::div_broadcast_list(snapped, snap())
+                    .except_list()
+                    .unwrap();
+            }
+            let rounded = list_func(scaled).except_list().unwrap();
+            Ok(HIR::div_broadcast_list(rounded, scale()))
+        }
+    }
+}
+
+// roundto(v, m) 降级为 round(v / m) * m，floorto/ceilto 同理
+fn lower_to_multiple(
+    args_hir: Vec<HIR>,
+    name: &str,
+    number_func: fn(NumberType) -> HIR,
+    list_func: fn(ListType) -> HIR,
+) -> Result<HIR, String> {
+    if !is_value_and_decimal_places(&args_hir) {
+        return Err(format!(
+            "{} function requires a value and a multiple as arguments",
+            name
+        ));
+    }
+    let mut args = args_hir.into_iter();
+    let value = args.next().unwrap();
+    let multiple = match constant_fold_hir(args.next().unwrap())? {
+        HIR::Number(NumberType::Constant(m)) if m != 0.0 => m,
+        _ => {
+            return Err(format!(
+                "{} function requires a constant non-zero multiple",
+                name
+            ));
+        }
+    };
+    let multiple = || NumberType::Constant(multiple);
+    match value {
+        HIR::Number(n) => {
+            let scaled = HIR::divide_number(n, multiple()).except_number().unwrap();
+            let rounded = number_func(scaled).except_number().unwrap();
+            Ok(HIR::multiply_number(rounded, multiple()))
+        }
+        HIR::List(l) => {
+            let scaled = HIR::div_broadcast_list(l, multiple())
+                .except_list()
+                .unwrap();
+            let rounded = list_func(scaled).except_list().unwrap();
+            Ok(HIR::multiply_broadcast_list(rounded, multiple()))
+        }
+    }
+}
+
+// ==========================================
+// Rep 专用函数
+// ==========================================
+
+// 没有 lt/lc 限制时，若重投条件对骰子的每一个面都成立，重投永远不会停止
+// 只检查面数与比较值都是常数的情况，其余情况交给运行时的递归限制
+fn check_reroll_not_always_true(
+    pool: &DicePoolType,
+    cp: &crate::types::hir::ModParam,
+) -> Result<(), String> {
+    let Some((low, high)) = constant_face_range(pool)? else {
+        return Ok(());
+    };
+    let target = match constant_fold_hir(HIR::Number((*cp.value).clone()))? {
+        HIR::Number(NumberType::Constant(n)) => n,
+        _ => return Ok(()),
+    };
+    let compare = get_compare_function(cp.operator.clone(), target);
+    // 满足条件的面构成一个区间 (!= 除外)，因此只需检查两端
+    let hits_gap = cp.operator == CompareOp::NotEqual
+        && target.fract() == 0.0
+        && (low as f64..=high as f64).contains(&target);
+    if compare(low as f64) && compare(high as f64) && !hits_gap {
+        return Err(format!(
+            "Reroll condition `{}` is always true for `{}`, so the dice would be rerolled forever; add an lt or lc limit",
+            cp, pool
+        ));
+    }
+    Ok(())
+}
+
+// 骰池中每个骰子可能的取值范围，面数不是常数或无法确定时返回 None
+fn constant_face_range(pool: &DicePoolType) -> Result<Option<(i32, i32)>, String> {
+    use DicePoolType::*;
+    match pool {
+        Standard(_, sides) => match constant_fold_hir(HIR::Number((**sides).clone()))? {
+            HIR::Number(NumberType::Constant(n)) if n >= 1.0 => Ok(Some((1, n as i32))),
+            _ => Ok(None),
+        },
+        Fudge(_) => Ok(Some((-1, 1))),
+        Coin(_) => Ok(Some((0, 1))),
+        Percentile => Ok(Some((1, 100))),
+        Labeled(_, faces) => Ok(Some((
+            faces.iter().map(|f| f.1).min().unwrap(),
+            faces.iter().map(|f| f.1).max().unwrap(),
+        ))),
+        // 只改变保留哪些骰子、而不改变骰子取值的修饰符
+        KeepHigh(inner, _)
+        | KeepLow(inner, _)
+        | DropHigh(inner, _)
+        | DropLow(inner, _)
+        | Explode(inner, _, _)
+        | ExplodeOnce(inner, _, _)
+        | ExplodeIfTotal(inner, _)
+        | Reroll(inner, _, _)
+        | RerollPool(inner, _, _)
+        | RerollMax(inner, _)
+        | ConditionalDropHigh(inner, _, _)
+        | ConditionalDropLow(inner, _, _)
+        | RemoveN(inner, _, _)
+        | KeepRelativeToAverage(inner, _)
+        | KeepDistinct(inner)
+        | KeepRankRange(inner, _, _)
+        | ExplodeMulti(inner, _, _, _)
+        | ExplodeUntil(inner, _, _, _) => constant_face_range(inner),
+        Each(inner, op, value) => match (constant_face_range(inner)?, value.as_ref()) {
+            (Some((min, max)), NumberType::Constant(v)) => {
+                let (a, b) = (op.apply(min, *v as i32), op.apply(max, *v as i32));
+                Ok(Some((a.min(b), a.max(b))))
+            }
+            _ => Ok(None),
+        },
+        _ => Ok(None),
+    }
+}
+
+// 重复次数必须在编译期即可确定，且为正整数
+fn repeat_expr(name: &str, inner: HIR, times: HIR, inner_position: &str) -> Result<HIR, String> {
+    let inner = inner.except_number().map_err(|_| {
+        format!("{name} function requires a number as the {inner_position} argument")
+    })?;
+    let times = match constant_fold_hir(times)? {
+        HIR::Number(NumberType::Constant(n)) if n >= 1.0 && n.fract() == 0.0 => n,
+        _ => {
+            return Err(format!(
+                "{name} function requires a constant positive integer as the repeat count"
+            ));
+        }
+    };
+    // 与列表重复 (**) 共用长度上限，在分配之前检查
+    if times > MAX_LIST_REPEAT_LEN as f64 {
+        return Err(format!(
+            "{name} function would produce {} elements (limit {})",
+            times, MAX_LIST_REPEAT_LEN
+        ));
+    }
+    Ok(HIR::explicit_list(vec![inner; times as usize]))
+}
+
 // ==========================================
 // 辅助函数
 // ==========================================
 
+// rp 比较的是整个骰池的总和，目标值必须是常量，以便在重投前就确定判定条件
+fn constant_pool_target(value: NumberType) -> Result<NumberType, String> {
+    match constant_fold_hir(HIR::Number(value))? {
+        HIR::Number(NumberType::Constant(n)) => Ok(NumberType::Constant(n)),
+        _ => Err("rp modifier requires a constant compare value".to_string()),
+    }
+}
+
+// 加权计数成功：比较值与权重都必须是常数，权重为正整数
+fn lower_success_weights(
+    lhs: Expr,
+    weights: Vec<(crate::types::expr::ModParam, Box<Expr>)>,
+) -> Result<HIR, String> {
+    let lowered_lhs = expect_modifier_pool("cs", lower_expr(lhs)?)?;
+    let weights = weights
+        .into_iter()
+        .map(|(param, weight)| {
+            let value = match constant_fold_hir(lower_expr(*param.value)?)? {
+                HIR::Number(NumberType::Constant(v)) => v,
+                _ => return Err("cs weight table requires constant compare values".to_string()),
+            };
+            let weight = match constant_fold_hir(lower_expr(*weight)?)? {
+                HIR::Number(NumberType::Constant(w))
+                    if w.fract() == 0.0 && w >= 1.0 && w <= i32::MAX as f64 =>
+                {
+                    w as i32
+                }
+                _ => return Err("cs weights must be positive integer constants".to_string()),
+            };
+            Ok((param.operator, value, weight))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    Ok(HIR::weighted_count_successes(lowered_lhs, weights))
+}
+
+// k2..4 的排名在投掷前就需要确定，因此上下界必须是常数
+fn lower_keep_rank_range(lhs: Expr, low: Expr, high: Expr) -> Result<HIR, String> {
+    let lowered_lhs = expect_modifier_pool("k..", lower_expr(lhs)?)?;
+    let rank = |bound: Expr| match constant_fold_hir(lower_expr(bound)?)? {
+        HIR::Number(NumberType::Constant(r))
+            if r.fract() == 0.0 && r >= 1.0 && r <= i32::MAX as f64 =>
+        {
+            Ok(r as i32)
+        }
+        _ => Err("k.. rank bounds must be positive integer constants".to_string()),
+    };
+    let (low, high) = (rank(low)?, rank(high)?);
+    if low > high {
+        return Err(format!(
+            "k.. rank range is empty: lower bound {} is greater than upper bound {}",
+            low, high
+        ));
+    }
+    Ok(HIR::keep_rank_range(lowered_lhs, low, high))
+}
+
+// !until(cs>=5, 3)：成功条件与成功数都要在投掷前确定，因此必须是常数
+fn lower_explode_until(
+    lhs: Expr,
+    param: crate::types::expr::ModParam,
+    quota: Expr,
+) -> Result<HIR, String> {
+    let lowered_lhs = expect_modifier_pool("!until", lower_expr(lhs)?)?;
+    let value = match constant_fold_hir(lower_expr(*param.value)?)? {
+        HIR::Number(NumberType::Constant(v)) => v,
+        _ => return Err("!until requires a constant success condition".to_string()),
+    };
+    let quota = match constant_fold_hir(lower_expr(quota)?)? {
+        HIR::Number(NumberType::Constant(n))
+            if n.fract() == 0.0 && n >= 1.0 && n <= i32::MAX as f64 =>
+        {
+            n as i32
+        }
+        _ => return Err("!until success count must be a positive integer constant".to_string()),
+    };
+    Ok(HIR::explode_until(
+        lowered_lhs,
+        param.operator,
+        value,
+        quota,
+    ))
+}
+
+// !x2：每次触发新增的骰子数在投掷前就需要确定，因此必须是常数
+fn lower_explode_multi(
+    lhs: Expr,
+    count: Expr,
+    param: Option<crate::types::expr::ModParam>,
+    limit: Option<crate::types::expr::Limit>,
+) -> Result<HIR, String> {
+    let lowered_lhs = expect_modifier_pool("!x", lower_expr(lhs)?)?;
+    let count = match constant_fold_hir(lower_expr(count)?)? {
+        HIR::Number(NumberType::Constant(n)) if n.fract() == 0.0 && n >= 1.0 => n,
+        _ => return Err("!x dice count must be a positive integer constant".to_string()),
+    };
+    // 每次触发新增的骰子数不能超过投掷器默认允许的骰子总数
+    if count > DEFAULT_DICE_COUNT_LIMIT as f64 {
+        return Err(format!(
+            "!x dice count must not exceed {}",
+            DEFAULT_DICE_COUNT_LIMIT
+        ));
+    }
+    let count = count as i32;
+    let compare_param = param.map(expr_mp_to_hir_mp).transpose()?;
+    let limit = limit.map(expr_limit_to_hir_limit).transpose()?;
+    if limit.as_ref().is_some_and(|l| l.limit_size.is_some()) {
+        return Err("lsz limit can only be used with the ! or !o explode modifier".to_string());
+    }
+    Ok(HIR::explode_multi(lowered_lhs, count, compare_param, limit))
+}
+
 fn expr_mp_to_hir_mp(
     mod_param: crate::types::expr::ModParam,
 ) -> Result<crate::types::hir::ModParam, String> {
@@ -549,5 +1561,17 @@ fn expr_limit_to_hir_limit(
         None
     };
 
-    Ok(HIR::limit_param(limit_times, limit_counts))
+    let limit_size = if let Some(limit_size) = limit.limit_size {
+        let size = constant_fold_hir(lower_expr(*limit_size)?)?;
+        match size {
+            HIR::Number(NumberType::Constant(n)) if n >= 1.0 && n.fract() == 0.0 => {
+                Some(NumberType::Constant(n))
+            }
+            _ => return Err("Limit size must be a constant positive integer".to_string()),
+        }
+    } else {
+        None
+    };
+
+    Ok(HIR::limit_param(limit_times, limit_counts, limit_size))
 }
@@ -1,5 +1,6 @@
+use oxidice::format_result_text;
 use oxidice::parse_dice_and_show;
-use oxidice::roll_without_animation;
+use oxidice::{DEFAULT_DICE_COUNT_LIMIT, DEFAULT_RECURSION_LIMIT, roll_without_animation};
 use std::io::{self, Write};
 
 fn main() {
@@ -35,8 +36,12 @@ fn main() {
             Err(e) => println!("Error: {}", e),
         }
 
-        match roll_without_animation(input.to_string(), 100, 1000) {
-            Ok(result) => println!("Roll result: {:?}", result),
+        match roll_without_animation(
+            input.to_string(),
+            DEFAULT_RECURSION_LIMIT,
+            DEFAULT_DICE_COUNT_LIMIT,
+        ) {
+            Ok(result) => println!("Roll result: {}", format_result_text(&result)),
             Err(e) => println!("Error during roll: {}", e),
         }
     }
@@ -4,8 +4,19 @@ use crate::types::hir::{
     NumberFunctionType, NumberType,
 };
 use crate::types::hir_rewriter::HirVisitor;
+use crate::types::list_ops::{compare_nan_last, every_other, keep_elements_preserve_order};
+use crate::types::runtime_value::RoundingMode;
+use crate::types::safe_integer::{checked_int_divide, checked_modulo};
 
-pub struct ConstantFolder;
+#[derive(Default)]
+pub struct ConstantFolder {
+    // 为 true 时，恰好位于 .5 的 round 不折叠，留给运行时按所选的 RoundingMode 计算
+    defer_rounding_ties: bool,
+    // 为 true 时，整数的平均值不是整数的 avg 不折叠，渲染时才能按需显示为分数
+    defer_fractional_avg: bool,
+    // 本轮是否替换过节点，用于判断折叠是否已到达定点
+    changed: bool,
+}
 
 impl HirVisitor for ConstantFolder {
     fn visit_number_self(&mut self, n: &mut NumberType) -> Result<(), String> {
@@ -18,26 +29,35 @@ impl HirVisitor for ConstantFolder {
                     None
                 }
             }
+            // 共享的值折叠为常数后，是否共享已经没有区别
+            Shared(_, inner) => match **inner {
+                NumberType::Constant(val) => Some(NumberType::Constant(val)),
+                _ => None,
+            },
             NumberBinary(bin_op) => fold_binary_op(bin_op)?,
-            NumberFunction(func) => fold_number_function(func)?,
+            NumberFunction(func) => {
+                fold_number_function(func, self.defer_rounding_ties, self.defer_fractional_avg)?
+            }
             DicePool(dice_pool) => fold_dice_pool(dice_pool),
             Constant(_) | SuccessPool(_) => None, // 无法折叠，也不应折叠
         };
         // 如果计算出了新值，替换当前节点
         if let Some(val) = new_val {
             *n = val;
+            self.changed = true;
         };
         Ok(())
     }
     fn visit_list_self(&mut self, l: &mut ListType) -> Result<(), String> {
         use ListType::*;
         let new_val = match l {
-            ListFunction(list_func) => fold_list_function(list_func),
+            ListFunction(list_func) => fold_list_function(list_func, self.defer_rounding_ties),
             ListBinary(list_bin_op) => fold_list_binary_op(list_bin_op)?,
             Explicit(_) => None, // 无法折叠，也不应折叠
         };
         if let Some(val) = new_val {
-            *l = val
+            *l = val;
+            self.changed = true;
         };
         Ok(())
     }
@@ -47,10 +67,36 @@ impl HirVisitor for ConstantFolder {
 // 入口函数
 // ==========================================
 
+// 折叠的最大轮数，正常表达式两三轮内即可收敛。
+// 达到上限时直接返回当前的 HIR：每一轮折叠都保持语义不变，剩下的只是没有化到最简
+const MAX_FOLD_PASSES: usize = 8;
+
 pub fn constant_fold_hir(hir: HIR) -> Result<HIR, String> {
+    fold_to_fixpoint(ConstantFolder::default(), hir)
+}
+
+// 供投掷使用的折叠：round 的取舍方式在运行时才确定，因此恰好位于 .5 的 round 不折叠，
+// 保证常数与骰子结果按同一种方式取舍；平均值是否显示为分数在渲染时才确定，
+// 因此整数的平均值不是整数时保留 avg 节点
+pub fn constant_fold_hir_for_runtime(hir: HIR) -> Result<HIR, String> {
+    let folder = ConstantFolder {
+        defer_rounding_ties: true,
+        defer_fractional_avg: true,
+        ..Default::default()
+    };
+    fold_to_fixpoint(folder, hir)
+}
+
+// 单轮折叠中新建的节点 (如 sum 展开出的加法树) 不会再被访问，因此重复折叠直到某一轮没有替换任何节点
+fn fold_to_fixpoint(mut folder: ConstantFolder, hir: HIR) -> Result<HIR, String> {
     let mut hir = hir;
-    let mut folder = ConstantFolder;
-    folder.visit_hir(&mut hir)?;
+    for _ in 0..MAX_FOLD_PASSES {
+        folder.changed = false;
+        folder.visit_hir(&mut hir)?;
+        if !folder.changed {
+            break;
+        }
+    }
     Ok(hir)
 }
 
@@ -58,14 +104,22 @@ pub fn constant_fold_hir(hir: HIR) -> Result<HIR, String> {
 // 细分函数定义
 // ==========================================
 
-fn fold_number_function(func: &mut NumberFunctionType) -> Result<Option<NumberType>, String> {
+fn fold_number_function(
+    func: &mut NumberFunctionType,
+    defer_rounding_ties: bool,
+    defer_fractional_avg: bool,
+) -> Result<Option<NumberType>, String> {
     use NumberFunctionType::*;
 
     match func {
         // --- 单值函数 ---
         Floor(inner) => Ok(try_map_const(inner, |v| v.floor())),
         Ceil(inner) => Ok(try_map_const(inner, |v| v.ceil())),
-        Round(inner) => Ok(try_map_const(inner, |v| v.round())),
+        Round(inner) => match try_get_constant_value(inner) {
+            Some(v) if defer_rounding_ties && RoundingMode::is_tie(v) => Ok(None),
+            _ => Ok(try_map_const(inner, |v| v.round())),
+        },
+        RoundEven(inner) => Ok(try_map_const(inner, |v| v.round_ties_even())),
         Abs(inner) => Ok(try_map_const(inner, |v| v.abs())),
 
         // --- 列表聚合函数 (Sum, Avg, Min, Max, Len) ---
@@ -83,15 +137,22 @@ fn fold_number_function(func: &mut NumberFunctionType) -> Result<Option<NumberTy
                 let sum_tree = iter.fold(first, |acc, item| {
                     NumberType::NumberBinary(NumberBinaryType::Add(Box::new(acc), Box::new(item)))
                 });
-                // 因为是新创建的，需要优化
-                let hir = HIR::Number(sum_tree);
-                let optimized_hir = constant_fold_hir(hir).ok()?;
-                let sum_tree = optimized_hir.except_number().ok()?;
+                // 新建的加法树留给下一轮折叠继续优化
                 Some(sum_tree)
             } else {
                 unreachable!("Already checked matches Explicit")
             }
         })),
+        Avg(list_box)
+            if defer_fractional_avg
+                && try_get_constant_values(list_box).is_some_and(|values| {
+                    values.iter().all(|v| v.fract() == 0.0)
+                        && !values.is_empty()
+                        && values.iter().sum::<f64>() % values.len() as f64 != 0.0
+                }) =>
+        {
+            Ok(None)
+        }
         Avg(list_box) => Ok(fold_list_aggregate(list_box, |nums| {
             if nums.is_empty() {
                 0.0
@@ -117,6 +178,15 @@ fn fold_number_function(func: &mut NumberFunctionType) -> Result<Option<NumberTy
                 }))
             }
         }
+        MaxOr(list_box, default) | MinOr(list_box, default) if is_empty_list(list_box) => {
+            Ok(Some((**default).clone()))
+        }
+        MaxOr(list_box, _) => Ok(fold_list_aggregate(list_box, |nums| {
+            nums.iter().fold(f64::NEG_INFINITY, |a, &b| a.max(b))
+        })),
+        MinOr(list_box, _) => Ok(fold_list_aggregate(list_box, |nums| {
+            nums.iter().fold(f64::INFINITY, |a, &b| a.min(b))
+        })),
         Len(list_box) => {
             // Len 比较特殊，只要是 Explicit 列表，不管里面是不是常数，长度都是固定的
             if let ListType::Explicit(vec) = &**list_box {
@@ -125,41 +195,100 @@ fn fold_number_function(func: &mut NumberFunctionType) -> Result<Option<NumberTy
                 Ok(None)
             }
         }
+        // 骰子池在运行时才有结果
+        HighDie(_) | LowDie(_) => Ok(None),
+        Margin(rolled, target) => {
+            match (
+                try_get_constant_value(rolled),
+                try_get_constant_value(target),
+            ) {
+                (Some(r), Some(t)) => Ok(Some(NumberType::Constant(r - t))),
+                _ => Ok(None),
+            }
+        }
+        GroupKh(groups, count) => {
+            match (
+                try_get_constant_values(groups),
+                try_get_constant_value(count),
+            ) {
+                (Some(values), Some(count)) => Ok(Some(NumberType::Constant(
+                    keep_elements_preserve_order(values, count, true)
+                        .into_iter()
+                        .sum(),
+                ))),
+                _ => Ok(None),
+            }
+        }
     }
 }
 
-fn fold_list_function(func: &mut ListFunctionType) -> Option<ListType> {
+fn fold_list_function(func: &mut ListFunctionType, defer_rounding_ties: bool) -> Option<ListType> {
     use ListFunctionType::*;
 
     match func {
         Floor(list_box) => try_map_constant_list(list_box, |v| v.floor()),
         Ceil(list_box) => try_map_constant_list(list_box, |v| v.ceil()),
+        Round(list_box)
+            if defer_rounding_ties
+                && try_get_constant_values(list_box)
+                    .is_some_and(|values| values.into_iter().any(RoundingMode::is_tie)) =>
+        {
+            None
+        }
         Round(list_box) => try_map_constant_list(list_box, |v| v.round()),
         Abs(list_box) => try_map_constant_list(list_box, |v| v.abs()),
         Max(list_box, num_box) if list_box.is_constant_list() && num_box.is_constant() => {
             let values = try_get_constant_values(list_box)?;
             let counts = try_get_constant_value(num_box)?;
-            Some(keep_elements_preserve_order(values, counts, true))
+            Some(ListType::Explicit(
+                keep_elements_preserve_order(values, counts, true)
+                    .into_iter()
+                    .map(NumberType::Constant)
+                    .collect(),
+            ))
         }
         Min(list_box, num_box) if list_box.is_constant_list() && num_box.is_constant() => {
             let values = try_get_constant_values(list_box)?;
             let counts = try_get_constant_value(num_box)?;
-            Some(keep_elements_preserve_order(values, counts, false))
+            Some(ListType::Explicit(
+                keep_elements_preserve_order(values, counts, false)
+                    .into_iter()
+                    .map(NumberType::Constant)
+                    .collect(),
+            ))
         }
         Sort(list_box) if list_box.is_constant_list() => {
             let mut values = try_get_constant_values(list_box)?;
-            values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+            values.sort_by(|a, b| compare_nan_last(*a, *b, false));
             Some(ListType::Explicit(
                 values.into_iter().map(NumberType::Constant).collect(),
             ))
         }
         SortDesc(list_box) if list_box.is_constant_list() => {
             let mut values = try_get_constant_values(list_box)?;
-            values.sort_by(|a, b| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+            values.sort_by(|a, b| compare_nan_last(*a, *b, true));
             Some(ListType::Explicit(
                 values.into_iter().map(NumberType::Constant).collect(),
             ))
         }
+        FilterEven(list_box) if list_box.is_constant_list() => {
+            let values = try_get_constant_values(list_box)?;
+            Some(ListType::Explicit(
+                every_other(&values, 0)
+                    .into_iter()
+                    .map(NumberType::Constant)
+                    .collect(),
+            ))
+        }
+        FilterOdd(list_box) if list_box.is_constant_list() => {
+            let values = try_get_constant_values(list_box)?;
+            Some(ListType::Explicit(
+                every_other(&values, 1)
+                    .into_iter()
+                    .map(NumberType::Constant)
+                    .collect(),
+            ))
+        }
         Filter(list_box, param) if list_box.is_constant_list() && param.is_constant() => {
             let cmp_func = param.get_compare_function()?;
             let values = try_get_constant_values(list_box)?;
@@ -252,7 +381,7 @@ fn fold_list_binary_op(list_bin_op: &mut ListBinaryType) -> Result<Option<ListTy
             if num == 0.0 {
                 return Err("Modulo by zero in list modulo".to_string());
             }
-            Ok(try_map_constant_list(list, |v| v % num))
+            try_map_constant_list_checked(list, |v| checked_modulo(v, num))
         }
         ModuloReverse(num, list) => {
             let Some(num) = try_get_constant_value(num) else {
@@ -269,7 +398,7 @@ fn fold_list_binary_op(list_bin_op: &mut ListBinaryType) -> Result<Option<ListTy
                     ));
                 }
             }
-            Ok(try_map_constant_list(list, |v| num % v))
+            try_map_constant_list_checked(list, |v| checked_modulo(num, v))
         }
         IntDivide(list, num) => {
             let Some(num) = try_get_constant_value(num) else {
@@ -278,7 +407,7 @@ fn fold_list_binary_op(list_bin_op: &mut ListBinaryType) -> Result<Option<ListTy
             if num == 0.0 {
                 return Err("Integer division by zero in list integer division".to_string());
             }
-            Ok(try_map_constant_list(list, |v| (v / num).floor()))
+            try_map_constant_list_checked(list, |v| checked_int_divide(v, num))
         }
         IntDivideReverse(num, list) => {
             let Some(num) = try_get_constant_value(num) else {
@@ -295,7 +424,7 @@ fn fold_list_binary_op(list_bin_op: &mut ListBinaryType) -> Result<Option<ListTy
                     ));
                 }
             }
-            Ok(try_map_constant_list(list, |v| (num / v).floor()))
+            try_map_constant_list_checked(list, |v| checked_int_divide(num, v))
         }
     }
 }
@@ -436,6 +565,21 @@ where
     }
 }
 
+// 与 try_map_constant_list 相同，但映射函数可能失败 (如整除的操作数超出安全范围)
+fn try_map_constant_list_checked<F>(list: &ListType, f: F) -> Result<Option<ListType>, String>
+where
+    F: Fn(f64) -> Result<f64, String>,
+{
+    let Some(values) = try_get_constant_values(list) else {
+        return Ok(None);
+    };
+    let folded = values
+        .into_iter()
+        .map(|v| f(v).map(NumberType::Constant))
+        .collect::<Result<Vec<NumberType>, String>>()?;
+    Ok(Some(ListType::Explicit(folded)))
+}
+
 fn try_get_constant_value(n: &NumberType) -> Option<f64> {
     if let NumberType::Constant(v) = n {
         Some(*v)
@@ -460,55 +604,51 @@ fn try_get_constant_values(list: &ListType) -> Option<Vec<f64>> {
     }
 }
 
-// 从列表中保留 count 个元素并保持相对顺序
-//keep_highest: true 为取最大 (Max), false 为取最小 (Min)
-pub fn keep_elements_preserve_order(
-    values: Vec<f64>,
-    raw_count: f64,
-    keep_highest: bool,
-) -> ListType {
-    if raw_count < 0.0 {
-        return ListType::Explicit(Vec::new());
-    }
-
-    let count = raw_count as usize;
-
-    if count >= values.len() {
-        return ListType::Explicit(values.into_iter().map(NumberType::Constant).collect());
+fn is_empty_list(list: &ListType) -> bool {
+    match list {
+        ListType::Explicit(vec) => vec.is_empty(),
+        _ => false,
     }
+}
 
-    let mut indexed_values: Vec<(usize, f64)> = values.into_iter().enumerate().collect();
-
-    indexed_values.sort_by(|a, b| {
-        let (val_a, val_b) = (a.1, b.1);
-        if keep_highest {
-            // true (Max): 降序 (b compare a)
-            val_b
-                .partial_cmp(&val_a)
-                .unwrap_or(std::cmp::Ordering::Equal)
-        } else {
-            // false (Min): 升序 (a compare b)
-            val_a
-                .partial_cmp(&val_b)
-                .unwrap_or(std::cmp::Ordering::Equal)
-        }
-    });
-
-    let mut top_k: Vec<(usize, f64)> = indexed_values.into_iter().take(count).collect();
-
-    top_k.sort_by_key(|(index, _)| *index);
-
-    let result = top_k
-        .into_iter()
-        .map(|(_, val)| NumberType::Constant(val))
-        .collect();
+// ==========================================
+// 单元测试
+// ==========================================
 
-    ListType::Explicit(result)
+#[cfg(test)]
+fn fold_input(input: &str) -> HIR {
+    let ast = crate::grammar::parse_dice(input).unwrap();
+    constant_fold_hir(crate::lower::lower_expr(ast).unwrap()).unwrap()
 }
 
-fn is_empty_list(list: &ListType) -> bool {
-    match list {
-        ListType::Explicit(vec) => vec.is_empty(),
-        _ => false,
+#[test]
+fn test_fold_reaches_fixpoint() {
+    // sum 展开出的加法树需要第二轮才能合并常数与同类骰子
+    let hir = fold_input("sum([1d6, 2, 1d6, 3])");
+    assert_eq!(format!("{}", hir), "2d6+5");
+    assert_eq!(constant_fold_hir(hir.clone()).unwrap(), hir);
+    // 已经折叠过的 HIR 再折叠一轮不应报告任何变化
+    for input in [
+        "2d6+5",
+        "1d6-1d4*2-3",
+        "-1d6+1d8",
+        "1d6*1d8*3",
+        "sum([1d6, 1d8]) - 1",
+    ] {
+        let mut folded = fold_input(input);
+        let mut folder = ConstantFolder::default();
+        folder.visit_hir(&mut folded).unwrap();
+        assert!(!folder.changed, "{} did not reach a fixpoint", input);
+    }
+    for input in [
+        "sum([1, 2]) * len([3, 1d6])",
+        "len(filter>1(sort([3, 1, 2]))) + sum([0.5, 0.5])",
+        "max([sum([1, 2]), len([1d6, 1d6, 1d6, 1d6])])",
+    ] {
+        assert!(
+            matches!(fold_input(input), HIR::Number(NumberType::Constant(_))),
+            "{} should fold to a constant",
+            input
+        );
     }
 }
@@ -1,4 +1,6 @@
 use crate::types::hir::{DicePoolType, NumberBinaryType, NumberType};
+use crate::types::runtime_value::get_compare_function;
+use crate::types::safe_integer::{checked_int_divide, checked_modulo};
 use std::{collections::BTreeMap, mem};
 
 // ==========================================
@@ -25,7 +27,7 @@ pub fn fold_binary_op(op: &mut NumberBinaryType) -> Result<Option<NumberType>, S
                 if *c2 == 0.0 {
                     return Err("Integer division by zero".to_string());
                 }
-                Ok(Some(NumberType::Constant((c1 / c2).floor())))
+                Ok(Some(NumberType::Constant(checked_int_divide(*c1, *c2)?)))
             } else {
                 Ok(None)
             }
@@ -35,7 +37,15 @@ pub fn fold_binary_op(op: &mut NumberBinaryType) -> Result<Option<NumberType>, S
                 if *c2 == 0.0 {
                     return Err("Modulo by zero".to_string());
                 }
-                Ok(Some(NumberType::Constant(c1 % c2)))
+                Ok(Some(NumberType::Constant(checked_modulo(*c1, *c2)?)))
+            } else {
+                Ok(None)
+            }
+        }
+        Compare(l, op, r) => {
+            if let (NumberType::Constant(c1), NumberType::Constant(c2)) = (&**l, &**r) {
+                let holds = get_compare_function(op.clone(), *c2)(*c1);
+                Ok(Some(NumberType::Constant(if holds { 1.0 } else { 0.0 })))
             } else {
                 Ok(None)
             }
@@ -52,6 +62,11 @@ fn fold_linear_add_sub(
     right: &mut NumberType,
     is_subtract: bool,
 ) -> Result<Option<NumberType>, String> {
+    // 已经是重组后的形式时不再重建，否则定点循环无法得知这一轮是否真的有变化
+    if is_rebuilt_add_sub(left, right, is_subtract) {
+        return Ok(None);
+    }
+
     // 【偷梁换柱】：用 0.0 替换掉原来的节点，从而拿到所有权 (Move)
     // 这一步是 Zero-Copy 的关键，我们拿到了原本的树的所有权
     let l_owned = mem::replace(left, NumberType::Constant(0.0));
@@ -252,6 +267,154 @@ fn mergeable_dice(node: &NumberType, sign: f64) -> Option<(DiceType, i32)> {
     }
 }
 
+// ------------------------------------------
+// 判断加减法是否已是重组后的形式 (只读，不移动节点)
+// ------------------------------------------
+// 按 flatten_add_sub + merge_terms 的规则推算出重组结果的项序列，再与原树逐项比较。
+// 未合并的项只会被原样移动，因此用指针判断是否是同一个节点
+enum ExpectedTerm<'a> {
+    Node(&'a NumberType),
+    Dice(DiceType, i32),
+}
+
+fn is_rebuilt_add_sub(left: &NumberType, right: &NumberType, is_subtract: bool) -> bool {
+    let mut terms = Vec::new();
+    let mut constant_acc = 0.0;
+    flatten_add_sub_ref(left, 1.0, &mut terms, &mut constant_acc);
+    flatten_add_sub_ref(
+        right,
+        if is_subtract { -1.0 } else { 1.0 },
+        &mut terms,
+        &mut constant_acc,
+    );
+
+    let mut dice_map = BTreeMap::new();
+    let mut expected = Vec::new();
+    let mut unmergeable_terms = Vec::new();
+    for (node, sign) in terms {
+        if let Some((dice_type, count)) = mergeable_dice(node, sign) {
+            *dice_map.entry(dice_type).or_insert(0) += count;
+        } else {
+            unmergeable_terms.push((ExpectedTerm::Node(node), sign));
+        }
+    }
+    for (dice_type, count) in dice_map.into_iter().rev() {
+        if count != 0 {
+            let sign = match dice_type {
+                DiceType::Standard(is_add, _)
+                | DiceType::Fudge(is_add)
+                | DiceType::Coin(is_add) => {
+                    if is_add {
+                        1.0
+                    } else {
+                        -1.0
+                    }
+                }
+            };
+            expected.push((ExpectedTerm::Dice(dice_type, count), sign));
+        }
+    }
+    expected.extend(unmergeable_terms);
+
+    if expected.is_empty() {
+        return false; // 会折叠为常数
+    }
+    if constant_acc == 0.0 {
+        // 常数消失，根节点就是最后一项
+        let Some(((last, last_sign), rest)) = expected.split_last() else {
+            return false;
+        };
+        !rest.is_empty()
+            && (*last_sign < 0.0) == is_subtract
+            && term_matches(right, last)
+            && add_chain_matches(left, rest)
+    } else {
+        // 常数作为最后一项，正数用加法，负数用减法
+        let expected_constant = if constant_acc > 0.0 {
+            constant_acc
+        } else {
+            -constant_acc
+        };
+        is_subtract != (constant_acc > 0.0)
+            && matches!(right, NumberType::Constant(c) if same_constant(*c, expected_constant))
+            && add_chain_matches(left, &expected)
+    }
+}
+
+fn flatten_add_sub_ref<'a>(
+    node: &'a NumberType,
+    sign: f64,
+    terms: &mut Vec<(&'a NumberType, f64)>,
+    acc: &mut f64,
+) {
+    match node {
+        NumberType::Constant(c) => *acc += c * sign,
+        NumberType::NumberBinary(NumberBinaryType::Add(l, r)) => {
+            flatten_add_sub_ref(l, sign, terms, acc);
+            flatten_add_sub_ref(r, sign, terms, acc);
+        }
+        NumberType::NumberBinary(NumberBinaryType::Subtract(l, r)) => {
+            flatten_add_sub_ref(l, sign, terms, acc);
+            flatten_add_sub_ref(r, -sign, terms, acc);
+        }
+        NumberType::Neg(inner) => flatten_add_sub_ref(inner, -sign, terms, acc),
+        other => terms.push((other, sign)),
+    }
+}
+
+// 与 rebuild_add_tree 的结构对应：左结合，第一项为负时包一层 Neg
+fn add_chain_matches(node: &NumberType, expected: &[(ExpectedTerm, f64)]) -> bool {
+    use NumberBinaryType::*;
+    match expected.split_last() {
+        None => false,
+        Some(((term, sign), [])) => {
+            if *sign < 0.0 {
+                matches!(node, NumberType::Neg(inner) if term_matches(inner, term))
+            } else {
+                term_matches(node, term)
+            }
+        }
+        Some(((term, sign), rest)) => match node {
+            NumberType::NumberBinary(Add(l, r)) if *sign > 0.0 => {
+                term_matches(r, term) && add_chain_matches(l, rest)
+            }
+            NumberType::NumberBinary(Subtract(l, r)) if *sign < 0.0 => {
+                term_matches(r, term) && add_chain_matches(l, rest)
+            }
+            _ => false,
+        },
+    }
+}
+
+fn term_matches(node: &NumberType, term: &ExpectedTerm) -> bool {
+    use DicePoolType::*;
+    match term {
+        ExpectedTerm::Node(expected) => std::ptr::eq(node, *expected),
+        ExpectedTerm::Dice(dice_type, count) => {
+            let count = *count as f64;
+            match (node, dice_type) {
+                (
+                    NumberType::DicePool(Standard(counts, sides)),
+                    DiceType::Standard(_, expected_sides),
+                ) => {
+                    matches!(**counts, NumberType::Constant(c) if c == count)
+                        && matches!(**sides, NumberType::Constant(s) if s == *expected_sides as f64)
+                }
+                (NumberType::DicePool(Fudge(counts)), DiceType::Fudge(_))
+                | (NumberType::DicePool(Coin(counts)), DiceType::Coin(_)) => {
+                    matches!(**counts, NumberType::Constant(c) if c == count)
+                }
+                _ => false,
+            }
+        }
+    }
+}
+
+// NaN 之间也视为相同，否则含 NaN 的常数项永远不会稳定
+fn same_constant(a: f64, b: f64) -> bool {
+    a == b || (a.is_nan() && b.is_nan())
+}
+
 // ==========================================
 // 2. 乘法线性优化
 // ==========================================
@@ -260,6 +423,10 @@ fn fold_linear_multiply(
     left: &mut NumberType,
     right: &mut NumberType,
 ) -> Result<Option<NumberType>, String> {
+    if is_rebuilt_multiply(left, right) {
+        return Ok(None);
+    }
+
     // 拿到所有权
     let l_owned = mem::replace(left, NumberType::Constant(0.0));
     let r_owned = mem::replace(right, NumberType::Constant(0.0));
@@ -317,6 +484,51 @@ fn rebuild_mul_tree(mut terms: Vec<NumberType>) -> NumberType {
     current
 }
 
+// 判断乘法是否已是重组后的形式，规则同 is_rebuilt_add_sub
+fn is_rebuilt_multiply(left: &NumberType, right: &NumberType) -> bool {
+    let mut terms = Vec::new();
+    let mut constant_acc = 1.0;
+    flatten_mul_ref(left, &mut terms, &mut constant_acc);
+    flatten_mul_ref(right, &mut terms, &mut constant_acc);
+
+    if constant_acc == 0.0 || terms.is_empty() {
+        return false; // 会折叠为常数
+    }
+    if constant_acc == 1.0 {
+        let Some((last, rest)) = terms.split_last() else {
+            return false;
+        };
+        !rest.is_empty() && std::ptr::eq(right, *last) && mul_chain_matches(left, rest)
+    } else {
+        matches!(right, NumberType::Constant(c) if same_constant(*c, constant_acc))
+            && mul_chain_matches(left, &terms)
+    }
+}
+
+fn flatten_mul_ref<'a>(node: &'a NumberType, terms: &mut Vec<&'a NumberType>, acc: &mut f64) {
+    match node {
+        NumberType::Constant(c) => *acc *= c,
+        NumberType::NumberBinary(NumberBinaryType::Multiply(l, r)) => {
+            flatten_mul_ref(l, terms, acc);
+            flatten_mul_ref(r, terms, acc);
+        }
+        other => terms.push(other),
+    }
+}
+
+fn mul_chain_matches(node: &NumberType, expected: &[&NumberType]) -> bool {
+    match expected.split_last() {
+        None => false,
+        Some((last, [])) => std::ptr::eq(node, *last),
+        Some((last, rest)) => match node {
+            NumberType::NumberBinary(NumberBinaryType::Multiply(l, r)) => {
+                std::ptr::eq(&**r, *last) && mul_chain_matches(l, rest)
+            }
+            _ => false,
+        },
+    }
+}
+
 // ==========================================
 // 3. 除法优化
 // ==========================================
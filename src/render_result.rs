@@ -1,15 +1,98 @@
 use crate::types::eval_graph::*;
+use crate::types::hir_fmt::{
+    explode_until_label, keep_relative_label, labeled_faces_text, success_weights_text,
+};
+use crate::types::number_format::{DEFAULT_MAX_DECIMAL_PLACES, format_output_number};
 use crate::types::output_node::*;
 use crate::types::runtime_value::*;
 
+// 结果输出中的数字统一保留默认的小数位数
+fn output_number(value: f64) -> String {
+    format_output_number(value, DEFAULT_MAX_DECIMAL_PLACES)
+}
+
+// 结果树的渲染选项
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenderOptions {
+    // 为 true 时骰池明细包含全部骰子 (被丢弃的骰子 is_kept 为 false)，
+    // 为 false 时只包含被保留的骰子，骰池的总和不受影响
+    pub show_all: bool,
+    // Fudge/Coin 骰子明细中 symbol 字段使用的符号
+    pub symbols: FaceSymbols,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            show_all: true,
+            symbols: FaceSymbols::default(),
+        }
+    }
+}
+
 pub fn render_result(graph: &EvalGraph, memory: &[NodeState]) -> OutputNode {
-    let builder = ResultTreeBuilder::new(graph, memory);
+    render_result_with_options(graph, memory, RenderOptions::default())
+}
+
+pub fn render_result_with_options(
+    graph: &EvalGraph,
+    memory: &[NodeState],
+    options: RenderOptions,
+) -> OutputNode {
+    let builder = ResultTreeBuilder::with_options(graph, memory, options);
     builder.build()
 }
 
+// 若根节点是加减法链，则将其拆分为若干项，每一项单独生成结果树
+pub fn render_breakdown(graph: &EvalGraph, memory: &[NodeState]) -> RollBreakdown {
+    let builder = ResultTreeBuilder::new(graph, memory);
+    let mut terms = Vec::new();
+    builder.collect_terms(graph.root, false, &mut terms);
+    RollBreakdown {
+        terms,
+        total: builder.get_value_summary(graph.root.to_index()),
+    }
+}
+
+fn face_type(face: &DiceFace) -> DiceFaceType {
+    match face {
+        DiceFace::Number(f) => DiceFaceType::Standard(*f),
+        DiceFace::Fudge => DiceFaceType::Fudge,
+        DiceFace::Coin => DiceFaceType::Coin,
+        DiceFace::Labeled(faces) => DiceFaceType::Labeled(
+            faces
+                .iter()
+                .map(|(label, value)| LabeledFace {
+                    label: label.clone(),
+                    value: *value,
+                })
+                .collect(),
+        ),
+    }
+}
+
+fn kept_results(details: &[DieDetail]) -> impl Iterator<Item = i32> + '_ {
+    details.iter().filter(|d| d.is_kept).map(|d| d.result)
+}
+
+fn coin_tally(details: &[DieDetail]) -> CoinTally {
+    let heads = kept_results(details).filter(|&r| r == 1).count() as i32;
+    let tails = kept_results(details).filter(|&r| r == 0).count() as i32;
+    CoinTally { heads, tails }
+}
+
+// 统计被保留的骰子中成功 (sign 为 1) 或失败 (sign 为 -1) 的个数，加权的成功也只算一个骰子
+fn count_kept_outcomes(details: &[DieDetail], sign: i32) -> i32 {
+    details
+        .iter()
+        .filter(|d| d.counts_toward_successes() && d.outcome.weight().signum() == sign)
+        .count() as i32
+}
+
 struct ResultTreeBuilder<'a> {
     graph: &'a EvalGraph,
     memory: &'a [NodeState],
+    options: RenderOptions,
 }
 
 impl<'a> ResultTreeBuilder<'a> {
@@ -20,36 +103,82 @@ impl<'a> ResultTreeBuilder<'a> {
                 RuntimeValue::List(l) => ValueSummary::List(l.clone()),
                 RuntimeValue::DicePool(dp) => ValueSummary::DicePool {
                     total: dp.total,
-                    face: match dp.face {
-                        DiceFace::Number(f) => DiceFaceType::Standard(f),
-                        DiceFace::Fudge => DiceFaceType::Fudge,
-                        DiceFace::Coin => DiceFaceType::Coin,
+                    raw_total: dp.details.iter().map(|d| d.result).sum(),
+                    face: face_type(&dp.face),
+                    details: self.convert_details(&dp.face, &dp.details, self.options.show_all),
+                    kept_details: self.kept_details(&dp.face, &dp.details),
+                    highest: kept_results(&dp.details).max(),
+                    lowest: kept_results(&dp.details).min(),
+                    coins: match dp.face {
+                        DiceFace::Coin => Some(coin_tally(&dp.details)),
+                        _ => None,
                     },
-                    details: dp.details.iter().map(|d| self.convert_detail(d)).collect(),
                 },
                 RuntimeValue::SuccessPool(sp) => ValueSummary::SuccessPool {
                     count: sp.success_count,
-                    face: match sp.face {
-                        DiceFace::Number(f) => DiceFaceType::Standard(f),
-                        DiceFace::Fudge => DiceFaceType::Fudge,
-                        DiceFace::Coin => DiceFaceType::Coin,
-                    },
-                    details: sp.details.iter().map(|d| self.convert_detail(d)).collect(),
+                    face: face_type(&sp.face),
+                    details: self.convert_details(&sp.face, &sp.details, self.options.show_all),
+                    kept_details: self.kept_details(&sp.face, &sp.details),
+                    highest: kept_results(&sp.details).max(),
+                    lowest: kept_results(&sp.details).min(),
+                    successes: count_kept_outcomes(&sp.details, 1),
+                    failures: count_kept_outcomes(&sp.details, -1),
                 },
             },
             _ => ValueSummary::NotComputed,
         }
     }
 
-    fn convert_detail(&self, d: &DieDetail) -> DieDetailSummary {
+    fn convert_details(
+        &self,
+        face: &DiceFace,
+        details: &[DieDetail],
+        show_all: bool,
+    ) -> Vec<DieDetailSummary> {
+        let face = face_type(face);
+        details
+            .iter()
+            .filter(|d| show_all || d.is_kept)
+            .map(|d| self.convert_detail(&face, d, show_all))
+            .collect()
+    }
+
+    // 完整名单之外再给出被保留的子集，两种视图可以同时展示
+    fn kept_details(
+        &self,
+        face: &DiceFace,
+        details: &[DieDetail],
+    ) -> Option<Vec<DieDetailSummary>> {
+        self.options
+            .show_all
+            .then(|| self.convert_details(face, details, false))
+    }
+
+    fn convert_detail(
+        &self,
+        face: &DiceFaceType,
+        d: &DieDetail,
+        show_all: bool,
+    ) -> DieDetailSummary {
         DieDetailSummary {
             result: d.result,
             is_kept: d.is_kept,
             roll_history: d.roll_history.clone(),
+            success_weight: d.outcome.weight().max(0),
             is_rerolled: d.is_rerolled,
+            // 只包含被保留的骰子时下标会错位，而被重投的骰子本身也不会出现
+            replaced_by: d.replaced_by.filter(|_| show_all),
             exploded_times: d.exploded_times,
+            was_clamped: d.was_clamped,
+            symbol: self
+                .options
+                .symbols
+                .symbol(face, d.result)
+                .map(str::to_string),
             outcome: match d.outcome {
-                DieOutcome::Success => OutcomeType::Success,
+                DieOutcome::Success | DieOutcome::WeightedSuccess(_) | DieOutcome::Spent => {
+                    OutcomeType::Success
+                }
                 DieOutcome::Failure => OutcomeType::Failure,
                 DieOutcome::None => OutcomeType::None,
             },
@@ -59,7 +188,19 @@ impl<'a> ResultTreeBuilder<'a> {
 
 impl<'a> ResultTreeBuilder<'a> {
     pub fn new(graph: &'a EvalGraph, memory: &'a [NodeState]) -> Self {
-        Self { graph, memory }
+        Self::with_options(graph, memory, RenderOptions::default())
+    }
+
+    pub fn with_options(
+        graph: &'a EvalGraph,
+        memory: &'a [NodeState],
+        options: RenderOptions,
+    ) -> Self {
+        Self {
+            graph,
+            memory,
+            options,
+        }
     }
 
     pub fn build(&self) -> OutputNode {
@@ -68,6 +209,23 @@ impl<'a> ResultTreeBuilder<'a> {
         node
     }
 
+    fn collect_terms(&self, node_id: NodeId, is_negative: bool, terms: &mut Vec<BreakdownTerm>) {
+        match &self.graph.nodes[node_id.to_index()] {
+            EvalNode::NumAdd(l, r) => {
+                self.collect_terms(*l, is_negative, terms);
+                self.collect_terms(*r, is_negative, terms);
+            }
+            EvalNode::NumSubtract(l, r) => {
+                self.collect_terms(*l, is_negative, terms);
+                self.collect_terms(*r, !is_negative, terms);
+            }
+            _ => {
+                let (node, _) = self.build_recursive(node_id);
+                terms.push(BreakdownTerm { is_negative, node });
+            }
+        }
+    }
+
     fn build_recursive(&self, node_id: NodeId) -> (OutputNode, Precedence) {
         let idx = node_id.to_index();
         let eval_node = &self.graph.nodes[idx];
@@ -76,11 +234,25 @@ impl<'a> ResultTreeBuilder<'a> {
 
         let (label, layout, current_prec) = match eval_node {
             // 原子节点
-            EvalNode::Constant(n) => (n.to_string(), NodeLayout::Atom, Precedence::Call),
+            EvalNode::Constant(n) => (output_number(*n), NodeLayout::Atom, Precedence::Call),
             EvalNode::ListConstruct(ids) => {
                 let children = ids.iter().map(|id| self.build_recursive(*id).0).collect();
                 ("".to_string(), NodeLayout::List(children), Precedence::Call)
             }
+            // 各元素没有单独的求值节点，只用于显示，沿用列表节点的 id
+            EvalNode::ConstantList(values) => {
+                let children = values
+                    .iter()
+                    .map(|v| OutputNode {
+                        id: node_id.0,
+                        label: output_number(*v),
+                        value: ValueSummary::Number(*v),
+                        layout: NodeLayout::Atom,
+                        wrap_in_parentheses: false,
+                    })
+                    .collect();
+                ("".to_string(), NodeLayout::List(children), Precedence::Call)
+            }
             // 单目运算
             EvalNode::NumNegate(id) => {
                 let prec = Precedence::Prefix;
@@ -97,6 +269,9 @@ impl<'a> ResultTreeBuilder<'a> {
             EvalNode::NumDivide(l, r) => self.math_infix("/", *l, *r, Precedence::Product),
             EvalNode::NumIntDivide(l, r) => self.math_infix("//", *l, *r, Precedence::Product),
             EvalNode::NumModulo(l, r) => self.math_infix("%", *l, *r, Precedence::Product),
+            EvalNode::NumCompare(l, op, r) => {
+                self.math_infix(&op.to_string(), *l, *r, Precedence::Compare)
+            }
             // 列表二元运算
             EvalNode::Concat(l, r) => self.list_infix("+", *l, *r, Precedence::Sum),
             EvalNode::ListAdd(l, r) => self.list_infix("+", *l, *r, Precedence::Sum),
@@ -117,16 +292,31 @@ impl<'a> ResultTreeBuilder<'a> {
             EvalNode::NumFloor(id) | EvalNode::ListFloor(id) => self.func("floor", vec![*id]),
             EvalNode::NumCeil(id) | EvalNode::ListCeil(id) => self.func("ceil", vec![*id]),
             EvalNode::NumRound(id) | EvalNode::ListRound(id) => self.func("round", vec![*id]),
+            EvalNode::NumRoundEven(id) => self.func("roundeven", vec![*id]),
             EvalNode::NumAbs(id) | EvalNode::ListAbs(id) => self.func("abs", vec![*id]),
             EvalNode::NumMax(id) => self.func("max", vec![*id]),
             EvalNode::NumMin(id) => self.func("min", vec![*id]),
+            EvalNode::NumMaxOr(l, d) => self.func("maxor", vec![*l, *d]),
+            EvalNode::NumMinOr(l, d) => self.func("minor", vec![*l, *d]),
             EvalNode::NumSum(id) => self.func("sum", vec![*id]),
             EvalNode::NumAvg(id) => self.func("avg", vec![*id]),
             EvalNode::NumLen(id) => self.func("len", vec![*id]),
+            EvalNode::NumMargin(r, t) => self.func("margin", vec![*r, *t]),
+            // 各组展开为参数，与输入时的写法一致
+            EvalNode::NumGroupKh(l, n) => match &self.graph.nodes[l.to_index()] {
+                EvalNode::ListConstruct(ids) => {
+                    self.func("groupkh", ids.iter().copied().chain([*n]).collect())
+                }
+                _ => self.func("groupkh", vec![*l, *n]),
+            },
+            EvalNode::DiceHighest(id) => self.func("highdie", vec![*id]),
+            EvalNode::DiceLowest(id) => self.func("lowdie", vec![*id]),
             EvalNode::ListMax(id1, id2) => self.func("max", vec![*id1, *id2]),
             EvalNode::ListMin(id1, id2) => self.func("min", vec![*id1, *id2]),
             EvalNode::ListSort(id) => self.func("sort", vec![*id]),
             EvalNode::ListSortDesc(id) => self.func("sortd", vec![*id]),
+            EvalNode::ListFilterEven(id) => self.func("filtereven", vec![*id]),
+            EvalNode::ListFilterOdd(id) => self.func("filterodd", vec![*id]),
             EvalNode::ListToListFromDicePool(id) | EvalNode::ListToListFromSuccessPool(id) => {
                 self.func("tolist", vec![*id])
             }
@@ -180,6 +370,19 @@ impl<'a> ResultTreeBuilder<'a> {
                     prec,
                 )
             }
+            EvalNode::DicePercentile => ("d%%".to_string(), NodeLayout::Atom, Precedence::Dice),
+            EvalNode::DiceLabeled(count, faces) => {
+                let prec = Precedence::Dice;
+                let (mut child, c_prec) = self.build_recursive(*count);
+                if c_prec <= prec {
+                    child.wrap_in_parentheses = true;
+                }
+                (
+                    format!("d[{}]", labeled_faces_text(faces)),
+                    NodeLayout::TightPostfix(Box::new(child)),
+                    prec,
+                )
+            }
             EvalNode::DiceCoin(count) => {
                 let prec = Precedence::Dice;
                 let (mut child, c_prec) = self.build_recursive(*count);
@@ -196,6 +399,55 @@ impl<'a> ResultTreeBuilder<'a> {
             EvalNode::DiceKeepLow(p, n) => self.simple_dice_mod("kl", *p, *n),
             EvalNode::DiceDropHigh(p, n) => self.simple_dice_mod("dh", *p, *n),
             EvalNode::DiceDropLow(p, n) => self.simple_dice_mod("dl", *p, *n),
+            EvalNode::DiceConditionalDropHigh(p, n, mp) => {
+                self.conditional_dice_mod("dh", *p, *n, mp)
+            }
+            EvalNode::DiceConditionalDropLow(p, n, mp) => {
+                self.conditional_dice_mod("dl", *p, *n, mp)
+            }
+            EvalNode::DiceRemoveN(p, n, mp) => self.conditional_dice_mod("rm", *p, *n, mp),
+            EvalNode::DiceFromSuccessPool(id) => self.func("asdice", vec![*id]),
+            EvalNode::DiceMapEach(pool, op, value) => {
+                let (pool_node, _) = self.build_recursive(*pool);
+                let (value_node, _) = self.build_recursive(*value);
+                // 调整值前显示运算符，如 each(4d6, +1)；该节点只用于显示，沿用调整值节点的 id
+                let adjustment = OutputNode {
+                    id: value_node.id,
+                    label: op.to_string(),
+                    value: value_node.value.clone(),
+                    layout: NodeLayout::Prefix(Box::new(value_node)),
+                    wrap_in_parentheses: false,
+                };
+                (
+                    "each".to_string(),
+                    NodeLayout::Function(vec![pool_node, adjustment]),
+                    Precedence::Call,
+                )
+            }
+            EvalNode::DiceKeepRelative(p, compare) => {
+                let (pool_node, _) = self.build_recursive(*p);
+                (
+                    format!("{}avg", keep_relative_label(compare)),
+                    NodeLayout::SpecialModifier(Box::new(pool_node), None, None, None, None),
+                    Precedence::Dice,
+                )
+            }
+            EvalNode::DiceKeepDistinct(p) => {
+                let (pool_node, _) = self.build_recursive(*p);
+                (
+                    "kdistinct".to_string(),
+                    NodeLayout::SpecialModifier(Box::new(pool_node), None, None, None, None),
+                    Precedence::Dice,
+                )
+            }
+            EvalNode::DiceKeepRankRange(p, low, high) => {
+                let (pool_node, _) = self.build_recursive(*p);
+                (
+                    format!("k{}..{}", low, high),
+                    NodeLayout::SpecialModifier(Box::new(pool_node), None, None, None, None),
+                    Precedence::Dice,
+                )
+            }
             EvalNode::DiceMin(p, n) => self.simple_dice_mod("min", *p, *n),
             EvalNode::DiceMax(p, n) => self.simple_dice_mod("max", *p, *n),
             EvalNode::DiceCountSuccesses(p, mp)
@@ -203,6 +455,30 @@ impl<'a> ResultTreeBuilder<'a> {
                 let op = format!("cs{}", mp.operator);
                 self.simple_dice_mod(&op, *p, mp.value)
             }
+            EvalNode::DiceSpendSuccessesFromDicePool(p, mp) => {
+                let op = format!("sp{}", mp.operator);
+                self.simple_dice_mod(&op, *p, mp.value)
+            }
+            EvalNode::DiceNetSuccess(p, mp) => {
+                let op = format!("net{}", mp.operator);
+                self.simple_dice_mod(&op, *p, mp.value)
+            }
+            EvalNode::DiceExplodeUntil(p, op, value, quota) => {
+                let (pool_node, _) = self.build_recursive(*p);
+                (
+                    explode_until_label(op, *value, *quota, output_number),
+                    NodeLayout::SpecialModifier(Box::new(pool_node), None, None, None, None),
+                    Precedence::Dice,
+                )
+            }
+            EvalNode::DiceWeightedSuccesses(p, weights) => {
+                let (pool_node, _) = self.build_recursive(*p);
+                (
+                    format!("cs{}", success_weights_text(weights, output_number)),
+                    NodeLayout::SpecialModifier(Box::new(pool_node), None, None, None, None),
+                    Precedence::Dice,
+                )
+            }
             EvalNode::DiceDeductFailures(p, mp)
             | EvalNode::DiceDeductFailuresFromDicePool(p, mp) => {
                 let op = format!("df{}", mp.operator);
@@ -213,8 +489,20 @@ impl<'a> ResultTreeBuilder<'a> {
                 self.simple_dice_mod(&op, *p, mp.value)
             }
             EvalNode::DiceExplode(pool, mp, limit) => self.explode("!", *pool, mp, limit),
+            EvalNode::DiceCascade(pool, mp, limit) => {
+                self.explode("cascade", *pool, &Some(mp.clone()), &Some(limit.clone()))
+            }
             EvalNode::DiceCompoundExplode(pool, mp, limit) => self.explode("!!", *pool, mp, limit),
+            EvalNode::DiceExplodeOnce(pool, mp, limit) => self.explode("!o", *pool, mp, limit),
+            EvalNode::DiceExplodeMulti(pool, count, mp, limit) => {
+                self.explode(&format!("!x{}", count), *pool, mp, limit)
+            }
             EvalNode::DiceReroll(pool, mp, limit) => self.reroll("r", *pool, mp, limit),
+            EvalNode::DiceRerollPool(pool, mp, limit) => self.reroll("rp", *pool, mp, limit),
+            EvalNode::DiceRerollMax(pool, mp) => self.reroll("rmax", *pool, mp, &None),
+            EvalNode::DiceExplodeIfTotal(pool, mp) => {
+                self.explode("!total", *pool, &Some(mp.clone()), &None)
+            }
         };
 
         let node = OutputNode {
@@ -303,6 +591,36 @@ impl<'a> ResultTreeBuilder<'a> {
         )
     }
 
+    fn conditional_dice_mod(
+        &self,
+        op: &str,
+        pool: NodeId,
+        count: NodeId,
+        mp: &ModParamNode,
+    ) -> (String, NodeLayout, Precedence) {
+        let prec = Precedence::Dice;
+        let (pool_node, _) = self.build_recursive(pool);
+        // 骰子池始终不加括号，个数与比较参数优先级低时加括号
+        let (mut count_node, count_prec) = self.build_recursive(count);
+        if count_prec <= prec {
+            count_node.wrap_in_parentheses = true;
+        }
+        let (mut val, val_prec) = self.build_recursive(mp.value);
+        if val_prec <= prec {
+            val.wrap_in_parentheses = true;
+        }
+
+        (
+            op.to_string(),
+            NodeLayout::ConditionalModifier(
+                Box::new(pool_node),
+                Box::new(count_node),
+                Box::new((mp.operator.to_string(), val)),
+            ),
+            prec,
+        )
+    }
+
     fn explode(
         &self,
         label: &str,
@@ -325,7 +643,7 @@ impl<'a> ResultTreeBuilder<'a> {
             None
         };
 
-        let (mut lt, mut lc) = (None, None);
+        let (mut lt, mut lc, mut lsz) = (None, None, None);
         if let Some(l) = limit {
             if let Some(id) = l.limit_times {
                 let (mut raw, raw_prec) = self.build_recursive(id);
@@ -341,11 +659,18 @@ impl<'a> ResultTreeBuilder<'a> {
                 }
                 lc = Some(Box::new(raw));
             }
+            if let Some(id) = l.limit_size {
+                let (mut raw, raw_prec) = self.build_recursive(id);
+                if raw_prec <= prec {
+                    raw.wrap_in_parentheses = true;
+                }
+                lsz = Some(Box::new(raw));
+            }
         }
 
         (
             label.to_string(),
-            NodeLayout::SpecialModifier(Box::new(pool_node), mp_data, lt, lc),
+            NodeLayout::SpecialModifier(Box::new(pool_node), mp_data, lt, lc, lsz),
             prec,
         )
     }
@@ -369,7 +694,7 @@ impl<'a> ResultTreeBuilder<'a> {
         }
         let mp_data = Some(Box::new((mp.operator.to_string(), val)));
 
-        let (mut lt, mut lc) = (None, None);
+        let (mut lt, mut lc, mut lsz) = (None, None, None);
         if let Some(l) = limit {
             if let Some(id) = l.limit_times {
                 let (mut raw, raw_prec) = self.build_recursive(id);
@@ -385,11 +710,18 @@ impl<'a> ResultTreeBuilder<'a> {
                 }
                 lc = Some(Box::new(raw));
             }
+            if let Some(id) = l.limit_size {
+                let (mut raw, raw_prec) = self.build_recursive(id);
+                if raw_prec <= prec {
+                    raw.wrap_in_parentheses = true;
+                }
+                lsz = Some(Box::new(raw));
+            }
         }
 
         (
             label.to_string(),
-            NodeLayout::SpecialModifier(Box::new(pool_node), mp_data, lt, lc),
+            NodeLayout::SpecialModifier(Box::new(pool_node), mp_data, lt, lc, lsz),
             prec,
         )
     }
@@ -0,0 +1,505 @@
+use crate::types::number_format::{DEFAULT_MAX_DECIMAL_PLACES, format_output_number};
+use crate::types::output_node::*;
+
+// ==========================================
+// 文本输出配置
+// ==========================================
+
+#[derive(Debug, Clone)]
+pub struct TextFormatOptions {
+    pub max_decimal_places: usize, // 数字最多保留的小数位数
+    pub symbols: FaceSymbols,      // Fudge/Coin 骰子结果的显示符号
+    pub avg_as_fraction: bool,     // 整数的平均值不是整数时显示为最简分数，如 "3/2"
+}
+
+impl Default for TextFormatOptions {
+    fn default() -> Self {
+        TextFormatOptions {
+            max_decimal_places: DEFAULT_MAX_DECIMAL_PLACES,
+            symbols: FaceSymbols::default(),
+            avg_as_fraction: false,
+        }
+    }
+}
+
+// ==========================================
+// 入口函数
+// ==========================================
+
+// 将结果树渲染为一行纯文本，形如 "2d6 [3, 5] + 1 = 9"
+pub fn format_result_text(node: &OutputNode) -> String {
+    format_result_text_with_options(node, &TextFormatOptions::default())
+}
+
+pub fn format_result_text_with_options(node: &OutputNode, options: &TextFormatOptions) -> String {
+    let writer = TextWriter { options };
+    let value_text = match writer.success_product_text(node) {
+        Some(text) => text,
+        None => match writer.avg_fraction_text(node) {
+            Some(text) => text,
+            None => writer.value_text(&node.value),
+        },
+    };
+    format!("{} = {}", writer.write_node(node, false), value_text)
+}
+
+// 将加法链拆分结果渲染为一行文本，形如 "2d6 [4, 5] = 9, 1d8 [6] = 6, +3, total 18"
+pub fn format_breakdown_text(breakdown: &RollBreakdown) -> String {
+    let options = TextFormatOptions::default();
+    let writer = TextWriter { options: &options };
+    let mut items: Vec<String> = breakdown
+        .terms
+        .iter()
+        .map(|term| writer.term_text(term))
+        .collect();
+    items.push(format!("total {}", writer.value_text(&breakdown.total)));
+    items.join(", ")
+}
+
+// ==========================================
+// 渲染实现
+// ==========================================
+
+struct TextWriter<'a> {
+    options: &'a TextFormatOptions,
+}
+
+impl<'a> TextWriter<'a> {
+    fn number(&self, value: f64) -> String {
+        format_output_number(value, self.options.max_decimal_places)
+    }
+
+    fn value_text(&self, value: &ValueSummary) -> String {
+        match value {
+            ValueSummary::Number(n) => self.number(*n),
+            ValueSummary::List(l) => self.list_text(l),
+            ValueSummary::DicePool { total, .. } => total.to_string(),
+            ValueSummary::SuccessPool { count, .. } => count.to_string(),
+            ValueSummary::NotComputed => "?".to_string(),
+        }
+    }
+
+    // 成功池乘以倍率 (如 "2d6cs>=5 * 2") 时同时展示成功数与倍率，形如 "3 successes × 2 = 6"
+    fn success_product_text(&self, node: &OutputNode) -> Option<String> {
+        let (NodeLayout::Infix(l, r), ValueSummary::Number(product)) = (&node.layout, &node.value)
+        else {
+            return None;
+        };
+        if node.label != "*" {
+            return None;
+        }
+        let (count, multiplier) = match (&l.value, &r.value) {
+            (ValueSummary::SuccessPool { count, .. }, ValueSummary::Number(m))
+            | (ValueSummary::Number(m), ValueSummary::SuccessPool { count, .. }) => (*count, *m),
+            _ => return None,
+        };
+        let noun = if count.abs() == 1 {
+            "success"
+        } else {
+            "successes"
+        };
+        Some(format!(
+            "{} {} × {} = {}",
+            count,
+            noun,
+            self.number(multiplier),
+            self.number(*product)
+        ))
+    }
+
+    // avg 的参数全为整数时，以最简分数展示不是整数的平均值，避免浮点误差
+    fn avg_fraction_text(&self, node: &OutputNode) -> Option<String> {
+        if !self.options.avg_as_fraction || node.label != "avg" {
+            return None;
+        }
+        let NodeLayout::Function(children) = &node.layout else {
+            return None;
+        };
+        let (sum, count) = match &children.first()?.value {
+            ValueSummary::List(values) => {
+                if values.iter().any(|v| v.fract() != 0.0 || v.abs() > 1e15) {
+                    return None;
+                }
+                (values.iter().sum::<f64>() as i64, values.len() as i64)
+            }
+            ValueSummary::DicePool { total, details, .. } => (
+                *total as i64,
+                details.iter().filter(|d| d.is_kept).count() as i64,
+            ),
+            _ => return None,
+        };
+        if count == 0 || sum % count == 0 {
+            return None;
+        }
+        let divisor = gcd(sum.abs(), count);
+        Some(format!("{}/{}", sum / divisor, count / divisor))
+    }
+
+    fn list_text(&self, values: &[f64]) -> String {
+        let items: Vec<String> = values.iter().map(|v| self.number(*v)).collect();
+        format!("[{}]", items.join(", "))
+    }
+
+    // 常量项直接显示带符号的值，其余项显示 "表达式 = 该项对总和的贡献"
+    fn term_text(&self, term: &BreakdownTerm) -> String {
+        let sign = if term.is_negative { -1.0 } else { 1.0 };
+        let contribution = match term.node.value {
+            ValueSummary::Number(n) => Some(n * sign),
+            ValueSummary::DicePool { total, .. } => Some(total as f64 * sign),
+            ValueSummary::SuccessPool { count, .. } => Some(count as f64 * sign),
+            _ => None,
+        };
+        let contribution_text = match contribution {
+            Some(v) => self.number(v),
+            None => self.value_text(&term.node.value),
+        };
+        match (&term.node.layout, contribution) {
+            (NodeLayout::Atom, Some(v)) if v >= 0.0 => format!("+{}", contribution_text),
+            (NodeLayout::Atom, _) => contribution_text,
+            _ => format!(
+                "{}{} = {}",
+                if term.is_negative { "-" } else { "" },
+                self.write_node(&term.node, false),
+                contribution_text
+            ),
+        }
+    }
+
+    // is_pool_operand 表示当前节点是外层骰池修饰符的操作数，
+    // 此时骰子明细由外层节点统一展示，避免重复
+    fn write_node(&self, node: &OutputNode, is_pool_operand: bool) -> String {
+        let is_pool = matches!(
+            node.value,
+            ValueSummary::DicePool { .. } | ValueSummary::SuccessPool { .. }
+        );
+        let label = &node.label;
+        let mut text = match &node.layout {
+            // 原子节点的值即为常量本身，按当前配置重新格式化
+            NodeLayout::Atom => match node.value {
+                ValueSummary::Number(n) => self.number(n),
+                _ => label.clone(),
+            },
+            NodeLayout::List(children) => {
+                let items: Vec<String> =
+                    children.iter().map(|c| self.write_node(c, false)).collect();
+                format!("[{}]", items.join(", "))
+            }
+            NodeLayout::Prefix(child) => format!("{}{}", label, self.write_node(child, false)),
+            NodeLayout::Infix(l, r) => format!(
+                "{} {} {}",
+                self.write_node(l, false),
+                label,
+                self.write_node(r, false)
+            ),
+            NodeLayout::TightInfix(l, r) => {
+                // "d" 的左侧是骰子个数，其余紧凑中缀 (kh, cs...) 的左侧都是被修饰的骰池
+                let left_is_operand = is_pool && label != "d";
+                format!(
+                    "{}{}{}",
+                    self.write_node(l, left_is_operand),
+                    label,
+                    self.write_node(r, false)
+                )
+            }
+            NodeLayout::TightPostfix(child) => {
+                format!("{}{}", self.write_node(child, false), label)
+            }
+            NodeLayout::Function(children) => {
+                // 返回骰池的函数 (如 asdice) 的参数是被转换的池，明细由外层统一展示
+                let items: Vec<String> = children
+                    .iter()
+                    .map(|c| self.write_node(c, is_pool))
+                    .collect();
+                format!("{}({})", label, items.join(", "))
+            }
+            NodeLayout::Filter(op, list, value) => format!(
+                "{}{}{}({})",
+                label,
+                op,
+                self.write_node(value, false),
+                self.write_node(list, false)
+            ),
+            NodeLayout::ConditionalModifier(pool, count, mp) => format!(
+                "{}{}{}{}{}",
+                self.write_node(pool, true),
+                label,
+                self.write_node(count, false),
+                mp.0,
+                self.write_node(&mp.1, false)
+            ),
+            NodeLayout::SpecialModifier(pool, mp, lt, lc, lsz) => {
+                let mut text = format!("{}{}", self.write_node(pool, true), label);
+                if let Some(mp) = mp {
+                    text.push_str(&mp.0);
+                    text.push_str(&self.write_node(&mp.1, false));
+                }
+                if let Some(lt) = lt {
+                    text.push_str("lt");
+                    text.push_str(&self.write_node(lt, false));
+                }
+                if let Some(lc) = lc {
+                    text.push_str("lc");
+                    text.push_str(&self.write_node(lc, false));
+                }
+                if let Some(lsz) = lsz {
+                    text.push_str("lsz");
+                    text.push_str(&self.write_node(lsz, false));
+                }
+                text
+            }
+        };
+
+        if is_pool && !is_pool_operand {
+            text.push(' ');
+            text.push_str(&self.details_text(&node.value));
+        }
+
+        if node.wrap_in_parentheses {
+            format!("({})", text)
+        } else {
+            text
+        }
+    }
+
+    // 骰子明细：被丢弃的骰子用 ~ ~ 包裹，成功标记 ✓，失败标记 ✗，
+    // Fudge/Coin 骰子的结果显示为配置的符号，聚合爆炸的骰子显示每一代的结果，
+    // 被重投的骰子与替换它的骰子显示在同一位置，如 "~1~→5"
+    fn details_text(&self, value: &ValueSummary) -> String {
+        let (face, details) = match value {
+            ValueSummary::DicePool { face, details, .. }
+            | ValueSummary::SuccessPool { face, details, .. } => (face, details),
+            _ => return String::new(),
+        };
+        let mut is_replacement = vec![false; details.len()];
+        for i in details.iter().filter_map(|d| d.replaced_by) {
+            if let Some(flag) = is_replacement.get_mut(i) {
+                *flag = true;
+            }
+        }
+        let items: Vec<String> = details
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !is_replacement[*i])
+            .map(|(_, d)| {
+                let mut chain = vec![self.die_text(face, d)];
+                let mut current = d;
+                while let Some(next) = current.replaced_by.and_then(|i| details.get(i)) {
+                    chain.push(self.die_text(face, next));
+                    current = next;
+                }
+                chain.join("→")
+            })
+            .collect();
+        format!("[{}]", items.join(", "))
+    }
+
+    fn die_text(&self, face: &DiceFaceType, d: &DieDetailSummary) -> String {
+        let mut item = match self.options.symbols.symbol(face, d.result) {
+            Some(symbol) => symbol.to_string(),
+            None => d.result.to_string(),
+        };
+        match d.outcome {
+            // 加权计数时每个成功各标记一次，如 "10✓✓"
+            OutcomeType::Success => item.push_str(&"✓".repeat(d.success_weight.max(1) as usize)),
+            OutcomeType::Failure => item.push('✗'),
+            OutcomeType::None => {}
+        }
+        if is_compound_exploded(d) {
+            item = format!("{} ({})", item, compound_chain_text(&d.roll_history));
+        }
+        if d.is_kept {
+            item
+        } else {
+            format!("~{}~", item)
+        }
+    }
+}
+
+// 聚合爆炸的骰子 roll_history 中依次记录了每一代的结果，显示在合并后的结果之后；
+// 被 min/max 改写过的骰子只显示最终结果
+fn is_compound_exploded(d: &DieDetailSummary) -> bool {
+    d.exploded_times > 0 && d.roll_history.len() == d.exploded_times as usize + 1 && !d.was_clamped
+}
+
+// 除最后一代外，每一代都触发了下一次爆炸，用 ! 标记，如 "6!→5!→2"
+fn compound_chain_text(history: &[i32]) -> String {
+    let last = history.len() - 1;
+    history
+        .iter()
+        .enumerate()
+        .map(|(i, r)| {
+            if i < last {
+                format!("{}!", r)
+            } else {
+                r.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("→")
+}
+
+fn gcd(a: i64, b: i64) -> i64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+// ==========================================
+// 单元测试
+// ==========================================
+
+#[test]
+fn test_format_constant_result() {
+    use crate::runtime::roll_without_animation;
+    let result = roll_without_animation("10/3".to_string(), 10, 10).unwrap();
+    assert_eq!(format_result_text(&result), "3.3333 = 3.3333");
+    let options = TextFormatOptions {
+        max_decimal_places: 1,
+        ..Default::default()
+    };
+    assert_eq!(
+        format_result_text_with_options(&result, &options),
+        "3.3 = 3.3"
+    );
+}
+
+#[test]
+fn test_format_integer_valued_result() {
+    use crate::runtime::roll_without_animation;
+    let result = roll_without_animation("[7.5, 1] * 2".to_string(), 10, 10).unwrap();
+    assert_eq!(format_result_text(&result), "[15, 2] = [15, 2]");
+}
+
+#[test]
+fn test_format_breakdown() {
+    use crate::runtime::roll_breakdown_with_fixed_results;
+    // 常量折叠会将同类项排序，1d8 排在 2d6 之前
+    let breakdown = roll_breakdown_with_fixed_results("2d6 + 1d8 + 3", &[6, 4, 5]).unwrap();
+    assert_eq!(breakdown.terms.len(), 3);
+    assert_eq!(
+        format_breakdown_text(&breakdown),
+        "1d8 [6] = 6, 2d6 [4, 5] = 9, +3, total 18"
+    );
+    let breakdown = roll_breakdown_with_fixed_results("1d20 - 1d4 - 2", &[15, 3]).unwrap();
+    assert_eq!(
+        format_breakdown_text(&breakdown),
+        "1d20 [15] = 15, -1d4 [3] = -3, -2, total 10"
+    );
+}
+
+#[test]
+fn test_format_fudge_with_custom_symbols() {
+    use crate::runtime::roll_with_fixed_results;
+    let result = roll_with_fixed_results("4dF", &[1, -1, 0, 1]).unwrap();
+    assert_eq!(format_result_text(&result), "4dF [+, -, 0, +] = 1");
+    let options = TextFormatOptions {
+        symbols: FaceSymbols {
+            fudge_plus: "▲".to_string(),
+            fudge_minus: "▼".to_string(),
+            fudge_blank: "■".to_string(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    assert_eq!(
+        format_result_text_with_options(&result, &options),
+        "4dF [▲, ▼, ■, ▲] = 1"
+    );
+    let result = roll_with_fixed_results("3dC", &[1, 0, 1]).unwrap();
+    assert_eq!(format_result_text(&result), "3dC [H, T, H] = 2");
+}
+
+#[test]
+fn test_format_large_product() {
+    use crate::runtime::roll_with_fixed_results;
+    let result = roll_with_fixed_results("1d6 * 4000000000000000", &[2]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "1d6 [2] * 4000000000000000 = 8000000000000000"
+    );
+    let result = roll_with_fixed_results("1d6 * 4000000000000000", &[3]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "1d6 [3] * 4000000000000000 = too large"
+    );
+    let result = roll_with_fixed_results("-1d6 * 1e300", &[3]).unwrap();
+    assert!(format_result_text(&result).ends_with(" = -too large"));
+}
+
+#[test]
+fn test_format_compound_explode_chain() {
+    use crate::runtime::roll_with_fixed_results;
+    // 6 与 5 触发了爆炸，2 没有触发
+    let result = roll_with_fixed_results("2d6!!>=5", &[6, 3, 5, 2]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "2d6!!>=5 [13 (6!→5!→2), 3] = 16"
+    );
+    // 被 lt 截断时，最后一代即使满足条件也没有触发爆炸
+    let result = roll_with_fixed_results("1d6!!lt1", &[6, 6]).unwrap();
+    assert_eq!(format_result_text(&result), "1d6!!lt1 [12 (6!→6)] = 12");
+    // 没有爆炸的骰子与普通骰子相同
+    let result = roll_with_fixed_results("2d6!!kh1", &[2, 4]).unwrap();
+    assert_eq!(format_result_text(&result), "2d6!!kh1 [~2~, 4] = 4");
+}
+
+#[test]
+fn test_format_success_times_multiplier() {
+    use crate::runtime::roll_with_fixed_results;
+    let result = roll_with_fixed_results("3d6cs>=5 * 2", &[5, 6, 3]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "3d6cs>=5 [5✓, 6✓, 3] * 2 = 2 successes × 2 = 4"
+    );
+    // 倍率写在左侧时同样识别，单个成功使用单数
+    let result = roll_with_fixed_results("1.5 * 2d6cs>=5", &[5, 2]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "2d6cs>=5 [5✓, 2] * 1.5 = 1 success × 1.5 = 1.5"
+    );
+    // 只在最外层展示，嵌套的乘法保持原样
+    let result = roll_with_fixed_results("2d6cs>=5 * 2 + 1", &[5, 2]).unwrap();
+    assert_eq!(format_result_text(&result), "2d6cs>=5 [5✓, 2] * 2 + 1 = 3");
+}
+
+#[test]
+fn test_format_avg_as_fraction() {
+    use crate::runtime::roll_with_fixed_results;
+    let options = TextFormatOptions {
+        avg_as_fraction: true,
+        ..Default::default()
+    };
+    let result = roll_with_fixed_results("avg([1d6, 1d6])", &[1, 2]).unwrap();
+    assert_eq!(format_result_text(&result), "avg([1d6 [1], 1d6 [2]]) = 1.5");
+    assert_eq!(
+        format_result_text_with_options(&result, &options),
+        "avg([1d6 [1], 1d6 [2]]) = 3/2"
+    );
+    // 分数会被约分，整数平均值依然显示为整数
+    let result = roll_with_fixed_results("avg([1d6, 1d6, 1d6, 1d6])", &[1, 2, 3, 6]).unwrap();
+    assert_eq!(
+        format_result_text_with_options(&result, &options),
+        "avg([1d6 [1], 1d6 [2], 1d6 [3], 1d6 [6]]) = 3"
+    );
+    let result = roll_with_fixed_results("avg([1d6, 1d6, 1d6, 1d6])", &[1, 2, 3, 4]).unwrap();
+    assert_eq!(
+        format_result_text_with_options(&result, &options),
+        "avg([1d6 [1], 1d6 [2], 1d6 [3], 1d6 [4]]) = 5/2"
+    );
+    // 投掷时不折叠整数的非整数平均值，常量参数同样显示为分数
+    let result = roll_with_fixed_results("avg([1,2])", &[]).unwrap();
+    assert_eq!(format_result_text(&result), "avg([1, 2]) = 1.5");
+    assert_eq!(
+        format_result_text_with_options(&result, &options),
+        "avg([1, 2]) = 3/2"
+    );
+    // 参数不全是整数时不显示为分数
+    let result = roll_with_fixed_results("avg([1.5,2])", &[]).unwrap();
+    assert_eq!(
+        format_result_text_with_options(&result, &options),
+        "1.75 = 1.75"
+    );
+    let result = roll_with_fixed_results("10/4", &[]).unwrap();
+    assert_eq!(
+        format_result_text_with_options(&result, &options),
+        "2.5 = 2.5"
+    );
+}
@@ -1,19 +1,31 @@
-use super::render_result::render_result;
+use super::render_result::{
+    RenderOptions, render_breakdown, render_result, render_result_with_options,
+};
 use super::runtime_engine::ExecutionContext;
-use crate::types::output_node::OutputNode;
+use crate::types::hir::HIR;
+use crate::types::output_node::{OutputNode, RollBreakdown};
 use crate::types::runtime_value::*;
+use rand::{Rng, SeedableRng, rngs::StdRng};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use tsify::Tsify;
 use wasm_bindgen::prelude::*;
 
-fn generate_response(request: &RuntimeRequest, counter: &mut u32) -> RuntimeResponse {
-    use rand::Rng;
-    let mut rng = rand::rng();
+// 未指定时使用的递归次数与骰子总数上限，与命令行程序一致
+pub const DEFAULT_RECURSION_LIMIT: u32 = 100;
+pub const DEFAULT_DICE_COUNT_LIMIT: u32 = 1000;
+
+fn generate_response<R: Rng>(
+    request: &RuntimeRequest,
+    counter: &mut u32,
+    rng: &mut R,
+) -> RuntimeResponse {
     let range = match request.face {
         DiceFace::Number(n) => 1..=n, // 这里内部保证n不会小于等于0，至少为1
         DiceFace::Coin => 0..=1,
         DiceFace::Fudge => -1..=1,
+        // 请求中的标签骰子已换成普通骰子，这里按面序号投掷以防万一
+        DiceFace::Labeled(ref faces) => 1..=faces.len() as i32,
     };
     let mut results = Vec::new();
     for _ in 0..request.count {
@@ -46,13 +58,18 @@ impl DiceRollerWithoutAnimation {
         dice_count_limit: u32,
     ) -> Result<Self, String> {
         use super::grammar::parse_dice;
-        use crate::compiler::compile_hir_to_eval_graph;
         use crate::lower::lower_expr;
-        use crate::optimizer::constant_fold::constant_fold_hir;
         let ast = parse_dice(dice_expr.as_str()).map_err(|_| "parse error".to_string())?;
         let hir = lower_expr(ast)?;
-        let hir = constant_fold_hir(hir)?;
-        let context = ExecutionContext::new(compile_hir_to_eval_graph(hir));
+        Self::from_hir(hir, recursion_limit, dice_count_limit)
+    }
+
+    // 跳过解析与类型检查，直接从已构造好的 HIR 开始 (仍会进行常量折叠)
+    pub fn from_hir(hir: HIR, recursion_limit: u32, dice_count_limit: u32) -> Result<Self, String> {
+        use crate::compiler::compile_hir_to_eval_graph;
+        use crate::optimizer::constant_fold::constant_fold_hir_for_runtime;
+        let hir = constant_fold_hir_for_runtime(hir)?;
+        let context = ExecutionContext::new(compile_hir_to_eval_graph(hir))?;
         Ok(DiceRollerWithoutAnimation {
             context,
             recursion_limit,
@@ -69,6 +86,8 @@ impl DiceRollerWithoutAnimation {
             return Err("Cannot evaluate: not in WaitingForEvaluation state".to_string());
         }
 
+        // dice_count_limit 在每轮请求后扣减，这里传入的是扣除已投掷骰子后剩余的数量
+        self.context.set_dice_budget(self.dice_count_limit);
         match self.context.eval_node(self.context.get_root_id()) {
             Ok(Some(_)) => {
                 let output_node =
@@ -128,6 +147,95 @@ impl DiceRollerWithoutAnimation {
             _ => Ok(None),
         }
     }
+
+    // 运行完成后，按指定的渲染选项重新生成结果树
+    pub fn try_get_results_with_options(
+        &self,
+        options: RenderOptions,
+    ) -> Result<Option<OutputNode>, String> {
+        match &self.state {
+            DiceRollerWithoutAnimationState::Error(e) => Err(e.clone()),
+            DiceRollerWithoutAnimationState::Done(_) => Ok(Some(render_result_with_options(
+                self.context.get_graph(),
+                self.context.get_memory(),
+                options,
+            ))),
+            _ => Ok(None),
+        }
+    }
+
+    // 运行完成后，将顶层加法链拆分为逐项结果
+    pub fn try_get_breakdown(&self) -> Result<Option<RollBreakdown>, String> {
+        match &self.state {
+            DiceRollerWithoutAnimationState::Error(e) => Err(e.clone()),
+            DiceRollerWithoutAnimationState::Done(_) => Ok(Some(render_breakdown(
+                self.context.get_graph(),
+                self.context.get_memory(),
+            ))),
+            _ => Ok(None),
+        }
+    }
+
+    // 整个表达式中爆炸产生骰子的总数上限，独立于各修饰符自身的 lt/lc
+    pub fn set_explosion_cap(&mut self, cap: u32) {
+        self.context.set_explosion_cap(Some(cap));
+    }
+
+    pub fn explosion_cap_reached(&self) -> bool {
+        self.context.explosion_cap_reached()
+    }
+
+    // 开启后成功池的净成功数不低于 0
+    pub fn set_clamp_net_successes(&mut self, clamp: bool) {
+        self.context.set_clamp_net_successes(clamp);
+    }
+
+    // round 在 .5 处的取舍方式，默认为四舍五入，需在开始投掷前调用
+    pub fn set_rounding_mode(&mut self, mode: RoundingMode) {
+        self.context.set_rounding_mode(mode);
+    }
+
+    // 开启后记录每一轮爆炸/重投，需在开始投掷前调用
+    pub fn set_trace_enabled(&mut self, enabled: bool) {
+        self.context.set_trace_enabled(enabled);
+    }
+
+    pub fn trace(&self) -> &[TraceEvent] {
+        self.context.trace()
+    }
+
+    // 使用随机结果运行至完成
+    pub fn roll_to_completion(&mut self) -> Result<(), String> {
+        self.roll_to_completion_with_rng(&mut rand::rng())
+    }
+
+    // 使用给定的随机数生成器运行至完成，传入固定种子的生成器即可复现结果
+    pub fn roll_to_completion_with_rng<R: Rng>(&mut self, rng: &mut R) -> Result<(), String> {
+        let mut counter: u32 = 0;
+        while self.try_get_results()?.is_none() {
+            self.evaluation()?;
+            if let DiceRollerWithoutAnimationState::WaitingForResponses(requests) = &self.state {
+                // 模拟骰子结果，这里简单地将每个请求都返回1
+                let responses: Vec<RuntimeResponse> = requests
+                    .into_iter()
+                    .map(|req| generate_response(req, &mut counter, rng))
+                    .collect();
+                self.set_responses(responses)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn run_without_animation(
+    dice_expr: String,
+    recursion_limit: u32,
+    dice_count_limit: u32,
+) -> Result<DiceRollerWithoutAnimation, String> {
+    let mut dice_roller =
+        DiceRollerWithoutAnimation::new(dice_expr, recursion_limit, dice_count_limit)?;
+    dice_roller.roll_to_completion()?;
+    Ok(dice_roller)
 }
 
 #[wasm_bindgen(js_name = rollWithoutAnimation)]
@@ -136,23 +244,148 @@ pub fn roll_without_animation(
     recursion_limit: u32,
     dice_count_limit: u32,
 ) -> Result<OutputNode, String> {
-    let mut dice_roller =
-        DiceRollerWithoutAnimation::new(dice_expr, recursion_limit, dice_count_limit)?;
+    let dice_roller = run_without_animation(dice_expr, recursion_limit, dice_count_limit)?;
+    Ok(dice_roller.try_get_results()?.unwrap())
+}
+
+// 使用固定种子投掷，相同的表达式与种子总是得到相同的结果
+pub fn roll_with_seed(dice_expr: &str, seed: u64) -> Result<OutputNode, String> {
+    let mut dice_roller = DiceRollerWithoutAnimation::new(
+        dice_expr.to_string(),
+        DEFAULT_RECURSION_LIMIT,
+        DEFAULT_DICE_COUNT_LIMIT,
+    )?;
+    dice_roller.roll_to_completion_with_rng(&mut StdRng::seed_from_u64(seed))?;
+    Ok(dice_roller.try_get_results()?.unwrap())
+}
+
+// 动画脚本中的一组骰子：某一轮里对同一种骰子发出的请求及其结果
+#[derive(Debug, Clone, Serialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+pub struct AnimatedRoll {
+    pub face: DiceFace,
+    pub values: Vec<i32>,
+}
+
+// 动画脚本的一帧：每轮动态求值投掷的骰子，最后一帧为最终结果
+#[derive(Debug, Clone, Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+#[serde(tag = "type", content = "value", rename_all = "camelCase")]
+pub enum AnimationFrame {
+    Round(Vec<AnimatedRoll>),
+    Final(OutputNode),
+}
+
+// 使用固定种子投掷，并按轮次记录每次请求的骰子，前端无需实现逐步求值协议即可播放动画
+#[wasm_bindgen(js_name = rollAnimated)]
+pub fn roll_animated(dice_expr: &str, seed: u64) -> Result<Vec<AnimationFrame>, String> {
+    let mut dice_roller = DiceRollerWithoutAnimation::new(
+        dice_expr.to_string(),
+        DEFAULT_RECURSION_LIMIT,
+        DEFAULT_DICE_COUNT_LIMIT,
+    )?;
+    let mut rng = StdRng::seed_from_u64(seed);
     let mut counter: u32 = 0;
-    while dice_roller.try_get_results()?.is_none() {
+    let mut frames = Vec::new();
+    loop {
+        if let Some(output) = dice_roller.try_get_results()? {
+            frames.push(AnimationFrame::Final(output));
+            return Ok(frames);
+        }
         dice_roller.evaluation()?;
         if let DiceRollerWithoutAnimationState::WaitingForResponses(requests) = &dice_roller.state {
-            // 模拟骰子结果，这里简单地将每个请求都返回1
             let responses: Vec<RuntimeResponse> = requests
-                .into_iter()
-                .map(|req| generate_response(req, &mut counter))
+                .iter()
+                .map(|req| generate_response(req, &mut counter, &mut rng))
+                .collect();
+            let rolls = requests
+                .iter()
+                .zip(responses.iter())
+                .map(|(req, resp)| AnimatedRoll {
+                    face: req.face.clone(),
+                    values: resp.results.iter().map(|(value, _)| *value).collect(),
+                })
                 .collect();
+            frames.push(AnimationFrame::Round(rolls));
             dice_roller.set_responses(responses)?;
         }
     }
+}
+
+// 只需要数值的调用方使用：骰子池取总和，成功池取净成功数，结果为列表时报错
+pub fn roll_total(dice_expr: &str) -> Result<f64, String> {
+    let result = roll_without_animation(
+        dice_expr.to_string(),
+        DEFAULT_RECURSION_LIMIT,
+        DEFAULT_DICE_COUNT_LIMIT,
+    )?;
+    total_of(&result)
+}
+
+// 与 roll_total 相同，但使用固定种子
+pub fn roll_total_with_seed(dice_expr: &str, seed: u64) -> Result<f64, String> {
+    total_of(&roll_with_seed(dice_expr, seed)?)
+}
+
+fn total_of(result: &OutputNode) -> Result<f64, String> {
+    result
+        .value
+        .numeric_total()
+        .ok_or_else(|| "The expression evaluates to a list, not a number".to_string())
+}
+
+// 直接求值一个 HIR，便于改写 HIR 的工具 (优化器实验等) 无需重新解析表达式
+pub fn eval_hir(hir: HIR) -> Result<OutputNode, String> {
+    let mut dice_roller = DiceRollerWithoutAnimation::from_hir(
+        hir,
+        DEFAULT_RECURSION_LIMIT,
+        DEFAULT_DICE_COUNT_LIMIT,
+    )?;
+    dice_roller.roll_to_completion()?;
     Ok(dice_roller.try_get_results()?.unwrap())
 }
 
+// 批量投掷多个表达式，第 i 个表达式使用种子 seed + i，
+// 因此结果可以复现，且各表达式之间互不影响 (某个表达式出错也不影响其他表达式)
+pub fn roll_batch(inputs: &[&str], seed: u64) -> Vec<Result<OutputNode, String>> {
+    inputs
+        .iter()
+        .enumerate()
+        .map(|(i, input)| roll_with_seed(input, seed.wrapping_add(i as u64)))
+        .collect()
+}
+
+// 投掷以逗号分隔的多个顶层表达式，如 "1d20+5, 2d6"，每个表达式得到各自完整的结果树
+// 与列表 [1d20+5, 2d6] 不同，各表达式不会被坍缩为数字；递归与骰子数限制对每个表达式分别生效
+pub fn roll_all(
+    dice_expr: &str,
+    recursion_limit: u32,
+    dice_count_limit: u32,
+) -> Result<Vec<OutputNode>, String> {
+    use super::grammar::parse_dice_all;
+    use crate::lower::lower_expr;
+    parse_dice_all(dice_expr)?
+        .into_iter()
+        .map(|ast| {
+            let hir = lower_expr(ast)?;
+            let mut dice_roller =
+                DiceRollerWithoutAnimation::from_hir(hir, recursion_limit, dice_count_limit)?;
+            dice_roller.roll_to_completion()?;
+            Ok(dice_roller.try_get_results()?.unwrap())
+        })
+        .collect()
+}
+
+#[wasm_bindgen(js_name = rollBreakdownWithoutAnimation)]
+pub fn roll_breakdown_without_animation(
+    dice_expr: String,
+    recursion_limit: u32,
+    dice_count_limit: u32,
+) -> Result<RollBreakdown, String> {
+    let dice_roller = run_without_animation(dice_expr, recursion_limit, dice_count_limit)?;
+    Ok(dice_roller.try_get_breakdown()?.unwrap())
+}
+
 // ==========================================
 // 用于配合 @3d-dice/dice-box 使用的类型
 // 对应项目地址：https://github.com/3d-dice/dice-box
@@ -213,11 +446,11 @@ impl DiceRollerWithDiceBox {
         use super::grammar::parse_dice;
         use crate::compiler::compile_hir_to_eval_graph;
         use crate::lower::lower_expr;
-        use crate::optimizer::constant_fold::constant_fold_hir;
+        use crate::optimizer::constant_fold::constant_fold_hir_for_runtime;
         let ast = parse_dice(dice_expr.as_str()).map_err(|_| "parse error".to_string())?;
         let hir = lower_expr(ast)?;
-        let hir = constant_fold_hir(hir)?;
-        let context = ExecutionContext::new(compile_hir_to_eval_graph(hir));
+        let hir = constant_fold_hir_for_runtime(hir)?;
+        let context = ExecutionContext::new(compile_hir_to_eval_graph(hir))?;
         Ok(DiceRollerWithDiceBox {
             context,
             recursion_limit,
@@ -234,6 +467,8 @@ impl DiceRollerWithDiceBox {
             return Err("Cannot evaluate: not in WaitingForEvaluation state".to_string());
         }
 
+        // dice_count_limit 在每轮请求后扣减，这里传入的是扣除已投掷骰子后剩余的数量
+        self.context.set_dice_budget(self.dice_count_limit);
         match self.context.eval_node(self.context.get_root_id()) {
             Ok(Some(_)) => {
                 let output_node =
@@ -339,8 +574,11 @@ impl DiceRollerWithDiceBox {
                 // 然后，检查所有没有被填入的请求，由内置的随机数生成器生成结果
                 for (i, req) in runtime_request.iter().enumerate() {
                     if runtime_responses[i].is_none() {
-                        runtime_responses[i] =
-                            Some(generate_response(req, &mut self._roll_id_counter));
+                        runtime_responses[i] = Some(generate_response(
+                            req,
+                            &mut self._roll_id_counter,
+                            &mut rand::rng(),
+                        ));
                     }
                 }
                 // 最后，收集所有响应，传递给引擎
@@ -356,6 +594,26 @@ impl DiceRollerWithDiceBox {
         }
     }
 
+    #[wasm_bindgen(js_name = setExplosionCap)]
+    pub fn set_explosion_cap(&mut self, cap: u32) {
+        self.context.set_explosion_cap(Some(cap));
+    }
+
+    #[wasm_bindgen(js_name = explosionCapReached)]
+    pub fn explosion_cap_reached(&self) -> bool {
+        self.context.explosion_cap_reached()
+    }
+
+    #[wasm_bindgen(js_name = setClampNetSuccesses)]
+    pub fn set_clamp_net_successes(&mut self, clamp: bool) {
+        self.context.set_clamp_net_successes(clamp);
+    }
+
+    #[wasm_bindgen(js_name = setRoundingMode)]
+    pub fn set_rounding_mode(&mut self, mode: RoundingMode) {
+        self.context.set_rounding_mode(mode);
+    }
+
     #[wasm_bindgen(js_name = removeRequests)]
     pub fn remove_requests(&mut self) -> Vec<DiceBoxId> {
         self.context
@@ -377,3 +635,1515 @@ impl DiceRollerWithDiceBox {
         }
     }
 }
+
+// ==========================================
+// 单元测试
+// ==========================================
+
+// 按给定顺序依次返回骰子结果，便于编写结果确定的求值测试
+#[cfg(test)]
+fn run_with_fixed_results(
+    dice_expr: &str,
+    values: &[i32],
+) -> Result<DiceRollerWithoutAnimation, String> {
+    let mut dice_roller = DiceRollerWithoutAnimation::new(dice_expr.to_string(), 100, 1000)?;
+    finish_with_fixed_results(&mut dice_roller, values)?;
+    Ok(dice_roller)
+}
+
+#[cfg(test)]
+fn finish_with_fixed_results(
+    dice_roller: &mut DiceRollerWithoutAnimation,
+    values: &[i32],
+) -> Result<(), String> {
+    let mut values = values.iter();
+    let mut counter: u32 = 0;
+    while dice_roller.try_get_results()?.is_none() {
+        dice_roller.evaluation()?;
+        if let DiceRollerWithoutAnimationState::WaitingForResponses(requests) = &dice_roller.state {
+            let mut responses = Vec::new();
+            for req in requests.iter() {
+                let mut results = Vec::new();
+                for _ in 0..req.count {
+                    let value = *values.next().expect("not enough fixed dice results");
+                    results.push((value, RollId(counter)));
+                    counter += 1;
+                }
+                responses.push(RuntimeResponse { results });
+            }
+            dice_roller.set_responses(responses)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn roll_with_fixed_results(
+    dice_expr: &str,
+    values: &[i32],
+) -> Result<OutputNode, String> {
+    Ok(run_with_fixed_results(dice_expr, values)?
+        .try_get_results()?
+        .unwrap())
+}
+
+#[cfg(test)]
+pub(crate) fn roll_breakdown_with_fixed_results(
+    dice_expr: &str,
+    values: &[i32],
+) -> Result<RollBreakdown, String> {
+    Ok(run_with_fixed_results(dice_expr, values)?
+        .try_get_breakdown()?
+        .unwrap())
+}
+
+#[test]
+fn test_show_all_dice() {
+    use crate::types::output_node::{DieDetailSummary, ValueSummary};
+    use crate::types::output_visitor::OutputVisitor;
+    struct PoolDetails(Vec<(usize, usize, i32)>);
+    impl OutputVisitor for PoolDetails {
+        fn visit_dice_pool(&mut self, _: &OutputNode, total: i32, details: &[DieDetailSummary]) {
+            let kept = details.iter().filter(|d| d.is_kept).count();
+            self.0.push((details.len(), kept, total));
+        }
+    }
+    let roller = run_with_fixed_results("4d6kh3", &[2, 5, 3, 6]).unwrap();
+    // 默认输出完整名单，被丢弃的骰子仅做标记
+    let result = roller.try_get_results().unwrap().unwrap();
+    let mut all = PoolDetails(Vec::new());
+    all.visit_output(&result);
+    assert_eq!(all.0, vec![(4, 3, 14)]);
+    // 同时给出只含被保留骰子的子集
+    let ValueSummary::DicePool {
+        details,
+        kept_details: Some(kept_details),
+        ..
+    } = &result.value
+    else {
+        panic!("expected a dice pool with kept details");
+    };
+    assert_eq!(details.len(), 4);
+    let kept_results: Vec<i32> = kept_details.iter().map(|d| d.result).collect();
+    assert_eq!(kept_results, vec![5, 3, 6]);
+    // 关闭 show_all 时只输出被保留的骰子，总和不变
+    let options = RenderOptions {
+        show_all: false,
+        ..Default::default()
+    };
+    let mut kept = PoolDetails(Vec::new());
+    kept.visit_output(
+        &roller
+            .try_get_results_with_options(options)
+            .unwrap()
+            .unwrap(),
+    );
+    assert_eq!(kept.0, vec![(3, 3, 14)]);
+    let result = roller
+        .try_get_results_with_options(RenderOptions {
+            show_all: false,
+            ..Default::default()
+        })
+        .unwrap()
+        .unwrap();
+    assert!(matches!(
+        result.value,
+        ValueSummary::DicePool {
+            kept_details: None,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn test_conditional_drop_low() {
+    use crate::render_text::format_result_text;
+    // 最低的骰子为 1，满足 <2，被丢弃
+    let result = roll_with_fixed_results("4d6dl1<2", &[1, 4, 5, 6]).unwrap();
+    assert_eq!(format_result_text(&result), "4d6dl1<2 [~1~, 4, 5, 6] = 15");
+    // 最低的骰子为 3，不满足 <2，保留
+    let result = roll_with_fixed_results("4d6dl1<2", &[3, 4, 5, 6]).unwrap();
+    assert_eq!(format_result_text(&result), "4d6dl1<2 [3, 4, 5, 6] = 18");
+}
+
+#[test]
+fn test_conditional_drop_high() {
+    use crate::render_text::format_result_text;
+    // 最高的两个骰子中只有 6 满足 >=6
+    let result = roll_with_fixed_results("4d6dh2>=6", &[2, 6, 5, 1]).unwrap();
+    assert_eq!(format_result_text(&result), "4d6dh2>=6 [2, ~6~, 5, 1] = 8");
+}
+
+#[test]
+fn test_roll_all_keeps_each_result_tree() {
+    use crate::types::output_node::ValueSummary;
+    // 顶层逗号：两个独立的结果树，各自保留骰池
+    let results = roll_all(
+        "1d20, 2d6",
+        DEFAULT_RECURSION_LIMIT,
+        DEFAULT_DICE_COUNT_LIMIT,
+    )
+    .unwrap();
+    assert_eq!(results.len(), 2);
+    assert!(
+        matches!(&results[0].value, ValueSummary::DicePool { details, .. } if details.len() == 1)
+    );
+    assert!(
+        matches!(&results[1].value, ValueSummary::DicePool { details, .. } if details.len() == 2)
+    );
+    // 列表字面量：一个结果树，其值为两个数字组成的列表
+    let results = roll_all(
+        "[1d20, 2d6]",
+        DEFAULT_RECURSION_LIMIT,
+        DEFAULT_DICE_COUNT_LIMIT,
+    )
+    .unwrap();
+    assert_eq!(results.len(), 1);
+    assert!(matches!(&results[0].value, ValueSummary::List(values) if values.len() == 2));
+    // let 绑定对每个表达式都生效
+    let results = roll_all("let n = 3; n * 2, n + 1", 10, 10).unwrap();
+    assert!(matches!(results[0].value, ValueSummary::Number(6.0)));
+    assert!(matches!(results[1].value, ValueSummary::Number(4.0)));
+    // 单表达式的接口不接受多个顶层表达式
+    assert!(roll_with_seed("1d20, 2d6", 0).is_err());
+}
+
+#[test]
+fn test_rounding_mode_is_shared_by_number_and_list() {
+    use crate::types::output_node::ValueSummary;
+    let roll = |expr: &str, mode: RoundingMode| {
+        let mut dice_roller = DiceRollerWithoutAnimation::new(expr.to_string(), 10, 10).unwrap();
+        dice_roller.set_rounding_mode(mode);
+        dice_roller.roll_to_completion().unwrap();
+        match dice_roller.try_get_results().unwrap().unwrap().value {
+            ValueSummary::List(values) => values,
+            other => panic!("expected a list, got {:?}", other),
+        }
+    };
+    let inputs = [
+        "round([0.5, 1.5, 2.5])",
+        "[round(0.5), round(1.5), round(2.5)]",
+        // 骰子结果同样在运行时取舍 (1d1 总是 1)
+        "round([1d1 / 2, 3d1 / 2, 5d1 / 2])",
+        "[round(1d1 / 2), round(3d1 / 2), round(5d1 / 2)]",
+    ];
+    for input in inputs {
+        assert_eq!(
+            roll(input, RoundingMode::HalfAwayFromZero),
+            vec![1.0, 2.0, 3.0]
+        );
+        assert_eq!(roll(input, RoundingMode::HalfEven), vec![0.0, 2.0, 2.0]);
+    }
+}
+
+#[test]
+fn test_unary_functions_on_success_pool() {
+    use crate::render_text::format_result_text;
+    // 净成功数 1 视为普通数字参与运算
+    let result = roll_with_fixed_results("abs(2d6cs>=4 - 3)", &[5, 2]).unwrap();
+    assert_eq!(format_result_text(&result), "abs(2d6cs>=4 [5✓, 2] - 3) = 2");
+    let result = roll_with_fixed_results("abs(3d6cs>=4df1)", &[1, 1, 6]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "abs(3d6cs>=4df=1 [1✗, 1✗, 6✓]) = 1"
+    );
+    let result = roll_with_fixed_results("floor(2d6cs>=4 / 2)", &[5, 4]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "floor(2d6cs>=4 [5✓, 4✓] / 2) = 1"
+    );
+}
+
+#[test]
+fn test_success_pool_in_arithmetic() {
+    use crate::render_text::format_result_text;
+    // 成功池与数字相加时取净成功数，结果是普通数字
+    let result = roll_with_fixed_results("2d6cs>=5 + 3", &[6, 1]).unwrap();
+    assert_eq!(format_result_text(&result), "2d6cs>=5 [6✓, 1] + 3 = 4");
+    assert_eq!(result.value.numeric_total(), Some(4.0));
+    let result = roll_with_fixed_results("3d6cs>=5df1 + 3", &[1, 1, 6]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "3d6cs>=5df=1 [1✗, 1✗, 6✓] + 3 = 2"
+    );
+    let result = roll_with_fixed_results("3 - 2d6cs>=5", &[5, 6]).unwrap();
+    assert_eq!(format_result_text(&result), "-(2d6cs>=5 [5✓, 6✓]) + 3 = 1");
+}
+
+#[test]
+fn test_compare_success_count() {
+    use crate::render_text::format_result_text;
+    // 比较的是成功数，而不是对骰子再做一次成功判定
+    let result = roll_with_fixed_results("(4d6cs>=5) >= 3", &[5, 6, 2, 6]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "4d6cs>=5 [5✓, 6✓, 2, 6✓] >= 3 = 1"
+    );
+    let result = roll_with_fixed_results("(4d6cs>=5) >= 3", &[5, 1, 2, 6]).unwrap();
+    assert_eq!(result.value.numeric_total(), Some(0.0));
+    let result = roll_with_fixed_results("((2d6) > 7) + 1", &[3, 4]).unwrap();
+    assert_eq!(format_result_text(&result), "(2d6 [3, 4] > 7) + 1 = 1");
+}
+
+#[test]
+fn test_aggregates_over_success_pools() {
+    // 变长参数中的成功池与骰池一样按数值参与聚合，成功池取净成功数
+    let total = |expr: &str, values: &[i32]| {
+        roll_with_fixed_results(expr, values)
+            .unwrap()
+            .value
+            .numeric_total()
+    };
+    assert_eq!(total("sum(2d6cs>=5, 3)", &[6, 1]), Some(4.0));
+    assert_eq!(total("max(2d6cs>=5, 3d6cs>4)", &[5, 6, 5, 6, 1]), Some(2.0));
+    assert_eq!(total("min(2d6cs>=5, 1d4, 2)", &[6, 1, 3]), Some(1.0));
+    assert_eq!(total("avg(4d6cs>3df1, 1)", &[4, 1, 6, 5]), Some(1.5));
+    // 折叠后骰池排在成功池之前，先投掷 2d6
+    assert_eq!(total("sum(3d6cs>=5df1, 2d6)", &[3, 4, 1, 1, 6]), Some(6.0));
+}
+
+#[test]
+fn test_large_constant_list() {
+    use crate::render_text::format_result_text;
+    use crate::types::output_node::ValueSummary;
+    // 长常数列表编译为单个节点，求值与显示与逐个元素编译时相同
+    let items: Vec<String> = (1..=20).map(|i| i.to_string()).collect();
+    let expr = format!("max([{}], 1d4)", items.join(","));
+    let result = roll_with_fixed_results(&expr, &[3]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        format!("max([{}], 1d4 [3]) = [18, 19, 20]", items.join(", "))
+    );
+    let items: Vec<String> = (1..=1000).map(|i| i.to_string()).collect();
+    let expr = format!("filter>990([{}])", items.join(","));
+    let result = roll_with_fixed_results(&expr, &[]).unwrap();
+    let ValueSummary::List(values) = &result.value else {
+        panic!("expected a list");
+    };
+    assert_eq!(values.len(), 10);
+    assert_eq!(values[0], 991.0);
+}
+
+#[test]
+fn test_spend_successes() {
+    use crate::render_text::format_result_text;
+    use crate::types::output_node::ValueSummary;
+    // 5 与 6 计为成功并被消耗，之后的 df 只作用于剩下的骰子
+    let result = roll_with_fixed_results("6d6sp>=5df<=5", &[5, 2, 6, 1, 6, 3]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "6d6sp>=5df<=5 [~5✓~, 2✗, ~6✓~, 1✗, ~6✓~, 3✗] = 0"
+    );
+    let ValueSummary::SuccessPool {
+        successes,
+        failures,
+        details,
+        ..
+    } = &result.value
+    else {
+        panic!("expected a success pool");
+    };
+    assert_eq!((*successes, *failures), (3, 3));
+    let kept: Vec<_> = details.iter().map(|d| d.is_kept).collect();
+    assert_eq!(kept, [false, true, false, true, false, true]);
+    // 被消耗的骰子仍计入 tolist，asdice 之后保持丢弃
+    let result = roll_with_fixed_results("tolist(4d6sp6)", &[6, 2, 6, 1]).unwrap();
+    let ValueSummary::List(values) = &result.value else {
+        panic!("expected a list");
+    };
+    assert_eq!(values, &[1.0, 0.0, 1.0, 0.0]);
+    let result = roll_with_fixed_results("asdice(4d6sp6)kh1", &[6, 2, 6, 1]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "asdice(4d6sp=6)kh1 [~6~, 2, ~6~, ~1~] = 2"
+    );
+}
+
+#[test]
+fn test_net_successes() {
+    use crate::render_text::format_result_text;
+    // >=7 为成功，其余保留的骰子为失败，被丢弃的骰子不参与
+    let result = roll_with_fixed_results("5d10net>=7", &[9, 6, 10, 1, 7]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "5d10net>=7 [9✓, 6✗, 10✓, 1✗, 7✓] = 1"
+    );
+    let result = roll_with_fixed_results("4d10kh3net>=7", &[2, 8, 3, 5]).unwrap();
+    assert_eq!(result.value.numeric_total(), Some(-1.0));
+    // 与阈值互补的 cs 与 df 组合结果相同
+    for seed in 0..20 {
+        let net = roll_with_seed("6d10net>=7", seed).unwrap();
+        let split = roll_with_seed("6d10cs>=7df<7", seed).unwrap();
+        assert_eq!(net.value.numeric_total(), split.value.numeric_total());
+    }
+}
+
+#[test]
+fn test_weighted_success_count() {
+    use crate::render_text::format_result_text;
+    use crate::types::output_node::ValueSummary;
+    // 10 计两个成功，7–9 计一个成功
+    let result = roll_with_fixed_results("6d10cs[=10:2, >=7:1]", &[10, 7, 9, 6, 1, 10]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "6d10cs[=10:2,>=7:1] [10✓✓, 7✓, 9✓, 6, 1, 10✓✓] = 6"
+    );
+    let ValueSummary::SuccessPool {
+        count,
+        successes,
+        details,
+        ..
+    } = &result.value
+    else {
+        panic!("expected a success pool");
+    };
+    assert_eq!((*count, *successes), (6, 4));
+    let weights: Vec<_> = details.iter().map(|d| d.success_weight).collect();
+    assert_eq!(weights, [2, 1, 1, 0, 0, 2]);
+    // 按顺序取第一个满足的条件，先写 >=7 时 10 也只计一个
+    let result = roll_with_fixed_results("2d10cs[>=7:1, =10:2]", &[10, 8]).unwrap();
+    assert_eq!(result.value.numeric_total(), Some(2.0));
+    // 之后的 df 与 tolist 都使用加权后的结果
+    let result = roll_with_fixed_results("3d10cs[=10:2, >=7:1]df1", &[10, 1, 7]).unwrap();
+    assert_eq!(result.value.numeric_total(), Some(2.0));
+    let result = roll_with_fixed_results("tolist(3d10cs[=10:3, >=7:1])", &[10, 1, 7]).unwrap();
+    let ValueSummary::List(values) = &result.value else {
+        panic!("expected a list");
+    };
+    assert_eq!(values, &[3.0, 0.0, 1.0]);
+}
+
+#[test]
+fn test_maxor_minor() {
+    use crate::render_text::format_result_text;
+    // 所有骰子都被丢弃时返回默认值
+    let result = roll_with_fixed_results("maxor(2d6kh0, 0)", &[5, 6]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "maxor(tolist(2d6kh0 [~5~, ~6~]), 0) = 0"
+    );
+    // 列表不为空时与 max/min 相同，默认值同样会被投掷并显示
+    let result = roll_with_fixed_results("minor(3d6, 1d4)", &[5, 2, 6, 3]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "minor(tolist(3d6 [5, 2, 6]), 1d4 [3]) = 2"
+    );
+    let result = roll_with_fixed_results("maxor(filter>4([1d6, 2d6]), -1)", &[3, 1, 2]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "maxor(filter>4([1d6 [3], 2d6 [1, 2]]), -1) = -1"
+    );
+}
+
+#[test]
+fn test_keep_rank_range() {
+    use crate::render_text::format_result_text;
+    // 从大到小排名为 6、5、4、3、2、1，保留第 2 到第 4 名
+    let result = roll_with_fixed_results("6d6k2..4", &[3, 6, 1, 5, 2, 4]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "6d6k2..4 [3, ~6~, ~1~, 5, ~2~, 4] = 12"
+    );
+    // 点数相同时先投出的排名靠前
+    let result = roll_with_fixed_results("6d6k2..3", &[5, 5, 5, 2, 6, 1]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "6d6k2..3 [5, 5, ~5~, ~2~, ~6~, ~1~] = 10"
+    );
+    // 只在仍被保留的骰子中排名，超出骰子数的部分被忽略
+    let result = roll_with_fixed_results("4d6dl1k2..9", &[1, 4, 6, 3]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "4d6dl1k2..9 [~1~, 4, ~6~, 3] = 7"
+    );
+}
+
+#[test]
+fn test_keep_distinct() {
+    use crate::render_text::format_result_text;
+    // 每种点数只保留第一个骰子
+    let result = roll_with_fixed_results("6d6kdistinct", &[1, 1, 2, 3, 3, 6]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "6d6kdistinct [1, ~1~, 2, 3, ~3~, 6] = 12"
+    );
+    // 已被丢弃的骰子不参与去重
+    let result = roll_with_fixed_results("4d6kl2kdistinct", &[5, 2, 2, 6]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "4d6kl2kdistinct [~5~, 2, ~2~, ~6~] = 2"
+    );
+}
+
+#[test]
+fn test_remove_matching_dice() {
+    use crate::render_text::format_result_text;
+    // 只移除第一个 6，第二个 6 保留
+    let result = roll_with_fixed_results("5d6rm=6", &[3, 6, 2, 6, 1]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "5d6rm1=6 [3, ~6~, 2, 6, 1] = 12"
+    );
+    // 与 dl 不同，移除不按大小排序，而是按投掷顺序
+    let result = roll_with_fixed_results("4d6rm2<4", &[3, 1, 2, 5]).unwrap();
+    assert_eq!(format_result_text(&result), "4d6rm2<4 [~3~, ~1~, 2, 5] = 7");
+    // 满足条件的骰子不足 N 个时全部移除
+    let result = roll_with_fixed_results("4d6rm3=6", &[6, 1, 2, 5]).unwrap();
+    assert_eq!(format_result_text(&result), "4d6rm3=6 [~6~, 1, 2, 5] = 8");
+}
+
+#[test]
+fn test_rep_rolls_independently() {
+    use crate::render_text::format_result_text;
+    let result = roll_with_fixed_results("max(rep(1d6, 3))", &[2, 6, 3]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "max([1d6 [2], 1d6 [6], 1d6 [3]]) = 6"
+    );
+}
+
+#[test]
+fn test_keep_elements_fold_matches_runtime() {
+    use crate::optimizer::constant_fold::constant_fold_hir;
+    use crate::types::hir::{HIR, ListType, NumberType};
+    use crate::types::output_node::ValueSummary;
+    // 常量列表在编译期折叠，骰子列表在运行时求值，两条路径在并列时必须保留同样的元素
+    let rolls = [3, 1, 3, 2, 3];
+    let literal = "[3, 1, 3, 2, 3]";
+    for func in ["max", "min"] {
+        for count in 0..=6 {
+            let ast = crate::grammar::parse_dice(&format!("{}({}, {})", func, literal, count));
+            let folded = match constant_fold_hir(crate::lower::lower_expr(ast.unwrap()).unwrap()) {
+                Ok(HIR::List(ListType::Explicit(items))) => items
+                    .into_iter()
+                    .map(|n| match n {
+                        NumberType::Constant(c) => c,
+                        other => panic!("unexpected element {}", other),
+                    })
+                    .collect::<Vec<f64>>(),
+                other => panic!("{}({}) did not fold: {:?}", func, count, other),
+            };
+            let input = format!("{}(tolist(5d6), {})", func, count);
+            let result = roll_with_fixed_results(&input, &rolls).unwrap();
+            match result.value {
+                ValueSummary::List(values) => assert_eq!(values, folded, "{}", input),
+                other => panic!("{} returned {:?}", input, other),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_global_explosion_cap() {
+    use crate::render_text::format_result_text;
+    let mut dice_roller =
+        DiceRollerWithoutAnimation::new("1d6! + 1d6!".to_string(), 100, 1000).unwrap();
+    dice_roller.set_explosion_cap(3);
+    // 两个骰池各自不断掷出 6，共爆炸 3 次后停止
+    finish_with_fixed_results(&mut dice_roller, &[6, 6, 6, 6, 6]).unwrap();
+    let result = dice_roller.try_get_results().unwrap().unwrap();
+    assert!(dice_roller.explosion_cap_reached());
+    assert_eq!(
+        format_result_text(&result),
+        "1d6! [6, 6, 6] + 1d6! [6, 6] = 30"
+    );
+
+    // 未达到上限时不标记
+    let mut dice_roller =
+        DiceRollerWithoutAnimation::new("1d6! + 1d6!".to_string(), 100, 1000).unwrap();
+    dice_roller.set_explosion_cap(3);
+    finish_with_fixed_results(&mut dice_roller, &[6, 2, 3]).unwrap();
+    assert!(!dice_roller.explosion_cap_reached());
+}
+
+#[test]
+fn test_explode_multi() {
+    use crate::render_text::format_result_text;
+    use crate::types::output_node::ValueSummary;
+    // 第一个 6 新增两个骰子，其中的 6 又新增两个
+    let result = roll_with_fixed_results("2d6!x2", &[6, 3, 6, 2, 1, 4]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "2d6!x2 [6, 3, 6, 2, 1, 4] = 22"
+    );
+    // 每个触发爆炸的骰子都恰好新增 N 个骰子
+    for seed in 0..50 {
+        let result = roll_with_seed("6d6!x3", seed).unwrap();
+        let ValueSummary::DicePool { details, .. } = &result.value else {
+            panic!("expected a dice pool");
+        };
+        let triggers = details.iter().filter(|d| d.result == 6).count();
+        assert!(
+            details
+                .iter()
+                .all(|d| d.exploded_times == (d.result == 6) as i32)
+        );
+        assert_eq!(details.len(), 6 + 3 * triggers, "seed {}", seed);
+    }
+    // 新增的骰子数超过剩余的骰子额度时直接报错，不构造请求
+    let mut dice_roller =
+        DiceRollerWithoutAnimation::new("1d2!x1000>=1lt1".to_string(), 10, 500).unwrap();
+    let err = finish_with_fixed_results(&mut dice_roller, &[1]).unwrap_err();
+    assert_eq!(err, "Dice count limit exceeded");
+    // 同一轮中的多个 !x 共用剩余额度：第一个请求 300 个之后只剩 198 个
+    let mut dice_roller =
+        DiceRollerWithoutAnimation::new("1d2!x300>=1lt1 + 1d2!x300>=1lt1".to_string(), 10, 500)
+            .unwrap();
+    dice_roller.evaluation().unwrap();
+    let DiceRollerWithoutAnimationState::WaitingForResponses(requests) = &dice_roller.state else {
+        panic!("expected the initial requests");
+    };
+    let responses = (0..requests.len() as u32)
+        .map(|i| RuntimeResponse {
+            results: vec![(1, RollId(i))],
+        })
+        .collect();
+    dice_roller.set_responses(responses).unwrap();
+    dice_roller.context.set_dice_budget(498);
+    let root = dice_roller.context.get_root_id();
+    assert_eq!(
+        dice_roller.context.eval_node(root).unwrap_err(),
+        "Dice count limit exceeded"
+    );
+    // 全局上限按新增的骰子计数，剩余额度不足 N 时停止
+    let mut dice_roller = DiceRollerWithoutAnimation::new("1d6!x2".to_string(), 100, 1000).unwrap();
+    dice_roller.set_explosion_cap(3);
+    finish_with_fixed_results(&mut dice_roller, &[6, 6, 1]).unwrap();
+    let result = dice_roller.try_get_results().unwrap().unwrap();
+    assert!(dice_roller.explosion_cap_reached());
+    assert_eq!(format_result_text(&result), "1d6!x2 [6, 6, 1] = 13");
+}
+
+#[test]
+fn test_explode_until_success_quota() {
+    use crate::render_text::format_result_text;
+    use crate::types::output_node::ValueSummary;
+    // 初始 [5, 2] 只有 1 个成功，还差 2 个：追加 [6, 1]，再追加 [5] 后达标
+    let result = roll_with_fixed_results("2d6!until(cs>=5, 3)", &[5, 2, 6, 1, 5]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "2d6!until(cs>=5,3) [5, 2, 6, 1, 5] = 19"
+    );
+    // 初始骰子已经达标时不再追加
+    let result = roll_with_fixed_results("3d6!until(cs>=5, 2)", &[6, 5, 1]).unwrap();
+    assert_eq!(result.value.numeric_total(), Some(12.0));
+    // 恰好在第 N 个成功出现时停止：成功数等于 N，且最后一个骰子是成功
+    for seed in 0..50 {
+        let result = roll_with_seed("1d6!until(cs>=5, 3)", seed).unwrap();
+        let ValueSummary::DicePool { details, .. } = &result.value else {
+            panic!("expected a dice pool");
+        };
+        let successes = details.iter().filter(|d| d.result >= 5).count();
+        assert_eq!(successes, 3, "seed {}", seed);
+        assert!(details.last().unwrap().result >= 5, "seed {}", seed);
+    }
+    // 无法达标时最多追加 100 个骰子
+    let result = roll_with_seed("2d6!until(cs>=7, 4)", 0).unwrap();
+    let ValueSummary::DicePool { details, .. } = &result.value else {
+        panic!("expected a dice pool");
+    };
+    assert_eq!(details.len(), 102);
+}
+
+#[test]
+fn test_asdice_round_trip() {
+    use crate::render_text::format_result_text;
+    // 成功标记被清除，骰子可以继续被 kh 等修饰符处理
+    let result = roll_with_fixed_results("asdice(4d6cs>=5)kh1", &[5, 2, 6, 3]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "asdice(4d6cs>=5)kh1 [~5~, ~2~, 6, ~3~] = 6"
+    );
+    // 已被丢弃的骰子保持丢弃状态
+    let result = roll_with_fixed_results("asdice(4d6dl1cs>=5)", &[5, 2, 6, 3]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "asdice(4d6dl1cs>=5) [5, ~2~, 6, 3] = 14"
+    );
+}
+
+#[test]
+fn test_percentile_dice() {
+    use crate::render_text::format_result_text;
+    // d10 的 10 面读作 0：十位 3、个位 7 得到 37
+    let result = roll_with_fixed_results("d%%", &[3, 7]).unwrap();
+    assert_eq!(format_result_text(&result), "d%% [37] = 37");
+    // 十位 00 且个位 0 读作 100
+    let result = roll_with_fixed_results("d%%", &[10, 10]).unwrap();
+    assert_eq!(format_result_text(&result), "d%% [100] = 100");
+    let result = roll_with_fixed_results("d%% + d%%", &[10, 5, 4, 10]).unwrap();
+    assert_eq!(format_result_text(&result), "d%% [5] + d%% [40] = 45");
+}
+
+#[test]
+fn test_success_target_from_another_die() {
+    use crate::render_text::format_result_text;
+    // 难度骰 1d6 只投掷一次，其结果作为整个 d10 骰池的成功阈值
+    let result = roll_with_fixed_results("5d10cs>=(1d6)", &[9, 7, 2, 5, 4, 5]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "5d10cs>=(1d6 [5]) [9✓, 7✓, 2, 5✓, 4] = 3"
+    );
+    let result = roll_with_fixed_results("5d10cs>=(1d6)df<(1d4)", &[9, 7, 2, 5, 1, 6, 2]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "5d10cs>=(1d6 [6])df<(1d4 [2]) [9✓, 7✓, 2, 5, 1✗] = 1"
+    );
+}
+
+#[test]
+fn test_explode_then_count_successes() {
+    use crate::render_text::format_result_text;
+    use crate::types::output_node::ValueSummary;
+    // 修饰符从左到右依次作用：先爆炸，爆炸产生的骰子同样参与成功计数
+    let result = roll_with_fixed_results("2d6!cs>=5", &[6, 3, 5]).unwrap();
+    assert_eq!(format_result_text(&result), "2d6!cs>=5 [6✓, 3, 5✓] = 2");
+    let result = roll_with_fixed_results("2d6cs>=5", &[6, 3]).unwrap();
+    assert_eq!(format_result_text(&result), "2d6cs>=5 [6✓, 3] = 1");
+    // 聚合爆炸将爆炸结果并入原骰子，按合并后的点数计数
+    let result = roll_with_fixed_results("2d6!!cs>=5", &[6, 3, 5]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "2d6!!cs>=5 [11✓ (6!→5), 3] = 1"
+    );
+    // 6 爆炸出 5，连锁爆炸时每一颗新骰子都参与成功判定
+    let result = roll_with_fixed_results("4d6!cs>=5", &[6, 2, 3, 1, 5]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "4d6!cs>=5 [6✓, 2, 3, 1, 5✓] = 2"
+    );
+    let result = roll_with_fixed_results("2d6!cs>=5", &[6, 1, 6, 4]).unwrap();
+    assert_eq!(format_result_text(&result), "2d6!cs>=5 [6✓, 1, 6✓, 4] = 2");
+    // 随机投掷中，成功数总是等于所有保留骰子 (含爆炸出的) 中满足条件的个数
+    let mut saw_exploded_success = false;
+    for seed in 0..200 {
+        let ValueSummary::SuccessPool { count, details, .. } =
+            roll_with_seed("4d6!cs>=5", seed).unwrap().value
+        else {
+            panic!("expected a success pool");
+        };
+        let expected = details
+            .iter()
+            .filter(|d| d.is_kept && d.result >= 5)
+            .count();
+        assert_eq!(count as usize, expected);
+        saw_exploded_success |= details.iter().skip(4).any(|d| d.result >= 5);
+    }
+    assert!(saw_exploded_success);
+}
+
+#[test]
+fn test_success_cascade() {
+    use crate::render_text::format_result_text;
+    use crate::types::output_node::ValueSummary;
+    // 两个成功奖励两颗骰子，其中的 6 再奖励一颗，lt2 之后不再奖励
+    let result = roll_with_fixed_results("3d6cascade>=5lt2", &[5, 2, 6, 6, 1, 5]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "3d6cascade>=5lt2 [5✓, 2, 6✓, 6✓, 1, 5✓] = 4"
+    );
+    let result = roll_with_fixed_results("2d6cascade>=5lt3", &[1, 2]).unwrap();
+    assert_eq!(format_result_text(&result), "2d6cascade>=5lt3 [1, 2] = 0");
+    // 每一轮奖励的骰子数等于上一轮的成功数，最多奖励 lt 轮
+    let mut saw_second_round = false;
+    for seed in 0..200 {
+        let ValueSummary::SuccessPool { count, details, .. } =
+            roll_with_seed("4d6cascade>=5lt2", seed).unwrap().value
+        else {
+            panic!("expected a success pool");
+        };
+        let successes = |dice: &[_]| {
+            dice.iter()
+                .filter(|d: &&crate::types::output_node::DieDetailSummary| d.result >= 5)
+                .count()
+        };
+        let first = successes(&details[..4]);
+        let second = successes(&details[4..4 + first]);
+        assert_eq!(details.len(), 4 + first + second, "seed {}", seed);
+        assert_eq!(count as usize, successes(&details), "seed {}", seed);
+        saw_second_round |= second > 0;
+    }
+    assert!(saw_second_round);
+}
+
+#[test]
+fn test_implicit_equal_mod_param() {
+    use crate::render_text::format_result_text;
+    // !3 只在投出 3 时爆炸，6 不会触发
+    let result = roll_with_fixed_results("2d6!3", &[3, 6, 2]).unwrap();
+    assert_eq!(format_result_text(&result), "2d6!=3 [3, 6, 2] = 11");
+    // r1 只重投 1，2 保留
+    let result = roll_with_fixed_results("2d6r1", &[1, 2, 5]).unwrap();
+    assert_eq!(format_result_text(&result), "2d6r=1 [~1~→5, 2] = 7");
+    // cs6 只有 6 计为成功
+    let result = roll_with_fixed_results("3d6cs6", &[6, 5, 4]).unwrap();
+    assert_eq!(format_result_text(&result), "3d6cs=6 [6✓, 5, 4] = 1");
+}
+
+#[test]
+fn test_reroll_transcript() {
+    use crate::render_text::format_result_text;
+    use crate::types::output_node::ValueSummary;
+    // 被重投的骰子仍留在明细中但不计入总和，文本中紧跟着替换它的骰子
+    let result = roll_with_fixed_results("2d6r1", &[1, 2, 1, 5]).unwrap();
+    assert_eq!(format_result_text(&result), "2d6r=1 [~1~→~1~→5, 2] = 7");
+    let ValueSummary::DicePool { details, total, .. } = &result.value else {
+        panic!("expected a dice pool");
+    };
+    assert_eq!(*total, 7);
+    let flags: Vec<_> = details
+        .iter()
+        .map(|d| (d.result, d.is_rerolled, d.is_kept, d.replaced_by))
+        .collect();
+    assert_eq!(
+        flags,
+        [
+            (1, true, false, Some(2)),
+            (2, false, true, None),
+            (1, true, false, Some(3)),
+            (5, false, true, None),
+        ]
+    );
+    // 替换后的骰子仍参与之后的修饰符
+    let result = roll_with_fixed_results("3d6r1kh2", &[1, 4, 6, 3]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "3d6r=1kh2 [~1~→~3~, 4, 6] = 10"
+    );
+    // 只输出被保留的骰子时不包含重投记录
+    let roller = run_with_fixed_results("2d6r1", &[1, 2, 5]).unwrap();
+    let options = RenderOptions {
+        show_all: false,
+        ..Default::default()
+    };
+    let result = roller
+        .try_get_results_with_options(options)
+        .unwrap()
+        .unwrap();
+    assert_eq!(format_result_text(&result), "2d6r=1 [2, 5] = 7");
+}
+
+#[test]
+fn test_keep_zero() {
+    use crate::render_text::format_result_text;
+    // 保留 0 个骰子时全部丢弃，总和为 0；丢弃 0 个骰子时全部保留
+    let result = roll_with_fixed_results("2d20kh0", &[5, 19]).unwrap();
+    assert_eq!(format_result_text(&result), "2d20kh0 [~5~, ~19~] = 0");
+    let result = roll_with_fixed_results("2d20kl0 + 1", &[5, 19]).unwrap();
+    assert_eq!(format_result_text(&result), "2d20kl0 [~5~, ~19~] + 1 = 1");
+    let result = roll_with_fixed_results("3d6dl0", &[3, 5, 6]).unwrap();
+    assert_eq!(format_result_text(&result), "3d6dl0 [3, 5, 6] = 14");
+}
+
+#[test]
+fn test_clamp_net_successes() {
+    use crate::render_text::format_result_text;
+    // 1 个成功，2 个失败，默认净成功数为 -1
+    let result = roll_with_fixed_results("3d6cs=6df=1", &[6, 1, 1]).unwrap();
+    assert_eq!(format_result_text(&result), "3d6cs=6df=1 [6✓, 1✗, 1✗] = -1");
+
+    // 开启后净成功数不低于 0
+    let mut dice_roller =
+        DiceRollerWithoutAnimation::new("3d6cs=6df=1".to_string(), 100, 1000).unwrap();
+    dice_roller.set_clamp_net_successes(true);
+    finish_with_fixed_results(&mut dice_roller, &[6, 1, 1]).unwrap();
+    let result = dice_roller.try_get_results().unwrap().unwrap();
+    assert_eq!(format_result_text(&result), "3d6cs=6df=1 [6✓, 1✗, 1✗] = 0");
+
+    // 成功多于失败时不受影响
+    let mut dice_roller =
+        DiceRollerWithoutAnimation::new("3d6cs=6df=1".to_string(), 100, 1000).unwrap();
+    dice_roller.set_clamp_net_successes(true);
+    finish_with_fixed_results(&mut dice_roller, &[6, 6, 1]).unwrap();
+    let result = dice_roller.try_get_results().unwrap().unwrap();
+    assert_eq!(format_result_text(&result), "3d6cs=6df=1 [6✓, 6✓, 1✗] = 1");
+}
+
+#[test]
+fn test_explode_pool_size_limit() {
+    use crate::render_text::format_result_text;
+    // 每个骰子都在爆炸，但骰子池最多增长到 5 个骰子
+    let result = roll_with_fixed_results("2d6!lsz5", &[6, 6, 6, 6, 6]).unwrap();
+    assert_eq!(format_result_text(&result), "2d6!lsz5 [6, 6, 6, 6, 6] = 30");
+    // 未达到上限时正常结束
+    let result = roll_with_fixed_results("2d6!lsz5", &[6, 2, 3]).unwrap();
+    assert_eq!(format_result_text(&result), "2d6!lsz5 [6, 2, 3] = 11");
+    // 初始骰子数已达到上限时不再爆炸
+    let result = roll_with_fixed_results("3d6!lsz2", &[6, 6, 6]).unwrap();
+    assert_eq!(format_result_text(&result), "3d6!lsz2 [6, 6, 6] = 18");
+    // 被 lc 拒绝的骰子不占用骰子池大小的余量
+    let result = roll_with_fixed_results("3d6!lc1lsz5", &[6, 6, 6, 6]).unwrap();
+    assert_eq!(format_result_text(&result), "3d6!lc1lsz5 [6, 6, 6, 6] = 24");
+}
+
+#[test]
+fn test_aggregate_kept_dice() {
+    use crate::render_text::format_result_text;
+    // sum 与 avg 只作用于被保留的骰子：(4 + 5 + 6) / 3 = 5
+    let result = roll_with_fixed_results("avg(4d6dl1)", &[1, 4, 5, 6]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "avg(tolist(4d6dl1 [~1~, 4, 5, 6])) = 5"
+    );
+    let result = roll_with_fixed_results("sum(4d6dl1)", &[1, 4, 5, 6]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "sum(tolist(4d6dl1 [~1~, 4, 5, 6])) = 15"
+    );
+    // 其他函数仍把骰子池当作一个数
+    let result = roll_with_fixed_results("max(4d6dl1)", &[1, 4, 5, 6]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "max([4d6dl1 [~1~, 4, 5, 6]]) = 15"
+    );
+}
+
+#[test]
+fn test_pool_raw_total() {
+    use crate::types::output_node::ValueSummary;
+    fn totals(expr: &str, values: &[i32]) -> (i32, i32) {
+        match roll_with_fixed_results(expr, values).unwrap().value {
+            ValueSummary::DicePool {
+                total, raw_total, ..
+            } => (total, raw_total),
+            _ => panic!("expected a dice pool"),
+        }
+    }
+    // 被丢弃的 1 只计入 raw_total
+    assert_eq!(totals("4d6dl1", &[3, 1, 5, 6]), (14, 15));
+    assert_eq!(totals("4d6", &[3, 1, 5, 6]), (15, 15));
+    assert_eq!(totals("2d20kh1", &[7, 18]), (18, 25));
+}
+
+#[test]
+fn test_pool_highest_and_lowest() {
+    use crate::types::output_node::ValueSummary;
+    fn high_low(expr: &str, values: &[i32]) -> (Option<i32>, Option<i32>) {
+        match roll_with_fixed_results(expr, values).unwrap().value {
+            ValueSummary::DicePool {
+                highest, lowest, ..
+            }
+            | ValueSummary::SuccessPool {
+                highest, lowest, ..
+            } => (highest, lowest),
+            _ => panic!("expected a pool"),
+        }
+    }
+    // 被丢弃的 2 不参与统计
+    assert_eq!(high_low("4d6kh3", &[2, 5, 3, 6]), (Some(6), Some(3)));
+    assert_eq!(high_low("3d10cs>=7", &[1, 10, 7]), (Some(10), Some(1)));
+    // 没有被保留的骰子
+    assert_eq!(high_low("2d6kh0", &[4, 5]), (None, None));
+}
+
+#[test]
+fn test_min_max_marks_clamped_dice() {
+    use crate::types::output_node::ValueSummary;
+    fn clamped(expr: &str, values: &[i32]) -> Vec<(i32, bool)> {
+        match roll_with_fixed_results(expr, values).unwrap().value {
+            ValueSummary::DicePool { details, .. } => {
+                details.iter().map(|d| (d.result, d.was_clamped)).collect()
+            }
+            _ => panic!("expected a dice pool"),
+        }
+    }
+    // 1 和 2 被提升到 3，原本就是 3 的骰子不算被改写
+    assert_eq!(
+        clamped("4d6min3", &[1, 5, 3, 2]),
+        vec![(3, true), (5, false), (3, false), (3, true)]
+    );
+    assert_eq!(
+        clamped("3d6max4", &[6, 4, 1]),
+        vec![(4, true), (4, false), (1, false)]
+    );
+    // 被丢弃的骰子不会被改写
+    assert_eq!(
+        clamped("3d6kh2min3", &[1, 5, 2]),
+        vec![(1, false), (5, false), (3, true)]
+    );
+}
+
+#[test]
+fn test_coin_tally() {
+    use crate::types::output_node::{CoinTally, ValueSummary};
+    fn coins(expr: &str, seed: u64) -> Option<CoinTally> {
+        match roll_with_seed(expr, seed).unwrap().value {
+            ValueSummary::DicePool { coins, .. } => coins,
+            _ => panic!("expected a dice pool"),
+        }
+    }
+    let tally = coins("10dC", 7).unwrap();
+    assert_eq!(tally.heads + tally.tails, 10);
+    // 正面的个数即为总和
+    let result = roll_with_fixed_results("10dC", &[1, 0, 1, 1, 0, 1, 0, 1, 1, 0]).unwrap();
+    match result.value {
+        ValueSummary::DicePool { total, coins, .. } => {
+            assert_eq!(coins, Some(CoinTally { heads: 6, tails: 4 }));
+            assert_eq!(total, 6);
+        }
+        _ => panic!("expected a dice pool"),
+    }
+    assert_eq!(coins("4d6", 7), None);
+}
+
+#[test]
+fn test_success_pool_tallies() {
+    use crate::types::output_node::ValueSummary;
+    // 7、9 为成功，1 为失败，4 两者都不是
+    let result = roll_with_fixed_results("4d10cs>=7df=1", &[7, 1, 9, 4]).unwrap();
+    match result.value {
+        ValueSummary::SuccessPool {
+            count,
+            successes,
+            failures,
+            ..
+        } => assert_eq!((successes, failures, count), (2, 1, 1)),
+        _ => panic!("expected a success pool"),
+    }
+}
+
+#[test]
+#[cfg(feature = "debug-modifiers")]
+fn test_reroll_max() {
+    use crate::render_text::format_result_text;
+    // 小于 3 的骰子不再投掷，直接换成最大面
+    let result = roll_with_fixed_results("4d6rmax<3", &[1, 5, 2, 4]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "4d6rmax<3 [~1~→6, 5, ~2~→6, 4] = 21"
+    );
+    let result = roll_with_fixed_results("3dFrmax<1", &[-1, 1, 0]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "3dFrmax<1 [~-~→+, +, ~0~→+] = 3"
+    );
+}
+
+#[test]
+#[cfg(not(feature = "debug-modifiers"))]
+fn test_reroll_max_requires_feature() {
+    assert!(roll_with_fixed_results("4d6rmax<3", &[1, 5, 2, 4]).is_err());
+}
+
+#[test]
+fn test_labeled_dice() {
+    use crate::render_text::format_result_text;
+    use crate::types::output_node::{DiceFaceType, ValueSummary};
+    // 投出第 3 面与第 2 面，显示标签，运算使用对应的值
+    let result = roll_with_fixed_results("2d[miss:0, hit:1, crit:2] * 3", &[3, 2]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "2d[miss:0,hit:1,crit:2] [crit, hit] * 3 = 9"
+    );
+    let result = roll_with_fixed_results("3d[miss:0, hit:1, crit:2]cs>=1", &[1, 3, 2]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "3d[miss:0,hit:1,crit:2]cs>=1 [miss, crit✓, hit✓] = 2"
+    );
+    let result = roll_with_fixed_results("2d[miss:0, hit:1, crit:2]", &[1, 3]).unwrap();
+    let ValueSummary::DicePool {
+        total,
+        face,
+        details,
+        ..
+    } = result.value
+    else {
+        panic!("expected a dice pool");
+    };
+    assert_eq!(total, 2);
+    assert!(matches!(face, DiceFaceType::Labeled(ref faces) if faces.len() == 3));
+    let symbols: Vec<_> = details.iter().map(|d| d.symbol.as_deref()).collect();
+    assert_eq!(symbols, [Some("miss"), Some("crit")]);
+    // 超出面数的投掷结果是错误
+    assert!(roll_with_fixed_results("1d[a:1, b:2]", &[3]).is_err());
+}
+
+#[test]
+fn test_alldice() {
+    use crate::types::output_node::{DieDetailSummary, OutputNode, ValueSummary};
+    use crate::types::output_visitor::OutputVisitor;
+    struct KeptDice(usize);
+    impl OutputVisitor for KeptDice {
+        fn visit_dice_pool(&mut self, _: &OutputNode, _: i32, details: &[DieDetailSummary]) {
+            self.0 += details.iter().filter(|d| d.is_kept).count();
+        }
+    }
+    // 长度等于各骰子池被保留的骰子数之和
+    let result =
+        roll_with_fixed_results("alldice(4d6kh3, 2d8, 1d4!)", &[1, 5, 4, 2, 7, 3, 4, 2]).unwrap();
+    let ValueSummary::List(values) = &result.value else {
+        panic!("expected a list");
+    };
+    assert_eq!(values, &[5.0, 4.0, 2.0, 7.0, 3.0, 4.0, 2.0]);
+    for seed in 0..20 {
+        let result = roll_with_seed("alldice(3d6dl1, 2d10!, 1d4)", seed).unwrap();
+        let ValueSummary::List(values) = &result.value else {
+            panic!("expected a list");
+        };
+        let mut counter = KeptDice(0);
+        counter.visit_output(&result);
+        assert_eq!(values.len(), counter.0, "seed {}", seed);
+    }
+}
+
+#[test]
+fn test_round_to_multiple() {
+    // 80 / 25 = 3.2，舍入后乘回倍数
+    let result = roll_with_fixed_results("roundto(3d6 * 10, 25)", &[2, 4, 2]).unwrap();
+    assert_eq!(result.value.numeric_total(), Some(75.0));
+    let result = roll_with_fixed_results("ceilto(3d6 * 10, 25)", &[2, 4, 2]).unwrap();
+    assert_eq!(result.value.numeric_total(), Some(100.0));
+    let result = roll_with_fixed_results("floorto(1d20, 5)", &[19]).unwrap();
+    assert_eq!(result.value.numeric_total(), Some(15.0));
+}
+
+#[test]
+fn test_highdie_lowdie() {
+    use crate::render_text::format_result_text;
+    // 取最大的一颗骰子再加 2，骰池本身仍然完整显示
+    let result = roll_with_fixed_results("highdie(4d6) + 2", &[3, 1, 6, 2]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "highdie(4d6 [3, 1, 6, 2]) + 2 = 8"
+    );
+    // 只考虑被保留的骰子
+    let result = roll_with_fixed_results("lowdie(4d6kh3)", &[3, 1, 6, 2]).unwrap();
+    assert_eq!(result.value.numeric_total(), Some(2.0));
+    let result = roll_with_fixed_results("highdie(2d20dh1)", &[17, 9]).unwrap();
+    assert_eq!(result.value.numeric_total(), Some(9.0));
+    // 没有保留任何骰子时报错
+    let err = roll_with_fixed_results("lowdie(2d6dl2)", &[3, 4]).unwrap_err();
+    assert!(err.contains("no kept dice"));
+}
+
+#[test]
+fn test_each_die_adjustment() {
+    use crate::render_text::format_result_text;
+    use crate::types::output_node::ValueSummary;
+    // 每颗骰子加 1，总和随之更新
+    let result = roll_with_fixed_results("each(4d6, +1)", &[3, 1, 6, 2]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "each(4d6, +1) [4, 2, 7, 3] = 16"
+    );
+    let ValueSummary::DicePool { details, .. } = &result.value else {
+        panic!("expected a dice pool");
+    };
+    let history: Vec<_> = details.iter().map(|d| d.roll_history.clone()).collect();
+    assert_eq!(history, [vec![3], vec![1], vec![6], vec![2]]);
+    // 先调整再保留
+    let result = roll_with_fixed_results("each(4d6, -1)kh3", &[3, 1, 6, 2]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "each(4d6, -1)kh3 [2, ~0~, 5, 1] = 8"
+    );
+    // 只调整保留的骰子
+    let result = roll_with_fixed_results("each(2d6kh1, *2)", &[4, 5]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "each(2d6kh1, *2) [~4~, 10] = 10"
+    );
+    let result = roll_with_fixed_results("each(3d6, +2)cs>=7", &[5, 4, 6]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "each(3d6, +2)cs>=7 [7✓, 6, 8✓] = 2"
+    );
+}
+
+#[test]
+fn test_roll_total() {
+    assert_eq!(roll_total("2 + 3 * 4").unwrap(), 14.0);
+    // 骰子池与成功池分别取总和与净成功数，与完整结果树一致
+    for (input, seed) in [("3d6 + 1", 7), ("4d6kh3", 8), ("6d10cs>=7df1", 9)] {
+        let total = roll_total_with_seed(input, seed).unwrap();
+        let result = roll_with_seed(input, seed).unwrap();
+        assert_eq!(Some(total), result.value.numeric_total());
+    }
+    let total = roll_total("3d6").unwrap();
+    assert!((3.0..=18.0).contains(&total));
+    assert!(roll_total("[1d6, 2]").is_err());
+    assert!(roll_total_with_seed("sort(3d6)", 0).is_err());
+}
+
+#[test]
+fn test_reroll_limit_times_vs_counts() {
+    use crate::render_text::format_result_text;
+    // lt 限制的是重投轮数：一轮内所有满足条件的骰子都会重投
+    let result = roll_with_fixed_results("3d6r<3lt1", &[1, 1, 1, 2, 4, 5]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "3d6r<3lt1 [~1~→2, ~1~→4, ~1~→5] = 11"
+    );
+    // lc 限制的是整个骰池重投的骰子总数：只有前两个骰子被重投
+    let result = roll_with_fixed_results("3d6r<3lc2", &[1, 1, 1, 4, 5]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "3d6r<3lc2 [~1~→4, ~1~→5, 1] = 10"
+    );
+    // lc 跨轮累计：同一个骰子的两次重投用尽了整个骰池的额度
+    let result = roll_with_fixed_results("2d6r<3lc2", &[1, 5, 2, 1]).unwrap();
+    assert_eq!(format_result_text(&result), "2d6r<3lc2 [~1~→~2~→1, 5] = 6");
+}
+
+#[test]
+fn test_reroll_pool() {
+    use crate::render_text::format_result_text;
+    // 总和 4 < 6，整池重投一次后总和 9，不再满足条件
+    let result = roll_with_fixed_results("2d6rp<6", &[1, 3, 4, 5]).unwrap();
+    assert_eq!(format_result_text(&result), "2d6rp<6 [~1~→4, ~3~→5] = 9");
+    // 不满足条件时不重投
+    let result = roll_with_fixed_results("2d6rerollpool<6", &[4, 3]).unwrap();
+    assert_eq!(format_result_text(&result), "2d6rp<6 [4, 3] = 7");
+    // lt 限制整池重投的次数，即使总和依然满足条件也会停止
+    let result = roll_with_fixed_results("2d6rp<13lt2", &[1, 1, 2, 2, 3, 3]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "2d6rp<13lt2 [~1~→~2~→3, ~1~→~2~→3] = 6"
+    );
+}
+
+#[test]
+fn test_reroll_pool_without_limit_stops_at_recursion_limit() {
+    // 条件永远满足且没有 lt 时，由投掷轮数限制兜底
+    let mut dice_roller = DiceRollerWithoutAnimation::new("1d6rp<7".to_string(), 10, 1000).unwrap();
+    let err = finish_with_fixed_results(&mut dice_roller, &[1; 20]).unwrap_err();
+    assert_eq!(err, "Recursion limit exceeded");
+}
+
+#[test]
+fn test_dynamic_op_trace() {
+    use TraceEvent::*;
+    // 第一轮 [6, 2] 中的 6 爆炸得到 6，第二轮继续爆炸得到 3 后结束
+    let mut dice_roller = DiceRollerWithoutAnimation::new("2d6!".to_string(), 100, 1000).unwrap();
+    dice_roller.set_trace_enabled(true);
+    finish_with_fixed_results(&mut dice_roller, &[6, 2, 6, 3]).unwrap();
+    let node_id = match dice_roller.trace().first() {
+        Some(Requested { node_id, .. }) => *node_id,
+        other => panic!("unexpected first event: {:?}", other),
+    };
+    let kind = DynamicOpKind::Explode;
+    assert_eq!(
+        dice_roller.trace(),
+        &[
+            Requested {
+                node_id,
+                kind,
+                triggered_by: vec![6],
+                count: 1
+            },
+            Resolved {
+                node_id,
+                kind,
+                results: vec![6]
+            },
+            Requested {
+                node_id,
+                kind,
+                triggered_by: vec![6],
+                count: 1
+            },
+            Resolved {
+                node_id,
+                kind,
+                results: vec![3]
+            },
+            Finished {
+                node_id,
+                kind,
+                total: 17
+            },
+        ]
+    );
+
+    // 未开启时不记录
+    let dice_roller = run_with_fixed_results("2d6!", &[6, 2, 6, 3]).unwrap();
+    assert!(dice_roller.trace().is_empty());
+}
+
+#[test]
+fn test_gen_rolls_independently() {
+    use crate::render_text::format_result_text;
+    let result = roll_with_fixed_results("gen(5, 1d6)", &[1, 2, 3, 4, 5]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "[1d6 [1], 1d6 [2], 1d6 [3], 1d6 [4], 1d6 [5]] = [1, 2, 3, 4, 5]"
+    );
+    let result = roll_with_fixed_results("len(gen(5, 1d6))", &[]).unwrap();
+    assert_eq!(format_result_text(&result), "5 = 5");
+}
+
+#[test]
+fn test_explode_if_total() {
+    use crate::render_text::format_result_text;
+    // 总和 14 < 20，即使有 6 也不爆炸
+    let result = roll_with_fixed_results("4d6!total>=20", &[6, 3, 4, 1, 6]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "4d6!total>=20 [6, 3, 4, 1] = 14"
+    );
+    // 总和 23 >= 20，三个 6 各爆炸一次，爆炸出的 6 不会再爆炸
+    let result = roll_with_fixed_results("4d6!total>=20", &[6, 6, 5, 6, 6, 2, 1]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "4d6!total>=20 [6, 6, 5, 6, 6, 2, 1] = 32"
+    );
+}
+
+#[test]
+fn test_explode_once() {
+    use crate::render_text::format_result_text;
+    // 两个 6 各爆炸一次，爆炸出的 6 不会再爆炸
+    let result = roll_with_fixed_results("3d6!o", &[6, 2, 6, 6, 6]).unwrap();
+    assert_eq!(format_result_text(&result), "3d6!o [6, 2, 6, 6, 6] = 26");
+    // 对比普通爆炸：同样的结果会继续请求新的骰子
+    let result = roll_with_fixed_results("3d6!", &[6, 2, 6, 6, 6, 1, 1]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "3d6! [6, 2, 6, 6, 6, 1, 1] = 28"
+    );
+}
+
+#[test]
+fn test_keep_above_average() {
+    use crate::render_text::format_result_text;
+    // 平均值为 4，只保留严格大于 4 的骰子
+    let result = roll_with_fixed_results("4d6kh>avg", &[5, 1, 6, 4]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "4d6kh>avg [5, ~1~, 6, ~4~] = 11"
+    );
+    // 平均值只基于被保留的骰子：丢弃 1 之后平均值为 5
+    let result = roll_with_fixed_results("4d6dl1kh>=avg", &[5, 1, 6, 4]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "4d6dl1kh>=avg [5, ~1~, 6, ~4~] = 11"
+    );
+    let result = roll_with_fixed_results("4d6kl<avg", &[5, 1, 6, 4]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "4d6kl<avg [~5~, 1, ~6~, ~4~] = 1"
+    );
+}
+
+#[test]
+fn test_roll_batch_matches_seeded_rolls() {
+    use crate::render_text::format_result_text;
+    let inputs = ["4d6kh3", "1d20+5", "1d6/0", "10d10!"];
+    let batch = roll_batch(&inputs, 42);
+    assert_eq!(batch.len(), inputs.len());
+    for (i, (input, result)) in inputs.iter().zip(&batch).enumerate() {
+        let single = roll_with_seed(input, 42 + i as u64);
+        assert_eq!(
+            result.as_ref().map(format_result_text),
+            single.as_ref().map(format_result_text)
+        );
+    }
+    // 出错的表达式不影响其他表达式
+    assert!(batch[2].is_err());
+    assert!(batch[3].is_ok());
+    // 同一种子总是得到相同的结果
+    let again = roll_batch(&inputs, 42);
+    assert_eq!(
+        format_result_text(batch[3].as_ref().unwrap()),
+        format_result_text(again[3].as_ref().unwrap())
+    );
+}
+
+#[test]
+fn test_roll_animated_frames() {
+    use crate::render_text::format_result_text;
+    let frames = roll_animated("10d6!", 15).unwrap();
+    // 这个种子下连续爆炸三轮：初始投掷 + 三轮爆炸投掷 + 最终结果
+    assert_eq!(frames.len(), 5);
+    let rounds: Vec<&Vec<AnimatedRoll>> = frames
+        .iter()
+        .filter_map(|frame| match frame {
+            AnimationFrame::Round(rolls) => Some(rolls),
+            AnimationFrame::Final(_) => None,
+        })
+        .collect();
+    assert_eq!(rounds.len(), frames.len() - 1);
+    assert_eq!(rounds[0].len(), 1);
+    assert!(matches!(rounds[0][0].face, DiceFace::Number(6)));
+    assert_eq!(rounds[0][0].values.len(), 10);
+    // 每轮新投掷的骰子数等于上一轮掷出的 6 的个数
+    for pair in rounds.windows(2) {
+        let sixes = pair[0]
+            .iter()
+            .flat_map(|roll| &roll.values)
+            .filter(|&&v| v == 6)
+            .count();
+        let rolled: usize = pair[1].iter().map(|roll| roll.values.len()).sum();
+        assert_eq!(rolled, sixes);
+    }
+    // 最后一帧与相同种子的普通投掷一致
+    match frames.last().unwrap() {
+        AnimationFrame::Final(output) => assert_eq!(
+            format_result_text(output),
+            format_result_text(&roll_with_seed("10d6!", 15).unwrap())
+        ),
+        other => panic!("unexpected frame {:?}", other),
+    }
+    // 不含骰子的表达式只有最终结果
+    assert_eq!(roll_animated("1+2", 7).unwrap().len(), 1);
+}
+
+#[test]
+fn test_integer_ops_reject_unsafe_operands() {
+    let err = roll_with_fixed_results("(1d6 * 100000000000000000000) // 3", &[4]).unwrap_err();
+    assert!(err.contains("safe integer range"));
+    let err = roll_with_fixed_results("[1d6 * 100000000000000000000] % 3", &[4]).unwrap_err();
+    assert!(err.contains("safe integer range"));
+    let result = roll_with_fixed_results("(1d6 * 1000000000000000) % 7", &[4]).unwrap();
+    assert_eq!(
+        crate::render_text::format_result_text(&result),
+        "1d6 [4] * 1000000000000000 % 7 = 3"
+    );
+}
+
+#[test]
+fn test_sort_places_nan_last() {
+    use crate::render_text::format_result_text;
+    let expr = "sort([1d6 * 1e308 * 10 - 1e308 * 10, 3, 1])";
+    let result = roll_with_fixed_results(expr, &[2]).unwrap();
+    assert!(format_result_text(&result).ends_with("= [1, 3, NaN]"));
+    let expr = "sortd([3, 1d6 * 1e308 * 10 - 1e308 * 10, 1])";
+    let result = roll_with_fixed_results(expr, &[2]).unwrap();
+    assert!(format_result_text(&result).ends_with("= [3, 1, NaN]"));
+}
+
+#[test]
+fn test_filter_by_position() {
+    use crate::render_text::format_result_text;
+    let result = roll_with_fixed_results("filtereven(tolist(5d6))", &[6, 1, 5, 2, 4]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "filtereven(tolist(5d6 [6, 1, 5, 2, 4])) = [6, 5, 4]"
+    );
+    let result = roll_with_fixed_results("filterodd(tolist(5d6))", &[6, 1, 5, 2, 4]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "filterodd(tolist(5d6 [6, 1, 5, 2, 4])) = [1, 2]"
+    );
+}
+
+#[test]
+fn test_margin_of_success() {
+    use crate::render_text::format_result_text;
+    let result = roll_with_fixed_results("margin(2d6+3, 10)", &[6, 4]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "margin(2d6 [6, 4] + 3, 10) = 3"
+    );
+    let result = roll_with_fixed_results("margin(1d20, 1d20)", &[8, 12]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "margin(1d20 [8], 1d20 [12]) = -4"
+    );
+}
+
+#[test]
+fn test_sortdice_sorts_kept_dice() {
+    use crate::render_text::format_result_text;
+    let result = roll_with_fixed_results("sortdice(4d6dl1)", &[5, 1, 6, 3]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "sort(tolist(4d6dl1 [5, ~1~, 6, 3])) = [3, 5, 6]"
+    );
+    let result = roll_with_fixed_results("sortdiced(4d6)", &[5, 1, 6, 3]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "sortd(tolist(4d6 [5, 1, 6, 3])) = [6, 5, 3, 1]"
+    );
+}
+
+#[test]
+fn test_eval_hir_from_builders() {
+    use crate::types::hir::NumberType;
+    use crate::types::output_node::ValueSummary;
+    // 2d6 + 3
+    let dice = HIR::standard_dice_pool(NumberType::Constant(2.0), NumberType::Constant(6.0))
+        .except_number()
+        .unwrap();
+    let hir = HIR::add_number(dice, NumberType::Constant(3.0));
+    let result = eval_hir(hir).unwrap();
+    assert_eq!(result.label, "+");
+    match result.value {
+        ValueSummary::Number(n) => assert!((5.0..=15.0).contains(&n)),
+        other => panic!("unexpected value {:?}", other),
+    }
+    // 不含骰子的 HIR 直接折叠为常数
+    let hir = HIR::multiply_number(NumberType::Constant(4.0), NumberType::Constant(2.5));
+    assert!(matches!(
+        eval_hir(hir).unwrap().value,
+        ValueSummary::Number(10.0)
+    ));
+}
+
+#[test]
+fn test_eval_hir_non_positive_sides() {
+    use crate::types::hir::NumberType;
+    use crate::types::output_node::ValueSummary;
+    // eval_hir 不经过类型检查；面数为 0 或负数时得到空骰池，而不是 panic 或不停地投掷
+    // 常数面数会被常量折叠为 0，因此用 1d1 + k 让面数只在运行时才确定
+    for offset in [-1.0, -5.0] {
+        let one = HIR::standard_dice_pool(NumberType::Constant(1.0), NumberType::Constant(1.0))
+            .except_number()
+            .unwrap();
+        let sides = HIR::add_number(one, NumberType::Constant(offset))
+            .except_number()
+            .unwrap();
+        let hir = HIR::standard_dice_pool(NumberType::Constant(3.0), sides);
+        let result = eval_hir(hir).unwrap();
+        match result.value {
+            ValueSummary::DicePool {
+                total, ref details, ..
+            } => {
+                assert_eq!(total, 0);
+                assert!(details.is_empty());
+            }
+            other => panic!("unexpected value {:?}", other),
+        }
+    }
+    // 常数面数在折叠阶段即得到 0
+    for sides in [0.0, -4.0] {
+        let hir = HIR::standard_dice_pool(NumberType::Constant(3.0), NumberType::Constant(sides));
+        assert_eq!(eval_hir(hir).unwrap().value.numeric_total(), Some(0.0));
+    }
+}
+
+#[test]
+fn test_groupkh_keeps_best_groups() {
+    use crate::render_text::format_result_text;
+    // 2d6 [3, 4] = 7, 3d4 [4, 4, 2] = 10, 1d8 [8] = 8
+    let values = [3, 4, 4, 4, 2, 8];
+    let result = roll_with_fixed_results("groupkh(2d6, 3d4, 1d8, 1)", &values).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "groupkh(2d6 [3, 4], 3d4 [4, 4, 2], 1d8 [8], 1) = 10"
+    );
+    let result = roll_with_fixed_results("groupkh(2d6, 3d4, 1d8, 2)", &values).unwrap();
+    assert!(format_result_text(&result).ends_with(" = 18"));
+    let result = roll_with_fixed_results("groupkh(2d6, 3d4, 1d8, 5)", &values).unwrap();
+    assert!(format_result_text(&result).ends_with(" = 25"));
+}
+
+#[test]
+fn test_let_binding_rolls() {
+    use crate::render_text::format_result_text;
+    // 普通绑定的每次引用都独立投掷 (两个 1d20 被常量折叠合并为 2d20)
+    let result = roll_with_fixed_results("let atk = 1d20+5; atk + atk", &[3, 17]).unwrap();
+    assert_eq!(format_result_text(&result), "2d20 [3, 17] + 10 = 30");
+    // share 绑定的所有引用共用同一次投掷
+    let result = roll_with_fixed_results("let share atk = 1d20+5; atk + atk", &[3]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "1d20 [3] + 5 + (1d20 [3] + 5) = 16"
+    );
+    // 共享的爆炸骰也只投掷 (并爆炸) 一次
+    let result = roll_with_fixed_results("let share x = 1d6!; [x, x * 2]", &[6, 2]).unwrap();
+    assert_eq!(
+        format_result_text(&result),
+        "[1d6! [6, 2], 1d6! [6, 2] * 2] = [8, 16]"
+    );
+}
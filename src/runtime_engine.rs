@@ -1,12 +1,25 @@
 use crate::types::eval_graph::*;
 use crate::types::expr::CompareOp;
+use crate::types::list_ops::{compare_nan_last, every_other, keep_elements_preserve_order};
 use crate::types::runtime_value::*;
+use crate::types::safe_integer::{checked_int_divide, checked_modulo};
+
+// !until 在一个节点中最多追加的骰子数，避免成功条件难以满足时无休止地投掷
+const EXPLODE_UNTIL_MAX_EXTRA_DICE: i32 = 100;
 
 pub struct ExecutionContext {
     graph: EvalGraph,                  // 代码 (只读)
     memory: Vec<NodeState>,            // 内存 (读写)
     pub requests: Vec<RuntimeRequest>, // 本轮需要外部骰子结果的请求列表
     pub remove_requests: Vec<RollId>,  // 本轮需要移除的外部骰子请求列表，主要用于动画
+    requested: Vec<bool>,              // 节点在本轮是否已经发出请求，与 requests 同步清空
+    explosion_cap: Option<u32>,        // 整个表达式中爆炸产生骰子的总数上限
+    explosion_count: u32,              // 整个表达式中已经因爆炸产生的骰子数
+    explosion_cap_reached: bool,       // 是否有爆炸因总数上限而被截断
+    dice_budget: Option<u32>,          // 还允许请求的骰子数，每轮求值前由投掷器设置，发出请求时扣减
+    clamp_net_successes: bool,         // 成功池的净成功数是否不低于 0
+    rounding_mode: RoundingMode,       // round 在 .5 处的取舍方式
+    trace: Option<Vec<TraceEvent>>,    // 动态操作的追踪记录，None 表示未开启
 }
 
 enum DiceFilterOp {
@@ -14,17 +27,76 @@ enum DiceFilterOp {
     KeepLow,
     DropHigh,
     DropLow,
+    Remove, // 不排序，按投掷顺序移除满足条件的骰子
 }
 
 impl ExecutionContext {
-    pub fn new(graph: EvalGraph) -> Self {
+    // 执行前先检查图的结构，手工构造的图中错误的 NodeId 会在这里被发现，而不是在求值时越界
+    pub fn new(graph: EvalGraph) -> Result<Self, String> {
+        validate(&graph)?;
         let len = graph.nodes.len();
-        Self {
+        Ok(Self {
             graph,
             memory: vec![NodeState::Waiting; len],
             requests: Vec::new(),
             remove_requests: Vec::new(),
+            requested: vec![false; len],
+            explosion_cap: None,
+            explosion_count: 0,
+            explosion_cap_reached: false,
+            dice_budget: None,
+            clamp_net_successes: false,
+            rounding_mode: RoundingMode::default(),
+            trace: None,
+        })
+    }
+
+    // 开启后记录每一轮爆炸/重投的请求与结果，用于排查动态操作的行为
+    pub fn set_trace_enabled(&mut self, enabled: bool) {
+        self.trace = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    pub fn trace(&self) -> &[TraceEvent] {
+        self.trace.as_deref().unwrap_or_default()
+    }
+
+    fn record(&mut self, event: TraceEvent) {
+        if let Some(trace) = self.trace.as_mut() {
+            trace.push(event);
+        }
+    }
+
+    // 部分规则中净成功数最低为 0，开启后失败多于成功时成功池的值记为 0
+    pub fn set_clamp_net_successes(&mut self, clamp: bool) {
+        self.clamp_net_successes = clamp;
+    }
+
+    // 数值与列表的 round 都按此方式取舍，需在开始求值前设置
+    pub fn set_rounding_mode(&mut self, mode: RoundingMode) {
+        self.rounding_mode = mode;
+    }
+
+    // 设置整个表达式中爆炸产生骰子的总数上限，None 表示不限制
+    pub fn set_explosion_cap(&mut self, cap: Option<u32>) {
+        self.explosion_cap = cap;
+    }
+
+    fn push_request(&mut self, request: RuntimeRequest) {
+        // 同一轮中先发出的请求已经占用了一部分余量，之后的检查只针对剩下的部分
+        if let Some(budget) = self.dice_budget.as_mut() {
+            *budget = budget.saturating_sub(request.count);
         }
+        self.requested[request.node_id.to_index()] = true;
+        self.requests.push(request);
+    }
+
+    // 投掷器剩余的骰子数，动态操作在构造请求前据此拒绝过大的请求，避免先分配再报错
+    pub fn set_dice_budget(&mut self, budget: u32) {
+        self.dice_budget = Some(budget);
+    }
+
+    pub fn explosion_cap_reached(&self) -> bool {
+        self.explosion_cap_reached
     }
 
     pub fn eval_node(&mut self, id: NodeId) -> Result<Option<RuntimeValue>, String> {
@@ -34,12 +106,17 @@ impl ExecutionContext {
             NodeState::Computed(v) => return Ok(Some(v.clone())),
             NodeState::Waiting | NodeState::Dynamic(_) => {} // 继续向下
         }
+        // 共享节点 (let share) 在同一轮中可能被访问多次，已经发出请求的节点只需等待结果
+        if self.requested[idx] {
+            return Ok(None);
+        }
         // 获取节点
         let node = &self.graph.nodes[idx];
 
         // 根据指令类型分发进行计算
         let result = match node {
             EvalNode::Constant(v) => Some(RuntimeValue::Number(*v)),
+            EvalNode::ConstantList(values) => Some(RuntimeValue::List(values.clone())),
             EvalNode::ListConstruct(elements) => {
                 let mut list = Vec::new();
                 let mut is_waiting = false;
@@ -74,7 +151,7 @@ impl ExecutionContext {
                     None
                 }
             }
-            EvalNode::NumSubtract(idx1, idx2) => {
+            EvalNode::NumSubtract(idx1, idx2) | EvalNode::NumMargin(idx1, idx2) => {
                 let (idx1, idx2) = (idx1.clone(), idx2.clone());
                 let (v1, v2) = (self.get_number(idx1)?, self.get_number(idx2)?);
                 if let (Some(n1), Some(n2)) = (v1, v2) {
@@ -111,7 +188,7 @@ impl ExecutionContext {
                     if n2 == 0.0 {
                         return Err("Integer division by zero".to_string());
                     }
-                    Some(RuntimeValue::Number((n1 / n2).floor()))
+                    Some(RuntimeValue::Number(checked_int_divide(n1, n2)?))
                 } else {
                     None
                 }
@@ -123,7 +200,17 @@ impl ExecutionContext {
                     if n2 == 0.0 {
                         return Err("Modulo by zero".to_string());
                     }
-                    Some(RuntimeValue::Number(n1 % n2))
+                    Some(RuntimeValue::Number(checked_modulo(n1, n2)?))
+                } else {
+                    None
+                }
+            }
+            EvalNode::NumCompare(idx1, op, idx2) => {
+                let (idx1, op, idx2) = (*idx1, op.clone(), *idx2);
+                let (v1, v2) = (self.get_number(idx1)?, self.get_number(idx2)?);
+                if let (Some(n1), Some(n2)) = (v1, v2) {
+                    let holds = get_compare_function(op, n2)(n1);
+                    Some(RuntimeValue::Number(if holds { 1.0 } else { 0.0 }))
                 } else {
                     None
                 }
@@ -165,7 +252,7 @@ impl ExecutionContext {
                     if n == 0.0 {
                         Err("Integer division by zero in ListIntDivide".to_string())
                     } else {
-                        Ok((x / n).floor())
+                        checked_int_divide(x, n)
                     }
                 })?
             }
@@ -174,7 +261,7 @@ impl ExecutionContext {
                     if n == 0.0 {
                         Err("Modulo by zero in ListModulo".to_string())
                     } else {
-                        Ok(x % n)
+                        checked_modulo(x, n)
                     }
                 })?
             }
@@ -195,7 +282,7 @@ impl ExecutionContext {
                     if x == 0.0 {
                         Err("Integer division by zero in ListIntDivideReverse".to_string())
                     } else {
-                        Ok((n / x).floor())
+                        checked_int_divide(n, x)
                     }
                 })?
             }
@@ -204,7 +291,7 @@ impl ExecutionContext {
                     if x == 0.0 {
                         Err("Modulo by zero in ListModuloReverse".to_string())
                     } else {
-                        Ok(n % x)
+                        checked_modulo(n, x)
                     }
                 })?
             }
@@ -217,7 +304,13 @@ impl ExecutionContext {
                 None => None,
             },
             EvalNode::NumRound(node) => match self.eval_node(*node)? {
-                Some(v) => Some(RuntimeValue::Number(v.except_number()?.round())),
+                Some(v) => Some(RuntimeValue::Number(
+                    self.rounding_mode.round(v.except_number()?),
+                )),
+                None => None,
+            },
+            EvalNode::NumRoundEven(node) => match self.eval_node(*node)? {
+                Some(v) => Some(RuntimeValue::Number(v.except_number()?.round_ties_even())),
                 None => None,
             },
             EvalNode::NumAbs(node) => match self.eval_node(*node)? {
@@ -248,6 +341,27 @@ impl ExecutionContext {
                     None
                 }
             }
+            EvalNode::NumMaxOr(list_id, default_id) => {
+                self.eval_aggregate_or(*list_id, *default_id, |list| {
+                    list.iter().fold(f64::NEG_INFINITY, |a, &b| a.max(b))
+                })?
+            }
+            EvalNode::NumMinOr(list_id, default_id) => {
+                self.eval_aggregate_or(*list_id, *default_id, |list| {
+                    list.iter().fold(f64::INFINITY, |a, &b| a.min(b))
+                })?
+            }
+            EvalNode::NumGroupKh(list_id, count_id) => {
+                let (list_id, count_id) = (*list_id, *count_id);
+                match (self.get_list(list_id)?, self.get_number(count_id)?) {
+                    (Some(list), Some(count)) => Some(RuntimeValue::Number(
+                        keep_elements_preserve_order(list, count, true)
+                            .into_iter()
+                            .sum(),
+                    )),
+                    _ => None,
+                }
+            }
             EvalNode::NumSum(node) => match self.get_list(*node)? {
                 Some(list) => {
                     let sum_value: f64 = list.iter().sum();
@@ -270,6 +384,28 @@ impl ExecutionContext {
                 Some(list) => Some(RuntimeValue::Number(list.len() as f64)),
                 None => None,
             },
+            EvalNode::DiceHighest(dp_id) | EvalNode::DiceLowest(dp_id) => {
+                let dp_id = *dp_id;
+                let highest = matches!(node, EvalNode::DiceHighest(_));
+                let name = if highest { "highdie" } else { "lowdie" };
+                match self.get_dice_pool(dp_id)? {
+                    Some(dice_pool) => {
+                        let kept = dice_pool.details.iter().filter(|d| d.is_kept);
+                        let value = if highest {
+                            kept.map(|d| d.result).max()
+                        } else {
+                            kept.map(|d| d.result).min()
+                        };
+                        match value {
+                            Some(v) => Some(RuntimeValue::Number(v as f64)),
+                            None => {
+                                return Err(format!("{name} called on a pool with no kept dice"));
+                            }
+                        }
+                    }
+                    None => None,
+                }
+            }
             EvalNode::ListFloor(node) => match self.eval_node(*node)? {
                 Some(v) => {
                     let list = v.except_list()?;
@@ -289,7 +425,8 @@ impl ExecutionContext {
             EvalNode::ListRound(node) => match self.eval_node(*node)? {
                 Some(v) => {
                     let list = v.except_list()?;
-                    let rounded: Vec<f64> = list.iter().map(|&x| x.round()).collect();
+                    let mode = self.rounding_mode;
+                    let rounded: Vec<f64> = list.iter().map(|&x| mode.round(x)).collect();
                     Some(RuntimeValue::List(rounded))
                 }
                 None => None,
@@ -332,7 +469,7 @@ impl ExecutionContext {
                 Some(v) => {
                     let list = v.except_list()?;
                     let mut sorted = list.clone();
-                    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+                    sorted.sort_by(|a, b| compare_nan_last(*a, *b, false));
                     Some(RuntimeValue::List(sorted))
                 }
                 None => None,
@@ -341,11 +478,19 @@ impl ExecutionContext {
                 Some(v) => {
                     let list = v.except_list()?;
                     let mut sorted = list.clone();
-                    sorted.sort_by(|a, b| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+                    sorted.sort_by(|a, b| compare_nan_last(*a, *b, true));
                     Some(RuntimeValue::List(sorted))
                 }
                 None => None,
             },
+            EvalNode::ListFilterEven(node) => match self.eval_node(*node)? {
+                Some(v) => Some(RuntimeValue::List(every_other(v.except_list()?, 0))),
+                None => None,
+            },
+            EvalNode::ListFilterOdd(node) => match self.eval_node(*node)? {
+                Some(v) => Some(RuntimeValue::List(every_other(v.except_list()?, 1))),
+                None => None,
+            },
             EvalNode::ListToListFromDicePool(node) => match self.eval_node(*node)? {
                 Some(v) => {
                     let dice_pool = v.except_dice_pool()?;
@@ -365,12 +510,8 @@ impl ExecutionContext {
                     let list: Vec<f64> = success_pool
                         .details
                         .iter()
-                        .filter(|d| d.is_kept == true)
-                        .map(|d| match d.outcome {
-                            DieOutcome::Success => 1.0,
-                            DieOutcome::Failure => -1.0,
-                            DieOutcome::None => 0.0,
-                        })
+                        .filter(|d| d.counts_toward_successes())
+                        .map(|d| d.outcome.weight() as f64)
                         .collect();
                     Some(RuntimeValue::List(list))
                 }
@@ -399,6 +540,7 @@ impl ExecutionContext {
                 if let (Some(c), Some(s)) = (count_val, sides_val) {
                     let count = c as i32;
                     let sides = s as i32;
+                    // 类型检查会拒绝 s < 2，但直接求值 HIR 时会绕过检查，此时面数非正得到空骰池
                     if sides <= 0 {
                         Some(RuntimeValue::DicePool(Box::new(DicePoolType {
                             total: 0,
@@ -412,7 +554,7 @@ impl ExecutionContext {
                             details: Vec::new(),
                         })))
                     } else {
-                        self.requests.push(RuntimeRequest {
+                        self.push_request(RuntimeRequest {
                             node_id: id,
                             face: DiceFace::Number(sides),
                             count: count as u32,
@@ -434,7 +576,7 @@ impl ExecutionContext {
                             details: Vec::new(),
                         })))
                     } else {
-                        self.requests.push(RuntimeRequest {
+                        self.push_request(RuntimeRequest {
                             node_id: id,
                             face: DiceFace::Fudge,
                             count: count as u32,
@@ -445,6 +587,38 @@ impl ExecutionContext {
                     None
                 }
             }
+            EvalNode::DiceLabeled(count_id, faces) => {
+                let face = DiceFace::Labeled(faces.clone());
+                let count_val = self.get_number(*count_id)?;
+                if let Some(c) = count_val {
+                    let count = c as i32;
+                    if count <= 0 {
+                        Some(RuntimeValue::DicePool(Box::new(DicePoolType {
+                            total: 0,
+                            face,
+                            details: Vec::new(),
+                        })))
+                    } else {
+                        self.push_request(RuntimeRequest {
+                            node_id: id,
+                            face: face.request_face(),
+                            count: count as u32,
+                        });
+                        None
+                    }
+                } else {
+                    None
+                }
+            }
+            EvalNode::DicePercentile => {
+                // 一次请求两个 d10，分别作为十位骰和个位骰
+                self.push_request(RuntimeRequest {
+                    node_id: id,
+                    face: DiceFace::Number(10),
+                    count: 2,
+                });
+                None
+            }
             EvalNode::DiceCoin(count_id) => {
                 let count_val = self.get_number(*count_id)?;
                 if let Some(c) = count_val {
@@ -456,7 +630,7 @@ impl ExecutionContext {
                             details: Vec::new(),
                         })))
                     } else {
-                        self.requests.push(RuntimeRequest {
+                        self.push_request(RuntimeRequest {
                             node_id: id,
                             face: DiceFace::Coin,
                             count: count as u32,
@@ -468,23 +642,84 @@ impl ExecutionContext {
                 }
             }
             EvalNode::DiceKeepHigh(dp_id, count_id) => {
-                self.apply_dice_filter(*dp_id, *count_id, DiceFilterOp::KeepHigh)?
+                self.apply_dice_filter(*dp_id, *count_id, DiceFilterOp::KeepHigh, None)?
             }
             EvalNode::DiceKeepLow(dp_id, count_id) => {
-                self.apply_dice_filter(*dp_id, *count_id, DiceFilterOp::KeepLow)?
+                self.apply_dice_filter(*dp_id, *count_id, DiceFilterOp::KeepLow, None)?
             }
             EvalNode::DiceDropHigh(dp_id, count_id) => {
-                self.apply_dice_filter(*dp_id, *count_id, DiceFilterOp::DropHigh)?
+                self.apply_dice_filter(*dp_id, *count_id, DiceFilterOp::DropHigh, None)?
             }
             EvalNode::DiceDropLow(dp_id, count_id) => {
-                self.apply_dice_filter(*dp_id, *count_id, DiceFilterOp::DropLow)?
+                self.apply_dice_filter(*dp_id, *count_id, DiceFilterOp::DropLow, None)?
+            }
+            EvalNode::DiceConditionalDropHigh(dp_id, count_id, mod_param_node) => self
+                .apply_dice_filter(
+                    *dp_id,
+                    *count_id,
+                    DiceFilterOp::DropHigh,
+                    Some(mod_param_node.clone()),
+                )?,
+            EvalNode::DiceConditionalDropLow(dp_id, count_id, mod_param_node) => self
+                .apply_dice_filter(
+                    *dp_id,
+                    *count_id,
+                    DiceFilterOp::DropLow,
+                    Some(mod_param_node.clone()),
+                )?,
+            EvalNode::DiceRemoveN(dp_id, count_id, mod_param_node) => self.apply_dice_filter(
+                *dp_id,
+                *count_id,
+                DiceFilterOp::Remove,
+                Some(mod_param_node.clone()),
+            )?,
+            EvalNode::DiceKeepRelative(dp_id, compare) => {
+                self.apply_keep_relative(*dp_id, compare.clone())?
             }
+            EvalNode::DiceKeepDistinct(dp_id) => self.apply_keep_distinct(*dp_id)?,
+            EvalNode::DiceKeepRankRange(dp_id, low, high) => {
+                self.apply_keep_rank_range(*dp_id, *low, *high)?
+            }
+            EvalNode::DiceFromSuccessPool(sp_id) => match self.eval_node(*sp_id)? {
+                Some(v) => {
+                    // 沿用成功池中的骰子明细，清除成功/失败标记
+                    let success_pool = v.except_success_pool()?;
+                    let mut details = success_pool.details.clone();
+                    for detail in details.iter_mut() {
+                        detail.outcome = DieOutcome::None;
+                    }
+                    let mut dice_pool = DicePoolType {
+                        total: 0,
+                        face: success_pool.face.clone(),
+                        details,
+                    };
+                    dice_pool.renew_total();
+                    Some(RuntimeValue::DicePool(Box::new(dice_pool)))
+                }
+                None => None,
+            },
             EvalNode::DiceMin(dp_id, target_id) => {
                 self.apply_dice_min_max(*dp_id, *target_id, false)?
             }
             EvalNode::DiceMax(dp_id, target_id) => {
                 self.apply_dice_min_max(*dp_id, *target_id, true)?
             }
+            EvalNode::DiceMapEach(dp_id, op, value_id) => {
+                let (dp_id, op, value_id) = (*dp_id, *op, *value_id);
+                let pool_ready = self.ensure_ready(dp_id)?;
+                let value = self.get_number(value_id)?;
+                match value {
+                    Some(value) if pool_ready => {
+                        let mut dice_pool = self.get_dice_pool(dp_id)?.unwrap();
+                        for detail in dice_pool.details.iter_mut().filter(|d| d.is_kept) {
+                            detail.result = op.apply(detail.result, value as i32);
+                        }
+                        dice_pool.renew_total();
+                        Some(RuntimeValue::DicePool(Box::new(dice_pool)))
+                    }
+                    _ => None,
+                }
+            }
             EvalNode::DiceSubtractFailures(dp_id, mod_param_node) => {
                 let (dp_id, mod_param_node) = (dp_id.clone(), mod_param_node.clone());
                 let pool_ready = self.ensure_ready(dp_id)?;
@@ -507,12 +742,55 @@ impl ExecutionContext {
                     None
                 }
             }
+            EvalNode::DiceRerollMax(dp_id, mod_param_node) => {
+                self.apply_reroll_max(*dp_id, mod_param_node.clone())?
+            }
             EvalNode::DiceCountSuccessesFromDicePool(dp_id, mod_param_node) => self
                 .into_success_pool_from_dice_pool(
                     *dp_id,
                     mod_param_node.clone(),
                     DieOutcome::Success,
                 )?,
+            EvalNode::DiceSpendSuccessesFromDicePool(dp_id, mod_param_node) => self
+                .into_success_pool_from_dice_pool(
+                    *dp_id,
+                    mod_param_node.clone(),
+                    DieOutcome::Spent,
+                )?,
+            EvalNode::DiceNetSuccess(dp_id, mod_param_node) => self.mark_successes_from_dice_pool(
+                *dp_id,
+                mod_param_node.clone(),
+                DieOutcome::Success,
+                Some(DieOutcome::Failure),
+            )?,
+            EvalNode::DiceWeightedSuccesses(dp_id, weights) => {
+                let dp_id = *dp_id;
+                let weights = weights.clone();
+                match self.get_dice_pool(dp_id)? {
+                    Some(dice_pool) => {
+                        let mut success_pool = SuccessPoolType {
+                            success_count: 0,
+                            face: dice_pool.face,
+                            details: dice_pool.details,
+                        };
+                        for detail in success_pool.details.iter_mut().filter(|d| d.is_kept) {
+                            // 取第一个满足的条件的权重
+                            let weight = weights.iter().find_map(|(op, value, weight)| {
+                                get_compare_function(op.clone(), *value)(detail.result as f64)
+                                    .then_some(*weight)
+                            });
+                            detail.outcome = match weight {
+                                Some(1) => DieOutcome::Success,
+                                Some(w) => DieOutcome::WeightedSuccess(w),
+                                None => DieOutcome::None,
+                            };
+                        }
+                        success_pool.renew_success_count(self.clamp_net_successes);
+                        Some(RuntimeValue::SuccessPool(Box::new(success_pool)))
+                    }
+                    None => None,
+                }
+            }
             EvalNode::DiceDeductFailuresFromDicePool(dp_id, mod_param_node) => self
                 .into_success_pool_from_dice_pool(
                     *dp_id,
@@ -530,29 +808,38 @@ impl ExecutionContext {
                 *dp_id,
                 mod_param_node.clone(),
                 limit_node.clone(),
-                |state| {
-                    let mut new_rolls = Vec::new();
-                    for (idx, value, roll_id) in state.pending_dice.iter() {
-                        // 原本的骰子标记explode + 1
-                        state.pool.details[*idx].exploded_times += 1;
-                        // 将新的骰子加入details列表
-                        let new_value = value.ok_or("Some value is missing".to_string())?;
-                        state.pool.details.push(DieDetail {
-                            result: new_value,
-                            roll_history: vec![new_value],
-                            roll_id: vec![roll_id.ok_or("Some value is missing")?],
-                            is_kept: true,
-                            outcome: DieOutcome::None,
-                            is_rerolled: false,
-                            exploded_times: 0,
-                        });
-                        // 记录新骰子的索引和结果
-                        new_rolls.push((state.pool.details.len() - 1, new_value));
-                    }
-                    Ok(new_rolls)
-                },
-                false,
+                merge_exploded_dice,
+                DynamicOpKind::Explode,
             )?,
+            // 爆炸产生的骰子同样加入骰子池，但不返回给扫描阶段，因此不会再次爆炸
+            EvalNode::DiceExplodeOnce(dp_id, mod_param_node, limit_node) => self
+                .process_dynamic_op(
+                    id,
+                    *dp_id,
+                    mod_param_node.clone(),
+                    limit_node.clone(),
+                    |state| merge_exploded_dice(state).map(|_| Vec::new()),
+                    DynamicOpKind::Explode,
+                )?,
+            // 总和条件见 process_dynamic_op，新骰子不返回给扫描阶段，因此只爆炸一轮
+            EvalNode::DiceExplodeIfTotal(dp_id, _) => self.process_dynamic_op(
+                id,
+                *dp_id,
+                None,
+                None,
+                |state| merge_exploded_dice(state).map(|_| Vec::new()),
+                DynamicOpKind::Explode,
+            )?,
+            // 每个触发爆炸的骰子新增 count 个骰子，新骰子同样可以继续爆炸
+            EvalNode::DiceExplodeMulti(dp_id, _, mod_param_node, limit_node) => self
+                .process_dynamic_op(
+                    id,
+                    *dp_id,
+                    mod_param_node.clone(),
+                    limit_node.clone(),
+                    merge_exploded_dice,
+                    DynamicOpKind::Explode,
+                )?,
             EvalNode::DiceCompoundExplode(dp_id, mod_param_node, limit_node) => self
                 .process_dynamic_op(
                     id,
@@ -576,8 +863,40 @@ impl ExecutionContext {
                         }
                         Ok(new_rolls)
                     },
-                    false,
+                    DynamicOpKind::Explode,
                 )?,
+            // 成功的骰子像爆炸一样奖励一个骰子，结束后统一判定成功
+            EvalNode::DiceCascade(dp_id, mod_param_node, limit_node) => {
+                let mod_param_node = mod_param_node.clone();
+                let pool = self.process_dynamic_op(
+                    id,
+                    *dp_id,
+                    Some(mod_param_node.clone()),
+                    Some(limit_node.clone()),
+                    merge_exploded_dice,
+                    DynamicOpKind::Cascade,
+                )?;
+                match pool {
+                    Some(pool) => {
+                        let pool = pool.except_dice_pool()?.clone();
+                        let target = self.get_number(mod_param_node.value)?.unwrap();
+                        let compare_func = get_compare_function(mod_param_node.operator, target);
+                        let mut success_pool = SuccessPoolType {
+                            success_count: 0,
+                            face: pool.face,
+                            details: pool.details,
+                        };
+                        for detail in success_pool.details.iter_mut().filter(|d| d.is_kept) {
+                            if compare_func(detail.result as f64) {
+                                detail.outcome = DieOutcome::Success;
+                            }
+                        }
+                        success_pool.renew_success_count(self.clamp_net_successes);
+                        Some(RuntimeValue::SuccessPool(Box::new(success_pool)))
+                    }
+                    None => None,
+                }
+            }
             EvalNode::DiceReroll(dp_id, mod_param_node, limit_node) => self.process_dynamic_op(
                 id,
                 *dp_id,
@@ -588,8 +907,10 @@ impl ExecutionContext {
                     let mut rolls_to_remove: Vec<RollId> = Vec::new();
                     for (idx, value, roll_id) in state.pending_dice.iter() {
                         // 原本的骰子标记为rerolled，并且不保留
+                        let new_idx = state.pool.details.len();
                         state.pool.details[*idx].is_rerolled = true;
                         state.pool.details[*idx].is_kept = false;
+                        state.pool.details[*idx].replaced_by = Some(new_idx);
                         rolls_to_remove.extend(state.pool.details[*idx].roll_id.iter());
                         // 将新的骰子加入details列表
                         let new_value = value.ok_or("Some value is missing".to_string())?;
@@ -600,15 +921,24 @@ impl ExecutionContext {
                             is_kept: true,
                             outcome: DieOutcome::None,
                             is_rerolled: false,
+                            replaced_by: None,
                             exploded_times: 0,
+                            was_clamped: false,
                         });
                         // 记录新骰子的索引和结果
                         new_rolls.push((state.pool.details.len() - 1, new_value));
                     }
                     Ok(new_rolls)
                 },
-                true,
+                DynamicOpKind::Reroll,
             )?,
+            EvalNode::DiceExplodeUntil(dp_id, op, value, quota) => {
+                let (dp_id, op, value, quota) = (*dp_id, op.clone(), *value, *quota);
+                self.process_explode_until(id, dp_id, op, value, quota)?
+            }
+            EvalNode::DiceRerollPool(dp_id, mod_param_node, limit_node) => {
+                self.process_pool_reroll(id, *dp_id, mod_param_node.clone(), limit_node.clone())?
+            }
         };
 
         // 存储结果并返回
@@ -642,7 +972,7 @@ impl ExecutionContext {
                     }
                     // 更新pending_dice
                     for (i, (_, v, id)) in state.pending_dice.iter_mut().enumerate() {
-                        *v = Some(dice_result[i].0);
+                        *v = Some(state.pool.face.face_value(dice_result[i].0)?);
                         *id = Some(dice_result[i].1);
                     }
                 }
@@ -665,7 +995,9 @@ impl ExecutionContext {
                                         is_kept: true,
                                         outcome: DieOutcome::None,
                                         is_rerolled: false,
+                                        replaced_by: None,
                                         exploded_times: 0,
+                                        was_clamped: false,
                                     })
                                     .collect(),
                             };
@@ -674,6 +1006,48 @@ impl ExecutionContext {
                                 Box::new(new_dice_pool),
                             ));
                         }
+                        EvalNode::DiceLabeled(_, faces) => {
+                            let face = DiceFace::Labeled(faces.clone());
+                            let details = dice_result
+                                .iter()
+                                .map(|(r, id)| {
+                                    let value = face.face_value(*r)?;
+                                    Ok(DieDetail {
+                                        result: value,
+                                        roll_history: vec![value],
+                                        roll_id: vec![*id],
+                                        is_kept: true,
+                                        outcome: DieOutcome::None,
+                                        is_rerolled: false,
+                                        replaced_by: None,
+                                        exploded_times: 0,
+                                        was_clamped: false,
+                                    })
+                                })
+                                .collect::<Result<Vec<_>, String>>()?;
+                            let mut new_dice_pool = DicePoolType {
+                                total: 0,
+                                face,
+                                details,
+                            };
+                            new_dice_pool.renew_total();
+                            self.memory[idx] = NodeState::Computed(RuntimeValue::DicePool(
+                                Box::new(new_dice_pool),
+                            ));
+                        }
+                        EvalNode::DicePercentile => {
+                            if dice_result.len() != 2 {
+                                return Err("Mismatched dice result length".to_string());
+                            }
+                            let detail = percentile_die(dice_result[0], dice_result[1]);
+                            self.memory[idx] = NodeState::Computed(RuntimeValue::DicePool(
+                                Box::new(DicePoolType {
+                                    total: detail.result,
+                                    face: DiceFace::Number(100),
+                                    details: vec![detail],
+                                }),
+                            ));
+                        }
                         _ => {
                             return Err("RuntimeResponse received for non-dice node".to_string());
                         }
@@ -686,6 +1060,9 @@ impl ExecutionContext {
         }
 
         // 清空请求列表
+        for request in self.requests.iter() {
+            self.requested[request.node_id.to_index()] = false;
+        }
         self.requests.clear();
         self.remove_requests.clear();
         Ok(())
@@ -764,11 +1141,16 @@ impl ExecutionContext {
         pool_id: NodeId,
         count_id: NodeId,
         op: DiceFilterOp,
+        condition: Option<ModParamNode>, // 条件丢弃：只丢弃满足条件的骰子
     ) -> Result<Option<RuntimeValue>, String> {
         let pool_ready = self.ensure_ready(pool_id)?;
         let count_val = self.get_number(count_id)?;
+        let condition_ready = match &condition {
+            Some(mod_param_node) => self.ensure_ready(mod_param_node.value)?,
+            None => true,
+        };
 
-        if pool_ready && count_val.is_some() {
+        if pool_ready && count_val.is_some() && condition_ready {
             let mut dice_pool = self.get_dice_pool(pool_id)?.unwrap();
             let raw_count = count_val.unwrap() as i32;
             let count = if raw_count < 0 { 0 } else { raw_count as usize };
@@ -800,9 +1182,22 @@ impl ExecutionContext {
                         // 升序：小 -> 大
                         val_a.cmp(&val_b)
                     }
+                    // 稳定排序，保持原有的投掷顺序
+                    DiceFilterOp::Remove => std::cmp::Ordering::Equal,
                 }
             });
 
+            // 条件丢弃：在前 K 个候选骰子中，只保留满足条件的作为丢弃对象
+            // 移除：在全部骰子中找出满足条件的，再丢弃其中的前 K 个
+            if let Some(mod_param_node) = condition {
+                let mod_param_value = self.get_number(mod_param_node.value)?.unwrap();
+                let compare_func = get_compare_function(mod_param_node.operator, mod_param_value);
+                if !matches!(op, DiceFilterOp::Remove) {
+                    active_indices.truncate(count);
+                }
+                active_indices.retain(|&idx| compare_func(dice_pool.details[idx].result as f64));
+            }
+
             // 执行丢弃逻辑
             match op {
                 DiceFilterOp::KeepHigh | DiceFilterOp::KeepLow => {
@@ -818,7 +1213,7 @@ impl ExecutionContext {
                         }
                     }
                 }
-                DiceFilterOp::DropHigh | DiceFilterOp::DropLow => {
+                DiceFilterOp::DropHigh | DiceFilterOp::DropLow | DiceFilterOp::Remove => {
                     // Drop K: 丢弃前 K 个
                     if count >= active_indices.len() {
                         // 数量超标，全丢
@@ -847,6 +1242,144 @@ impl ExecutionContext {
         }
     }
 
+    // 两阶段：先求出被保留骰子的平均值，再丢弃与平均值比较后不满足条件的骰子
+    fn apply_keep_relative(
+        &mut self,
+        pool_id: NodeId,
+        compare: CompareOp,
+    ) -> Result<Option<RuntimeValue>, String> {
+        if !self.ensure_ready(pool_id)? {
+            return Ok(None);
+        }
+        let mut dice_pool = self.get_dice_pool(pool_id)?.unwrap();
+        let kept: Vec<i32> = dice_pool
+            .details
+            .iter()
+            .filter(|d| d.is_kept)
+            .map(|d| d.result)
+            .collect();
+        if kept.is_empty() {
+            return Ok(Some(RuntimeValue::DicePool(Box::new(dice_pool))));
+        }
+        let average = kept.iter().map(|&r| r as f64).sum::<f64>() / kept.len() as f64;
+        let compare_func = get_compare_function(compare, average);
+        for detail in dice_pool.details.iter_mut() {
+            if detail.is_kept && !compare_func(detail.result as f64) {
+                detail.is_kept = false;
+                self.remove_requests.extend(detail.roll_id.iter());
+            }
+        }
+        dice_pool.renew_total();
+        Ok(Some(RuntimeValue::DicePool(Box::new(dice_pool))))
+    }
+
+    // maxor/minor：列表为空时返回默认值，默认值总会被求值，以便结果树中完整显示
+    fn eval_aggregate_or(
+        &mut self,
+        list_id: NodeId,
+        default_id: NodeId,
+        aggregate: impl Fn(&[f64]) -> f64,
+    ) -> Result<Option<RuntimeValue>, String> {
+        let list = self.get_list(list_id)?;
+        let default = self.get_number(default_id)?;
+        match (list, default) {
+            (Some(list), Some(default)) if list.is_empty() => {
+                Ok(Some(RuntimeValue::Number(default)))
+            }
+            (Some(list), Some(_)) => Ok(Some(RuntimeValue::Number(aggregate(&list)))),
+            _ => Ok(None),
+        }
+    }
+
+    // 满足条件的骰子标记为重投并丢弃，换成一颗最大面的新骰子，不需要真正投掷
+    fn apply_reroll_max(
+        &mut self,
+        pool_id: NodeId,
+        mod_param_node: ModParamNode,
+    ) -> Result<Option<RuntimeValue>, String> {
+        let pool_ready = self.ensure_ready(pool_id)?;
+        let mod_param_ready = self.ensure_ready(mod_param_node.value)?;
+        if !pool_ready || !mod_param_ready {
+            return Ok(None);
+        }
+        let mut dice_pool = self.get_dice_pool(pool_id)?.unwrap();
+        let mod_param_value = self.get_number(mod_param_node.value)?.unwrap();
+        let compare_func = get_compare_function(mod_param_node.operator, mod_param_value);
+        let max_face = dice_pool.face.max_value();
+        let mut new_dice = Vec::new();
+        let original_len = dice_pool.details.len();
+        for detail in dice_pool.details.iter_mut() {
+            if detail.is_kept && compare_func(detail.result as f64) {
+                detail.is_kept = false;
+                detail.is_rerolled = true;
+                detail.replaced_by = Some(original_len + new_dice.len());
+                self.remove_requests.extend(detail.roll_id.iter());
+                new_dice.push(DieDetail {
+                    result: max_face,
+                    roll_history: vec![max_face],
+                    roll_id: Vec::new(),
+                    is_kept: true,
+                    outcome: DieOutcome::None,
+                    is_rerolled: false,
+                    replaced_by: None,
+                    exploded_times: 0,
+                    was_clamped: false,
+                });
+            }
+        }
+        dice_pool.details.extend(new_dice);
+        dice_pool.renew_total();
+        Ok(Some(RuntimeValue::DicePool(Box::new(dice_pool))))
+    }
+
+    // 按投掷顺序，每种点数只保留第一个仍被保留的骰子
+    fn apply_keep_distinct(&mut self, pool_id: NodeId) -> Result<Option<RuntimeValue>, String> {
+        if !self.ensure_ready(pool_id)? {
+            return Ok(None);
+        }
+        let mut dice_pool = self.get_dice_pool(pool_id)?.unwrap();
+        let mut seen = std::collections::HashSet::new();
+        for detail in dice_pool.details.iter_mut() {
+            if detail.is_kept && !seen.insert(detail.result) {
+                detail.is_kept = false;
+                self.remove_requests.extend(detail.roll_id.iter());
+            }
+        }
+        dice_pool.renew_total();
+        Ok(Some(RuntimeValue::DicePool(Box::new(dice_pool))))
+    }
+
+    // 按点数从大到小为被保留的骰子排名 (点数相同时先投出的排名靠前)，
+    // 只保留排名在 [low, high] 内的骰子
+    fn apply_keep_rank_range(
+        &mut self,
+        pool_id: NodeId,
+        low: i32,
+        high: i32,
+    ) -> Result<Option<RuntimeValue>, String> {
+        if !self.ensure_ready(pool_id)? {
+            return Ok(None);
+        }
+        let mut dice_pool = self.get_dice_pool(pool_id)?.unwrap();
+        let mut ranked: Vec<usize> = (0..dice_pool.details.len())
+            .filter(|&i| dice_pool.details[i].is_kept)
+            .collect();
+        ranked.sort_by(|&a, &b| {
+            dice_pool.details[b]
+                .result
+                .cmp(&dice_pool.details[a].result)
+        });
+        for (rank, idx) in (1..).zip(ranked) {
+            if rank < low || rank > high {
+                dice_pool.details[idx].is_kept = false;
+                self.remove_requests
+                    .extend(dice_pool.details[idx].roll_id.iter());
+            }
+        }
+        dice_pool.renew_total();
+        Ok(Some(RuntimeValue::DicePool(Box::new(dice_pool))))
+    }
+
     fn apply_dice_min_max(
         &mut self,
         pool_id: NodeId,
@@ -864,9 +1397,11 @@ impl ExecutionContext {
                 if detail.is_kept {
                     if is_max && detail.result > target_val {
                         detail.result = target_val;
+                        detail.was_clamped = true;
                         changed = true;
                     } else if !is_max && detail.result < target_val {
                         detail.result = target_val;
+                        detail.was_clamped = true;
                         changed = true;
                     }
                 }
@@ -885,6 +1420,17 @@ impl ExecutionContext {
         pool_id: NodeId,
         mod_param_node: ModParamNode,
         outcome: DieOutcome,
+    ) -> Result<Option<RuntimeValue>, String> {
+        self.mark_successes_from_dice_pool(pool_id, mod_param_node, outcome, None)
+    }
+
+    // miss_outcome 为 Some 时，不满足条件的保留骰子也被标记 (net 将其记为失败)
+    fn mark_successes_from_dice_pool(
+        &mut self,
+        pool_id: NodeId,
+        mod_param_node: ModParamNode,
+        outcome: DieOutcome,
+        miss_outcome: Option<DieOutcome>,
     ) -> Result<Option<RuntimeValue>, String> {
         let pool_ready = self.ensure_ready(pool_id)?;
         let mod_param_ready = self.ensure_ready(mod_param_node.value)?;
@@ -894,6 +1440,8 @@ impl ExecutionContext {
             let mod_param_op = mod_param_node.operator;
             let compare_func = get_compare_function(mod_param_op, mod_param_value);
 
+            // get_dice_pool 已经复制了一份骰池 (原值仍留在 memory 中供渲染使用)，
+            // 这里直接移动其明细，不再复制
             let mut success_pool = SuccessPoolType {
                 success_count: 0,
                 face: dice_pool.face,
@@ -904,10 +1452,16 @@ impl ExecutionContext {
                 if detail.is_kept {
                     if compare_func(detail.result as f64) {
                         detail.outcome = outcome.clone();
+                        // 被消耗的成功骰不再保留，之后的修饰符不再作用于它
+                        if outcome == DieOutcome::Spent {
+                            detail.is_kept = false;
+                        }
+                    } else if let Some(miss_outcome) = &miss_outcome {
+                        detail.outcome = miss_outcome.clone();
                     }
                 }
             }
-            success_pool.renew_success_count();
+            success_pool.renew_success_count(self.clamp_net_successes);
             Ok(Some(RuntimeValue::SuccessPool(Box::new(success_pool))))
         } else {
             Ok(None)
@@ -935,7 +1489,7 @@ impl ExecutionContext {
                     }
                 }
             }
-            success_pool.renew_success_count();
+            success_pool.renew_success_count(self.clamp_net_successes);
             Ok(Some(RuntimeValue::SuccessPool(Box::new(success_pool))))
         } else {
             Ok(None)
@@ -949,12 +1503,22 @@ impl ExecutionContext {
         mod_param_node: Option<ModParamNode>,
         limit_node: Option<LimitNode>,
         merge_fn: MergeFn,
-        removed: bool, // 如果为真，满足条件的骰子将会从动画中移除
+        kind: DynamicOpKind, // 重投时满足条件的骰子将会从动画中移除
     ) -> Result<Option<RuntimeValue>, String>
     where
         MergeFn: Fn(&mut DynamicState) -> Result<Vec<(usize, i32)>, String>,
     {
         let idx = node_id.to_index();
+        // 每个满足条件的骰子新增的骰子数，只有 !x 会大于 1
+        let dice_per_trigger = match &self.graph.nodes[idx] {
+            EvalNode::DiceExplodeMulti(_, count, _, _) => *count as u32,
+            _ => 1,
+        };
+        // !total 只在骰子池总和满足条件时才开始扫描
+        let total_condition = match &self.graph.nodes[idx] {
+            EvalNode::DiceExplodeIfTotal(_, mp) => Some(mp.clone()),
+            _ => None,
+        };
 
         // ====================================================
         // 阶段 1: 如果不是dynamic状态则进行初始化，同时拿到最新一次的投掷数据
@@ -978,12 +1542,29 @@ impl ExecutionContext {
                     },
                     None => true,
                 };
+                let limit_size_ready = match &limit_node {
+                    Some(ln) => match ln.limit_size {
+                        Some(id) => self.ensure_ready(id)?,
+                        None => true,
+                    },
+                    None => true,
+                };
                 let mod_ready = match &mod_param_node {
                     Some(node) => self.ensure_ready(node.value.clone())?,
                     None => true,
                 };
+                let total_ready = match &total_condition {
+                    Some(node) => self.ensure_ready(node.value)?,
+                    None => true,
+                };
 
-                if dp_ready && limit_count_ready && limit_times_ready && mod_ready {
+                if dp_ready
+                    && limit_count_ready
+                    && limit_times_ready
+                    && limit_size_ready
+                    && mod_ready
+                    && total_ready
+                {
                     let initial_pool = self.get_dice_pool(dp_id)?.unwrap();
                     let limit_count = match &limit_node {
                         Some(ln) => match ln.limit_counts {
@@ -1005,10 +1586,21 @@ impl ExecutionContext {
                         },
                         None => None,
                     };
+                    let limit_size = match &limit_node {
+                        Some(ln) => match ln.limit_size {
+                            Some(id) => {
+                                let val = self.get_number(id)?.unwrap();
+                                Some(val as usize)
+                            }
+                            None => None,
+                        },
+                        None => None,
+                    };
                     self.memory[idx] = NodeState::Dynamic(Box::new(DynamicState {
                         pool: initial_pool,
                         limit_times: limit_times,
                         limit_count: limit_count,
+                        limit_size,
                         pending_dice: Vec::new(),
                     }));
                     true
@@ -1031,11 +1623,7 @@ impl ExecutionContext {
             None => {
                 // 先获取当前的最大面值
                 let max_face_val = if let NodeState::Dynamic(state) = &self.memory[idx] {
-                    match state.pool.face {
-                        DiceFace::Number(n) => n as f64,
-                        DiceFace::Fudge => 1.0, // Fudge: -1, 0, 1
-                        DiceFace::Coin => 1.0,  // Coin: 0, 1
-                    }
+                    state.pool.face.max_value() as f64
                 } else {
                     unreachable!()
                 };
@@ -1043,6 +1631,13 @@ impl ExecutionContext {
             }
         };
         let compare_func = get_compare_function(operator, target_value);
+        let total_compare = match total_condition {
+            Some(node) => {
+                let val = self.get_number(node.value)?.unwrap();
+                Some(get_compare_function(node.operator, val))
+            }
+            None => None,
+        };
 
         // ====================================================
         // 阶段 3: 状态机循环 (State Machine Loop)
@@ -1050,11 +1645,26 @@ impl ExecutionContext {
 
         let mut request_to_send: Option<RuntimeRequest> = None;
         let mut final_result: Option<RuntimeValue> = None;
+        let tracing = self.trace.is_some();
+        let mut events = Vec::new();
+        let trace_id = node_id.to_index() as u32;
 
         if let NodeState::Dynamic(state) = &mut self.memory[idx] {
             // --- A: 合并阶段 ---
             // 并收集新的骰子结果
-            let new_dice = if is_init {
+            if tracing && !is_init {
+                events.push(TraceEvent::Resolved {
+                    node_id: trace_id,
+                    kind,
+                    results: state.pending_dice.iter().filter_map(|d| d.1).collect(),
+                });
+            }
+            let new_dice = if is_init
+                && let Some(total_compare) = &total_compare
+                && !total_compare(state.pool.total as f64)
+            {
+                Vec::new()
+            } else if is_init {
                 state
                     .pool
                     .details
@@ -1071,34 +1681,98 @@ impl ExecutionContext {
             // --- B: 扫描阶段 ---
             // 是否达到次数限制，没有达到，则可以继续扫描
             if state.try_resume_times() {
+                // 爆炸还需受全局上限约束
+                let mut explosion_budget = match (&kind, self.explosion_cap) {
+                    (DynamicOpKind::Explode, Some(cap)) => {
+                        Some(cap.saturating_sub(self.explosion_count))
+                    }
+                    _ => None,
+                };
+                // 骰子池大小上限：本轮最多还能加入多少个骰子
+                let mut size_budget = state
+                    .limit_size
+                    .map(|size| size.saturating_sub(state.pool.details.len()));
+                let mut triggered_by = Vec::new();
                 let new_rolls = new_dice
                     .into_iter()
                     .filter_map(|(i, result)| {
-                        if compare_func(result as f64) && state.try_resume_count() {
+                        if !compare_func(result as f64) {
+                            return None;
+                        }
+                        if size_budget == Some(0) {
+                            return None;
+                        }
+                        if !state.try_resume_count() {
+                            return None;
+                        }
+                        let accepted = match explosion_budget.as_mut() {
+                            Some(budget) if *budget < dice_per_trigger => {
+                                // 全局上限已用尽，停止爆炸并标记
+                                self.explosion_cap_reached = true;
+                                false
+                            }
+                            Some(budget) => {
+                                *budget -= dice_per_trigger;
+                                true
+                            }
                             // 这个骰子符合条件，并且次数限制允许，加入新请求列表
+                            None => true,
+                        };
+                        if accepted {
+                            // 只有真正加入的骰子才占用骰子池大小的余量
+                            if let Some(budget) = size_budget.as_mut() {
+                                *budget -= 1;
+                            }
+                            triggered_by.push(result);
                             Some(i)
                         } else {
                             None
                         }
                     })
                     .collect::<Vec<usize>>();
+                // !x 每个触发的骰子新增多个骰子，先检查总数再构造待处理列表
+                let requested = new_rolls.len() as u64 * dice_per_trigger as u64;
+                if self
+                    .dice_budget
+                    .is_some_and(|budget| requested > budget as u64)
+                {
+                    return Err("Dice count limit exceeded".to_string());
+                }
+                if kind == DynamicOpKind::Explode {
+                    self.explosion_count = self
+                        .explosion_count
+                        .saturating_add(requested.min(u32::MAX as u64) as u32);
+                }
 
                 // 如果不为空，准备新的接受对象，并准备请求
                 if !new_rolls.is_empty() {
-                    if removed {
+                    if kind == DynamicOpKind::Reroll {
                         for &idx in new_rolls.iter() {
                             self.remove_requests
                                 .extend(state.pool.details[idx].roll_id.iter());
                         }
                     }
 
-                    state.pending_dice = new_rolls.iter().map(|i| (*i, None, None)).collect();
+                    state.pending_dice = new_rolls
+                        .iter()
+                        .flat_map(|i| {
+                            std::iter::repeat_n((*i, None, None), dice_per_trigger as usize)
+                        })
+                        .collect();
 
                     // 构造请求
-                    let count = new_rolls.len() as i32;
+                    let count = state.pending_dice.len() as i32;
+                    if tracing {
+                        events.push(TraceEvent::Requested {
+                            node_id: trace_id,
+                            kind,
+                            triggered_by,
+                            count: count as u32,
+                        });
+                    }
                     request_to_send = Some(RuntimeRequest {
                         node_id,
-                        face: state.pool.face.clone(),
+                        face: state.pool.face.request_face(),
                         count: count as u32,
                     });
                 }
@@ -1108,16 +1782,26 @@ impl ExecutionContext {
             // 没有请求要发，且没有待处理的骰子，说明结束了
             if request_to_send.is_none() && state.pending_dice.is_empty() {
                 state.pool.renew_total();
+                if tracing {
+                    events.push(TraceEvent::Finished {
+                        node_id: trace_id,
+                        kind,
+                        total: state.pool.total,
+                    });
+                }
                 final_result = Some(RuntimeValue::DicePool(Box::new(state.pool.clone())));
             }
         }
+        for event in events {
+            self.record(event);
+        }
 
         // ====================================================
         // 阶段 4: 执行副作用
         // ====================================================
 
         if let Some(req) = request_to_send {
-            self.requests.push(req);
+            self.push_request(req);
             return Ok(None);
         }
 
@@ -1129,6 +1813,244 @@ impl ExecutionContext {
         }
     }
 
+    // 追加骰子直到成功数达标：每轮请求的骰子数等于还差的成功数，因此恰好在
+    // 第 N 个成功出现时停止，与逐个投掷的结果相同；追加的骰子数受单节点与全局爆炸上限约束
+    fn process_explode_until(
+        &mut self,
+        node_id: NodeId,
+        dp_id: NodeId,
+        op: CompareOp,
+        value: f64,
+        quota: i32,
+    ) -> Result<Option<RuntimeValue>, String> {
+        let idx = node_id.to_index();
+
+        if !matches!(self.memory[idx], NodeState::Dynamic(_)) {
+            if !self.ensure_ready(dp_id)? {
+                return Ok(None);
+            }
+            let initial_pool = self.get_dice_pool(dp_id)?.unwrap();
+            self.memory[idx] = NodeState::Dynamic(Box::new(DynamicState {
+                pool: initial_pool,
+                limit_times: None,
+                limit_count: Some(EXPLODE_UNTIL_MAX_EXTRA_DICE),
+                limit_size: None,
+                pending_dice: Vec::new(),
+            }));
+        }
+
+        let compare_func = get_compare_function(op, value);
+        let NodeState::Dynamic(state) = &mut self.memory[idx] else {
+            unreachable!()
+        };
+
+        let trace_id = node_id.to_index() as u32;
+        let kind = DynamicOpKind::Explode;
+
+        // 合并上一轮追加的骰子
+        if let Some(trace) = self.trace.as_mut()
+            && !state.pending_dice.is_empty()
+        {
+            trace.push(TraceEvent::Resolved {
+                node_id: trace_id,
+                kind,
+                results: state.pending_dice.iter().filter_map(|d| d.1).collect(),
+            });
+        }
+        for (_, value, roll_id) in std::mem::take(&mut state.pending_dice) {
+            let new_value = value.ok_or("Some value is missing".to_string())?;
+            state.pool.details.push(DieDetail {
+                result: new_value,
+                roll_history: vec![new_value],
+                roll_id: vec![roll_id.ok_or("Some value is missing")?],
+                is_kept: true,
+                outcome: DieOutcome::None,
+                is_rerolled: false,
+                replaced_by: None,
+                exploded_times: 0,
+                was_clamped: false,
+            });
+        }
+
+        let successes = state
+            .pool
+            .details
+            .iter()
+            .filter(|d| d.is_kept && compare_func(d.result as f64))
+            .count();
+        let missing = (quota as usize).saturating_sub(successes);
+        // limit_count 记录本节点还能追加的骰子数
+        let count_budget = state.limit_count.unwrap_or(0).max(0) as usize;
+        let mut count = missing.min(count_budget);
+        if let Some(cap) = self.explosion_cap {
+            let explosion_budget = cap.saturating_sub(self.explosion_count) as usize;
+            if explosion_budget < count {
+                self.explosion_cap_reached = true;
+                count = explosion_budget;
+            }
+        }
+
+        if count > 0 {
+            state.limit_count = Some((count_budget - count) as i32);
+            self.explosion_count += count as u32;
+            if let Some(trace) = self.trace.as_mut() {
+                trace.push(TraceEvent::Requested {
+                    node_id: trace_id,
+                    kind,
+                    triggered_by: Vec::new(),
+                    count: count as u32,
+                });
+            }
+            let face = state.pool.face.request_face();
+            state.pending_dice = vec![(0, None, None); count];
+            self.push_request(RuntimeRequest {
+                node_id,
+                face,
+                count: count as u32,
+            });
+            return Ok(None);
+        }
+
+        state.pool.renew_total();
+        if let Some(trace) = self.trace.as_mut() {
+            trace.push(TraceEvent::Finished {
+                node_id: trace_id,
+                kind,
+                total: state.pool.total,
+            });
+        }
+        let result = RuntimeValue::DicePool(Box::new(state.pool.clone()));
+        self.memory[idx] = NodeState::Computed(result.clone());
+        Ok(Some(result))
+    }
+
+    // 整池重投：骰子池总和满足条件时，重投所有被保留的骰子，直到不满足条件或达到 lt 次数限制
+    fn process_pool_reroll(
+        &mut self,
+        node_id: NodeId,
+        dp_id: NodeId,
+        mod_param_node: ModParamNode,
+        limit_node: Option<LimitNode>,
+    ) -> Result<Option<RuntimeValue>, String> {
+        let idx = node_id.to_index();
+
+        // 初始化：等待依赖项就绪后进入 dynamic 状态
+        if !matches!(self.memory[idx], NodeState::Dynamic(_)) {
+            let dp_ready = self.ensure_ready(dp_id)?;
+            let mod_ready = self.ensure_ready(mod_param_node.value)?;
+            let limit_times_ready = match limit_node.as_ref().and_then(|ln| ln.limit_times) {
+                Some(id) => self.ensure_ready(id)?,
+                None => true,
+            };
+            if !(dp_ready && mod_ready && limit_times_ready) {
+                return Ok(None);
+            }
+            let initial_pool = self.get_dice_pool(dp_id)?.unwrap();
+            let limit_times = match limit_node.as_ref().and_then(|ln| ln.limit_times) {
+                Some(id) => Some(self.get_number(id)?.unwrap() as i32),
+                None => None,
+            };
+            self.memory[idx] = NodeState::Dynamic(Box::new(DynamicState {
+                pool: initial_pool,
+                limit_times,
+                limit_count: None,
+                limit_size: None,
+                pending_dice: Vec::new(),
+            }));
+        }
+
+        let target_value = self.get_number(mod_param_node.value)?.unwrap();
+        let compare_func = get_compare_function(mod_param_node.operator, target_value);
+
+        let NodeState::Dynamic(state) = &mut self.memory[idx] else {
+            unreachable!()
+        };
+
+        let trace_id = node_id.to_index() as u32;
+        let kind = DynamicOpKind::RerollPool;
+
+        // 合并上一轮的重投结果：旧骰子标记为重投并丢弃，新骰子加入骰子池
+        if let Some(trace) = self.trace.as_mut()
+            && !state.pending_dice.is_empty()
+        {
+            trace.push(TraceEvent::Resolved {
+                node_id: trace_id,
+                kind,
+                results: state.pending_dice.iter().filter_map(|d| d.1).collect(),
+            });
+        }
+        for (die_idx, value, roll_id) in std::mem::take(&mut state.pending_dice) {
+            let new_value = value.ok_or("Some value is missing".to_string())?;
+            let new_idx = state.pool.details.len();
+            let old_die = &mut state.pool.details[die_idx];
+            old_die.is_rerolled = true;
+            old_die.is_kept = false;
+            old_die.replaced_by = Some(new_idx);
+            state.pool.details.push(DieDetail {
+                result: new_value,
+                roll_history: vec![new_value],
+                roll_id: vec![roll_id.ok_or("Some value is missing")?],
+                is_kept: true,
+                outcome: DieOutcome::None,
+                is_rerolled: false,
+                replaced_by: None,
+                exploded_times: 0,
+                was_clamped: false,
+            });
+        }
+        state.pool.renew_total();
+
+        // 判定：总和满足条件且次数限制允许时，重投所有被保留的骰子
+        let kept_indices: Vec<usize> = state
+            .pool
+            .details
+            .iter()
+            .enumerate()
+            .filter(|(_, d)| d.is_kept)
+            .map(|(i, _)| i)
+            .collect();
+        if !kept_indices.is_empty()
+            && compare_func(state.pool.total as f64)
+            && state.try_resume_times()
+        {
+            // 被重投的骰子需要从动画中移除
+            for &i in kept_indices.iter() {
+                self.remove_requests
+                    .extend(state.pool.details[i].roll_id.iter());
+            }
+            if let Some(trace) = self.trace.as_mut() {
+                trace.push(TraceEvent::Requested {
+                    node_id: trace_id,
+                    kind,
+                    triggered_by: kept_indices
+                        .iter()
+                        .map(|&i| state.pool.details[i].result)
+                        .collect(),
+                    count: kept_indices.len() as u32,
+                });
+            }
+            let request = RuntimeRequest {
+                node_id,
+                face: state.pool.face.request_face(),
+                count: kept_indices.len() as u32,
+            };
+            state.pending_dice = kept_indices.into_iter().map(|i| (i, None, None)).collect();
+            self.push_request(request);
+            return Ok(None);
+        }
+
+        if let Some(trace) = self.trace.as_mut() {
+            trace.push(TraceEvent::Finished {
+                node_id: trace_id,
+                kind,
+                total: state.pool.total,
+            });
+        }
+        let result = RuntimeValue::DicePool(Box::new(state.pool.clone()));
+        self.memory[idx] = NodeState::Computed(result.clone());
+        Ok(Some(result))
+    }
+
     pub fn get_root_id(&self) -> NodeId {
         self.graph.root.clone()
     }
@@ -1142,50 +2064,53 @@ impl ExecutionContext {
     }
 }
 
-fn keep_elements_preserve_order(values: Vec<f64>, raw_count: f64, keep_highest: bool) -> Vec<f64> {
-    if raw_count < 0.0 {
-        return Vec::new();
+// 将十位骰与个位骰组合为一个 1~100 的骰子：d10 的 10 面读作 0，
+// 十位为 00 且个位为 0 时读作 100，roll_history 中依次记录十位与个位的读数
+fn percentile_die(tens: (i32, RollId), units: (i32, RollId)) -> DieDetail {
+    let tens_value = (tens.0 % 10) * 10;
+    let units_value = units.0 % 10;
+    let result = match tens_value + units_value {
+        0 => 100,
+        v => v,
+    };
+    DieDetail {
+        result,
+        roll_history: vec![tens_value, units_value],
+        roll_id: vec![tens.1, units.1],
+        is_kept: true,
+        outcome: DieOutcome::None,
+        is_rerolled: false,
+        replaced_by: None,
+        exploded_times: 0,
+        was_clamped: false,
     }
-
-    let count = raw_count as usize;
-
-    if count >= values.len() {
-        return values;
-    }
-
-    let mut indexed_values: Vec<(usize, f64)> = values.into_iter().enumerate().collect();
-
-    indexed_values.sort_by(|a, b| {
-        let (val_a, val_b) = (a.1, b.1);
-        if keep_highest {
-            // true (Max): 降序 (b compare a)
-            val_b
-                .partial_cmp(&val_a)
-                .unwrap_or(std::cmp::Ordering::Equal)
-        } else {
-            // false (Min): 升序 (a compare b)
-            val_a
-                .partial_cmp(&val_b)
-                .unwrap_or(std::cmp::Ordering::Equal)
-        }
-    });
-
-    let mut top_k: Vec<(usize, f64)> = indexed_values.into_iter().take(count).collect();
-
-    top_k.sort_by_key(|(index, _)| *index);
-
-    let result = top_k.into_iter().map(|(_, val)| val).collect();
-
-    result
 }
 
-fn get_compare_function(op: CompareOp, number: f64) -> impl Fn(f64) -> bool {
-    move |x: f64| match op {
-        CompareOp::Greater => x > number,
-        CompareOp::GreaterEqual => x >= number,
-        CompareOp::Less => x < number,
-        CompareOp::LessEqual => x <= number,
-        CompareOp::Equal => (x - number).abs() < f64::EPSILON,
-        CompareOp::NotEqual => (x - number).abs() >= f64::EPSILON,
+// 普通爆炸的合并逻辑：把爆炸产生的新骰子加入骰子池，返回新骰子的索引和结果供下一轮扫描
+fn merge_exploded_dice(state: &mut DynamicState) -> Result<Vec<(usize, i32)>, String> {
+    let mut new_rolls = Vec::new();
+    let mut previous = None;
+    for (idx, value, roll_id) in state.pending_dice.iter() {
+        // 原本的骰子标记explode + 1；!x 的一次触发对应多个相邻的待处理骰子，只计一次
+        if previous != Some(*idx) {
+            state.pool.details[*idx].exploded_times += 1;
+        }
+        previous = Some(*idx);
+        // 将新的骰子加入details列表
+        let new_value = value.ok_or("Some value is missing".to_string())?;
+        state.pool.details.push(DieDetail {
+            result: new_value,
+            roll_history: vec![new_value],
+            roll_id: vec![roll_id.ok_or("Some value is missing")?],
+            is_kept: true,
+            outcome: DieOutcome::None,
+            is_rerolled: false,
+            replaced_by: None,
+            exploded_times: 0,
+            was_clamped: false,
+        });
+        // 记录新骰子的索引和结果
+        new_rolls.push((state.pool.details.len() - 1, new_value));
     }
+    Ok(new_rolls)
 }
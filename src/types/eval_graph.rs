@@ -1,4 +1,4 @@
-use super::expr::CompareOp;
+use super::expr::{CompareOp, EachOp};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct NodeId(pub u32); // A simple wrapper for node identifiers
@@ -14,10 +14,43 @@ pub struct EvalGraph {
     pub root: NodeId,
 }
 
+// 检查图中引用的节点是否都存在，且图中没有环
+// 编译器总是先生成子节点再生成父节点，因此要求每个节点只引用下标比自己小的节点，
+// 这同时保证了图是无环的
+pub fn validate(graph: &EvalGraph) -> Result<(), String> {
+    if graph.root.to_index() >= graph.nodes.len() {
+        return Err(format!(
+            "Root node {} is out of range ({} nodes)",
+            graph.root.0,
+            graph.nodes.len()
+        ));
+    }
+    for (index, node) in graph.nodes.iter().enumerate() {
+        for child in node.children() {
+            if child.to_index() >= graph.nodes.len() {
+                return Err(format!(
+                    "Node {} references node {}, which is out of range ({} nodes)",
+                    index,
+                    child.0,
+                    graph.nodes.len()
+                ));
+            }
+            if child.to_index() >= index {
+                return Err(format!(
+                    "Node {} references node {}, which is not emitted before it",
+                    index, child.0
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
 pub enum EvalNode {
     // 数值类型与列表类型的基本类型
     Constant(f64),
     ListConstruct(Vec<NodeId>),
+    ConstantList(Vec<f64>), // 元素全为常数的长列表，直接保存数值，不再逐个建立常数节点
 
     // 单目运算
     NumNegate(NodeId),
@@ -29,6 +62,7 @@ pub enum EvalNode {
     NumDivide(NodeId, NodeId),
     NumIntDivide(NodeId, NodeId),
     NumModulo(NodeId, NodeId),
+    NumCompare(NodeId, CompareOp, NodeId), // 满足比较条件为 1，否则为 0
     // 二元运算——列表
     Concat(NodeId, NodeId),
     ListAdd(NodeId, NodeId),
@@ -46,12 +80,21 @@ pub enum EvalNode {
     NumFloor(NodeId),
     NumCeil(NodeId),
     NumRound(NodeId),
+    NumRoundEven(NodeId),
     NumAbs(NodeId),
     NumMax(NodeId),
     NumMin(NodeId),
+    NumMaxOr(NodeId, NodeId), // 列表为空时取第二个节点的值
+    NumMinOr(NodeId, NodeId),
     NumSum(NodeId),
     NumAvg(NodeId),
     NumLen(NodeId),
+    // 结果为第一个节点减第二个节点，输出时显示为 margin
+    NumMargin(NodeId, NodeId),
+    // 保留总和最大的若干组并求和，输出时显示为 groupkh
+    NumGroupKh(NodeId, NodeId),
+    DiceHighest(NodeId), // 被保留骰子中的最大点数
+    DiceLowest(NodeId),  // 被保留骰子中的最小点数
     // 函数调用——返回列表
     ListFloor(NodeId),
     ListCeil(NodeId),
@@ -61,6 +104,8 @@ pub enum EvalNode {
     ListMin(NodeId, NodeId),
     ListSort(NodeId),
     ListSortDesc(NodeId),
+    ListFilterEven(NodeId), // 保留下标为偶数的元素 (下标从 0 开始)
+    ListFilterOdd(NodeId),  // 保留下标为奇数的元素 (下标从 0 开始)
     ListToListFromDicePool(NodeId),
     ListToListFromSuccessPool(NodeId),
     ListFilter(NodeId, ModParamNode),
@@ -69,20 +114,147 @@ pub enum EvalNode {
     DiceStandard(NodeId, NodeId),
     DiceFudge(NodeId),
     DiceCoin(NodeId),
+    DicePercentile,
+    DiceLabeled(NodeId, Vec<(String, i32)>), // 带标签的骰子，投掷 d(面数) 后映射为对应面的值
     DiceKeepHigh(NodeId, NodeId),
     DiceKeepLow(NodeId, NodeId),
     DiceDropHigh(NodeId, NodeId),
     DiceDropLow(NodeId, NodeId),
     DiceMin(NodeId, NodeId),
     DiceMax(NodeId, NodeId),
+    DiceMapEach(NodeId, EachOp, NodeId), // 对每颗保留的骰子做同样的运算，each(XdY, +N)
     DiceExplode(NodeId, Option<ModParamNode>, Option<LimitNode>),
     DiceCompoundExplode(NodeId, Option<ModParamNode>, Option<LimitNode>),
+    DiceExplodeOnce(NodeId, Option<ModParamNode>, Option<LimitNode>),
+    DiceExplodeMulti(NodeId, i32, Option<ModParamNode>, Option<LimitNode>), // 每次触发新增多个骰子
+    DiceExplodeUntil(NodeId, CompareOp, f64, i32), // 追加骰子直到成功数达到指定值
     DiceReroll(NodeId, ModParamNode, Option<LimitNode>),
+    DiceRerollPool(NodeId, ModParamNode, Option<LimitNode>),
     DiceSubtractFailures(NodeId, ModParamNode),
+    DiceRerollMax(NodeId, ModParamNode), // 满足条件的骰子改为最大面 (调试用)
+    DiceExplodeIfTotal(NodeId, ModParamNode), // 总和满足条件时，最大面的骰子爆炸一轮
+    DiceConditionalDropHigh(NodeId, NodeId, ModParamNode),
+    DiceConditionalDropLow(NodeId, NodeId, ModParamNode),
+    DiceRemoveN(NodeId, NodeId, ModParamNode), // 按投掷顺序移除前 N 个满足条件的骰子
+    DiceKeepRelative(NodeId, CompareOp),       // 保留与被保留骰子平均值比较后满足条件的骰子
+    DiceKeepDistinct(NodeId),                  // 每种点数只保留第一个骰子
+    DiceKeepRankRange(NodeId, i32, i32),       // 保留从大到小排名在 [low, high] 内的骰子
+    DiceFromSuccessPool(NodeId),
     DiceCountSuccessesFromDicePool(NodeId, ModParamNode),
+    DiceSpendSuccessesFromDicePool(NodeId, ModParamNode), // 计数成功，并将成功的骰子标记为不保留
+    DiceNetSuccess(NodeId, ModParamNode),                 // 满足条件为成功，其余保留的骰子为失败
+    DiceWeightedSuccesses(NodeId, Vec<(CompareOp, f64, i32)>), // 按权重表计数成功，取第一个满足的条件
     DiceDeductFailuresFromDicePool(NodeId, ModParamNode),
     DiceCountSuccesses(NodeId, ModParamNode),
     DiceDeductFailures(NodeId, ModParamNode),
+    DiceCascade(NodeId, ModParamNode, LimitNode), // 每个成功奖励一个骰子，结果为成功池
+}
+
+impl EvalNode {
+    // 该节点直接引用的所有节点 (包括比较参数与限制中的节点)
+    pub fn children(&self) -> Vec<NodeId> {
+        use EvalNode::*;
+        match self {
+            Constant(_) | ConstantList(_) | DicePercentile => Vec::new(),
+            ListConstruct(items) => items.clone(),
+            NumNegate(a)
+            | NumFloor(a)
+            | NumCeil(a)
+            | NumRound(a)
+            | NumRoundEven(a)
+            | NumAbs(a)
+            | NumMax(a)
+            | NumMin(a)
+            | NumSum(a)
+            | NumAvg(a)
+            | NumLen(a)
+            | DiceHighest(a)
+            | DiceLowest(a)
+            | ListFloor(a)
+            | ListCeil(a)
+            | ListRound(a)
+            | ListAbs(a)
+            | ListSort(a)
+            | ListSortDesc(a)
+            | ListFilterEven(a)
+            | ListFilterOdd(a)
+            | ListToListFromDicePool(a)
+            | ListToListFromSuccessPool(a)
+            | DiceFudge(a)
+            | DiceCoin(a)
+            | DiceLabeled(a, _)
+            | DiceKeepRelative(a, _)
+            | DiceKeepDistinct(a)
+            | DiceKeepRankRange(a, _, _)
+            | DiceWeightedSuccesses(a, _)
+            | DiceExplodeUntil(a, _, _, _)
+            | DiceFromSuccessPool(a) => vec![*a],
+            NumAdd(a, b)
+            | NumSubtract(a, b)
+            | NumMargin(a, b)
+            | NumGroupKh(a, b)
+            | NumMultiply(a, b)
+            | NumDivide(a, b)
+            | NumIntDivide(a, b)
+            | NumModulo(a, b)
+            | NumCompare(a, _, b)
+            | Concat(a, b)
+            | ListAdd(a, b)
+            | ListMultiply(a, b)
+            | ListSubtract(a, b)
+            | ListSubtractReverse(a, b)
+            | ListDivide(a, b)
+            | ListDivideReverse(a, b)
+            | ListIntDivide(a, b)
+            | ListIntDivideReverse(a, b)
+            | ListModulo(a, b)
+            | ListModuloReverse(a, b)
+            | NumMaxOr(a, b)
+            | NumMinOr(a, b)
+            | ListMax(a, b)
+            | ListMin(a, b)
+            | DiceStandard(a, b)
+            | DiceKeepHigh(a, b)
+            | DiceKeepLow(a, b)
+            | DiceDropHigh(a, b)
+            | DiceDropLow(a, b)
+            | DiceMin(a, b)
+            | DiceMax(a, b)
+            | DiceMapEach(a, _, b) => vec![*a, *b],
+            ListFilter(a, mp)
+            | DiceSubtractFailures(a, mp)
+            | DiceRerollMax(a, mp)
+            | DiceExplodeIfTotal(a, mp)
+            | DiceCountSuccessesFromDicePool(a, mp)
+            | DiceSpendSuccessesFromDicePool(a, mp)
+            | DiceNetSuccess(a, mp)
+            | DiceDeductFailuresFromDicePool(a, mp)
+            | DiceCountSuccesses(a, mp)
+            | DiceDeductFailures(a, mp) => vec![*a, mp.value],
+            DiceConditionalDropHigh(a, b, mp)
+            | DiceConditionalDropLow(a, b, mp)
+            | DiceRemoveN(a, b, mp) => vec![*a, *b, mp.value],
+            DiceExplode(a, mp, limit)
+            | DiceCompoundExplode(a, mp, limit)
+            | DiceExplodeOnce(a, mp, limit)
+            | DiceExplodeMulti(a, _, mp, limit) => {
+                let mut ids = vec![*a];
+                ids.extend(mp.iter().map(|mp| mp.value));
+                ids.extend(limit.iter().flat_map(LimitNode::children));
+                ids
+            }
+            DiceReroll(a, mp, limit) | DiceRerollPool(a, mp, limit) => {
+                let mut ids = vec![*a, mp.value];
+                ids.extend(limit.iter().flat_map(LimitNode::children));
+                ids
+            }
+            DiceCascade(a, mp, limit) => {
+                let mut ids = vec![*a, mp.value];
+                ids.extend(limit.children());
+                ids
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -95,4 +267,79 @@ pub struct ModParamNode {
 pub struct LimitNode {
     pub limit_times: Option<NodeId>,
     pub limit_counts: Option<NodeId>,
+    pub limit_size: Option<NodeId>,
+}
+
+impl LimitNode {
+    fn children(&self) -> impl Iterator<Item = NodeId> {
+        [self.limit_times, self.limit_counts, self.limit_size]
+            .into_iter()
+            .flatten()
+    }
+}
+
+// ==========================================
+// 单元测试
+// ==========================================
+
+#[test]
+fn test_validate_compiled_graph() {
+    use crate::compiler::compile_hir_to_eval_graph;
+    use crate::grammar::parse_dice;
+    use crate::lower::lower_expr;
+    let expr = "let share a = 1d6; 4d6!!>=5lt2kh(a) + maxor(filter>3(3d8), a) + 2d10cs>5";
+    let hir = lower_expr(parse_dice(expr).unwrap()).unwrap();
+    assert_eq!(validate(&compile_hir_to_eval_graph(hir)), Ok(()));
+}
+
+#[test]
+fn test_validate_malformed_graph() {
+    use crate::runtime_engine::ExecutionContext;
+    // 引用不存在的节点
+    let graph = EvalGraph {
+        nodes: vec![
+            EvalNode::Constant(1.0),
+            EvalNode::NumAdd(NodeId(0), NodeId(5)),
+        ],
+        root: NodeId(1),
+    };
+    assert!(validate(&graph).unwrap_err().contains("out of range"));
+    assert!(ExecutionContext::new(graph).is_err());
+    // 两个节点互相引用，形成环
+    let graph = EvalGraph {
+        nodes: vec![
+            EvalNode::NumNegate(NodeId(1)),
+            EvalNode::NumNegate(NodeId(0)),
+        ],
+        root: NodeId(1),
+    };
+    assert!(validate(&graph).unwrap_err().contains("not emitted before"));
+    // 节点引用自身
+    let graph = EvalGraph {
+        nodes: vec![EvalNode::DiceFudge(NodeId(0))],
+        root: NodeId(0),
+    };
+    assert!(validate(&graph).is_err());
+    // 比较参数中的节点同样会被检查
+    let graph = EvalGraph {
+        nodes: vec![
+            EvalNode::Constant(4.0),
+            EvalNode::DiceStandard(NodeId(0), NodeId(0)),
+            EvalNode::DiceCountSuccesses(
+                NodeId(1),
+                ModParamNode {
+                    operator: CompareOp::Greater,
+                    value: NodeId(3),
+                },
+            ),
+        ],
+        root: NodeId(2),
+    };
+    assert!(validate(&graph).is_err());
+    // 根节点不存在
+    let graph = EvalGraph {
+        nodes: vec![EvalNode::Constant(1.0)],
+        root: NodeId(1),
+    };
+    assert!(validate(&graph).unwrap_err().contains("Root node"));
 }
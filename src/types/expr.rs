@@ -12,6 +12,7 @@ pub enum BinOp {
     Div,
     Mod,
     Idiv,
+    Compare(CompareOp), // 数值比较，结果为 1 或 0
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -24,10 +25,36 @@ pub enum CompareOp {
     NotEqual,
 }
 
+// each(pool, +N) 中作用于每颗骰子的运算
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EachOp {
+    Add,
+    Sub,
+    Mul,
+}
+
+impl EachOp {
+    // 骰子结果是 i32，溢出时取边界值而不是 panic
+    pub fn apply(&self, die: i32, value: i32) -> i32 {
+        match self {
+            EachOp::Add => die.saturating_add(value),
+            EachOp::Sub => die.saturating_sub(value),
+            EachOp::Mul => die.saturating_mul(value),
+        }
+    }
+}
+
 // 修饰符相关
 // Type1: keep_high、 keep_low、drop_high、drop_low、min、max 等接受1个atom
 // Type2: compound_explode、explode、reroll 接受1个mod_param，一个limit
 // Type3: count_successes等接受一个mod_param，没有limit
+// Type4: 条件丢弃 (dh、dl 带比较条件) 接受1个atom和1个mod_param
+// Type5: 与骰池平均值比较的保留 (kh>avg、kl<avg 等)，只有比较符，没有参数
+// Type6: kdistinct 等不接受任何参数
+// Type7: 加权计数成功 cs[=10:2, >=7:1]，接受一张比较条件到权重的表
+// Type8: 按排名区间保留 k2..4，接受上下两个atom
+// Type9: 每次触发爆炸出多个骰子 !x2，接受1个atom、可选的mod_param与limit
+// Type10: 追加骰子直到成功数达标 !until(cs>=5, 3)，接受1个mod_param与1个表达式
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Type1Op {
@@ -43,7 +70,10 @@ pub enum Type1Op {
 pub enum Type2Op {
     CompoundExplode,
     Explode,
+    ExplodeOnce, // 只有最初投出的骰子会爆炸，爆炸产生的骰子不会再爆炸
     Reroll,
+    RerollPool, // 骰子池总和满足条件时重投整个骰子池
+    Cascade,    // 每个成功奖励一个骰子，奖励骰子再次判定，得到成功池
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -51,6 +81,22 @@ pub enum Type3Op {
     CountSuccesses,
     DeductFailures,
     SubtractFailures,
+    Spend,          // 计数成功，成功的骰子被消耗 (不再保留)
+    Net,            // 满足条件计为成功，不满足计为失败，取净成功数
+    RerollMax,      // 满足条件的骰子直接改为最大面，仅在 debug-modifiers feature 下可解析
+    ExplodeIfTotal, // 骰子池总和满足条件时，最大面的骰子爆炸一轮
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type4Op {
+    ConditionalDropHigh,
+    ConditionalDropLow,
+    RemoveMatching, // 按投掷顺序移除前 N 个满足条件的骰子
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type6Op {
+    KeepDistinct, // 每种点数只保留第一个骰子
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -63,6 +109,7 @@ pub struct ModParam {
 pub struct Limit {
     pub limit_times: Option<Box<Expr>>,
     pub limit_counts: Option<Box<Expr>>,
+    pub limit_size: Option<Box<Expr>>, // 爆炸后骰子池的最大骰子数
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -87,11 +134,66 @@ pub struct Type3Modifier {
     pub param: ModParam,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct Type4Modifier {
+    pub lhs: Box<Expr>,
+    pub op: Type4Op,
+    pub count: Box<Expr>,
+    pub param: ModParam,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Type5Modifier {
+    pub lhs: Box<Expr>,
+    pub compare: CompareOp, // 骰子与被保留骰子平均值的比较方式
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Type6Modifier {
+    pub lhs: Box<Expr>,
+    pub op: Type6Op,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Type7Modifier {
+    pub lhs: Box<Expr>,
+    pub weights: Vec<(ModParam, Box<Expr>)>, // 按顺序取第一个满足的条件的权重
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Type8Modifier {
+    pub lhs: Box<Expr>,
+    pub low: Box<Expr>,  // 最高排名 (从 1 开始，1 为最大的骰子)
+    pub high: Box<Expr>, // 最低排名，包含在内
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Type9Modifier {
+    pub lhs: Box<Expr>,
+    pub count: Box<Expr>, // 每次触发新增的骰子数
+    pub param: Option<ModParam>,
+    pub limit: Option<Limit>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Type10Modifier {
+    pub lhs: Box<Expr>,
+    pub param: ModParam,  // 成功条件
+    pub quota: Box<Expr>, // 需要达到的成功数
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum ModifierNode {
     Type1(Type1Modifier),
     Type2(Type2Modifier),
     Type3(Type3Modifier),
+    Type4(Type4Modifier),
+    Type5(Type5Modifier),
+    Type6(Type6Modifier),
+    Type7(Type7Modifier),
+    Type8(Type8Modifier),
+    Type9(Type9Modifier),
+    Type10(Type10Modifier),
 }
 
 // 函数相关
@@ -105,12 +207,32 @@ pub enum FunctionName {
     Min,
     Sum,
     Avg,
+    Ravg,      // ravg(list)，等价于 roundeven(avg(list))
+    RoundEven, // roundeven(x)，四舍六入五成双
     Len,
     Rpdice,
+    Rep,
+    Gen, // gen(N, expr)，参数顺序与 rep 相反
+    AsDice,
     Sortd,
+    FilterEven, // filtereven(list)，保留下标为偶数的元素 (下标从 0 开始)
+    FilterOdd,  // filterodd(list)，保留下标为奇数的元素 (下标从 0 开始)
     Sort,
+    Margin,    // margin(roll, target)，等价于 roll - target
+    GroupKh,   // groupkh(g1, ..., gk, n)，保留总和最大的 n 组并求和
+    MaxOr,     // maxor(list, default)，列表为空时返回 default
+    MinOr,     // minor(list, default)，列表为空时返回 default
+    SortDice,  // sortdice(pool)，等价于 sort(tolist(pool))
+    SortDiced, // sortdiced(pool)，等价于 sortd(tolist(pool))
     ToList,
+    HighDie, // highdie(pool)，被保留骰子中的最大点数
+    LowDie,  // lowdie(pool)，被保留骰子中的最小点数
+    RoundTo, // roundto(x, m)，等价于 round(x / m) * m
+    FloorTo, // floorto(x, m)，等价于 floor(x / m) * m
+    CeilTo,  // ceilto(x, m)，等价于 ceil(x / m) * m
+    AllDice, // alldice(p1, ..., pk)，等价于 tolist(p1) + ... + tolist(pk)
     Filter(ModParam),
+    Each(EachOp), // each(pool, +N)，对骰池中每颗保留的骰子做同样的运算
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -122,9 +244,22 @@ pub struct FunctionCall {
 // 骰子相关
 #[derive(Debug, Clone, PartialEq)]
 pub enum DiceType {
-    Standard { count: Box<Expr>, sides: Box<Expr> },
-    Fudge { count: Box<Expr> },
-    Coin { count: Box<Expr> },
+    Standard {
+        count: Box<Expr>,
+        sides: Box<Expr>,
+    },
+    Fudge {
+        count: Box<Expr>,
+    },
+    Coin {
+        count: Box<Expr>,
+    },
+    Percentile, // d%%：十位骰与个位骰组合的百分骰
+    // d[miss:0, hit:1]：每一面带有标签的骰子，面值必须是常数整数
+    Labeled {
+        count: Box<Expr>,
+        faces: Vec<(String, Expr)>,
+    },
 }
 
 // 二元运算
@@ -135,6 +270,21 @@ pub struct BinaryOp {
     pub rhs: Box<Expr>,
 }
 
+// let 绑定: let name = value; 或 let share name = value;
+#[derive(Debug, Clone, PartialEq)]
+pub struct LetBinding {
+    pub name: String,
+    pub value: Box<Expr>,
+    pub shared: bool, // 为 true 时每次引用共用同一次投掷，否则每次引用独立投掷
+}
+
+// 表达式开头的若干 let 绑定，以及使用这些绑定的主体表达式
+#[derive(Debug, Clone, PartialEq)]
+pub struct LetExpr {
+    pub bindings: Vec<LetBinding>,
+    pub body: Box<Expr>,
+}
+
 // 表达式定义
 #[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
@@ -145,6 +295,11 @@ pub enum Expr {
     Modifier(ModifierNode),
     Function(FunctionCall),
     Binary(BinaryOp),
+    Let(LetExpr),
+    Var(String), // 对 let 绑定的引用
+    // 共享绑定展开后的值，编号相同的引用共用同一次投掷
+    // 只在降低阶段替换变量时产生，不由语法直接产生
+    Shared(u32, Box<Expr>),
 }
 
 impl Expr {
@@ -175,6 +330,17 @@ impl Expr {
         })
     }
 
+    pub fn percentile_dice() -> Self {
+        Expr::Dice(DiceType::Percentile)
+    }
+
+    pub fn labeled_dice(count: Expr, faces: Vec<(String, Expr)>) -> Self {
+        Expr::Dice(DiceType::Labeled {
+            count: Box::new(count),
+            faces,
+        })
+    }
+
     pub fn list(elements: Vec<Expr>) -> Self {
         Expr::List(elements)
     }
@@ -221,6 +387,84 @@ impl Expr {
         }))
     }
 
+    pub fn modifier_type4(lhs: Expr, op: Type4Op, count: Expr, param: ModParam) -> Self {
+        Expr::Modifier(ModifierNode::Type4(Type4Modifier {
+            lhs: Box::new(lhs),
+            op,
+            count: Box::new(count),
+            param,
+        }))
+    }
+
+    pub fn modifier_type5(lhs: Expr, compare: CompareOp) -> Self {
+        Expr::Modifier(ModifierNode::Type5(Type5Modifier {
+            lhs: Box::new(lhs),
+            compare,
+        }))
+    }
+
+    pub fn modifier_type6(lhs: Expr, op: Type6Op) -> Self {
+        Expr::Modifier(ModifierNode::Type6(Type6Modifier {
+            lhs: Box::new(lhs),
+            op,
+        }))
+    }
+
+    pub fn modifier_type8(lhs: Expr, low: Expr, high: Expr) -> Self {
+        Expr::Modifier(ModifierNode::Type8(Type8Modifier {
+            lhs: Box::new(lhs),
+            low: Box::new(low),
+            high: Box::new(high),
+        }))
+    }
+
+    pub fn modifier_type9(
+        lhs: Expr,
+        count: Expr,
+        param: Option<ModParam>,
+        limit: Option<Limit>,
+    ) -> Self {
+        Expr::Modifier(ModifierNode::Type9(Type9Modifier {
+            lhs: Box::new(lhs),
+            count: Box::new(count),
+            param,
+            limit,
+        }))
+    }
+
+    pub fn modifier_type10(lhs: Expr, param: ModParam, quota: Expr) -> Self {
+        Expr::Modifier(ModifierNode::Type10(Type10Modifier {
+            lhs: Box::new(lhs),
+            param,
+            quota: Box::new(quota),
+        }))
+    }
+
+    pub fn modifier_type7(lhs: Expr, weights: Vec<(ModParam, Expr)>) -> Self {
+        Expr::Modifier(ModifierNode::Type7(Type7Modifier {
+            lhs: Box::new(lhs),
+            weights: weights
+                .into_iter()
+                .map(|(param, weight)| (param, Box::new(weight)))
+                .collect(),
+        }))
+    }
+
+    pub fn let_in(bindings: Vec<LetBinding>, body: Expr) -> Self {
+        Expr::Let(LetExpr {
+            bindings,
+            body: Box::new(body),
+        })
+    }
+
+    pub fn let_binding(name: &str, value: Expr, shared: bool) -> LetBinding {
+        LetBinding {
+            name: name.to_string(),
+            value: Box::new(value),
+            shared,
+        }
+    }
+
     pub fn mod_param(operator: CompareOp, value: Expr) -> ModParam {
         ModParam {
             operator,
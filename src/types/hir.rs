@@ -1,4 +1,4 @@
-use super::expr::CompareOp;
+use super::expr::{CompareOp, EachOp};
 
 // ==========================================
 // HIR: 高级中间表示 (High-level Intermediate Representation)
@@ -22,6 +22,8 @@ pub enum NumberType {
     NumberBinary(NumberBinaryType),
     NumberFunction(NumberFunctionType),
     Neg(Box<NumberType>), // 唯一一个单目运算符，就不单独定义枚举了
+    // let share 绑定的值，编号相同的节点编译为同一个求值节点，只投掷一次
+    Shared(u32, Box<NumberType>),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -29,6 +31,8 @@ pub enum DicePoolType {
     Standard(Box<NumberType>, Box<NumberType>),   // XdY
     Fudge(Box<NumberType>),                       // XdF
     Coin(Box<NumberType>),                        // XdC
+    Percentile,                                   // d%%
+    Labeled(Box<NumberType>, Vec<(String, i32)>), // Xd[label:value, ...]
     KeepHigh(Box<DicePoolType>, Box<NumberType>), // (XdY)khZ
     KeepLow(Box<DicePoolType>, Box<NumberType>),  // (XdY)kl
     DropHigh(Box<DicePoolType>, Box<NumberType>), // (XdY)dhZ
@@ -37,8 +41,25 @@ pub enum DicePoolType {
     Max(Box<DicePoolType>, Box<NumberType>),      // (XdY)maxZ
     Explode(Box<DicePoolType>, Option<ModParam>, Option<Limit>), // (XdY)![mod_param][limit]
     CompoundExplode(Box<DicePoolType>, Option<ModParam>, Option<Limit>), // (XdY)!![mod_param][limit]
-    Reroll(Box<DicePoolType>, ModParam, Option<Limit>),                  // (XdY)r[mod_param][limit]
-    SubtractFailures(Box<DicePoolType>, ModParam),                       // (XdY)sfmod_param
+    ExplodeOnce(Box<DicePoolType>, Option<ModParam>, Option<Limit>), // (XdY)!o[mod_param][limit]
+    Reroll(Box<DicePoolType>, ModParam, Option<Limit>),              // (XdY)r[mod_param][limit]
+    RerollPool(Box<DicePoolType>, ModParam, Option<Limit>),          // (XdY)rp[mod_param][lt]
+    SubtractFailures(Box<DicePoolType>, ModParam),                   // (XdY)sfmod_param
+    RerollMax(Box<DicePoolType>, ModParam),                          // (XdY)rmaxmod_param
+    ConditionalDropHigh(Box<DicePoolType>, Box<NumberType>, ModParam), // (XdY)dhZ mod_param
+    ConditionalDropLow(Box<DicePoolType>, Box<NumberType>, ModParam), // (XdY)dlZ mod_param
+    RemoveN(Box<DicePoolType>, Box<NumberType>, ModParam),           // (XdY)rmZ mod_param
+    FromSuccessPool(Box<SuccessPoolType>),                           // asdice(success_pool_type)
+    KeepRelativeToAverage(Box<DicePoolType>, CompareOp),             // (XdY)kh>avg、(XdY)kl<avg
+    KeepDistinct(Box<DicePoolType>),                                 // (XdY)kdistinct
+    KeepRankRange(Box<DicePoolType>, i32, i32),                      // (XdY)k2..4，排名从 1 开始
+    Each(Box<DicePoolType>, EachOp, Box<NumberType>),                // each(XdY, +N)
+    // (XdY)!xN[mod_param][limit]，每次触发新增 N 个骰子
+    ExplodeMulti(Box<DicePoolType>, i32, Option<ModParam>, Option<Limit>),
+    // (XdY)!until(cs op value, N)，不断追加骰子直到成功数达到 N
+    ExplodeUntil(Box<DicePoolType>, CompareOp, f64, i32),
+    // (XdY)!totalmod_param，总和满足条件时，最大面的骰子爆炸一轮
+    ExplodeIfTotal(Box<DicePoolType>, ModParam),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -47,6 +68,13 @@ pub enum SuccessPoolType {
     DeductFailuresFromDicePool(Box<DicePoolType>, ModParam), // success_pool_type df dice_pool_type
     CountSuccesses(Box<SuccessPoolType>, ModParam),          // success_pool_type cs mod_param
     DeductFailures(Box<SuccessPoolType>, ModParam),          // success_pool_type df mod_param
+    Cascade(Box<DicePoolType>, ModParam, Limit), // dice_pool_type cascade mod_param limit
+    // dice_pool_type cs[op value:weight, ...]，取第一个满足的条件的权重
+    WeightedCountFromDicePool(Box<DicePoolType>, Vec<(CompareOp, f64, i32)>),
+    // dice_pool_type sp mod_param，成功的骰子被消耗 (不再保留) 但仍计数
+    SpendFromDicePool(Box<DicePoolType>, ModParam),
+    // dice_pool_type net mod_param，满足条件为成功，其余保留的骰子为失败
+    NetFromDicePool(Box<DicePoolType>, ModParam),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -57,6 +85,7 @@ pub enum NumberBinaryType {
     Divide(Box<NumberType>, Box<NumberType>),
     IntDivide(Box<NumberType>, Box<NumberType>),
     Modulo(Box<NumberType>, Box<NumberType>),
+    Compare(Box<NumberType>, CompareOp, Box<NumberType>), // 满足比较条件为 1，否则为 0
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -64,12 +93,21 @@ pub enum NumberFunctionType {
     Floor(Box<NumberType>),
     Ceil(Box<NumberType>),
     Round(Box<NumberType>),
+    RoundEven(Box<NumberType>), // 银行家舍入，.5 舍入到最近的偶数
     Abs(Box<NumberType>),
     Max(Box<ListType>),
     Min(Box<ListType>),
+    MaxOr(Box<ListType>, Box<NumberType>), // 列表为空时返回第二个参数
+    MinOr(Box<ListType>, Box<NumberType>), // 列表为空时返回第二个参数
     Sum(Box<ListType>),
     Avg(Box<ListType>),
     Len(Box<ListType>),
+    HighDie(Box<DicePoolType>), // 被保留骰子中的最大点数
+    LowDie(Box<DicePoolType>),  // 被保留骰子中的最小点数
+    // margin(roll, target)，结果为 roll - target
+    Margin(Box<NumberType>, Box<NumberType>),
+    // groupkh(g1, ..., gk, n)，保留总和最大的 n 组并求和
+    GroupKh(Box<ListType>, Box<NumberType>),
 }
 
 // ==========================================
@@ -110,6 +148,8 @@ pub enum ListFunctionType {
     Min(Box<ListType>, Box<NumberType>),   // list_function_type min number_type
     Sort(Box<ListType>),                   // list_function_type sort
     SortDesc(Box<ListType>),               // list_function_type sortdesc
+    FilterEven(Box<ListType>),             // list_function_type filtereven
+    FilterOdd(Box<ListType>),              // list_function_type filterodd
     ToListFromDicePool(Box<DicePoolType>), // tolist dice_pool_type
     ToListFromSuccessPool(Box<SuccessPoolType>), // tolist success_pool_type
     Filter(Box<ListType>, ModParam),       // list_function_type filter mod_param
@@ -129,6 +169,7 @@ pub struct ModParam {
 pub struct Limit {
     pub limit_times: Option<Box<NumberType>>,
     pub limit_counts: Option<Box<NumberType>>,
+    pub limit_size: Option<Box<NumberType>>, // 必须为常量正整数
 }
 
 // ==========================================
@@ -144,6 +185,10 @@ impl HIR {
         HIR::Number(NumberType::Neg(Box::new(num)))
     }
 
+    pub fn shared(id: u32, num: NumberType) -> Self {
+        HIR::Number(NumberType::Shared(id, Box::new(num)))
+    }
+
     pub fn standard_dice_pool(count: NumberType, sides: NumberType) -> Self {
         HIR::Number(NumberType::DicePool(DicePoolType::Standard(
             Box::new(count),
@@ -155,10 +200,21 @@ impl HIR {
         HIR::Number(NumberType::DicePool(DicePoolType::Coin(Box::new(count))))
     }
 
+    pub fn labeled_dice_pool(count: NumberType, faces: Vec<(String, i32)>) -> Self {
+        HIR::Number(NumberType::DicePool(DicePoolType::Labeled(
+            Box::new(count),
+            faces,
+        )))
+    }
+
     pub fn fudge_dice_pool(count: NumberType) -> Self {
         HIR::Number(NumberType::DicePool(DicePoolType::Fudge(Box::new(count))))
     }
 
+    pub fn percentile_dice_pool() -> Self {
+        HIR::Number(NumberType::DicePool(DicePoolType::Percentile))
+    }
+
     pub fn explicit_list(elements: Vec<NumberType>) -> Self {
         HIR::List(ListType::Explicit(elements))
     }
@@ -205,6 +261,14 @@ impl HIR {
         )))
     }
 
+    pub fn compare_number(left: NumberType, op: CompareOp, right: NumberType) -> Self {
+        HIR::Number(NumberType::NumberBinary(NumberBinaryType::Compare(
+            Box::new(left),
+            op,
+            Box::new(right),
+        )))
+    }
+
     pub fn add_list(left: ListType, right: ListType) -> Self {
         HIR::List(ListType::ListBinary(ListBinaryType::AddList(
             Box::new(left),
@@ -318,6 +382,12 @@ impl HIR {
         ))))
     }
 
+    pub fn round_even_number(num: NumberType) -> Self {
+        HIR::Number(NumberType::NumberFunction(NumberFunctionType::RoundEven(
+            Box::new(num),
+        )))
+    }
+
     pub fn abs_number(num: NumberType) -> Self {
         HIR::Number(NumberType::NumberFunction(NumberFunctionType::Abs(
             Box::new(num),
@@ -343,6 +413,20 @@ impl HIR {
         )))
     }
 
+    pub fn max_or_number(list: ListType, default: NumberType) -> Self {
+        HIR::Number(NumberType::NumberFunction(NumberFunctionType::MaxOr(
+            Box::new(list),
+            Box::new(default),
+        )))
+    }
+
+    pub fn min_or_number(list: ListType, default: NumberType) -> Self {
+        HIR::Number(NumberType::NumberFunction(NumberFunctionType::MinOr(
+            Box::new(list),
+            Box::new(default),
+        )))
+    }
+
     pub fn min_number(list: ListType) -> Self {
         HIR::Number(NumberType::NumberFunction(NumberFunctionType::Min(
             Box::new(list),
@@ -374,6 +458,32 @@ impl HIR {
         )))
     }
 
+    pub fn margin(rolled: NumberType, target: NumberType) -> Self {
+        HIR::Number(NumberType::NumberFunction(NumberFunctionType::Margin(
+            Box::new(rolled),
+            Box::new(target),
+        )))
+    }
+
+    pub fn group_kh(groups: ListType, count: NumberType) -> Self {
+        HIR::Number(NumberType::NumberFunction(NumberFunctionType::GroupKh(
+            Box::new(groups),
+            Box::new(count),
+        )))
+    }
+
+    pub fn high_die(dice_pool: DicePoolType) -> Self {
+        HIR::Number(NumberType::NumberFunction(NumberFunctionType::HighDie(
+            Box::new(dice_pool),
+        )))
+    }
+
+    pub fn low_die(dice_pool: DicePoolType) -> Self {
+        HIR::Number(NumberType::NumberFunction(NumberFunctionType::LowDie(
+            Box::new(dice_pool),
+        )))
+    }
+
     pub fn sort_list(list: ListType) -> Self {
         HIR::List(ListType::ListFunction(ListFunctionType::Sort(Box::new(
             list,
@@ -386,6 +496,18 @@ impl HIR {
         )))
     }
 
+    pub fn filter_even_list(list: ListType) -> Self {
+        HIR::List(ListType::ListFunction(ListFunctionType::FilterEven(
+            Box::new(list),
+        )))
+    }
+
+    pub fn filter_odd_list(list: ListType) -> Self {
+        HIR::List(ListType::ListFunction(ListFunctionType::FilterOdd(
+            Box::new(list),
+        )))
+    }
+
     pub fn tolist_from_dice_pool(dice_pool: DicePoolType) -> Self {
         HIR::List(ListType::ListFunction(
             ListFunctionType::ToListFromDicePool(Box::new(dice_pool)),
@@ -405,10 +527,15 @@ impl HIR {
         }
     }
 
-    pub fn limit_param(limit_times: Option<NumberType>, limit_counts: Option<NumberType>) -> Limit {
+    pub fn limit_param(
+        limit_times: Option<NumberType>,
+        limit_counts: Option<NumberType>,
+        limit_size: Option<NumberType>,
+    ) -> Limit {
         Limit {
             limit_times: limit_times.map(Box::new),
             limit_counts: limit_counts.map(Box::new),
+            limit_size: limit_size.map(Box::new),
         }
     }
 
@@ -456,6 +583,14 @@ impl HIR {
         )))
     }
 
+    pub fn each_die(dice_pool: DicePoolType, op: EachOp, value: NumberType) -> Self {
+        HIR::Number(NumberType::DicePool(DicePoolType::Each(
+            Box::new(dice_pool),
+            op,
+            Box::new(value),
+        )))
+    }
+
     pub fn reroll(dice_pool: DicePoolType, mod_param: ModParam, limit: Option<Limit>) -> Self {
         HIR::Number(NumberType::DicePool(DicePoolType::Reroll(
             Box::new(dice_pool),
@@ -463,6 +598,13 @@ impl HIR {
             limit,
         )))
     }
+    pub fn reroll_pool(dice_pool: DicePoolType, mod_param: ModParam, limit: Option<Limit>) -> Self {
+        HIR::Number(NumberType::DicePool(DicePoolType::RerollPool(
+            Box::new(dice_pool),
+            mod_param,
+            limit,
+        )))
+    }
     pub fn explode(
         dice_pool: DicePoolType,
         mod_param: Option<ModParam>,
@@ -474,6 +616,38 @@ impl HIR {
             limit,
         )))
     }
+    pub fn explode_once(
+        dice_pool: DicePoolType,
+        mod_param: Option<ModParam>,
+        limit: Option<Limit>,
+    ) -> Self {
+        HIR::Number(NumberType::DicePool(DicePoolType::ExplodeOnce(
+            Box::new(dice_pool),
+            mod_param,
+            limit,
+        )))
+    }
+    pub fn explode_multi(
+        dice_pool: DicePoolType,
+        count: i32,
+        mod_param: Option<ModParam>,
+        limit: Option<Limit>,
+    ) -> Self {
+        HIR::Number(NumberType::DicePool(DicePoolType::ExplodeMulti(
+            Box::new(dice_pool),
+            count,
+            mod_param,
+            limit,
+        )))
+    }
+    pub fn explode_until(dice_pool: DicePoolType, op: CompareOp, value: f64, quota: i32) -> Self {
+        HIR::Number(NumberType::DicePool(DicePoolType::ExplodeUntil(
+            Box::new(dice_pool),
+            op,
+            value,
+            quota,
+        )))
+    }
     pub fn compound_explode(
         dice_pool: DicePoolType,
         mod_param: Option<ModParam>,
@@ -492,16 +666,114 @@ impl HIR {
             mod_param,
         )))
     }
+    pub fn explode_if_total(dice_pool: DicePoolType, mod_param: ModParam) -> Self {
+        HIR::Number(NumberType::DicePool(DicePoolType::ExplodeIfTotal(
+            Box::new(dice_pool),
+            mod_param,
+        )))
+    }
+    pub fn reroll_max(dice_pool: DicePoolType, mod_param: ModParam) -> Self {
+        HIR::Number(NumberType::DicePool(DicePoolType::RerollMax(
+            Box::new(dice_pool),
+            mod_param,
+        )))
+    }
+    pub fn conditional_drop_high(
+        dice_pool: DicePoolType,
+        count: NumberType,
+        mod_param: ModParam,
+    ) -> Self {
+        HIR::Number(NumberType::DicePool(DicePoolType::ConditionalDropHigh(
+            Box::new(dice_pool),
+            Box::new(count),
+            mod_param,
+        )))
+    }
+
+    pub fn keep_relative_to_average(dice_pool: DicePoolType, compare: CompareOp) -> Self {
+        HIR::Number(NumberType::DicePool(DicePoolType::KeepRelativeToAverage(
+            Box::new(dice_pool),
+            compare,
+        )))
+    }
+
+    pub fn keep_rank_range(dice_pool: DicePoolType, low: i32, high: i32) -> Self {
+        HIR::Number(NumberType::DicePool(DicePoolType::KeepRankRange(
+            Box::new(dice_pool),
+            low,
+            high,
+        )))
+    }
+
+    pub fn keep_distinct(dice_pool: DicePoolType) -> Self {
+        HIR::Number(NumberType::DicePool(DicePoolType::KeepDistinct(Box::new(
+            dice_pool,
+        ))))
+    }
+
+    pub fn conditional_drop_low(
+        dice_pool: DicePoolType,
+        count: NumberType,
+        mod_param: ModParam,
+    ) -> Self {
+        HIR::Number(NumberType::DicePool(DicePoolType::ConditionalDropLow(
+            Box::new(dice_pool),
+            Box::new(count),
+            mod_param,
+        )))
+    }
+
+    pub fn remove_n(dice_pool: DicePoolType, count: NumberType, mod_param: ModParam) -> Self {
+        HIR::Number(NumberType::DicePool(DicePoolType::RemoveN(
+            Box::new(dice_pool),
+            Box::new(count),
+            mod_param,
+        )))
+    }
+
+    pub fn asdice(success_pool: SuccessPoolType) -> Self {
+        HIR::Number(NumberType::DicePool(DicePoolType::FromSuccessPool(
+            Box::new(success_pool),
+        )))
+    }
+
     pub fn count_successes_from_dice_pool(dice_pool: DicePoolType, mod_param: ModParam) -> Self {
         HIR::Number(NumberType::SuccessPool(
             SuccessPoolType::CountSuccessesFromDicePool(Box::new(dice_pool), mod_param),
         ))
     }
+    pub fn spend_successes_from_dice_pool(dice_pool: DicePoolType, mod_param: ModParam) -> Self {
+        HIR::Number(NumberType::SuccessPool(SuccessPoolType::SpendFromDicePool(
+            Box::new(dice_pool),
+            mod_param,
+        )))
+    }
+    pub fn net_successes_from_dice_pool(dice_pool: DicePoolType, mod_param: ModParam) -> Self {
+        HIR::Number(NumberType::SuccessPool(SuccessPoolType::NetFromDicePool(
+            Box::new(dice_pool),
+            mod_param,
+        )))
+    }
     pub fn deduct_failures_from_dice_pool(dice_pool: DicePoolType, mod_param: ModParam) -> Self {
         HIR::Number(NumberType::SuccessPool(
             SuccessPoolType::DeductFailuresFromDicePool(Box::new(dice_pool), mod_param),
         ))
     }
+    pub fn weighted_count_successes(
+        dice_pool: DicePoolType,
+        weights: Vec<(CompareOp, f64, i32)>,
+    ) -> Self {
+        HIR::Number(NumberType::SuccessPool(
+            SuccessPoolType::WeightedCountFromDicePool(Box::new(dice_pool), weights),
+        ))
+    }
+    pub fn cascade(dice_pool: DicePoolType, mod_param: ModParam, limit: Limit) -> Self {
+        HIR::Number(NumberType::SuccessPool(SuccessPoolType::Cascade(
+            Box::new(dice_pool),
+            mod_param,
+            limit,
+        )))
+    }
     pub fn count_successes(success_pool: SuccessPoolType, mod_param: ModParam) -> Self {
         HIR::Number(NumberType::SuccessPool(SuccessPoolType::CountSuccesses(
             Box::new(success_pool),
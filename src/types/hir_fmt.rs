@@ -1,5 +1,7 @@
-use super::expr::CompareOp;
+use super::expr::{CompareOp, EachOp};
 use super::hir::*;
+use super::hir_rewriter::HirVisitor;
+use super::number_format::format_exact_number;
 use std::fmt;
 
 // ==========================================
@@ -8,6 +10,7 @@ use std::fmt;
 
 #[derive(PartialEq, PartialOrd, Copy, Clone)]
 enum Precedence {
+    Compare = 5,  // 数值比较
     Sum = 10,     // 加法、减法
     Product = 20, // 乘法、除法、取模
     Dice = 30,    // 骰子运算 (d, kh, !, etc.)
@@ -21,6 +24,14 @@ enum Precedence {
 
 impl fmt::Display for HIR {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // 共享的值先写成 let share 绑定，表达式中只写引用名，使输出能重新解析为相同的含义
+        let mut collector = SharedCollector::default();
+        collector
+            .visit_hir(&mut self.clone())
+            .map_err(|_| fmt::Error)?;
+        for (id, value) in &collector.bindings {
+            write!(f, "let share {} = {}; ", shared_name(*id), value)?;
+        }
         match self {
             HIR::Number(n) => write!(f, "{}", n),
             HIR::List(l) => write!(f, "{}", l),
@@ -28,6 +39,36 @@ impl fmt::Display for HIR {
     }
 }
 
+// 收集所有 share 绑定的值，被引用的绑定排在引用它的绑定之前
+#[derive(Default)]
+struct SharedCollector {
+    bindings: Vec<(u32, NumberType)>,
+}
+
+impl HirVisitor for SharedCollector {
+    fn visit_number_self(&mut self, n: &mut NumberType) -> Result<(), String> {
+        if let NumberType::Shared(id, value) = n
+            && !self.bindings.iter().any(|(bound, _)| bound == id)
+        {
+            self.bindings.push((*id, (**value).clone()));
+        }
+        Ok(())
+    }
+}
+
+// share 绑定的引用名：v 后接编号的字母形式，如 va, vb, ..., vz, vab
+fn shared_name(id: u32) -> String {
+    let mut name = String::from("v");
+    let mut id = id;
+    loop {
+        name.push((b'a' + (id % 26) as u8) as char);
+        id /= 26;
+        if id == 0 {
+            break name;
+        }
+    }
+}
+
 // ==========================================
 // NumberType 实现
 // ==========================================
@@ -40,24 +81,52 @@ impl NumberType {
             NumberType::SuccessPool(_) => Precedence::Dice,
             NumberType::NumberFunction(_) => Precedence::Call,
             NumberType::Neg(_) => Precedence::Prefix,
+            // 引用名与相邻的字母会被读作一个名字，因此在骰子运算中需要加括号，如 (va)d6
+            NumberType::Shared(_, _) => Precedence::Dice,
             NumberType::NumberBinary(op) => match op {
                 NumberBinaryType::Add(_, _) | NumberBinaryType::Subtract(_, _) => Precedence::Sum,
                 NumberBinaryType::Multiply(_, _)
                 | NumberBinaryType::Divide(_, _)
                 | NumberBinaryType::IntDivide(_, _)
                 | NumberBinaryType::Modulo(_, _) => Precedence::Product,
+                NumberBinaryType::Compare(_, _, _) => Precedence::Compare,
             },
         }
     }
 }
 
+impl NumberType {
+    // 输出的最后一项是骰子池或成功池 (可能带修饰符)
+    fn ends_with_dice(&self) -> bool {
+        match self {
+            NumberType::DicePool(_) | NumberType::SuccessPool(_) => true,
+            NumberType::NumberBinary(op) => {
+                let rhs = match op {
+                    NumberBinaryType::Add(_, r)
+                    | NumberBinaryType::Subtract(_, r)
+                    | NumberBinaryType::Multiply(_, r)
+                    | NumberBinaryType::Divide(_, r)
+                    | NumberBinaryType::IntDivide(_, r)
+                    | NumberBinaryType::Modulo(_, r)
+                    | NumberBinaryType::Compare(_, _, r) => r,
+                };
+                rhs.precedence() > self.precedence() && rhs.ends_with_dice()
+            }
+            _ => false,
+        }
+    }
+}
+
 impl fmt::Display for NumberType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            NumberType::Constant(c) => write!(f, "{}", c),
+            NumberType::Constant(c) => {
+                write!(f, "{}", format_exact_number(*c))
+            }
             NumberType::DicePool(d) => write!(f, "{}", d),
             NumberType::SuccessPool(s) => write!(f, "{}", s),
             NumberType::NumberFunction(func) => write!(f, "{}", func),
+            NumberType::Shared(id, _) => write!(f, "{}", shared_name(*id)),
             NumberType::Neg(inner) => {
                 if inner.precedence() < Precedence::Prefix {
                     write!(f, "-({})", inner)
@@ -67,6 +136,7 @@ impl fmt::Display for NumberType {
             }
             NumberType::NumberBinary(op) => {
                 let prec = self.precedence();
+                let compare_symbol;
                 let (lhs, symbol, rhs) = match op {
                     NumberBinaryType::Add(l, r) => (l, "+", r),
                     NumberBinaryType::Subtract(l, r) => (l, "-", r),
@@ -74,10 +144,16 @@ impl fmt::Display for NumberType {
                     NumberBinaryType::Divide(l, r) => (l, "/", r),
                     NumberBinaryType::IntDivide(l, r) => (l, "//", r),
                     NumberBinaryType::Modulo(l, r) => (l, "%", r),
+                    NumberBinaryType::Compare(l, compare, r) => {
+                        compare_symbol = compare.to_string();
+                        (l, compare_symbol.as_str(), r)
+                    }
                 };
 
-                // 左侧：如果优先级低于当前，加括号
-                if lhs.precedence() < prec {
+                // 左侧：如果优先级低于当前，加括号；
+                // 比较的左侧以骰子结尾时也要加括号，否则比较符会被读作修饰符的条件，如 (4d6dl1)<3
+                let is_compare = matches!(op, NumberBinaryType::Compare(_, _, _));
+                if lhs.precedence() < prec || (is_compare && lhs.ends_with_dice()) {
                     write!(f, "({})", lhs)?;
                 } else {
                     write!(f, "{}", lhs)?;
@@ -104,6 +180,15 @@ impl fmt::Display for NumberType {
 // DicePoolType 实现
 // ==========================================
 
+// 标签骰子各面的文本形式，如 "miss:0,hit:1"
+pub fn labeled_faces_text(faces: &[(String, i32)]) -> String {
+    faces
+        .iter()
+        .map(|(label, value)| format!("{}:{}", label, value))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
 impl fmt::Display for DicePoolType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -122,6 +207,14 @@ impl fmt::Display for DicePoolType {
                     write!(f, "{}", sides)
                 }
             }
+            DicePoolType::Labeled(count, faces) => {
+                if count.precedence() <= Precedence::Dice {
+                    write!(f, "({})", count)?;
+                } else {
+                    write!(f, "{}", count)?;
+                }
+                write!(f, "d[{}]", labeled_faces_text(faces))
+            }
             DicePoolType::Fudge(count) => {
                 if count.precedence() <= Precedence::Dice {
                     write!(f, "({})dF", count)
@@ -129,6 +222,7 @@ impl fmt::Display for DicePoolType {
                     write!(f, "{}dF", count)
                 }
             }
+            DicePoolType::Percentile => write!(f, "d%%"),
             DicePoolType::Coin(count) => {
                 if count.precedence() <= Precedence::Dice {
                     write!(f, "({})dC", count)
@@ -189,6 +283,34 @@ impl fmt::Display for DicePoolType {
                 }
                 Ok(())
             }
+            DicePoolType::ExplodeOnce(inner, mp, limit) => {
+                write!(f, "{}!o", inner)?;
+                if let Some(mp) = mp {
+                    write!(f, "{}", mp)?;
+                }
+                if let Some(l) = limit {
+                    write!(f, "{}", l)?;
+                }
+                Ok(())
+            }
+            DicePoolType::ExplodeMulti(inner, count, mp, limit) => {
+                write!(f, "{}!x{}", inner, count)?;
+                if let Some(mp) = mp {
+                    write!(f, "{}", mp)?;
+                }
+                if let Some(l) = limit {
+                    write!(f, "{}", l)?;
+                }
+                Ok(())
+            }
+            DicePoolType::ExplodeUntil(inner, op, value, quota) => {
+                write!(
+                    f,
+                    "{}{}",
+                    inner,
+                    explode_until_label(op, *value, *quota, format_exact_number)
+                )
+            }
             DicePoolType::CompoundExplode(inner, mp, limit) => {
                 write!(f, "{}!!", inner)?;
                 if let Some(mp) = mp {
@@ -206,7 +328,46 @@ impl fmt::Display for DicePoolType {
                 }
                 Ok(())
             }
+            DicePoolType::RerollPool(inner, mp, limit) => {
+                write!(f, "{}rp{}", inner, mp)?;
+                if let Some(l) = limit {
+                    write!(f, "{}", l)?;
+                }
+                Ok(())
+            }
             DicePoolType::SubtractFailures(inner, mp) => write!(f, "{}sf{}", inner, mp),
+            DicePoolType::RerollMax(inner, mp) => write!(f, "{}rmax{}", inner, mp),
+            DicePoolType::ExplodeIfTotal(inner, mp) => write!(f, "{}!total{}", inner, mp),
+            DicePoolType::FromSuccessPool(s) => write!(f, "asdice({})", s),
+            DicePoolType::Each(inner, op, n) => write!(f, "each({},{}{})", inner, op, n),
+            DicePoolType::ConditionalDropHigh(inner, n, mp) => {
+                if n.precedence() <= Precedence::Dice {
+                    write!(f, "{}dh({}){}", inner, n, mp)
+                } else {
+                    write!(f, "{}dh{}{}", inner, n, mp)
+                }
+            }
+            DicePoolType::ConditionalDropLow(inner, n, mp) => {
+                if n.precedence() <= Precedence::Dice {
+                    write!(f, "{}dl({}){}", inner, n, mp)
+                } else {
+                    write!(f, "{}dl{}{}", inner, n, mp)
+                }
+            }
+            DicePoolType::RemoveN(inner, n, mp) => {
+                if n.precedence() <= Precedence::Dice {
+                    write!(f, "{}rm({}){}", inner, n, mp)
+                } else {
+                    write!(f, "{}rm{}{}", inner, n, mp)
+                }
+            }
+            DicePoolType::KeepDistinct(inner) => write!(f, "{}kdistinct", inner),
+            DicePoolType::KeepRankRange(inner, low, high) => {
+                write!(f, "{}k{}..{}", inner, low, high)
+            }
+            DicePoolType::KeepRelativeToAverage(inner, compare) => {
+                write!(f, "{}{}avg", inner, keep_relative_label(compare))
+            }
         }
     }
 }
@@ -220,8 +381,19 @@ impl fmt::Display for SuccessPoolType {
         match self {
             SuccessPoolType::CountSuccessesFromDicePool(dp, mp) => write!(f, "{}cs{}", dp, mp),
             SuccessPoolType::DeductFailuresFromDicePool(dp, mp) => write!(f, "{}df{}", dp, mp),
+            SuccessPoolType::SpendFromDicePool(dp, mp) => write!(f, "{}sp{}", dp, mp),
+            SuccessPoolType::NetFromDicePool(dp, mp) => write!(f, "{}net{}", dp, mp),
             SuccessPoolType::CountSuccesses(inner, mp) => write!(f, "{}cs{}", inner, mp),
             SuccessPoolType::DeductFailures(inner, mp) => write!(f, "{}df{}", inner, mp),
+            SuccessPoolType::Cascade(dp, mp, limit) => write!(f, "{}cascade{}{}", dp, mp, limit),
+            SuccessPoolType::WeightedCountFromDicePool(dp, weights) => {
+                write!(
+                    f,
+                    "{}cs{}",
+                    dp,
+                    success_weights_text(weights, format_exact_number)
+                )
+            }
         }
     }
 }
@@ -357,12 +529,29 @@ impl fmt::Display for NumberFunctionType {
             NumberFunctionType::Floor(n) => write!(f, "floor({})", n),
             NumberFunctionType::Ceil(n) => write!(f, "ceil({})", n),
             NumberFunctionType::Round(n) => write!(f, "round({})", n),
+            NumberFunctionType::RoundEven(n) => write!(f, "roundeven({})", n),
             NumberFunctionType::Abs(n) => write!(f, "abs({})", n),
             NumberFunctionType::Max(l) => write!(f, "max({})", l),
             NumberFunctionType::Min(l) => write!(f, "min({})", l),
+            NumberFunctionType::MaxOr(l, d) => write!(f, "maxor({},{})", l, d),
+            NumberFunctionType::MinOr(l, d) => write!(f, "minor({},{})", l, d),
             NumberFunctionType::Sum(l) => write!(f, "sum({})", l),
             NumberFunctionType::Avg(l) => write!(f, "avg({})", l),
             NumberFunctionType::Len(l) => write!(f, "len({})", l),
+            NumberFunctionType::HighDie(d) => write!(f, "highdie({})", d),
+            NumberFunctionType::Margin(r, t) => write!(f, "margin({},{})", r, t),
+            NumberFunctionType::LowDie(d) => write!(f, "lowdie({})", d),
+            // 各组按输入时的写法展开为参数
+            NumberFunctionType::GroupKh(l, n) => match &**l {
+                ListType::Explicit(groups) => {
+                    write!(f, "groupkh(")?;
+                    for group in groups {
+                        write!(f, "{},", group)?;
+                    }
+                    write!(f, "{})", n)
+                }
+                _ => write!(f, "groupkh({},{})", l, n),
+            },
         }
     }
 }
@@ -379,6 +568,8 @@ impl fmt::Display for ListFunctionType {
             ListFunctionType::Min(l, n) => write!(f, "min({},{})", l, n),
             ListFunctionType::Sort(l) => write!(f, "sort({})", l),
             ListFunctionType::SortDesc(l) => write!(f, "sortd({})", l),
+            ListFunctionType::FilterEven(l) => write!(f, "filtereven({})", l),
+            ListFunctionType::FilterOdd(l) => write!(f, "filterodd({})", l),
             ListFunctionType::ToListFromDicePool(d) => write!(f, "tolist({})", d),
             ListFunctionType::ToListFromSuccessPool(s) => write!(f, "tolist({})", s),
             ListFunctionType::Filter(l, mp) => {
@@ -401,6 +592,37 @@ impl fmt::Display for ListFunctionType {
 // 辅助类型实现
 // ==========================================
 
+// kh>avg 等修饰符的文本 (不含 avg)，大于类比较为 kh，小于类比较为 kl
+pub fn keep_relative_label(compare: &CompareOp) -> String {
+    match compare {
+        CompareOp::Greater | CompareOp::GreaterEqual => format!("kh{}", compare),
+        _ => format!("kl{}", compare),
+    }
+}
+
+// !until 的显示形式，如 "!until(cs>=5,3)"，HIR 显示与结果输出共用，
+// number 决定比较值的格式：HIR 显示不做舍入，结果输出保留有限的小数位
+pub fn explode_until_label(
+    op: &CompareOp,
+    value: f64,
+    quota: i32,
+    number: fn(f64) -> String,
+) -> String {
+    format!("!until(cs{}{},{})", op, number(value), quota)
+}
+
+// 权重表的显示形式，如 "[=10:2,>=7:1]"，HIR 显示与结果输出共用，number 的含义同上
+pub fn success_weights_text(
+    weights: &[(CompareOp, f64, i32)],
+    number: fn(f64) -> String,
+) -> String {
+    let entries: Vec<String> = weights
+        .iter()
+        .map(|(op, value, weight)| format!("{}{}:{}", op, number(*value), weight))
+        .collect();
+    format!("[{}]", entries.join(","))
+}
+
 impl fmt::Display for CompareOp {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let s = match self {
@@ -415,6 +637,17 @@ impl fmt::Display for CompareOp {
     }
 }
 
+impl fmt::Display for EachOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            EachOp::Add => "+",
+            EachOp::Sub => "-",
+            EachOp::Mul => "*",
+        };
+        write!(f, "{}", s)
+    }
+}
+
 impl fmt::Display for ModParam {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if self.value.precedence() <= Precedence::Dice {
@@ -441,6 +674,13 @@ impl fmt::Display for Limit {
                 write!(f, "lc{}", counts)?;
             }
         }
+        if let Some(size) = &self.limit_size {
+            if size.precedence() <= Precedence::Dice {
+                write!(f, "lsz({})", size)?;
+            } else {
+                write!(f, "lsz{}", size)?;
+            }
+        }
         Ok(())
     }
 }
@@ -48,6 +48,7 @@ pub trait HirVisitor {
             NumberFunction(f) => self.visit_number_function(f),
             // Rust 的 Deref Coercion 会自动将 &mut Box<T> 视为 &mut T
             Neg(x) => self.visit_number(x),
+            Shared(_, x) => self.visit_number(x),
         }
     }
 
@@ -74,18 +75,24 @@ pub trait HirVisitor {
             }
             Fudge(x) => self.visit_number(x),
             Coin(x) => self.visit_number(x),
+            Labeled(x, _) => self.visit_number(x),
+            Percentile => Ok(()), // 叶子节点，无需递归
             KeepHigh(d, n)
             | KeepLow(d, n)
             | DropHigh(d, n)
             | DropLow(d, n)
             | Min(d, n)
-            | Max(d, n) => {
+            | Max(d, n)
+            | Each(d, _, n) => {
                 self.visit_dice_pool(d)?;
                 self.visit_number(n)?;
                 Ok(())
             }
             // 处理 Option 类型
-            Explode(d, mp, lim) | CompoundExplode(d, mp, lim) => {
+            Explode(d, mp, lim)
+            | CompoundExplode(d, mp, lim)
+            | ExplodeOnce(d, mp, lim)
+            | ExplodeMulti(d, _, mp, lim) => {
                 self.visit_dice_pool(d)?;
                 if let Some(m) = mp {
                     self.visit_mod_param(m)?;
@@ -95,7 +102,7 @@ pub trait HirVisitor {
                 }
                 Ok(())
             }
-            Reroll(d, mp, lim) => {
+            Reroll(d, mp, lim) | RerollPool(d, mp, lim) => {
                 self.visit_dice_pool(d)?;
                 self.visit_mod_param(mp)?;
                 if let Some(l) = lim {
@@ -103,11 +110,22 @@ pub trait HirVisitor {
                 }
                 Ok(())
             }
-            SubtractFailures(d, mp) => {
+            SubtractFailures(d, mp) | RerollMax(d, mp) | ExplodeIfTotal(d, mp) => {
                 self.visit_dice_pool(d)?;
                 self.visit_mod_param(mp)?;
                 Ok(())
             }
+            FromSuccessPool(s) => self.visit_success_pool(s),
+            KeepRelativeToAverage(d, _)
+            | KeepDistinct(d)
+            | KeepRankRange(d, _, _)
+            | ExplodeUntil(d, _, _, _) => self.visit_dice_pool(d),
+            ConditionalDropHigh(d, n, mp) | ConditionalDropLow(d, n, mp) | RemoveN(d, n, mp) => {
+                self.visit_dice_pool(d)?;
+                self.visit_number(n)?;
+                self.visit_mod_param(mp)?;
+                Ok(())
+            }
         }
     }
 
@@ -127,7 +145,10 @@ pub trait HirVisitor {
     fn visit_success_pool_children(&mut self, s: &mut SuccessPoolType) -> Result<(), String> {
         use SuccessPoolType::*;
         match s {
-            CountSuccessesFromDicePool(d, mp) | DeductFailuresFromDicePool(d, mp) => {
+            CountSuccessesFromDicePool(d, mp)
+            | DeductFailuresFromDicePool(d, mp)
+            | SpendFromDicePool(d, mp)
+            | NetFromDicePool(d, mp) => {
                 self.visit_dice_pool(d)?;
                 self.visit_mod_param(mp)?;
                 Ok(())
@@ -137,6 +158,13 @@ pub trait HirVisitor {
                 self.visit_mod_param(mp)?;
                 Ok(())
             }
+            Cascade(d, mp, lim) => {
+                self.visit_dice_pool(d)?;
+                self.visit_mod_param(mp)?;
+                self.visit_limit(lim)
+            }
+            // 权重表中只有常数
+            WeightedCountFromDicePool(d, _) => self.visit_dice_pool(d),
         }
     }
 
@@ -161,7 +189,8 @@ pub trait HirVisitor {
             | Multiply(l, r)
             | Divide(l, r)
             | IntDivide(l, r)
-            | Modulo(l, r) => {
+            | Modulo(l, r)
+            | Compare(l, _, r) => {
                 self.visit_number(l)?;
                 self.visit_number(r)?;
                 Ok(())
@@ -188,9 +217,18 @@ pub trait HirVisitor {
     ) -> Result<(), String> {
         use NumberFunctionType::*;
         match nf {
-            Floor(n) | Ceil(n) | Round(n) | Abs(n) => self.visit_number(n),
+            Floor(n) | Ceil(n) | Round(n) | RoundEven(n) | Abs(n) => self.visit_number(n),
             // 这些函数内部包含 ListType，调用 visit_list
             Max(l) | Min(l) | Sum(l) | Avg(l) | Len(l) => self.visit_list(l),
+            HighDie(d) | LowDie(d) => self.visit_dice_pool(d),
+            Margin(r, t) => {
+                self.visit_number(r)?;
+                self.visit_number(t)
+            }
+            MaxOr(l, d) | MinOr(l, d) | GroupKh(l, d) => {
+                self.visit_list(l)?;
+                self.visit_number(d)
+            }
         }
     }
 
@@ -280,7 +318,8 @@ pub trait HirVisitor {
     fn visit_list_function_children(&mut self, lf: &mut ListFunctionType) -> Result<(), String> {
         use ListFunctionType::*;
         match lf {
-            Floor(l) | Ceil(l) | Round(l) | Abs(l) | Sort(l) | SortDesc(l) => {
+            Floor(l) | Ceil(l) | Round(l) | Abs(l) | Sort(l) | SortDesc(l) | FilterEven(l)
+            | FilterOdd(l) => {
                 self.visit_list(l)?;
                 Ok(())
             }
@@ -316,6 +355,9 @@ pub trait HirVisitor {
         if let Some(n) = &mut lim.limit_counts {
             self.visit_number(n)?;
         }
+        if let Some(n) = &mut lim.limit_size {
+            self.visit_number(n)?;
+        }
         Ok(())
     }
 }
@@ -0,0 +1,172 @@
+// ==========================================
+// 常量折叠与运行时共用的列表运算
+// ==========================================
+
+use crate::types::expr::CompareOp;
+use crate::types::runtime_value::get_compare_function;
+
+// 选出应保留的 count 个元素的下标，按原顺序排列
+// keep_highest: true 为取最大 (Max), false 为取最小 (Min)
+// 排序是稳定的，数值相同时保留下标最小的那些
+pub fn keep_indices_preserve_order(
+    values: &[f64],
+    raw_count: f64,
+    keep_highest: bool,
+) -> Vec<usize> {
+    if raw_count < 0.0 {
+        return Vec::new();
+    }
+
+    let count = raw_count as usize;
+
+    if count >= values.len() {
+        return (0..values.len()).collect();
+    }
+
+    let mut indexed_values: Vec<(usize, f64)> = values.iter().copied().enumerate().collect();
+
+    indexed_values.sort_by(|a, b| {
+        let (val_a, val_b) = (a.1, b.1);
+        if keep_highest {
+            // true (Max): 降序 (b compare a)
+            val_b
+                .partial_cmp(&val_a)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        } else {
+            // false (Min): 升序 (a compare b)
+            val_a
+                .partial_cmp(&val_b)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        }
+    });
+
+    let mut top_k: Vec<usize> = indexed_values
+        .into_iter()
+        .take(count)
+        .map(|(index, _)| index)
+        .collect();
+
+    top_k.sort_unstable();
+
+    top_k
+}
+
+// 从列表中保留 count 个元素并保持相对顺序
+pub fn keep_elements_preserve_order(
+    values: Vec<f64>,
+    raw_count: f64,
+    keep_highest: bool,
+) -> Vec<f64> {
+    keep_indices_preserve_order(&values, raw_count, keep_highest)
+        .into_iter()
+        .map(|index| values[index])
+        .collect()
+}
+
+// 排序用的比较函数：NaN 无论升序还是降序都排在最后，保证排序结果确定，其余元素按数值比较
+pub fn compare_nan_last(a: f64, b: f64, descending: bool) -> std::cmp::Ordering {
+    use std::cmp::Ordering::*;
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => Equal,
+        (true, false) => Greater,
+        (false, true) => Less,
+        (false, false) if descending => b.partial_cmp(&a).unwrap_or(Equal),
+        (false, false) => a.partial_cmp(&b).unwrap_or(Equal),
+    }
+}
+
+// 从下标 start 开始每隔一个元素取一个，start 为 0 时保留偶数下标，为 1 时保留奇数下标
+pub fn every_other(values: &[f64], start: usize) -> Vec<f64> {
+    values.iter().skip(start).step_by(2).copied().collect()
+}
+
+// 按条件把列表拆成 (满足条件, 不满足条件) 两部分，各自保持原顺序
+// 表达式中禁止嵌套列表，因此只在 API 层面提供
+pub fn partition(values: &[f64], op: CompareOp, threshold: f64) -> (Vec<f64>, Vec<f64>) {
+    let matches = get_compare_function(op, threshold);
+    values.iter().copied().partition(|&v| matches(v))
+}
+
+// ==========================================
+// 单元测试
+// ==========================================
+
+#[test]
+fn test_keep_indices_ties_keep_earliest() {
+    let values = [3.0, 1.0, 3.0, 2.0, 3.0];
+    // 三个 3 中只保留两个时，保留最靠前的两个
+    assert_eq!(keep_indices_preserve_order(&values, 2.0, true), vec![0, 2]);
+    assert_eq!(
+        keep_indices_preserve_order(&values, 4.0, true),
+        vec![0, 2, 3, 4]
+    );
+
+    let values = [1.0, 2.0, 1.0, 1.0];
+    assert_eq!(keep_indices_preserve_order(&values, 2.0, false), vec![0, 2]);
+    assert_eq!(
+        keep_indices_preserve_order(&values, 3.0, false),
+        vec![0, 2, 3]
+    );
+
+    assert_eq!(
+        keep_indices_preserve_order(&values, 0.0, true),
+        Vec::<usize>::new()
+    );
+    assert_eq!(
+        keep_indices_preserve_order(&values, -1.0, true),
+        Vec::<usize>::new()
+    );
+    assert_eq!(
+        keep_indices_preserve_order(&values, 9.0, false),
+        vec![0, 1, 2, 3]
+    );
+}
+
+#[test]
+fn test_keep_elements_preserve_order() {
+    assert_eq!(
+        keep_elements_preserve_order(vec![3.0, 1.0, 3.0, 2.0, 3.0], 4.0, true),
+        vec![3.0, 3.0, 2.0, 3.0]
+    );
+    assert_eq!(
+        keep_elements_preserve_order(vec![5.0, 1.0, 4.0, 1.0], 2.0, false),
+        vec![1.0, 1.0]
+    );
+}
+
+#[test]
+fn test_compare_nan_last() {
+    let mut values = [2.0, f64::NAN, 1.0, 3.0];
+    values.sort_by(|a, b| compare_nan_last(*a, *b, false));
+    assert_eq!(&values[..3], &[1.0, 2.0, 3.0]);
+    assert!(values[3].is_nan());
+    values.sort_by(|a, b| compare_nan_last(*a, *b, true));
+    assert_eq!(&values[..3], &[3.0, 2.0, 1.0]);
+    assert!(values[3].is_nan());
+}
+
+#[test]
+fn test_every_other() {
+    assert_eq!(
+        every_other(&[1.0, 2.0, 3.0, 4.0, 5.0], 0),
+        vec![1.0, 3.0, 5.0]
+    );
+    assert_eq!(every_other(&[1.0, 2.0, 3.0, 4.0, 5.0], 1), vec![2.0, 4.0]);
+    assert_eq!(every_other(&[1.0], 1), Vec::<f64>::new());
+}
+
+#[test]
+fn test_partition() {
+    assert_eq!(
+        partition(&[1.0, 4.0, 2.0, 6.0], CompareOp::Greater, 3.0),
+        (vec![4.0, 6.0], vec![1.0, 2.0])
+    );
+    assert_eq!(
+        partition(&[5.0, 1.0, 5.0], CompareOp::Equal, 5.0),
+        (vec![5.0, 5.0], vec![1.0])
+    );
+    assert_eq!(
+        partition(&[], CompareOp::LessEqual, 3.0),
+        (Vec::new(), Vec::new())
+    );
+}
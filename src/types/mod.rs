@@ -3,5 +3,11 @@ pub mod expr;
 pub mod hir;
 pub mod hir_fmt;
 pub mod hir_rewriter;
+pub mod list_ops;
+pub mod number_format;
+#[cfg(feature = "cache")]
+pub mod output_cache;
 pub mod output_node;
+pub mod output_visitor;
 pub mod runtime_value;
+pub mod safe_integer;
@@ -0,0 +1,110 @@
+// ==========================================
+// 数字格式化
+// ==========================================
+
+use super::safe_integer::MAX_SAFE_INTEGER;
+
+// 默认保留的最大小数位数
+pub const DEFAULT_MAX_DECIMAL_PLACES: usize = 4;
+
+// 绝对值超出可显示范围的结果显示为该文本 (负数前加 -)
+pub const TOO_LARGE_TEXT: &str = "too large";
+
+// 将浮点数格式化为便于阅读的文本：
+// 最多保留 max_decimal_places 位小数，去掉末尾多余的 0，整数值不带小数点 (5 而不是 5.0)
+pub fn format_number(value: f64, max_decimal_places: usize) -> String {
+    if !value.is_finite() {
+        return value.to_string();
+    }
+    let mut text = format!("{:.*}", max_decimal_places, value);
+    if text.contains('.') {
+        let trimmed_len = text.trim_end_matches('0').trim_end_matches('.').len();
+        text.truncate(trimmed_len);
+    }
+    // 舍入后可能得到 -0，统一显示为 0
+    if text == "-0" {
+        text = "0".to_string();
+    }
+    text
+}
+
+// HIR 的显示需要能被重新解析并得到完全相同的值，因此使用最短的可往返表示，不做舍入；
+// Rust 的 f64 显示从不使用科学计数法，-0 统一显示为 0
+pub fn format_exact_number(value: f64) -> String {
+    if value == 0.0 {
+        return "0".to_string();
+    }
+    value.to_string()
+}
+
+// 用于结果输出的格式化：数字总是以十进制展开，不使用科学计数法，
+// 绝对值超过 2^53 时末几位已不精确，一长串数字也难以阅读，因此显示为 "too large"
+pub fn format_output_number(value: f64, max_decimal_places: usize) -> String {
+    if value.is_finite() && value.abs() > MAX_SAFE_INTEGER {
+        let sign = if value < 0.0 { "-" } else { "" };
+        return format!("{}{}", sign, TOO_LARGE_TEXT);
+    }
+    format_number(value, max_decimal_places)
+}
+
+// ==========================================
+// 单元测试
+// ==========================================
+
+#[test]
+fn test_format_repeating_decimal() {
+    assert_eq!(
+        format_number(10.0 / 3.0, DEFAULT_MAX_DECIMAL_PLACES),
+        "3.3333"
+    );
+    assert_eq!(format_number(2.0 / 3.0, 2), "0.67");
+}
+
+#[test]
+fn test_format_integer_valued() {
+    assert_eq!(format_number(5.0, DEFAULT_MAX_DECIMAL_PLACES), "5");
+    assert_eq!(format_number(-12.0, DEFAULT_MAX_DECIMAL_PLACES), "-12");
+    assert_eq!(format_number(2.5, DEFAULT_MAX_DECIMAL_PLACES), "2.5");
+    assert_eq!(format_number(-0.00001, DEFAULT_MAX_DECIMAL_PLACES), "0");
+}
+
+#[test]
+fn test_format_output_magnitude() {
+    assert_eq!(
+        format_output_number(8e15, DEFAULT_MAX_DECIMAL_PLACES),
+        "8000000000000000"
+    );
+    assert_eq!(
+        format_output_number(1e20, DEFAULT_MAX_DECIMAL_PLACES),
+        "too large"
+    );
+    assert_eq!(
+        format_output_number(-1e300, DEFAULT_MAX_DECIMAL_PLACES),
+        "-too large"
+    );
+    assert_eq!(format_output_number(1e-7, DEFAULT_MAX_DECIMAL_PLACES), "0");
+    assert_eq!(
+        format_number(1e20, DEFAULT_MAX_DECIMAL_PLACES),
+        "100000000000000000000"
+    );
+}
+
+#[test]
+fn test_format_exact_round_trip() {
+    for value in [
+        1.0 / 3.0,
+        0.33331,
+        0.33334,
+        0.1 + 0.2,
+        1e-7,
+        1e20,
+        -2.5,
+        5.0,
+    ] {
+        let text = format_exact_number(value);
+        assert!(!text.contains('e'), "{}", text);
+        assert_eq!(text.parse::<f64>().unwrap(), value, "{}", text);
+    }
+    assert_eq!(format_exact_number(-0.0), "0");
+    assert_eq!(format_exact_number(5.0), "5");
+}
@@ -0,0 +1,54 @@
+use super::output_node::{OutputNode, RollBreakdown};
+
+// ==========================================
+// 结果树的二进制序列化 (feature = "cache")
+// ==========================================
+
+// 宿主程序可以把投掷结果编码为紧凑的字节串保存下来，
+// 之后直接解码并重新渲染，而无需重新投掷
+
+pub fn output_to_bytes(node: &OutputNode) -> Result<Vec<u8>, String> {
+    rmp_serde::to_vec_named(node).map_err(|e| format!("Failed to encode result: {}", e))
+}
+
+pub fn output_from_bytes(bytes: &[u8]) -> Result<OutputNode, String> {
+    rmp_serde::from_slice(bytes).map_err(|e| format!("Failed to decode result: {}", e))
+}
+
+pub fn breakdown_to_bytes(breakdown: &RollBreakdown) -> Result<Vec<u8>, String> {
+    rmp_serde::to_vec_named(breakdown).map_err(|e| format!("Failed to encode breakdown: {}", e))
+}
+
+pub fn breakdown_from_bytes(bytes: &[u8]) -> Result<RollBreakdown, String> {
+    rmp_serde::from_slice(bytes).map_err(|e| format!("Failed to decode breakdown: {}", e))
+}
+
+// ==========================================
+// 单元测试
+// ==========================================
+
+#[test]
+fn test_output_bytes_round_trip() {
+    use crate::render_text::format_result_text;
+    use crate::runtime::roll_with_fixed_results;
+    let expr = "max(4d6kh3!, 2dF) + 3d6cs>4 + floor(2dC / 2)";
+    let result = roll_with_fixed_results(expr, &[6, 2, 5, 1, 3, 1, -1, 4, 5, 2, 1, 0]).unwrap();
+    let bytes = output_to_bytes(&result).unwrap();
+    let decoded = output_from_bytes(&bytes).unwrap();
+    assert_eq!(format_result_text(&decoded), format_result_text(&result));
+    assert_eq!(output_to_bytes(&decoded).unwrap(), bytes);
+    assert!(output_from_bytes(&bytes[..bytes.len() / 2]).is_err());
+}
+
+#[test]
+fn test_breakdown_bytes_round_trip() {
+    use crate::render_text::format_breakdown_text;
+    use crate::runtime::roll_breakdown_with_fixed_results;
+    let breakdown = roll_breakdown_with_fixed_results("2d6 - 1d8 + 3", &[4, 5, 7]).unwrap();
+    let bytes = breakdown_to_bytes(&breakdown).unwrap();
+    let decoded = breakdown_from_bytes(&bytes).unwrap();
+    assert_eq!(
+        format_breakdown_text(&decoded),
+        format_breakdown_text(&breakdown)
+    );
+}
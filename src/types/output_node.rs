@@ -1,9 +1,12 @@
+#[cfg(feature = "cache")]
+use serde::Deserialize;
 use serde::Serialize;
 use tsify::Tsify;
 
 // 优先级枚举，完全参考你的 HIR 定义
 #[derive(PartialEq, PartialOrd, Clone, Copy)]
 pub enum Precedence {
+    Compare = 5,  // >=, <, =, ...
     Sum = 10,     // +, -
     Product = 20, // *, /, %
     Dice = 30,    // d, kh, !, etc.
@@ -12,34 +15,100 @@ pub enum Precedence {
 }
 
 #[derive(Debug, Clone, Serialize, Tsify)]
+#[cfg_attr(feature = "cache", derive(Deserialize))]
 #[serde(tag = "type", content = "value", rename_all = "camelCase")]
 pub enum DiceFaceType {
-    Standard(i32), // 标准骰子，面数
-    Fudge,         // Fudge骰子
-    Coin,          // Coin骰子
+    Standard(i32),             // 标准骰子，面数
+    Fudge,                     // Fudge骰子
+    Coin,                      // Coin骰子
+    Labeled(Vec<LabeledFace>), // 带标签的骰子，按顺序列出每一面
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Tsify)]
+#[cfg_attr(feature = "cache", derive(Deserialize))]
+#[serde(rename_all = "camelCase")]
+pub struct LabeledFace {
+    pub label: String,
+    pub value: i32,
+}
+
+// 3D 前端用于选择骰子模型的形状提示，常见面数对应正多面体，其余为通用形状
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Tsify)]
+#[serde(rename_all = "camelCase")]
+pub enum DiceShape {
+    D4,
+    D6,
+    D8,
+    D10,
+    D12,
+    D20,
+    Generic,
+}
+
+impl DiceFaceType {
+    // Fudge 骰子是六面体，标签骰子按面数选择，硬币与其他面数没有对应的多面体
+    pub fn shape_hint(&self) -> DiceShape {
+        if let DiceFaceType::Labeled(faces) = self {
+            return DiceFaceType::Standard(faces.len() as i32).shape_hint();
+        }
+        match self {
+            DiceFaceType::Standard(4) => DiceShape::D4,
+            DiceFaceType::Standard(6) | DiceFaceType::Fudge => DiceShape::D6,
+            DiceFaceType::Standard(8) => DiceShape::D8,
+            DiceFaceType::Standard(10) => DiceShape::D10,
+            DiceFaceType::Standard(12) => DiceShape::D12,
+            DiceFaceType::Standard(20) => DiceShape::D20,
+            _ => DiceShape::Generic,
+        }
+    }
 }
 
 // 简化的值的摘要，方便前端直接显示，不需要处理复杂的 Enum
 #[derive(Debug, Clone, Serialize, Tsify)]
+#[cfg_attr(feature = "cache", derive(Deserialize))]
 #[serde(tag = "type", content = "value", rename_all = "camelCase")]
 pub enum ValueSummary {
     Number(f64),
     List(Vec<f64>),
     DicePool {
         total: i32,
+        raw_total: i32, // 全部骰子 (包括被丢弃的) 之和，便于核对丢弃了多少
         face: DiceFaceType,
         details: Vec<DieDetailSummary>,
+        // details 为完整名单 (show_all) 时，另给出只含被保留骰子的明细；否则 details 即为该子集，此处为 None
+        kept_details: Option<Vec<DieDetailSummary>>,
+        highest: Option<i32>,     // 被保留的骰子中的最大值，骰池为空时为 None
+        lowest: Option<i32>,      // 被保留的骰子中的最小值，骰池为空时为 None
+        coins: Option<CoinTally>, // 硬币骰池中正反面的个数，其他骰池为 None
     },
     SuccessPool {
-        count: i32,
+        count: i32, // 净成功数
         face: DiceFaceType,
         details: Vec<DieDetailSummary>,
+        kept_details: Option<Vec<DieDetailSummary>>, // 同上
+        highest: Option<i32>,
+        lowest: Option<i32>,
+        successes: i32, // 被保留的骰子中成功的个数
+        failures: i32,  // 被保留的骰子中失败的个数
     },
     NotComputed, // 对应 Waiting 或 Error，应该不会出现在最终结果中，但是保留，以保证健壮性
 }
 
+impl ValueSummary {
+    // 结果的数值：骰子池取总和，成功池取净成功数，列表与未计算的值返回 None
+    pub fn numeric_total(&self) -> Option<f64> {
+        match self {
+            ValueSummary::Number(n) => Some(*n),
+            ValueSummary::DicePool { total, .. } => Some(*total as f64),
+            ValueSummary::SuccessPool { count, .. } => Some(*count as f64),
+            ValueSummary::List(_) | ValueSummary::NotComputed => None,
+        }
+    }
+}
+
 // 定义节点的渲染布局模式
 #[derive(Debug, Clone, Serialize, Tsify)]
+#[cfg_attr(feature = "cache", derive(Deserialize))]
 #[serde(tag = "type", content = "children", rename_all = "camelCase")]
 pub enum NodeLayout {
     Atom,                                         // 原子值，直接显示 label (例如常量)
@@ -51,16 +120,20 @@ pub enum NodeLayout {
     Function(Vec<OutputNode>), // 函数调用: Label + "(" + Children.join(",") + ")"
     // 特殊函数调用: Label + Children[0] + Children[2] + "(" + Children[1] + ")"
     Filter(Box<String>, Box<OutputNode>, Box<OutputNode>),
-    // 特殊修饰符，如爆炸、重投等，mod_param, lt, lc
+    // 特殊修饰符，如爆炸、重投等，mod_param, lt, lc, lsz
     SpecialModifier(
         Box<OutputNode>,
         Option<Box<(String, OutputNode)>>,
         Option<Box<OutputNode>>,
         Option<Box<OutputNode>>,
+        Option<Box<OutputNode>>,
     ),
+    // 条件丢弃: Children[0] + Label + Children[1] + mod_param (例如 4d6dl1<2)
+    ConditionalModifier(Box<OutputNode>, Box<OutputNode>, Box<(String, OutputNode)>),
 }
 
 #[derive(Debug, Clone, Serialize, Tsify)]
+#[cfg_attr(feature = "cache", derive(Deserialize))]
 #[serde(rename_all = "camelCase")]
 pub enum OutcomeType {
     Success,
@@ -69,18 +142,75 @@ pub enum OutcomeType {
 }
 
 #[derive(Debug, Clone, Serialize, Tsify)]
+#[cfg_attr(feature = "cache", derive(Deserialize))]
 #[serde(rename_all = "camelCase")]
 pub struct DieDetailSummary {
     pub result: i32,
     pub is_kept: bool,
     pub roll_history: Vec<i32>, // 包含聚合爆炸的所有结果
+    pub success_weight: i32,    // 计为几个成功：成功为 1，加权计数 cs[...] 时为对应权重，否则为 0
     pub is_rerolled: bool,      // 是否导致了重掷
+    // 被重投时，替换它的新骰子在 details 中的下标；只包含被保留的骰子时为 None
+    pub replaced_by: Option<usize>,
     pub exploded_times: i32,    // 该骰子爆炸了多少次，用于compound骰子显示
+    pub was_clamped: bool,      // 结果是否被 min/max 修饰符改写过
+    pub symbol: Option<String>, // Fudge/Coin 骰子结果的显示符号，标准骰子为 None
     pub outcome: OutcomeType,   // "Success", "Failure", "None"
 }
 
+// 硬币骰池 (XdC) 中被保留的骰子里，正面 (1) 与反面 (0) 的个数
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Tsify)]
+#[cfg_attr(feature = "cache", derive(Deserialize))]
+#[serde(rename_all = "camelCase")]
+pub struct CoinTally {
+    pub heads: i32,
+    pub tails: i32,
+}
+
+// Fudge 与 Coin 骰子结果的显示符号，默认为 +/-/0 与 H/T
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FaceSymbols {
+    pub fudge_plus: String,
+    pub fudge_minus: String,
+    pub fudge_blank: String,
+    pub coin_heads: String,
+    pub coin_tails: String,
+}
+
+impl Default for FaceSymbols {
+    fn default() -> Self {
+        FaceSymbols {
+            fudge_plus: "+".to_string(),
+            fudge_minus: "-".to_string(),
+            fudge_blank: "0".to_string(),
+            coin_heads: "H".to_string(),
+            coin_tails: "T".to_string(),
+        }
+    }
+}
+
+impl FaceSymbols {
+    // 返回骰子结果对应的符号，标准骰子或超出面值范围的结果 (如聚合爆炸的和) 返回 None
+    pub fn symbol<'a>(&'a self, face: &'a DiceFaceType, result: i32) -> Option<&'a str> {
+        match (face, result) {
+            (DiceFaceType::Fudge, 1) => Some(&self.fudge_plus),
+            (DiceFaceType::Fudge, -1) => Some(&self.fudge_minus),
+            (DiceFaceType::Fudge, 0) => Some(&self.fudge_blank),
+            (DiceFaceType::Coin, 1) => Some(&self.coin_heads),
+            (DiceFaceType::Coin, 0) => Some(&self.coin_tails),
+            // 多个面取值相同时显示第一个标签
+            (DiceFaceType::Labeled(faces), _) => faces
+                .iter()
+                .find(|f| f.value == result)
+                .map(|f| f.label.as_str()),
+            _ => None,
+        }
+    }
+}
+
 // 核心输出节点
 #[derive(Debug, Clone, Serialize, Tsify)]
+#[cfg_attr(feature = "cache", derive(Deserialize))]
 #[serde(rename_all = "camelCase")]
 #[tsify(into_wasm_abi)]
 pub struct OutputNode {
@@ -100,3 +230,43 @@ pub struct OutputNode {
     // 在rust中渲染完成，避免由js来处理优先级关系
     pub wrap_in_parentheses: bool,
 }
+
+// 顶层加法链的逐项拆分，如 2d6 + 1d8 + 3 拆分为三项
+#[derive(Debug, Clone, Serialize, Tsify)]
+#[cfg_attr(feature = "cache", derive(Deserialize))]
+#[serde(rename_all = "camelCase")]
+#[tsify(into_wasm_abi)]
+pub struct RollBreakdown {
+    pub terms: Vec<BreakdownTerm>,
+    pub total: ValueSummary,
+}
+
+#[derive(Debug, Clone, Serialize, Tsify)]
+#[cfg_attr(feature = "cache", derive(Deserialize))]
+#[serde(rename_all = "camelCase")]
+pub struct BreakdownTerm {
+    pub is_negative: bool, // 该项在加法链中是否被减去
+    pub node: OutputNode,  // 该项自身的结果树
+}
+
+// ==========================================
+// 单元测试
+// ==========================================
+
+#[test]
+fn test_dice_shape_hint() {
+    assert_eq!(DiceFaceType::Standard(20).shape_hint(), DiceShape::D20);
+    assert_eq!(DiceFaceType::Standard(4).shape_hint(), DiceShape::D4);
+    assert_eq!(DiceFaceType::Fudge.shape_hint(), DiceShape::D6);
+    assert_eq!(DiceFaceType::Standard(7).shape_hint(), DiceShape::Generic);
+    assert_eq!(DiceFaceType::Standard(100).shape_hint(), DiceShape::Generic);
+    assert_eq!(DiceFaceType::Coin.shape_hint(), DiceShape::Generic);
+    let faces = ["miss", "hit", "crit", "crit"].map(|label| LabeledFace {
+        label: label.to_string(),
+        value: 1,
+    });
+    assert_eq!(
+        DiceFaceType::Labeled(faces.to_vec()).shape_hint(),
+        DiceShape::D4
+    );
+}
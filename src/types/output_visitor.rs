@@ -0,0 +1,130 @@
+use super::output_node::*;
+
+// ==========================================
+// 结果树遍历 (只读 Visitor Pattern)
+// ==========================================
+
+// 深度优先遍历 OutputNode，先访问子节点，再按值的种类访问自身
+// 骰池修饰符 (kh, !, cs 等) 节点的明细中已经包含了其操作数骰池的全部骰子，
+// 因此操作数骰池不会再触发 visit_dice_pool / visit_success_pool，
+// 每一组骰子只会被访问一次
+pub trait OutputVisitor {
+    // ==========================================
+    // 顶层入口
+    // ==========================================
+    fn visit_output(&mut self, node: &OutputNode) {
+        self.visit_node(node, false);
+    }
+
+    fn visit_node(&mut self, node: &OutputNode, is_pool_operand: bool) {
+        self.visit_node_children(node);
+        self.visit_node_self(node, is_pool_operand);
+    }
+
+    fn visit_node_children(&mut self, node: &OutputNode) {
+        for (child, is_pool_operand) in node.children() {
+            self.visit_node(child, is_pool_operand);
+        }
+    }
+
+    fn visit_node_self(&mut self, node: &OutputNode, is_pool_operand: bool) {
+        match &node.value {
+            ValueSummary::Number(n) => self.visit_number(node, *n),
+            ValueSummary::List(l) => self.visit_list(node, l),
+            ValueSummary::DicePool { total, details, .. } if !is_pool_operand => {
+                self.visit_dice_pool(node, *total, details)
+            }
+            ValueSummary::SuccessPool { count, details, .. } if !is_pool_operand => {
+                self.visit_success_pool(node, *count, details)
+            }
+            _ => {}
+        }
+    }
+
+    // ==========================================
+    // 按值的种类访问
+    // ==========================================
+    fn visit_number(&mut self, _node: &OutputNode, _value: f64) {}
+
+    fn visit_list(&mut self, _node: &OutputNode, _values: &[f64]) {}
+
+    fn visit_dice_pool(&mut self, _node: &OutputNode, _total: i32, _details: &[DieDetailSummary]) {}
+
+    fn visit_success_pool(
+        &mut self,
+        _node: &OutputNode,
+        _count: i32,
+        _details: &[DieDetailSummary],
+    ) {
+    }
+}
+
+impl OutputNode {
+    // 按显示顺序返回子节点，以及该子节点是否为当前骰池修饰符的操作数骰池
+    pub fn children(&self) -> Vec<(&OutputNode, bool)> {
+        let is_pool = matches!(
+            self.value,
+            ValueSummary::DicePool { .. } | ValueSummary::SuccessPool { .. }
+        );
+        match &self.layout {
+            NodeLayout::Atom => Vec::new(),
+            NodeLayout::List(children) => children.iter().map(|c| (c, false)).collect(),
+            NodeLayout::Prefix(child) | NodeLayout::TightPostfix(child) => vec![(child, false)],
+            NodeLayout::Infix(l, r) => vec![(l, false), (r, false)],
+            // "d" 的左侧是骰子个数，其余紧凑中缀 (kh, cs...) 的左侧都是被修饰的骰池
+            NodeLayout::TightInfix(l, r) => vec![(l, is_pool && self.label != "d"), (r, false)],
+            NodeLayout::Function(children) => children.iter().map(|c| (c, is_pool)).collect(),
+            NodeLayout::Filter(_, list, value) => vec![(value, false), (list, false)],
+            NodeLayout::SpecialModifier(pool, mp, lt, lc, lsz) => {
+                let mut children = vec![(pool.as_ref(), true)];
+                children.extend(mp.iter().map(|mp| (&mp.1, false)));
+                children.extend(lt.iter().map(|lt| (lt.as_ref(), false)));
+                children.extend(lc.iter().map(|lc| (lc.as_ref(), false)));
+                children.extend(lsz.iter().map(|lsz| (lsz.as_ref(), false)));
+                children
+            }
+            NodeLayout::ConditionalModifier(pool, count, mp) => {
+                vec![(pool, true), (count, false), (&mp.1, false)]
+            }
+        }
+    }
+}
+
+// ==========================================
+// 单元测试
+// ==========================================
+
+#[cfg(test)]
+struct DiceCounter {
+    dice: usize,
+    kept: usize,
+}
+
+#[cfg(test)]
+impl OutputVisitor for DiceCounter {
+    fn visit_dice_pool(&mut self, _node: &OutputNode, _total: i32, details: &[DieDetailSummary]) {
+        self.dice += details.len();
+        self.kept += details.iter().filter(|d| d.is_kept).count();
+    }
+
+    fn visit_success_pool(
+        &mut self,
+        _node: &OutputNode,
+        _count: i32,
+        details: &[DieDetailSummary],
+    ) {
+        self.dice += details.len();
+        self.kept += details.iter().filter(|d| d.is_kept).count();
+    }
+}
+
+#[test]
+fn test_count_dice_in_nested_result() {
+    use crate::runtime::roll_with_fixed_results;
+    // 第一轮投出 1d4 [3] 与 2d8 [6, 1]，第二轮投出 3d6 [2, 5, 4] 并保留最高的 2 个
+    let result = roll_with_fixed_results("(1d4)d6kh2 + 2d8cs>5", &[3, 6, 1, 2, 5, 4]).unwrap();
+    let mut counter = DiceCounter { dice: 0, kept: 0 };
+    counter.visit_output(&result);
+    assert_eq!(counter.dice, 6);
+    assert_eq!(counter.kept, 5);
+}
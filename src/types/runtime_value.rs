@@ -1,4 +1,7 @@
 use crate::types::eval_graph::*;
+use crate::types::expr::CompareOp;
+use serde::{Deserialize, Serialize};
+use tsify::Tsify;
 
 // ==========================================
 // 运行时值
@@ -37,17 +40,74 @@ pub struct SuccessPoolType {
 }
 
 impl SuccessPoolType {
-    pub fn renew_success_count(&mut self) {
-        self.success_count = self
+    // clamp_at_zero 为 true 时，失败多于成功的净成功数记为 0 而不是负数
+    pub fn renew_success_count(&mut self, clamp_at_zero: bool) {
+        let net_count: i32 = self
             .details
             .iter()
-            .filter(|d| d.is_kept)
-            .map(|d| match d.outcome {
-                DieOutcome::Success => 1,
-                DieOutcome::Failure => -1,
-                DieOutcome::None => 0,
-            })
+            .filter(|d| d.counts_toward_successes())
+            .map(|d| d.outcome.weight())
             .sum();
+        self.success_count = if clamp_at_zero {
+            net_count.max(0)
+        } else {
+            net_count
+        };
+    }
+
+    // 不重新投掷，按新的成功条件重新统计 (例如查看 "若难度不同会怎样")
+    // 原有的成功标记全部按新条件重新判定，失败标记 (df) 与已消耗的骰子 (sp) 保持不变，
+    // 与 cs 之后再 df 时失败优先的规则一致；净成功数不做截断
+    pub fn recount(&self, op: CompareOp, target: f64) -> SuccessPoolType {
+        let compare_func = get_compare_function(op, target);
+        let mut recounted = self.clone();
+        for detail in recounted.details.iter_mut() {
+            if matches!(detail.outcome, DieOutcome::Failure | DieOutcome::Spent) {
+                continue;
+            }
+            detail.outcome = if detail.is_kept && compare_func(detail.result as f64) {
+                DieOutcome::Success
+            } else {
+                DieOutcome::None
+            };
+        }
+        recounted.renew_success_count(false);
+        recounted
+    }
+}
+
+// round 在 .5 处的取舍方式，数值与列表的 round 共用同一个设置
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+#[serde(rename_all = "camelCase")]
+pub enum RoundingMode {
+    #[default]
+    HalfAwayFromZero, // 四舍五入，2.5 -> 3，-2.5 -> -3
+    HalfEven, // 四舍六入五成双，2.5 -> 2，3.5 -> 4
+}
+
+impl RoundingMode {
+    pub fn round(self, x: f64) -> f64 {
+        match self {
+            RoundingMode::HalfAwayFromZero => x.round(),
+            RoundingMode::HalfEven => x.round_ties_even(),
+        }
+    }
+
+    // 两种取舍方式结果不同的值 (恰好位于两个整数正中间)
+    pub fn is_tie(x: f64) -> bool {
+        x.round() != x.round_ties_even()
+    }
+}
+
+pub fn get_compare_function(op: CompareOp, number: f64) -> impl Fn(f64) -> bool {
+    move |x: f64| match op {
+        CompareOp::Greater => x > number,
+        CompareOp::GreaterEqual => x >= number,
+        CompareOp::Less => x < number,
+        CompareOp::LessEqual => x <= number,
+        CompareOp::Equal => (x - number).abs() < f64::EPSILON,
+        CompareOp::NotEqual => (x - number).abs() >= f64::EPSILON,
     }
 }
 
@@ -92,11 +152,25 @@ impl RuntimeValue {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum DieOutcome {
-    None,    // 不参与成功/失败统计
-    Success, // 成功
-    Failure, // 失败
+    None,                 // 不参与成功/失败统计
+    Success,              // 成功
+    Failure,              // 失败
+    WeightedSuccess(i32), // 成功，计为多个成功 (加权计数 cs[=10:2, >=7:1])
+    Spent,                // 成功，且骰子已被消耗 (sp)：不再保留，但仍计入成功数
+}
+
+impl DieOutcome {
+    // 该骰子对净成功数的贡献
+    pub fn weight(&self) -> i32 {
+        match self {
+            DieOutcome::Success | DieOutcome::Spent => 1,
+            DieOutcome::Failure => -1,
+            DieOutcome::None => 0,
+            DieOutcome::WeightedSuccess(w) => *w,
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -115,15 +189,56 @@ pub struct DieDetail {
     pub roll_history: Vec<i32>, // 对于聚合爆炸，会记录所有的投掷结果
     pub is_kept: bool,
     pub outcome: DieOutcome,
-    pub is_rerolled: bool,   // 是否导致了重掷
-    pub exploded_times: i32, // 该骰子爆炸了多少次，用于compound骰子显示
+    pub is_rerolled: bool,          // 是否导致了重掷
+    pub replaced_by: Option<usize>, // 被重投时，替换它的新骰子在 details 中的下标
+    pub exploded_times: i32,        // 该骰子爆炸了多少次，用于compound骰子显示
+    pub was_clamped: bool,          // 结果是否被 min/max 修饰符改写过
 }
 
-#[derive(Debug, Clone)]
+impl DieDetail {
+    // 是否计入成功池的净成功数：被保留的骰子，以及被 sp 消耗的骰子
+    pub fn counts_toward_successes(&self) -> bool {
+        self.is_kept || self.outcome == DieOutcome::Spent
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Tsify)]
+#[serde(tag = "type", content = "value", rename_all = "camelCase")]
 pub enum DiceFace {
     Number(i32),
     Fudge,
     Coin,
+    Labeled(Vec<(String, i32)>), // 带标签的骰子，按顺序记录每一面的标签与值
+}
+
+impl DiceFace {
+    // 向外部请求投掷时使用的面型：标签骰子按面数投掷一个普通骰子
+    pub fn request_face(&self) -> DiceFace {
+        match self {
+            DiceFace::Labeled(faces) => DiceFace::Number(faces.len() as i32),
+            other => other.clone(),
+        }
+    }
+
+    // 将外部返回的投掷结果换算为骰子的值，标签骰子的第 n 面对应投掷结果 n
+    pub fn face_value(&self, roll: i32) -> Result<i32, String> {
+        match self {
+            DiceFace::Labeled(faces) => usize::try_from(roll - 1)
+                .ok()
+                .and_then(|i| faces.get(i))
+                .map(|face| face.1)
+                .ok_or_else(|| format!("Dice result {} is out of range for a labeled die", roll)),
+            _ => Ok(roll),
+        }
+    }
+
+    pub fn max_value(&self) -> i32 {
+        match self {
+            DiceFace::Number(n) => *n,
+            DiceFace::Fudge | DiceFace::Coin => 1,
+            DiceFace::Labeled(faces) => faces.iter().map(|f| f.1).max().unwrap_or(0),
+        }
+    }
 }
 
 // ==========================================
@@ -141,6 +256,7 @@ pub struct DynamicState {
     pub pool: DicePoolType,
     pub limit_times: Option<i32>,
     pub limit_count: Option<i32>,
+    pub limit_size: Option<usize>, // 骰子池最多包含的骰子数，仅用于爆炸
     // 记录哪些骰子索引触发了这次操作 (用于Compound/Reroll定位)，并存储对应的掷骰结果
     pub pending_dice: Vec<(usize, Option<i32>, Option<RollId>)>,
 }
@@ -181,3 +297,96 @@ pub struct RuntimeRequest {
 pub struct RuntimeResponse {
     pub results: Vec<(i32, RollId)>, // 每个骰子的结果和对应的投掷 ID
 }
+
+// ==========================================
+// 动态操作追踪
+// ==========================================
+
+// 动态操作的种类：爆炸产生的骰子计入全局爆炸上限，重投的骰子需要从动画中移除
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DynamicOpKind {
+    Explode,    // ! 与 !!
+    Reroll,     // r
+    RerollPool, // rp
+    Cascade,    // cascade
+}
+
+// 每一轮爆炸/重投的记录，node_id 为计算图中的节点编号
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceEvent {
+    // 满足条件的骰子 (爆炸时保留，重投时被移除) 及本轮请求的骰子数
+    Requested {
+        node_id: u32,
+        kind: DynamicOpKind,
+        triggered_by: Vec<i32>,
+        count: u32,
+    },
+    // 本轮请求得到的投掷结果
+    Resolved {
+        node_id: u32,
+        kind: DynamicOpKind,
+        results: Vec<i32>,
+    },
+    // 没有新的请求，动态操作结束，total 为最终骰子池的总和
+    Finished {
+        node_id: u32,
+        kind: DynamicOpKind,
+        total: i32,
+    },
+}
+
+// ==========================================
+// 单元测试
+// ==========================================
+
+#[test]
+fn test_recount_success_pool() {
+    let die = |result: i32, is_kept: bool, outcome: DieOutcome| DieDetail {
+        result,
+        roll_id: Vec::new(),
+        roll_history: vec![result],
+        is_kept,
+        outcome,
+        is_rerolled: false,
+        replaced_by: None,
+        exploded_times: 0,
+        was_clamped: false,
+    };
+    // 5d10cs>=7df=1 投出 [9, 7, 5, 1]，另有一颗被丢弃的 10
+    let pool = SuccessPoolType {
+        success_count: 1,
+        face: DiceFace::Number(10),
+        details: vec![
+            die(9, true, DieOutcome::Success),
+            die(7, true, DieOutcome::Success),
+            die(5, true, DieOutcome::None),
+            die(1, true, DieOutcome::Failure),
+            die(10, false, DieOutcome::None),
+        ],
+    };
+    let easier = pool.recount(CompareOp::GreaterEqual, 5.0);
+    assert_eq!(easier.success_count, 2);
+    assert_eq!(easier.details[2].outcome, DieOutcome::Success);
+    assert_eq!(easier.details[3].outcome, DieOutcome::Failure);
+    assert_eq!(easier.details[4].outcome, DieOutcome::None);
+    let harder = pool.recount(CompareOp::Greater, 8.0);
+    assert_eq!(harder.success_count, 0);
+    assert_eq!(harder.details[1].outcome, DieOutcome::None);
+    // 原骰池不受影响
+    assert_eq!(pool.success_count, 1);
+}
+
+#[test]
+fn test_except_number_on_pools() {
+    // 成功池参与算术运算时取净成功数
+    let success_pool = |success_count: i32| {
+        RuntimeValue::SuccessPool(Box::new(SuccessPoolType {
+            success_count,
+            face: DiceFace::Number(6),
+            details: Vec::new(),
+        }))
+    };
+    assert_eq!(success_pool(2).except_number(), Ok(2.0));
+    assert_eq!(success_pool(-1).except_number(), Ok(-1.0));
+    assert!(RuntimeValue::List(vec![1.0]).except_number().is_err());
+}
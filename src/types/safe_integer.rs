@@ -0,0 +1,46 @@
+// ==========================================
+// 整数运算的安全范围检查
+// ==========================================
+
+// f64 能精确表示全部整数的上界 2^53，超出此范围后相邻的整数无法区分
+pub const MAX_SAFE_INTEGER: f64 = 9_007_199_254_740_992.0;
+
+// 整除与取模按整数语义计算，操作数超出安全范围时结果会悄悄失真，因此直接报错
+fn check_integer_operands(op: &str, l: f64, r: f64) -> Result<(), String> {
+    for v in [l, r] {
+        if !v.is_finite() || v.abs() > MAX_SAFE_INTEGER {
+            return Err(format!(
+                "Operand {} of {} is outside the safe integer range (±2^53)",
+                v, op
+            ));
+        }
+    }
+    Ok(())
+}
+
+// 调用方需先检查除数是否为 0，以便给出各自的错误信息
+pub fn checked_int_divide(l: f64, r: f64) -> Result<f64, String> {
+    check_integer_operands("integer division", l, r)?;
+    Ok((l / r).floor())
+}
+
+pub fn checked_modulo(l: f64, r: f64) -> Result<f64, String> {
+    check_integer_operands("modulo", l, r)?;
+    Ok(l % r)
+}
+
+// ==========================================
+// 单元测试
+// ==========================================
+
+#[test]
+fn test_checked_integer_ops() {
+    assert_eq!(checked_int_divide(7.0, 2.0), Ok(3.0));
+    assert_eq!(checked_int_divide(-7.0, 2.0), Ok(-4.0));
+    assert_eq!(checked_modulo(7.0, 3.0), Ok(1.0));
+    assert_eq!(checked_modulo(MAX_SAFE_INTEGER, 10.0), Ok(2.0));
+    assert!(checked_int_divide(MAX_SAFE_INTEGER * 2.0, 3.0).is_err());
+    assert!(checked_modulo(5.0, -1e20).is_err());
+    assert!(checked_modulo(f64::INFINITY, 3.0).is_err());
+    assert!(checked_int_divide(f64::NAN, 3.0).is_err());
+}
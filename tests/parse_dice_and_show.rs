@@ -1,4 +1,4 @@
-use oxidice::parse_dice_and_show;
+use oxidice::{canonicalize, parse_dice_and_show, try_const_eval};
 
 fn test_legal_input(input: &str, expected: &str) {
     match parse_dice_and_show(input) {
@@ -46,6 +46,17 @@ fn constant_fold() {
     test_legal_input("avg([])", "0");
     test_legal_input("len([1d8, 2d8, 3d8] + [4d6, 5d6])", "5");
     test_legal_input("len(tolist(1d6))", "len(tolist(1d6))");
+    // 子节点先折叠为常数列表，len 随后即可折叠
+    test_legal_input("len(sort([3, 1, 2]))", "3");
+    test_legal_input("len(filter>1([1, 2, 3]))", "2");
+    test_legal_input("len(sortd(filter>1([1, 2, 3] + [4])))", "3");
+    test_legal_input("len(filter>1([1d6, 2, 3]))", "len(filter>1([1d6,2,3]))");
+    test_legal_input("avg(4d6dl1)", "avg(tolist(4d6dl1))");
+    test_legal_input("sum(4d6dl1)", "sum(tolist(4d6dl1))");
+    test_legal_input("max(4d6)", "max([4d6])");
+    test_legal_input("len(4d6)", "1");
+    test_legal_input("filter>3(4d6)", "filter>3([4d6])");
+    test_legal_input("max(4d6, 3)", "max([4d6,3])");
     test_legal_input("rpdice(sum([1d8, 2d8, 3d8] + [4d6, 5d6]))", "12d8+18d6");
     test_legal_input("(1d6)d(1d20)", "(1d6)d(1d20)");
     test_legal_input("floor([1.2, 2.5, 3.7])", "[1,2,3]");
@@ -78,7 +89,15 @@ fn constant_fold() {
     test_legal_input("filter<3([1,2,3,4,5])", "[1,2]");
     test_legal_input("filter<=3([1,2,3,4,5])", "[1,2,3]");
     test_legal_input("filter=3([1,2,3,4,5])", "[3]");
-    test_legal_input("filter=(1d6)([1,2,3,4,5])", "filter=(1d6)([1,2,3,4,5])");
+    test_legal_input("filter!=3([1,2,3,4,5])", "[1,2,4,5]");
+    test_legal_input("filter=(1+2)([1,2,3,4,5])", "[3]");
+    // 比较值必须是常量
+    test_illegal_input("filter=(1d6)([1,2,3,4,5])");
+    assert_eq!(
+        parse_dice_and_show("filter>(1d6)([1,2,3])"),
+        Err("filter function requires a constant compare value".to_string())
+    );
+    test_illegal_input("filter>1d6([1,2,3])");
     test_legal_input("filter<3([1d6,2,3,4,5])", "filter<3([1d6,2,3,4,5])");
     test_legal_input("[1,2,3] + tolist(1d6)", "[1,2,3]+tolist(1d6)");
     test_legal_input("[1,2,3]**3", "[1,2,3,1,2,3,1,2,3]");
@@ -136,6 +155,53 @@ fn constant_fold() {
     test_legal_input("abs(-1d6)", "abs(-(1d6))");
     test_legal_input("-abs(-1d6)", "-abs(-(1d6))");
     test_legal_input("rpdice(1d6 + 1dF + 1dC)", "2dC+2dF+2d6");
+    // HIR 显示不做舍入，重新解析后得到相同的值
+    test_legal_input("10 / 3", "3.3333333333333335");
+    test_legal_input("1d6 * (1 / 3)", "1d6*0.3333333333333333");
+    test_legal_input("1d6*0.3333333333333333", "1d6*0.3333333333333333");
+    test_legal_input("1e20 * 1d6", "1d6*100000000000000000000");
+    test_legal_input("1e-7 * 1d6", "1d6*0.0000001");
+    test_legal_input("7.5 * 2", "15");
+}
+
+#[test]
+fn const_eval() {
+    assert_eq!(try_const_eval("2+3*4"), Ok(Some(14.0)));
+    assert_eq!(try_const_eval("round(7 / 2)"), Ok(Some(4.0)));
+    assert_eq!(try_const_eval("1d6 - 1d6 + 2"), Ok(None));
+    assert_eq!(try_const_eval("1d6+1"), Ok(None));
+    assert_eq!(try_const_eval("[1, 2, 3]"), Ok(None));
+    assert!(try_const_eval("2 +").is_err());
+    assert!(try_const_eval("1 / 0").is_err());
+}
+
+#[test]
+fn canonical_cache_keys() {
+    let same = |a: &str, b: &str| assert_eq!(canonicalize(a), canonicalize(b), "{} vs {}", a, b);
+    same("4d6kh3 + 2", "  4d6kh3+2 ");
+    same("max(1d6 , 1d8)", "max(1d6,1d8)");
+    same("(2d6cs>=5) >= 3", "(2d6cs>=5)>=3");
+    same("2+3 * 1d6", "3*1d6 + 2");
+    same("1d6 + 1d8 + 1", "1 + 1d8 + 1d6");
+    assert_eq!(canonicalize("2 * (3 * 1d6)"), Ok("1d6*6".to_string()));
+    assert_ne!(canonicalize("1d6 - 2"), canonicalize("2 - 1d6"));
+    assert!(canonicalize("2 +").is_err());
+    // 常数不做舍入，相近但不同的常数得到不同的键
+    assert_ne!(canonicalize("1d6*0.33331"), canonicalize("1d6*0.33334"));
+    assert_eq!(canonicalize("1d6*0.33331"), Ok("1d6*0.33331".to_string()));
+    assert_ne!(canonicalize("1d6*(1/3)"), canonicalize("1d6*0.3333"));
+    // 共享绑定与比较的分组都保留在键中
+    assert_ne!(
+        canonicalize("let share x = 1d6; x - x"),
+        canonicalize("1d6-1d6")
+    );
+    assert_ne!(canonicalize("4d6dl<3"), canonicalize("(4d6dl)<3"));
+    assert!(canonicalize("4d6dl <3").is_err());
+    // 键本身可以重新解析，得到同一个键
+    for input in ["let share x = 1d6; x - x", "(4d6dl)<3", "2+3 * 1d6"] {
+        let key = canonicalize(input).unwrap();
+        assert_eq!(canonicalize(&key), Ok(key.clone()), "{}", input);
+    }
 }
 
 #[test]
@@ -152,6 +218,8 @@ fn illegal_expressions() {
     test_illegal_input("[1,2,3] ** (2 - 3)");
     test_illegal_input("[1,2,3] ** 1d6");
     test_illegal_input("tolist(1d6) ** 4");
+    test_illegal_input("[1] ** 1000000000");
+    test_illegal_input("[1, 2, 3] ** 5000");
     test_illegal_input("2 / 0");
     test_illegal_input("2 // 0");
     test_illegal_input("2 % 0");
@@ -161,6 +229,11 @@ fn illegal_expressions() {
     test_illegal_input("2 / [1,2,0]");
     test_illegal_input("2 // [1,2,0]");
     test_illegal_input("2 % [1,2,0]");
+    test_illegal_input("100000000000000000000 % 3");
+    test_illegal_input("100000000000000000000 // 3");
+    test_illegal_input("7 % -100000000000000000000");
+    test_illegal_input("[1, 100000000000000000000] // 3");
+    test_illegal_input("100000000000000000000 % [1, 2]");
     test_illegal_input("1d6/0/3");
     test_illegal_input("1d6/2/0");
     test_illegal_input("(1/0)d6");
@@ -189,6 +262,12 @@ fn illegal_expressions() {
     test_illegal_input("10d6!!<[1,2]");
     test_illegal_input("10d6!<3lt[1,2]lc10");
     test_illegal_input("10d6!<3lt3lc[1,2]");
+    test_illegal_input("2d6!lsz0");
+    test_illegal_input("2d6!lsz2.5");
+    test_illegal_input("2d6!lsz(1d6)");
+    test_illegal_input("2d6!!lsz5");
+    test_illegal_input("2d6r1lsz5");
+    test_illegal_input("2d6!lsz5lsz6");
     test_illegal_input("max()");
     test_illegal_input("max([])");
     test_illegal_input("min([])");
@@ -200,6 +279,8 @@ fn fold_binary_op() {
     test_legal_input("1d6/1d6", "1d6/1d6");
     test_legal_input("1d6%1d6", "1d6%1d6");
     test_legal_input("5%2", "1");
+    test_legal_input("9007199254740992 % 10", "2");
+    test_legal_input("9007199254740992 // 2", "4503599627370496");
     test_legal_input("1 + 1d6 - 1d6 + 1d8 - 1d8 - 2", "1d8+1d6-1d8-1d6-1");
     test_legal_input("2d6kh + 3d6kh", "2d6kh1+3d6kh1");
     test_legal_input("1 + 0d6 - 0d6 + 0d8 - 0d8 - 2", "-1");
@@ -218,6 +299,8 @@ fn fold_binary_op() {
 #[test]
 fn modifiers() {
     test_legal_input("10d6kh(1+1)", "10d6kh2");
+    test_legal_input("2d20kh0", "2d20kh0");
+    test_legal_input("2d20kl0+1", "2d20kl0+1");
     test_legal_input("10d6kl(2*2)", "10d6kl4");
     test_legal_input("10d6dh(5-2)", "10d6dh3");
     test_legal_input("10d6dl(8//2)", "10d6dl4");
@@ -229,6 +312,8 @@ fn modifiers() {
     test_legal_input("10d6cs<3", "10d6cs<3");
     test_legal_input("10d6cs<=3", "10d6cs<=3");
     test_legal_input("10d6cs<>3", "10d6cs<>3");
+    test_legal_input("10d6!cs>3", "10d6!cs>3");
+    test_legal_input("10d6!!cs>3", "10d6!!cs>3");
     test_legal_input("10d6df=1", "10d6df=1");
     test_legal_input("10d6max(2*3-1)", "10d6max5");
     test_legal_input("10d6min2", "10d6min2");
@@ -236,8 +321,595 @@ fn modifiers() {
     test_legal_input("10d6!<3lt3lc10", "10d6!<3lt3lc10");
     test_legal_input("10d6!!<3lt3lc10", "10d6!!<3lt3lc10");
     test_legal_input("10d6!<3lc10", "10d6!<3lc10");
+    test_legal_input("2d6!lsz5", "2d6!lsz5");
+    test_legal_input("2d6!lsz(2+3)lt3", "2d6!lt3lsz5");
+    test_legal_input("2d6!>5lc3lsz8", "2d6!>5lc3lsz8");
     test_legal_input("10d6!<3lt3", "10d6!<3lt3");
     test_legal_input("10d6!!<3", "10d6!!<3");
     test_legal_input("10d6!!", "10d6!!");
     test_legal_input("10d6r<3lt3lc10", "10d6r<3lt3lc10");
+    test_legal_input("3d6rp<10", "3d6rp<10");
+    test_legal_input("3d6RerollPool<(5+5)lt2", "3d6rp<10lt2");
+    test_legal_input("3d6rp8", "3d6rp=8");
+    test_illegal_input("3d6rp");
+    test_illegal_input("3d6rp<(1d6)");
+    test_illegal_input("3d6rp<10lc2");
+    test_illegal_input("3d6cs>3rp<10");
+    test_legal_input("4d6dl1<2", "4d6dl1<2");
+    test_legal_input("4d6dh>=(2+3)", "4d6dh1>=5");
+    test_legal_input("4d6dl(1d2)<2", "4d6dl(1d2)<2");
+    test_legal_input("5d6rm=6", "5d6rm1=6");
+    test_legal_input("5d6Remove(1+1)>=5", "5d6rm2>=5");
+    test_illegal_input("5d6rm6");
+    test_illegal_input("5d6rm[1,2]=6");
+    test_illegal_input("[1,2]rm=6");
+}
+
+#[test]
+fn reroll_display_round_trips() {
+    // 显示结果再次解析时得到相同的表达式，r 与 rp 不会被混淆
+    for input in [
+        "2d20r<5",
+        "2d20r<5lt1",
+        "2d20R(1+1)lc1",
+        "3d6rp<10lt1",
+        "2d20r<5!",
+    ] {
+        let shown = parse_dice_and_show(input).unwrap();
+        assert_eq!(parse_dice_and_show(&shown).unwrap(), shown, "{}", input);
+    }
+    // 目前没有只重投一次的 ro 修饰符，用 lt1 表示
+    test_illegal_input("2d20ro<5");
+}
+
+#[test]
+fn always_true_reroll() {
+    // 每个面都满足条件，且没有 lt/lc 限制，重投永远不会停止
+    test_illegal_input("2d6r<7");
+    test_illegal_input("2d6r<=(3+3)");
+    test_illegal_input("4dFr!=2");
+    test_illegal_input("2d6kh1r>0");
+    test_legal_input("2d6r<3", "2d6r<3");
+    test_legal_input("4dFr!=0", "4dFr<>0");
+    test_legal_input("2d6r<7lt3", "2d6r<7lt3");
+    test_legal_input("2d6r<7lc2", "2d6r<7lc2");
+    // 比较值不是常数时无法在解析时判断
+    test_legal_input("2d6r<(1d6)", "2d6r<(1d6)");
+}
+
+#[test]
+fn keep_distinct() {
+    test_legal_input("6d6kdistinct", "6d6kdistinct");
+    test_legal_input("6d6KDistinctkh2", "6d6kdistinctkh2");
+    test_legal_input("(6d6)kdistinct + 1", "6d6kdistinct+1");
+    test_illegal_input("[1,2,3]kdistinct");
+    test_illegal_input("6d6kdistinct3");
+}
+
+#[test]
+fn keep_rank_range() {
+    test_legal_input("6d6k2..4", "6d6k2..4");
+    test_legal_input("6d6K1..1", "6d6k1..1");
+    test_legal_input("6d6k(1+1)..(2*2) + 1", "6d6k2..4+1");
+    test_legal_input("6d6k3..3kh1", "6d6k3..3kh1");
+    test_legal_input("let x = 2; 6d6kx..4", "6d6k2..4");
+    // 上下界必须是正整数常量，且下界不大于上界
+    test_illegal_input("6d6k4..2");
+    test_illegal_input("6d6k0..2");
+    test_illegal_input("6d6k1.5..2");
+    test_illegal_input("6d6k(1d4)..4");
+    test_illegal_input("6d6k2..");
+    test_illegal_input("[1,2,3]k1..2");
+}
+
+#[test]
+fn explode_once() {
+    test_legal_input("4d6!o", "4d6!o");
+    test_legal_input("4d6!o>=5lc2", "4d6!o>=5lc2");
+    test_legal_input("4d6!olsz6", "4d6!olsz6");
+    test_illegal_input("4d6!!o");
+    test_illegal_input("[1,2]!o");
+}
+
+#[test]
+fn explode_multi() {
+    test_legal_input("4d6!x2", "4d6!x2");
+    test_legal_input("4d6!X3>=5lc2", "4d6!x3>=5lc2");
+    test_legal_input("4d6!x(1+1)lt3 + 1", "4d6!x2lt3+1");
+    test_legal_input("4d6!x2kh3", "4d6!x2kh3");
+    // 不带个数时仍是普通的爆炸
+    test_legal_input("let x = 5; 4d6!x", "4d6!=5");
+    test_illegal_input("4d6!x0");
+    test_illegal_input("4d6!x1.5");
+    test_illegal_input("4d6!x(1d2)");
+    test_illegal_input("4d6!x2lsz6");
+    test_illegal_input("1d2!x2000000000>=1lt1");
+    test_illegal_input("[1,2]!x2");
+}
+
+#[test]
+fn keep_relative_to_average() {
+    test_legal_input("4d6kh>avg", "4d6kh>avg");
+    test_legal_input("4d6kl<=avg + 1", "4d6kl<=avg+1");
+    test_legal_input("4d6!kh>=avg", "4d6!kh>=avg");
+    test_illegal_input("4d6cs>3kh>avg");
+    test_illegal_input("[1,2]kh>avg");
+}
+
+#[test]
+fn empty_expression() {
+    for input in ["", "   ", "\t"] {
+        assert_eq!(
+            parse_dice_and_show(input),
+            Err("Empty expression".to_string())
+        );
+    }
+}
+
+#[test]
+fn dice_operand_errors() {
+    // 骰池作为个数或面数是合法的
+    test_legal_input("(1d6)d6", "(1d6)d6");
+    test_legal_input("3d(1d6)", "3d(1d6)");
+    // 列表不能作为个数或面数，报错需指明是哪一部分
+    assert_eq!(
+        parse_dice_and_show("[1, 2]d6"),
+        Err("Dice count must be a number, got a list".to_string())
+    );
+    assert_eq!(
+        parse_dice_and_show("3d[1, 2]"),
+        Err("Dice sides must be a number, got a list".to_string())
+    );
+    assert_eq!(
+        parse_dice_and_show("[1, 2]dF"),
+        Err("Fudge dice count must be a number, got a list".to_string())
+    );
+}
+
+#[test]
+fn modifier_on_parenthesized_group() {
+    // 括号中只有一个骰池时，修饰符作用于该骰池
+    test_legal_input("(2d6)kh1", "2d6kh1");
+    test_legal_input("(4d6dl1)!", "4d6dl1!");
+    test_legal_input("(asdice(5d10cs>=7))kh2", "asdice(5d10cs>=7)kh2");
+    // 骰子之间的运算结果只是一个数字，修饰符不能作用于它，报错需说明原因
+    assert_eq!(
+        parse_dice_and_show("(2d6 + 1d8)kh1"),
+        Err(
+            "The kh modifier can only be applied to a dice pool, but 2d6+1d8 is a number, \
+             not a dice pool (arithmetic on dice gives a plain number; \
+             apply the modifier to a single dice expression instead)"
+                .to_string()
+        )
+    );
+    assert_eq!(
+        parse_dice_and_show("(3)!"),
+        Err("The ! modifier can only be applied to a dice pool, but 3 is a constant".to_string())
+    );
+    assert_eq!(
+        parse_dice_and_show("[1, 2]r1"),
+        Err("The r modifier can only be applied to a dice pool, but [1,2] is a list".to_string())
+    );
+    assert_eq!(
+        parse_dice_and_show("(2d6cs>3)kh>avg"),
+        Err(
+            "The kh>avg modifier can only be applied to a dice pool, but 2d6cs>3 is a \
+             success pool (use asdice(...) to turn it back into a dice pool)"
+                .to_string()
+        )
+    );
+    assert_eq!(
+        parse_dice_and_show("(1d6 * 2)cs>3"),
+        Err(
+            "The cs modifier can only be applied to a dice pool or success pool, \
+             but 1d6*2 is a number, not a dice pool (arithmetic on dice gives a plain number; \
+             apply the modifier to a single dice expression instead)"
+                .to_string()
+        )
+    );
+}
+
+#[test]
+fn rep_function() {
+    test_legal_input("sum(rep(1d6, 3))", "3d6");
+    test_legal_input("rep(1d6 + 1, 1 + 1)", "[1d6+1,1d6+1]");
+    test_legal_input("max(rep(4d6dl, 2))", "max([4d6dl1,4d6dl1])");
+    test_illegal_input("rep(1d6, 0)");
+    test_illegal_input("rep(1d6, 1.5)");
+    test_illegal_input("rep(1d6, 1d4)");
+    test_illegal_input("rep([1, 2], 2)");
+    test_illegal_input("rep(1d6)");
+    test_illegal_input("rep(1d6, 100000000)");
+}
+
+#[test]
+fn gen_function() {
+    test_legal_input("gen(3, 1d6)", "[1d6,1d6,1d6]");
+    test_legal_input("len(gen(5, 1d6))", "5");
+    test_legal_input("gen(1 + 1, 4d6dl)", "[4d6dl1,4d6dl1]");
+    test_illegal_input("gen(0, 1d6)");
+    test_illegal_input("gen(1d4, 1d6)");
+    test_illegal_input("gen(2, [1, 2])");
+    test_illegal_input("gen(2)");
+    test_illegal_input("gen(100000000, 1d6)");
+}
+
+#[test]
+fn margin_function() {
+    test_legal_input("margin(2d6+3, 10)", "margin(2d6+3,10)");
+    test_legal_input("margin(1d20, 1d20)", "margin(1d20,1d20)");
+    test_legal_input("margin(15, 10)", "5");
+    test_illegal_input("margin(1d20)");
+    test_illegal_input("margin(1d20, 10, 5)");
+    test_illegal_input("margin([1, 2], 10)");
+}
+
+#[test]
+fn ravg_rounds_half_to_even() {
+    test_legal_input("ravg([1, 2])", "2");
+    test_legal_input("ravg([2, 3])", "2");
+    test_legal_input("ravg([3, 4])", "4");
+    test_legal_input("ravg(1, 2, 4)", "2");
+    test_legal_input("ravg(4d6)", "roundeven(avg(tolist(4d6)))");
+    test_legal_input("roundeven(-2.5)", "-2");
+    test_legal_input("roundeven(1d6/2)", "roundeven(1d6/2)");
+    test_illegal_input("roundeven([1.5, 2.5])");
+    test_illegal_input("roundeven(1, 2)");
+}
+
+#[test]
+fn groupkh_function() {
+    test_legal_input("groupkh(2d6, 3d4, 1)", "groupkh(2d6,3d4,1)");
+    test_legal_input("groupkh(2d6, 1+2, 1d6)", "groupkh(2d6,3,1d6)");
+    test_legal_input("groupkh(3, 7, 5, 2)", "12");
+    test_illegal_input("groupkh(2d6)");
+    test_illegal_input("groupkh([2d6, 3d4], 1)");
+}
+
+#[test]
+fn unary_functions_on_success_pool() {
+    test_legal_input("floor(2d6cs>=4)", "floor(2d6cs>=4)");
+    test_legal_input("abs(2d6cs>=4 - 3)", "abs(2d6cs>=4-3)");
+    test_legal_input("ceil(4d6cs>4df1 / 2)", "ceil(4d6cs>4df=1/2)");
+    test_legal_input("roundeven(2d6cs>=4 / 2)", "roundeven(2d6cs>=4/2)");
+    test_legal_input("abs(2d6cs>=4, 1)", "abs([2d6cs>=4,1])");
+}
+
+#[test]
+fn success_pool_in_arithmetic() {
+    test_legal_input("2d6cs>=5 + 3", "2d6cs>=5+3");
+    test_legal_input("[2d6cs>=5 + 3, 1]", "[2d6cs>=5+3,1]");
+    test_legal_input("(2d6cs>=5 + 3)d6", "(2d6cs>=5+3)d6");
+    test_legal_input("len(2d6cs>=5 + 3)", "1");
+    // 相加后是普通数字，不再是骰池
+    test_illegal_input("(2d6cs>=5 + 3)kh1");
+    test_illegal_input("(2d6cs>=5 + 3)cs>1");
+}
+
+#[test]
+fn aggregates_over_success_pools() {
+    // 成功池作为聚合函数的参数时取净成功数
+    test_legal_input("sum(2d6cs>=5, 3)", "2d6cs>=5+3");
+    test_legal_input("max(2d6cs>=5, 3d6cs>4)", "max([2d6cs>=5,3d6cs>4])");
+    test_legal_input("min(2d6cs>=5, 1d4, 2)", "min([2d6cs>=5,1d4,2])");
+    test_legal_input("avg(4d6cs>3df1, 1)", "avg([4d6cs>3df=1,1])");
+    test_legal_input("len(2d6cs>=5, 3)", "2");
+}
+
+#[test]
+fn maxor_minor_functions() {
+    test_legal_input("maxor([], 0)", "0");
+    test_legal_input("minor([], 1d4)", "1d4");
+    test_legal_input("maxor([3, 1, 2], 0)", "3");
+    test_legal_input("minor([3, 1, 2], 0)", "1");
+    test_legal_input("maxor(4d6dl1, 0)", "maxor(tolist(4d6dl1),0)");
+    test_legal_input("minor([1d6, 2], -1)", "minor([1d6,2],-1)");
+    test_illegal_input("maxor([1, 2])");
+    test_illegal_input("maxor(1, 2)");
+    test_illegal_input("minor([1, 2], [3])");
+    test_illegal_input("maxor([1], 2, 3)");
+}
+
+#[test]
+fn let_bindings() {
+    test_legal_input("let atk = 1d20+5; atk + atk", "2d20+10");
+    test_legal_input(
+        "let share atk = 1d20+5; atk + atk",
+        "let share va = 1d20+5; va+va",
+    );
+    test_legal_input(
+        "let share a = 1d6; let share b = a * 2; b + a",
+        "let share va = 1d6; let share vb = va*2; vb+va",
+    );
+    test_legal_input("let share n = 1d4; (n)d6", "let share va = 1d4; (va)d6");
+    test_legal_input("let n = 3; let p = (n)d6; (p)kh2", "3d6kh2");
+    test_legal_input("let share c = 2 + 3; c * c", "25");
+    test_illegal_input("let share l = [1d6, 2]; l");
+    test_illegal_input("let share p = 4d6; (p)kh3");
+    test_illegal_input("let x = 1d6; y");
+}
+
+#[test]
+fn let_share_round_trip() {
+    // 输出中保留 let share 绑定，重新解析后得到相同的输出
+    for input in [
+        "let share atk = 1d20+5; atk + atk",
+        "let share a = 1d6; let share b = a * 2; b + a",
+        "let share n = 1d4; (n)d6 + n",
+        "let share x = 1d6; -x + [x, 2]",
+    ] {
+        let printed = parse_dice_and_show(input).unwrap();
+        assert_eq!(parse_dice_and_show(&printed).unwrap(), printed, "{}", input);
+    }
+}
+
+#[test]
+fn let_bindings_expansion_limit() {
+    // 变量名只能由字母组成：xa, xb, ..., xz, xba, ...
+    let name = |mut i: usize| {
+        let mut name = String::from("x");
+        loop {
+            name.push((b'a' + (i % 26) as u8) as char);
+            i /= 26;
+            if i == 0 {
+                break name;
+            }
+        }
+    };
+    // 每一层都引用上一层两次，替换后的表达式成倍增长
+    let mut input = format!("let {} = 1d6; ", name(0));
+    for i in 1..40 {
+        input.push_str(&format!(
+            "let {} = {}+{}; ",
+            name(i),
+            name(i - 1),
+            name(i - 1)
+        ));
+    }
+    input.push_str(&name(39));
+    test_illegal_input(&input);
+}
+
+#[test]
+fn sort_places_nan_last() {
+    test_legal_input("sort([1e308*10 - 1e308*10, 3, 1])", "[1,3,NaN]");
+    test_legal_input("sortd([1e308*10 - 1e308*10, 3, 1])", "[3,1,NaN]");
+}
+
+#[test]
+fn filter_by_position() {
+    test_legal_input("filtereven([1,2,3,4])", "[1,3]");
+    test_legal_input("filterodd([1,2,3,4])", "[2,4]");
+    test_legal_input("filterodd([1])", "[]");
+    test_legal_input("filtereven(tolist(4d6))", "filtereven(tolist(4d6))");
+    test_illegal_input("filtereven(1d6)");
+    test_illegal_input("filterodd([1,2],[3,4])");
+    test_illegal_input("filterodd(1,2,3)");
+}
+
+#[test]
+fn sortdice_function() {
+    test_legal_input("sortdice(4d6)", "sort(tolist(4d6))");
+    test_legal_input("sortdiced(4d6dl1)", "sortd(tolist(4d6dl1))");
+    test_legal_input("sortdice(5d10cs>7)", "sort(tolist(5d10cs>7))");
+    test_illegal_input("sortdice(3)");
+    test_illegal_input("sortdice([1, 2])");
+    test_illegal_input("sortdice(1d6, 2)");
+}
+
+#[test]
+fn rounding_with_decimal_places() {
+    test_legal_input("round(3.14159, 2)", "3.14");
+    test_legal_input("floor(3.999, 1)", "3.9");
+    test_legal_input("ceil(3.001, 2)", "3.01");
+    test_legal_input("round(2.5, 0)", "3");
+    test_legal_input("round([1.234, 5.678], 1)", "[1.2,5.7]");
+    test_legal_input("round(1d6 / 3, 2)", "round(1d6/3*100)/100");
+    // 放大后的浮点误差不会让 floor/ceil 多舍去或多进一位
+    test_legal_input("floor(0.29, 2)", "0.29");
+    test_legal_input("ceil(1.1, 2)", "1.1");
+    test_legal_input("floor([0.29, 1.15], 2)", "[0.29,1.15]");
+    test_legal_input("round(1.5, 15)", "1.5");
+    test_illegal_input("round(3.14, 16)");
+    test_illegal_input("round(3.14, 400)");
+    test_illegal_input("round(3.14159, -1)");
+    test_illegal_input("round(3.14159, 1.5)");
+    test_illegal_input("round(3.14159, 1d4)");
+}
+
+#[test]
+fn numeric_comparison() {
+    test_legal_input("(2d6cs>=5) >= 3", "(2d6cs>=5)>=3");
+    test_legal_input("1d20 + 5 >= 15", "1d20+5>=15");
+    test_legal_input("((1d6) = 6) + 1", "((1d6)=6)+1");
+    test_legal_input("3 > 2", "1");
+    test_legal_input("1 + 1 <> 2", "0");
+    // 左侧以骰子或修饰符结尾时必须加括号，空白不改变含义
+    test_legal_input("4d6dl<3", "4d6dl1<3");
+    test_legal_input("(4d6dl)<3", "(4d6dl1)<3");
+    test_legal_input("(1d20 + 1d6) >= 15", "(1d20+1d6)>=15");
+    test_legal_input("1 + (1d6) > 3", "1d6+1>3");
+    test_illegal_input("4d6dl <3");
+    test_illegal_input("4d6kh2>3");
+    test_illegal_input("2d20 < 3");
+    test_illegal_input("2d6cs>=5 >= 3");
+    test_illegal_input("1d6 > 2 > 1");
+    test_illegal_input("[1, 2] > 1");
+    test_illegal_input("2d6 >=");
+    // 输出重新解析后含义不变
+    for input in [
+        "(4d6dl)<3",
+        "(1d20 + 1d6) >= 15",
+        "((4d6kh2) > 3) * 2",
+        "4d6dl<3",
+    ] {
+        let printed = parse_dice_and_show(input).unwrap();
+        assert_eq!(parse_dice_and_show(&printed).unwrap(), printed, "{}", input);
+    }
+}
+
+#[test]
+fn alldice_concatenates_pools() {
+    test_legal_input("alldice(2d6, 3d8)", "tolist(2d6)+tolist(3d8)");
+    test_legal_input("alldice(4d6kh3)", "tolist(4d6kh3)");
+    test_legal_input("sum(alldice(2d6, 1d4!))", "sum(tolist(2d6)+tolist(1d4!))");
+    test_illegal_input("alldice()");
+    test_illegal_input("alldice(2d6, 3)");
+    test_illegal_input("alldice([1, 2])");
+    test_illegal_input("alldice(4d6cs>3)");
+}
+
+#[test]
+fn rounding_to_multiple() {
+    test_legal_input("roundto(12, 5)", "10");
+    test_legal_input("roundto(13, 5)", "15");
+    test_legal_input("floorto(19, 10)", "10");
+    test_legal_input("ceilto(11, 10)", "20");
+    test_legal_input("roundto(7.3, 0.5)", "7.5");
+    test_legal_input("roundto([12, 13, 17], 5)", "[10,15,15]");
+    test_legal_input("roundto(3d6 * 10, 2 * 5)", "round(3d6*10/10)*10");
+    test_legal_input("floorto(1d100, 25)", "floor(1d100/25)*25");
+    test_illegal_input("roundto(12, 0)");
+    test_illegal_input("roundto(12, 1d4)");
+    test_illegal_input("roundto(12, [5])");
+    test_illegal_input("roundto(12)");
+    test_illegal_input("roundto(12, 5, 1)");
+}
+
+#[test]
+fn labeled_dice() {
+    test_legal_input("2d[miss:0, hit:1, crit:2]", "2d[miss:0,hit:1,crit:2]");
+    test_legal_input("d[a:1, b:(2+3)]kh1 + 1", "1d[a:1,b:5]kh1+1");
+    test_legal_input("(1d4)d[-:-1, blank:0, +:1]", "(1d4)d[-:-1,blank:0,+:1]");
+    // 面值必须是常数整数
+    test_illegal_input("d[a:1d6]");
+    test_illegal_input("d[a:0.5]");
+    test_illegal_input("d[a:[1, 2]]");
+    // 所有面都满足重投条件时会无限重投
+    test_illegal_input("2d[a:1, b:1]r1");
+}
+
+#[test]
+fn weighted_success_count() {
+    test_legal_input("10d10cs[=10:2, >=7:1]", "10d10cs[=10:2,>=7:1]");
+    test_legal_input("10d10cs[10:2, >=(3+4):1]", "10d10cs[=10:2,>=7:1]");
+    test_legal_input("4d6kh3cs[>=5:(1+1)]df1", "4d6kh3cs[>=5:2]df=1");
+    test_legal_input("10d10cs[=10:2,>=7:1] + 1", "10d10cs[=10:2,>=7:1]+1");
+    // 比较值与权重必须是常数，权重必须是正整数，只能作用于骰池
+    test_illegal_input("10d10cs[=10:0]");
+    test_illegal_input("10d10cs[=10:1.5]");
+    test_illegal_input("10d10cs[=10:1d4]");
+    test_illegal_input("10d10cs[>=(1d6):1]");
+    test_illegal_input("(4d6cs>3)cs[>=5:2]");
+    test_illegal_input("[1, 2]cs[>=5:2]");
+    test_illegal_input("10d10cs[]");
+}
+
+#[test]
+fn spend_successes() {
+    test_legal_input("6d6sp>=5", "6d6sp>=5");
+    test_legal_input("6d6SPEND6", "6d6sp=6");
+    test_legal_input("6d6sp>=(2+3)df1", "6d6sp>=5df=1");
+    test_legal_input("asdice(6d6sp>=5)kh2", "asdice(6d6sp>=5)kh2");
+    // 比较值必须是常数，只能作用于骰池
+    test_illegal_input("6d6sp>(1d6)");
+    test_illegal_input("(4d6cs>3)sp5");
+    test_illegal_input("[1, 2]sp5");
+    test_illegal_input("6d6sp");
+}
+
+#[test]
+fn explode_until_success_quota() {
+    test_legal_input("2d6!until(cs>=5, 3)", "2d6!until(cs>=5,3)");
+    test_legal_input("2d6!until(cs>=(2+3), 1+2)kh2", "2d6!until(cs>=5,3)kh2");
+    test_legal_input("2d6!until(cs6, 1)cs6", "2d6!until(cs=6,1)cs=6");
+    test_illegal_input("2d6!until(cs>=(1d6), 3)");
+    test_illegal_input("2d6!until(cs>=5, 1d4)");
+    test_illegal_input("2d6!until(cs>=5, 0)");
+    test_illegal_input("2d6!until(cs>=5, 1.5)");
+    test_illegal_input("(2d6cs>5)!until(cs>=5, 3)");
+}
+
+#[test]
+fn explode_if_total() {
+    test_legal_input("4d6!total>=20", "4d6!total>=20");
+    test_legal_input("4d6!TOTAL(10+10)kh3", "4d6!total=20kh3");
+    test_illegal_input("4d6!total>=(1d20)");
+    test_illegal_input("(4d6cs>3)!total>=20");
+    test_illegal_input("4d6!total");
+}
+
+#[test]
+fn net_successes() {
+    test_legal_input("5d10net>=7", "5d10net>=7");
+    test_legal_input("5d10NET(3+4)", "5d10net=7");
+    test_legal_input("5d10net>=7df1", "5d10net>=7df=1");
+    test_illegal_input("5d10net>(1d6)");
+    test_illegal_input("(4d6cs>3)net5");
+    test_illegal_input("5d10net");
+}
+
+#[test]
+fn success_cascade() {
+    test_legal_input("6d10cascade>=8lt3", "6d10cascade>=8lt3");
+    test_legal_input("4d6kh3cascade=(2*3)lt(1+1)", "4d6kh3cascade=6lt2");
+    test_legal_input("4d6cascade>=5lt2 + 1", "4d6cascade>=5lt2+1");
+    test_legal_input("4d6cascade>=5lt2cs=1", "4d6cascade>=5lt2cs=1");
+    // 成功条件与轮数必须是常量，且只接受 lt 限制
+    test_illegal_input("4d6cascade>=(1d6)lt2");
+    test_illegal_input("4d6cascade>=5lt(1d3)");
+    test_illegal_input("4d6cascade>=5lt2lc1");
+    test_illegal_input("4d6cascade>=5lt2lsz8");
+    test_illegal_input("4d6cascade>=5");
+    test_illegal_input("(2d6cs>3)cascade>=5lt2");
+}
+
+#[test]
+fn each_die_adjustment() {
+    test_legal_input("each(4d6, +1)", "each(4d6,+1)");
+    test_legal_input("each(4d6, 1)", "each(4d6,+1)");
+    test_legal_input("each(4d6, -1)kh3", "each(4d6,-1)kh3");
+    test_legal_input("each(4d6, +(-1))", "each(4d6,-1)");
+    test_legal_input("each(2d6, *(1+1)) + 3", "each(2d6,*2)+3");
+    test_legal_input("each(each(2d6, +1), *2)", "each(each(2d6,+1),*2)");
+    test_legal_input("each(4d6kh3, +1)cs>=6", "each(4d6kh3,+1)cs>=6");
+    // 调整值必须是常数整数，第一个参数必须是骰池
+    test_illegal_input("each(4d6, 1d4)");
+    test_illegal_input("each(4d6, 0.5)");
+    test_illegal_input("each(4d6, [1, 2])");
+    test_illegal_input("each(2d6cs>3, 1)");
+    test_illegal_input("each(2d6 + 1, 1)");
+    test_illegal_input("each(4d6)");
+    test_illegal_input("each(4d6, 1, 2)");
+}
+
+#[test]
+fn highdie_lowdie() {
+    test_legal_input("highdie(4d6) + 2", "highdie(4d6)+2");
+    test_legal_input("lowdie(4d6kh3)", "lowdie(4d6kh3)");
+    test_legal_input("highdie(2d20!)*2", "highdie(2d20!)*2");
+    // 只接受一个骰池参数
+    test_illegal_input("highdie([1, 2, 3])");
+    test_illegal_input("highdie(2d6cs>3)");
+    test_illegal_input("lowdie(4d6, 2d6)");
+    test_illegal_input("lowdie()");
+}
+
+#[test]
+fn percentile_dice() {
+    test_legal_input("d%%", "d%%");
+    test_legal_input("d%% + 10", "d%%+10");
+    test_legal_input("d%%kh1", "d%%kh1");
+    test_illegal_input("2d%%");
+    test_illegal_input("(1d4)d%%");
+}
+
+#[test]
+fn asdice_function() {
+    test_legal_input("asdice(4d6cs>=5)kh1", "asdice(4d6cs>=5)kh1");
+    test_legal_input("asdice(4d6cs>=5df1)!", "asdice(4d6cs>=5df=1)!");
+    test_legal_input("asdice(4d6cs>=5) + 1", "asdice(4d6cs>=5)+1");
+    test_illegal_input("asdice(3d6)");
+    test_illegal_input("asdice(3)");
+    test_illegal_input("asdice(3d6cs>3, 3d6cs>3)");
 }